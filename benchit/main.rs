@@ -5,20 +5,24 @@ extern crate env_logger;
 extern crate futures;
 extern crate getopts;
 extern crate histogram;
+extern crate libc;
 #[macro_use]
 extern crate log;
 extern crate bytes;
+extern crate num_cpus;
 extern crate rand;
 extern crate tokio;
 
 use bytes::Bytes;
-use client::{AppendSentFuture, Configuration, Connection, LogServerClient};
+use client::{AppendSentFuture, Configuration, Connection, Endpoint, LogServerClient};
 use futures::stream::poll_fn;
 use futures::{Async, Future, Poll, Stream};
 use getopts::Options;
 use rand::{distributions::Alphanumeric, rngs::SmallRng, Rng, SeedableRng};
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::env;
+use std::fs;
+use std::io;
 use std::process::exit;
 use std::rc::Rc;
 use std::time::{Duration, Instant};
@@ -31,6 +35,10 @@ macro_rules! to_ms {
     };
 }
 
+/// Upper bound of the first latency bucket of the `--hist-buckets` output,
+/// in nanos. Each following bucket doubles the bound.
+const FIRST_BUCKET_NANOS: u64 = 62_500;
+
 struct RandomSource {
     chars: usize,
     rand: SmallRng,
@@ -53,11 +61,105 @@ impl RandomSource {
     }
 }
 
+/// Records read from a file, appended in order and looping back to
+/// the start once exhausted.
+struct FileSource {
+    records: Vec<Bytes>,
+    pos: usize,
+}
+
+impl FileSource {
+    /// Reads newline-delimited records, or records prefixed with a
+    /// big-endian u32 length.
+    fn open(path: &str, length_delimited: bool) -> io::Result<FileSource> {
+        let data = Bytes::from(fs::read(path)?);
+        let records = if length_delimited {
+            let mut records = Vec::new();
+            let mut pos = 0;
+            while pos < data.len() {
+                if pos + 4 > data.len() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Truncated record length",
+                    ));
+                }
+                let mut len = [0u8; 4];
+                len.copy_from_slice(&data[pos..pos + 4]);
+                let len = u32::from_be_bytes(len) as usize;
+                pos += 4;
+                if pos + len > data.len() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Truncated record",
+                    ));
+                }
+                records.push(data.slice(pos, pos + len));
+                pos += len;
+            }
+            records
+        } else {
+            let mut records = Vec::new();
+            let mut start = 0;
+            for (i, b) in data.iter().enumerate() {
+                if *b == b'\n' {
+                    if i > start {
+                        records.push(data.slice(start, i));
+                    }
+                    start = i + 1;
+                }
+            }
+            if start < data.len() {
+                records.push(data.slice_from(start));
+            }
+            records
+        };
+
+        if records.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "No records in input file",
+            ));
+        }
+
+        info!("Loaded {} records from {}", records.len(), path);
+        Ok(FileSource { records, pos: 0 })
+    }
+
+    fn next_record(&mut self) -> Bytes {
+        let record = self.records[self.pos].clone();
+        self.pos = (self.pos + 1) % self.records.len();
+        record
+    }
+}
+
+/// Payloads appended by the benchmark.
+enum Payloads {
+    /// The same random payload for each append
+    Random(Bytes),
+    /// Records from a shared file source, along with the count of bytes appended
+    File(Rc<RefCell<FileSource>>, Rc<Cell<u64>>),
+}
+
+impl Payloads {
+    fn next_payload(&mut self) -> Bytes {
+        match *self {
+            Payloads::Random(ref b) => b.clone(),
+            Payloads::File(ref source, ref appended_bytes) => {
+                let record = source.borrow_mut().next_record();
+                appended_bytes.set(appended_bytes.get() + record.len() as u64);
+                record
+            }
+        }
+    }
+}
+
 #[allow(dead_code)]
 struct Metrics {
     state: histogram::Histogram,
     conn: Connection,
     msg_size: usize,
+    appended_bytes: Option<Rc<Cell<u64>>>,
+    hist_buckets: bool,
 }
 
 impl Metrics {
@@ -65,12 +167,16 @@ impl Metrics {
         mut conn: Connection,
         start_instant: Instant,
         msg_size: usize,
+        appended_bytes: Option<Rc<Cell<u64>>>,
+        hist_buckets: bool,
     ) -> impl Future<Item = (), Error = ()> {
         let replies = conn.raw_replies(0);
         let metrics = Rc::new(RefCell::new(Metrics {
             state: histogram::Histogram::default(),
             conn,
             msg_size,
+            appended_bytes,
+            hist_buckets,
         }));
 
         let periodic_report = {
@@ -115,21 +221,52 @@ impl Metrics {
         self.state.increment(nanos).unwrap();
     }
 
+    /// Counts of the latencies in log-spaced buckets, as the upper bound of
+    /// each bucket in nanos and the count. Empty buckets are omitted.
+    fn log_buckets(&self) -> Vec<(u64, u64)> {
+        let mut buckets: Vec<(u64, u64)> = Vec::new();
+        for bucket in &self.state {
+            if bucket.count() == 0 {
+                continue;
+            }
+
+            let mut bound = FIRST_BUCKET_NANOS;
+            while bound <= bucket.value() {
+                bound *= 2;
+            }
+            match buckets.last_mut() {
+                Some(last) if last.0 == bound => last.1 += bucket.count(),
+                _ => buckets.push((bound, bucket.count())),
+            }
+        }
+        buckets
+    }
+
     fn snapshot(&mut self) -> Result<(), &str> {
-        let (requests, p95, p99, p999, max) = {
+        let (requests, p95, p99, p999, max, buckets) = {
+            let buckets = if self.hist_buckets {
+                Some(self.log_buckets())
+            } else {
+                None
+            };
             let v = (
                 self.state.entries(),
                 self.state.percentile(95.0)?,
                 self.state.percentile(99.0)?,
                 self.state.percentile(99.9)?,
                 self.state.maximum()?,
+                buckets,
             );
             self.state.clear();
             v
         };
 
         let req_per_sec = (requests as f32) / 10f32;
-        let mb_per_sec = req_per_sec * (self.msg_size as f32) / 1_000_000f32;
+        let mb_per_sec = match self.appended_bytes {
+            // measure from the actual payloads in the input file
+            Some(ref bytes) => (bytes.replace(0) as f32) / 10f32 / 1_000_000f32,
+            None => req_per_sec * (self.msg_size as f32) / 1_000_000f32,
+        };
         info!("AVG REQ/s :: {}, {} MB/s", req_per_sec, mb_per_sec);
         info!(
             "LATENCY(ms) :: p95: {}, p99: {}, p999: {}, max: {}",
@@ -138,14 +275,57 @@ impl Metrics {
             to_ms!(p999),
             to_ms!(max)
         );
+        if let Some(buckets) = buckets {
+            let buckets = buckets
+                .iter()
+                .map(|&(bound, count)| format!("<{}: {}", to_ms!(bound), count))
+                .collect::<Vec<_>>();
+            info!("LATENCY BUCKETS(ms) :: {}", buckets.join(", "));
+        }
         Ok(())
     }
 }
 
+/// Pins the current thread to the CPU core.
+#[cfg(target_os = "linux")]
+fn pin_to_core(core: usize) -> io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+        libc::CPU_SET(core, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_to_core(_core: usize) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "CPU pinning is only supported on Linux",
+    ))
+}
+
+/// Pins the benchmark worker to a dedicated core, before the worker
+/// starts its runtime.
+fn pin_worker(worker: usize) {
+    let core = worker % num_cpus::get();
+    match pin_to_core(core) {
+        Ok(()) => info!("Pinned worker {} to core {}", worker, core),
+        Err(e) => error!("Unable to pin worker {} to core {}: {}", worker, core, e),
+    }
+}
+
 struct BenchOptions {
     management_server_addr: String,
     throughput: u32,
     bytes: usize,
+    pin_cpus: bool,
+    input: Option<String>,
+    length_delimited: bool,
+    hist_buckets: bool,
 }
 
 impl BenchOptions {
@@ -158,11 +338,32 @@ impl BenchOptions {
         opts.optopt(
             "a",
             "management-address",
-            "address of the management server",
+            "address of the management server, or unix:PATH to connect directly to a local node",
             "HOST:PORT",
         );
         opts.optopt("t", "throughput", "number of connections per second", "N");
         opts.optopt("b", "bytes", "number of bytes per message", "N");
+        opts.optopt(
+            "i",
+            "input",
+            "append records from the file, rather than random bytes",
+            "FILE",
+        );
+        opts.optflag(
+            "",
+            "length-delimited",
+            "input records are prefixed by a big-endian u32 length, rather than newline-delimited",
+        );
+        opts.optflag(
+            "",
+            "pin-cpus",
+            "pin each benchmark worker thread to a dedicated core",
+        );
+        opts.optflag(
+            "",
+            "hist-buckets",
+            "also print the counts of latencies in log-spaced buckets with each summary",
+        );
         opts.optflag("h", "help", "print this help menu");
 
         let matches = match opts.parse(&args[1..]) {
@@ -190,6 +391,10 @@ impl BenchOptions {
             management_server_addr: mgmt_addr,
             throughput,
             bytes,
+            pin_cpus: matches.opt_present("pin-cpus"),
+            input: matches.opt_str("i"),
+            length_delimited: matches.opt_present("length-delimited"),
+            hist_buckets: matches.opt_present("hist-buckets"),
         }
     }
 }
@@ -204,7 +409,7 @@ struct Appender {
     conn: Connection,
     interval: Interval,
     state: AppenderState,
-    rand: Bytes,
+    payloads: Payloads,
 }
 
 impl Future for Appender {
@@ -227,7 +432,8 @@ impl Future for Appender {
                     // with the time delta.
                     let since_start = Instant::now() - self.start_instant;
                     let req_id = since_start.as_nanos() as u64;
-                    AppenderState::Sending(self.conn.raw_append(0, req_id, self.rand.clone()))
+                    let payload = self.payloads.next_payload();
+                    AppenderState::Sending(self.conn.raw_append(0, req_id, payload))
                 }
             };
             self.state = next_state;
@@ -241,32 +447,58 @@ pub fn main() {
     let opts = BenchOptions::parse();
 
     let mut client_config = Configuration::default();
-    client_config
-        .management_server(&opts.management_server_addr)
-        .unwrap();
+    if opts.management_server_addr.starts_with("unix:") {
+        let endpoint: Endpoint = opts.management_server_addr.parse().unwrap();
+        client_config.direct(endpoint);
+    } else {
+        client_config
+            .management_server(&opts.management_server_addr)
+            .unwrap();
+    }
     let client = LogServerClient::new(client_config);
 
+    // TODO: pin each worker once the benchmark is multi-threaded
+    if opts.pin_cpus {
+        pin_worker(0);
+    }
+
     let mut rt = Runtime::new().unwrap();
     let start_instant = Instant::now();
 
+    let file_source = opts.input.as_ref().map(|path| {
+        let source = FileSource::open(path, opts.length_delimited).unwrap_or_else(|e| {
+            error!("Unable to read input file {}: {}", path, e);
+            exit(1);
+        });
+        (Rc::new(RefCell::new(source)), Rc::new(Cell::new(0u64)))
+    });
+    let appended_bytes = file_source.as_ref().map(|&(_, ref bytes)| bytes.clone());
+
     let msg_size = opts.bytes;
+    let hist_buckets = opts.hist_buckets;
     rt.spawn(
         client
             .new_connection()
             .map_err(|e| {
                 error!("Error opening connection: {}", e);
             })
-            .and_then(move |conn| Metrics::spawn(conn, start_instant, msg_size)),
+            .and_then(move |conn| {
+                Metrics::spawn(conn, start_instant, msg_size, appended_bytes, hist_buckets)
+            }),
     );
 
     let mut throughput = opts.throughput;
     let mut rand = RandomSource::new(opts.bytes);
+    let mut payloads = move || match file_source {
+        Some((ref source, ref bytes)) => Payloads::File(source.clone(), bytes.clone()),
+        None => Payloads::Random(rand.random_chars().into()),
+    };
 
     // spawn connections that run ever 1ms
     while throughput > 1000 {
         throughput -= 1000;
 
-        let rand: Bytes = rand.random_chars().into();
+        let payloads = payloads();
         rt.spawn(
             client
                 .new_connection()
@@ -281,14 +513,14 @@ pub fn main() {
                         start_instant + Duration::from_millis(1),
                         Duration::from_millis(1),
                     ),
-                    rand,
+                    payloads,
                 }),
         );
     }
 
     if throughput > 0 {
         let wait = Duration::from_millis((1000 / throughput).into());
-        let rand: Bytes = rand.random_chars().into();
+        let payloads = payloads();
         rt.spawn(
             client
                 .new_connection()
@@ -299,7 +531,7 @@ pub fn main() {
                     conn,
                     state: AppenderState::Waiting,
                     interval: Interval::new(start_instant + wait, wait),
-                    rand,
+                    payloads,
                     start_instant,
                 }),
         );
@@ -33,6 +33,9 @@ const USAGE: &str = "
     read [offset]
         Reads the log from the starting offset.
 
+    summary
+        Shows the size and offsets of the log.
+
     help
         Shows this menu.
 
@@ -126,6 +129,12 @@ fn request_stream(mut conn: client::Connection) -> impl Stream<Item = String, Er
                         trace!("GOT OFFSET: {:?}", off);
                         off.map(|off| format!("{}", off)).unwrap_or_default()
                     })),
+                    "summary" => Box::new(conn.summary().map(|s| {
+                        format!(
+                            "offsets: {:?}..{:?}, {} bytes in {} segments",
+                            s.first_offset, s.last_offset, s.bytes, s.segments
+                        )
+                    })),
                     "read" => match u64::from_str_radix(&rest, 10) {
                         Ok(offset) => {
                             Box::new(conn.read(offset, MAX_READ_BYTES).map(|msgs| {
@@ -1,6 +1,7 @@
 use fnv::FnvHashMap;
 use futures::sync::oneshot;
 use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use goodbye::Goodbye;
 use protocol::{LogStorageClient, ReplyRequest};
 use rand::{rngs::OsRng, RngCore};
 use std::cell::RefCell;
@@ -10,8 +11,8 @@ use tokio::executor::current_thread::spawn;
 
 const START_REQUEST_SIZE: usize = 64;
 
-pub type Receiver = oneshot::Receiver<()>;
-pub type Sender = oneshot::Sender<()>;
+pub type Receiver = oneshot::Receiver<Result<(), Goodbye>>;
+pub type Sender = oneshot::Sender<Result<(), Goodbye>>;
 
 struct RequestMapState(FnvHashMap<u64, Sender>, u64);
 
@@ -28,6 +29,21 @@ type RequestMap = Rc<RefCell<RequestMapState>>;
 
 struct Completor(RequestMap);
 
+impl Completor {
+    /// Fails all outstanding requests after the server closed the stream
+    fn goodbye(&mut self, goodbye: Goodbye) {
+        let mut p = self.0.borrow_mut();
+        warn!(
+            "Server closed reply stream ({}), failing {} requests",
+            goodbye,
+            p.0.len()
+        );
+        for (_, v) in p.0.drain() {
+            v.send(Err(goodbye)).unwrap_or(());
+        }
+    }
+}
+
 impl Sink for Completor {
     type SinkItem = Vec<u64>;
     type SinkError = ();
@@ -36,7 +52,7 @@ impl Sink for Completor {
         let mut p = self.0.borrow_mut();
         for req_id in item {
             if let Some(v) = p.0.remove(&req_id) {
-                v.send(()).unwrap_or(());
+                v.send(Ok(())).unwrap_or(());
             }
         }
         Ok(AsyncSink::Ready)
@@ -82,13 +98,14 @@ impl RequestManager {
             io::Error::new(io::ErrorKind::Other, "Error opening stream")
         })?;
 
-        spawn(
-            reply_stream
-                .map(|reply| reply.client_request_ids)
-                .map_err(|_| ())
-                .forward(Completor(map.clone()))
-                .map(|_| ()),
-        );
+        let mut completor = Completor(map.clone());
+        spawn(reply_stream.map_err(|_| ()).for_each(move |mut reply| {
+            completor.start_send(reply.take_client_request_ids())?;
+            if let Some(goodbye) = Goodbye::from_reason(reply.get_goodbye()) {
+                completor.goodbye(goodbye);
+            }
+            Ok(())
+        }));
 
         Ok(RequestManager {
             requests: map,
@@ -133,9 +150,31 @@ mod tests {
 
         // ensure triggered
         assert_eq!(
-            Ok(Async::Ready(())),
+            Ok(Async::Ready(Ok(()))),
+            recv.poll_future_notify(&notify_noop(), 1)
+        );
+    }
+
+    #[test]
+    fn waitingpool_goodbye_fails_requests() {
+        let map = Rc::new(RefCell::new(RequestMapState::default()));
+
+        let mut waiting_pool = Completor(map.clone());
+        let mut mgr = RequestManager {
+            requests: map.clone(),
+            client_id: 0,
+        };
+
+        let (_, recv) = mgr.push_req();
+        let mut recv = spawn(recv);
+
+        waiting_pool.goodbye(Goodbye::Draining);
+
+        assert_eq!(
+            Ok(Async::Ready(Err(Goodbye::Draining))),
             recv.poll_future_notify(&notify_noop(), 1)
         );
+        assert!(map.borrow().0.is_empty());
     }
 
     fn notify_noop() -> NotifyHandle {
@@ -169,7 +208,7 @@ mod tests {
                 );
                 waiting_pool.start_send(vec![req_id]).unwrap();
                 assert_eq!(
-                    Ok(Async::Ready(())),
+                    Ok(Async::Ready(Ok(()))),
                     recv.poll_future_notify(&notify_noop(), 1)
                 );
             }
@@ -198,7 +237,7 @@ mod tests {
 
             for mut recv in recvs {
                 assert_eq!(
-                    Ok(Async::Ready(())),
+                    Ok(Async::Ready(Ok(()))),
                     recv.poll_future_notify(&notify_noop(), 1)
                 );
             }
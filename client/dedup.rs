@@ -0,0 +1,124 @@
+use futures::{Async, Poll, Stream};
+
+/// Filters redelivered entries from a stream of `(offset, entry)` pairs, for
+/// consumers of at-least-once delivery.
+///
+/// Entries at or below the last offset seen are dropped as duplicates, as
+/// retries and resumed reads only redeliver recent entries. An offset more
+/// than `max_rewind` below the last offset seen is treated as a reset of the
+/// log, such as by truncation: the last offset seen is cleared and the entry
+/// is passed through. Resets within the window can be signalled with
+/// `reset`.
+pub struct DedupStream<S> {
+    stream: S,
+    last_offset: Option<u64>,
+    max_rewind: u64,
+}
+
+impl<S, T> DedupStream<S>
+where
+    S: Stream<Item = (u64, T)>,
+{
+    pub fn new(stream: S, max_rewind: u64) -> DedupStream<S> {
+        DedupStream {
+            stream,
+            last_offset: None,
+            max_rewind,
+        }
+    }
+
+    /// Last offset passed through the stream.
+    pub fn last_offset(&self) -> Option<u64> {
+        self.last_offset
+    }
+
+    /// Clears the last offset seen, passing through the next entry at
+    /// any offset.
+    pub fn reset(&mut self) {
+        self.last_offset = None;
+    }
+
+    fn is_duplicate(&self, offset: u64) -> bool {
+        match self.last_offset {
+            Some(last) if offset <= last => {
+                if last - offset <= self.max_rewind {
+                    return true;
+                }
+                debug!(
+                    "Offset {} is {} behind the last offset {}, resetting",
+                    offset,
+                    last - offset,
+                    last
+                );
+                false
+            }
+            _ => false,
+        }
+    }
+}
+
+impl<S, T> Stream for DedupStream<S>
+where
+    S: Stream<Item = (u64, T)>,
+{
+    type Item = (u64, T);
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<(u64, T)>, S::Error> {
+        loop {
+            let (offset, entry) = match try_ready!(self.stream.poll()) {
+                Some(v) => v,
+                None => return Ok(Async::Ready(None)),
+            };
+
+            if self.is_duplicate(offset) {
+                trace!("Dropping duplicate entry at offset {}", offset);
+                continue;
+            }
+            self.last_offset = Some(offset);
+            return Ok(Async::Ready(Some((offset, entry))));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+
+    fn dedup(offsets: Vec<u64>, max_rewind: u64) -> Vec<u64> {
+        let entries = offsets.into_iter().map(|off| (off, ())).collect::<Vec<_>>();
+        DedupStream::new(stream::iter_ok::<_, ()>(entries), max_rewind)
+            .wait()
+            .map(|res| res.unwrap().0)
+            .collect()
+    }
+
+    #[test]
+    fn filters_redelivered_entries() {
+        // a retried read and a resumed read redeliver entries
+        assert_eq!(
+            vec![0, 1, 2, 3, 4, 5, 6],
+            dedup(vec![0, 1, 2, 1, 2, 3, 4, 3, 4, 4, 5, 6], 100)
+        );
+    }
+
+    #[test]
+    fn resets_after_truncation() {
+        // truncated to offset 2, far behind the last offset
+        assert_eq!(vec![0, 1, 2, 3, 2, 3], dedup(vec![0, 1, 2, 3, 2, 3], 0));
+        assert_eq!(
+            vec![500, 501, 10, 11],
+            dedup(vec![500, 501, 501, 10, 11, 11], 100)
+        );
+
+        // truncated to offset 4, within the window
+        let entries = vec![(5, ()), (4, ())];
+        let mut s = DedupStream::new(stream::iter_ok::<_, ()>(entries), 100);
+        assert_eq!(Ok(Async::Ready(Some((5, ())))), s.poll());
+        s.reset();
+        assert_eq!(None, s.last_offset());
+        assert_eq!(Ok(Async::Ready(Some((4, ())))), s.poll());
+        assert_eq!(Some(4), s.last_offset());
+    }
+}
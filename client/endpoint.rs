@@ -0,0 +1,78 @@
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+const UDS_PREFIX: &str = "unix:";
+
+/// Address of a storage node.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub enum Endpoint {
+    /// TCP socket address
+    Tcp(SocketAddr),
+
+    /// Unix domain socket path, for clients on the same host as the node
+    Uds(PathBuf),
+}
+
+impl Endpoint {
+    /// Address in the format used to open the gRPC channel.
+    pub(crate) fn grpc_addr(&self) -> String {
+        match *self {
+            Endpoint::Tcp(ref addr) => format!("{}:{}", addr.ip(), addr.port()),
+            Endpoint::Uds(ref path) => format!("{}{}", UDS_PREFIX, path.display()),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.grpc_addr())
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = io::Error;
+
+    /// Parses `unix:/path/to/socket` as a Unix domain socket, otherwise
+    /// `host:port` as a TCP address.
+    fn from_str(s: &str) -> Result<Endpoint, io::Error> {
+        if s.starts_with(UDS_PREFIX) {
+            let path = &s[UDS_PREFIX.len()..];
+            if path.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Empty unix domain socket path",
+                ));
+            }
+            return Ok(Endpoint::Uds(PathBuf::from(path)));
+        }
+
+        s.to_socket_addrs()?
+            .next()
+            .map(Endpoint::Tcp)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No SocketAddress found"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_uds() {
+        let e: Endpoint = "unix:/tmp/log.sock".parse().unwrap();
+        assert_eq!(Endpoint::Uds(PathBuf::from("/tmp/log.sock")), e);
+        assert_eq!("unix:/tmp/log.sock", e.grpc_addr());
+
+        assert!("unix:".parse::<Endpoint>().is_err());
+    }
+
+    #[test]
+    fn parse_tcp() {
+        let e: Endpoint = "127.0.0.1:4000".parse().unwrap();
+        assert_eq!(Endpoint::Tcp("127.0.0.1:4000".parse().unwrap()), e);
+        assert_eq!("127.0.0.1:4000", e.grpc_addr());
+    }
+}
@@ -0,0 +1,74 @@
+use protocol::GoodbyeReason;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Reason the server closed the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Goodbye {
+    /// The server is shutting down. Requests should not be retried.
+    Shutdown,
+
+    /// The server is draining. Requests should be sent to another node.
+    Draining,
+
+    /// The connection was idle for too long.
+    IdleTimeout,
+
+    /// The server is overloaded. Requests should be retried with backoff.
+    Overloaded,
+}
+
+impl Goodbye {
+    pub(crate) fn from_reason(reason: GoodbyeReason) -> Option<Goodbye> {
+        match reason {
+            GoodbyeReason::GOODBYE_NONE => None,
+            GoodbyeReason::SHUTDOWN => Some(Goodbye::Shutdown),
+            GoodbyeReason::DRAINING => Some(Goodbye::Draining),
+            GoodbyeReason::IDLE_TIMEOUT => Some(Goodbye::IdleTimeout),
+            GoodbyeReason::OVERLOADED => Some(Goodbye::Overloaded),
+        }
+    }
+
+    /// Extracts the goodbye from an error returned by the client, if the
+    /// error was caused by the server closing the connection.
+    pub fn from_error(e: &io::Error) -> Option<Goodbye> {
+        e.get_ref()
+            .and_then(|inner| inner.downcast_ref::<Goodbye>())
+            .cloned()
+    }
+
+    /// Whether the request may succeed if retried, possibly on another node.
+    pub fn is_retryable(self) -> bool {
+        self != Goodbye::Shutdown
+    }
+}
+
+impl fmt::Display for Goodbye {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl Error for Goodbye {
+    fn description(&self) -> &str {
+        match *self {
+            Goodbye::Shutdown => "Server shutting down",
+            Goodbye::Draining => "Server draining",
+            Goodbye::IdleTimeout => "Connection idle timeout",
+            Goodbye::Overloaded => "Server overloaded",
+        }
+    }
+}
+
+impl From<Goodbye> for io::Error {
+    fn from(goodbye: Goodbye) -> io::Error {
+        let kind = match goodbye {
+            Goodbye::Shutdown => io::ErrorKind::ConnectionAborted,
+            Goodbye::Draining => io::ErrorKind::ConnectionReset,
+            Goodbye::IdleTimeout => io::ErrorKind::TimedOut,
+            Goodbye::Overloaded => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, goodbye)
+    }
+}
@@ -1,5 +1,6 @@
 #![feature(test)]
 extern crate bytes;
+extern crate crc32fast;
 extern crate test;
 #[macro_use]
 extern crate futures;
@@ -12,28 +13,50 @@ extern crate rand;
 extern crate tokio;
 
 mod append;
+mod dedup;
+mod endpoint;
+mod goodbye;
 mod protocol;
+mod shard;
+mod socket;
 
 use bytes::Bytes;
-use futures::future::Join;
+use futures::future::{join_all, Join};
+use futures::sync::oneshot;
 use futures::{Async, Future, Poll};
 use grpcio::{ChannelBuilder, EnvBuilder, Environment};
 use protocol::*;
+use rand::{rngs::OsRng, RngCore};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
 use std::sync::Arc;
 use std::{io, mem, time};
 use tokio::timer::Delay;
 
-pub use protocol::{AppendSentFuture, LatestOffsetFuture, QueryFuture, Reply, ReplyStream};
+pub use dedup::DedupStream;
+pub use endpoint::Endpoint;
+pub use goodbye::Goodbye;
+pub use protocol::{
+    AppendAckStream, AppendNowFuture, AppendSentFuture, ChecksumMismatch, CreditGrantedFuture,
+    DurableOffsetFuture, FilteredQueryFuture, FlushFuture, FramedQueryFuture, LatestOffsetFuture,
+    LogEntry, LogSummary, LowWatermarkFuture, MetadataQueryFuture, OffsetTrimmed, PageFuture,
+    QueryFuture, Reply, ReplyStream, SegmentInfo, SegmentsFuture, StopQueryFuture, SummaryFuture,
+};
+pub use shard::{shard_for_key, ShardedConnectFuture, ShardedConnection};
+pub use socket::SocketOptions;
 
 // TODO: use exponential backoff
 const SNAPSHOT_BACKOFF_DELAY: time::Duration = time::Duration::from_secs(1);
 
+/// Default maximum payload of an append, the default maximum message size
+/// of the server.
+pub const DEFAULT_MAX_MESSAGE_BYTES: usize = 1_048_576;
+
 pub struct AppendFuture(AppendFutureState, append::Receiver);
 
 enum AppendFutureState {
     Sending(AppendSentFuture),
     Waiting,
+    Rejected(io::Error),
 }
 
 impl Future for AppendFuture {
@@ -50,8 +73,12 @@ impl Future for AppendFuture {
                         return Ok(Async::NotReady);
                     }
                 },
+                AppendFutureState::Rejected(e) => return Err(e),
                 AppendFutureState::Waiting => match self.1.poll() {
-                    Ok(Async::Ready(_)) | Err(_) => {
+                    Ok(Async::Ready(Err(goodbye))) => {
+                        return Err(goodbye.into());
+                    }
+                    Ok(Async::Ready(Ok(()))) | Err(_) => {
                         // TODO: handle err
                         return Ok(Async::Ready(()));
                     }
@@ -64,26 +91,266 @@ impl Future for AppendFuture {
     }
 }
 
+/// Start of a paged read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PageStart {
+    /// Offset of the first page.
+    Offset(u64),
+
+    /// Cursor returned with the previous page.
+    Cursor(String),
+}
+
+/// Predicate on the key and headers of entries, evaluated by the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReadFilterPredicate {
+    /// Entries with the key.
+    KeyEquals(Bytes),
+
+    /// Entries with any of the keys, up to 1024 keys.
+    KeyIn(Vec<Bytes>),
+
+    /// Entries with a header of the name and value.
+    HeaderEquals(String, String),
+}
+
 // TODO: repoll configuration from the management server
 pub struct Connection {
     req_mgr: append::RequestManager,
     head_conn: LogStorageClient,
     tail_conn: LogStorageClient,
+    max_message_bytes: usize,
+    allow_empty_payloads: bool,
+    // producer ID and next sequence of idempotent appends, if enabled
+    producer: Option<(u64, u64)>,
 }
 
 impl Connection {
+    /// Appends an entry, completing once the entry is replicated.
+    ///
+    /// Payloads larger than the maximum message size of the configuration,
+    /// or empty unless allowed, fail with `ErrorKind::InvalidInput` without a
+    /// request to the server, as do those the server rejects as too large.
+    ///
+    /// With idempotence enabled, each append is numbered with the next
+    /// sequence of the producer, returned by `last_sequence`.
     pub fn append(&mut self, body: Bytes) -> AppendFuture {
+        self.append_traced(body, "")
+    }
+
+    /// Appends an entry with the trace context of the caller, such as a W3C
+    /// `traceparent` header, which the server attaches to its spans.
+    pub fn append_traced(&mut self, body: Bytes, trace_id: &str) -> AppendFuture {
+        self.append_request("", body, trace_id)
+    }
+
+    /// Appends an entry to a named topic, created by the server on the first
+    /// append. Topics other than the default are not replicated, so the
+    /// append only completes when the head node is also the tail.
+    pub fn append_to_topic(&mut self, topic: &str, body: Bytes) -> AppendFuture {
+        self.append_request(topic, body, "")
+    }
+
+    /// Retries the idempotent append with the sequence, such as after a
+    /// timeout. If the original append succeeded, the entry is not appended
+    /// again and the retry completes as the original.
+    ///
+    /// Panics unless idempotence is enabled.
+    pub fn retry_append(&mut self, sequence: u64, body: Bytes) -> AppendFuture {
+        let (producer_id, _) = self.producer.expect("Idempotence is not enabled");
+        self.append_sequenced("", body, "", Some((producer_id, sequence)))
+    }
+
+    fn append_request(&mut self, topic: &str, body: Bytes, trace_id: &str) -> AppendFuture {
+        let producer = self.next_sequence();
+        self.append_sequenced(topic, body, trace_id, producer)
+    }
+
+    fn append_sequenced(
+        &mut self,
+        topic: &str,
+        body: Bytes,
+        trace_id: &str,
+        producer: Option<(u64, u64)>,
+    ) -> AppendFuture {
+        if let Err(e) = self.check_payload(&body) {
+            // the request is never sent, so there is no reply to wait for
+            let (_, res) = oneshot::channel();
+            return AppendFuture(AppendFutureState::Rejected(e), res);
+        }
         let (client_request_id, res) = self.req_mgr.push_req();
 
         let mut append_req = AppendRequest::new();
         append_req.set_payload(body);
         append_req.set_client_id(self.req_mgr.client_id());
         append_req.set_client_request_id(client_request_id);
+        append_req.set_trace_id(trace_id.into());
+        append_req.set_topic(topic.into());
+        append_req.set_crc32(crc32fast::hash(&append_req.payload));
+        if let Some((producer_id, sequence)) = producer {
+            append_req.set_producer(producer_sequence(producer_id, sequence));
+        }
 
         let sent = AppendSentFuture::new(self.head_conn.append_async(&append_req));
         AppendFuture(AppendFutureState::Sending(sent), res)
     }
 
+    /// Rejects payloads the server would reject, as configured.
+    fn check_payload(&self, body: &Bytes) -> Result<(), io::Error> {
+        if body.is_empty() && !self.allow_empty_payloads {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Empty payload"));
+        }
+        if body.len() > self.max_message_bytes {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Entry of {} bytes exceeds the maximum message size of {} bytes",
+                    body.len(),
+                    self.max_message_bytes
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Appends a batch of entries in order, yielding the index within the batch
+    /// and the offset of each entry as it is written to the head node.
+    ///
+    /// The acks do not indicate that the entries are replicated.
+    pub fn append_stream(&mut self, payloads: Vec<Bytes>) -> AppendAckStream {
+        let mut req = AppendBatchRequest::new();
+        // replies for the entries use the index as the request ID, so use
+        // a distinct client ID from the appends awaiting replies
+        req.set_client_id(OsRng::new().unwrap().next_u64());
+        req.set_crc32s(payloads.iter().map(|p| crc32fast::hash(p)).collect());
+        req.set_payloads(payloads.into());
+        self.head_conn.append_batch(&req).into()
+    }
+
+    /// Appends a batch of entries in order, returning the offset and payload
+    /// of each entry as stored once the entries are written to the head node.
+    ///
+    /// The result does not indicate that the entries are replicated.
+    pub fn append_and_fetch(&mut self, payloads: Vec<Bytes>) -> QueryFuture {
+        let mut req = AppendBatchRequest::new();
+        req.set_client_id(OsRng::new().unwrap().next_u64());
+        req.set_crc32s(payloads.iter().map(|p| crc32fast::hash(p)).collect());
+        req.set_payloads(payloads.into());
+        QueryFuture::new(self.head_conn.append_and_fetch_async(&req))
+    }
+
+    /// Appends a single entry without waiting for a batch, returning the
+    /// offset once the entry is written to the head node, and flushed to
+    /// disk if `flush` is set.
+    ///
+    /// Intended for rare, latency sensitive entries such as control records.
+    /// The result does not indicate that the entry is replicated.
+    ///
+    /// With idempotence enabled, each append is numbered with the next
+    /// sequence of the producer, returned by `last_sequence`.
+    pub fn append_now(&mut self, payload: Bytes, flush: bool) -> AppendNowFuture {
+        let producer = self.next_sequence();
+        self.append_now_request("", payload, flush, producer)
+    }
+
+    /// Appends a single entry to a named topic as with `append_now`, the
+    /// topic created by the server on the first append.
+    pub fn append_now_to_topic(
+        &mut self,
+        topic: &str,
+        payload: Bytes,
+        flush: bool,
+    ) -> AppendNowFuture {
+        let producer = self.next_sequence();
+        self.append_now_request(topic, payload, flush, producer)
+    }
+
+    /// Producer ID and sequence of the next idempotent append, if enabled.
+    fn next_sequence(&mut self) -> Option<(u64, u64)> {
+        self.producer
+            .as_mut()
+            .map(|&mut (producer_id, ref mut next)| {
+                *next += 1;
+                (producer_id, *next - 1)
+            })
+    }
+
+    /// Enables idempotent appends with `append` and `append_now` for the
+    /// producer, with sequences from zero. The producer ID is to be unique to
+    /// the producer and not reused with sequences from zero once appended to.
+    pub fn enable_idempotence(&mut self, producer_id: u64) {
+        self.producer = Some((producer_id, 0));
+    }
+
+    /// Sequence of the last append with idempotence enabled.
+    pub fn last_sequence(&self) -> Option<u64> {
+        match self.producer {
+            Some((_, next)) if next > 0 => Some(next - 1),
+            _ => None,
+        }
+    }
+
+    /// Retries the idempotent append with the sequence, such as after a
+    /// timeout. If the original append succeeded, the entry is not appended
+    /// again and the offset of the original is returned.
+    ///
+    /// Panics unless idempotence is enabled.
+    pub fn retry_append_now(
+        &mut self,
+        sequence: u64,
+        payload: Bytes,
+        flush: bool,
+    ) -> AppendNowFuture {
+        let (producer_id, _) = self.producer.expect("Idempotence is not enabled");
+        self.append_now_request("", payload, flush, Some((producer_id, sequence)))
+    }
+
+    fn append_now_request(
+        &mut self,
+        topic: &str,
+        payload: Bytes,
+        flush: bool,
+        producer: Option<(u64, u64)>,
+    ) -> AppendNowFuture {
+        let mut req = AppendNowRequest::new();
+        req.set_client_id(OsRng::new().unwrap().next_u64());
+        req.set_crc32(crc32fast::hash(&payload));
+        req.set_payload(payload);
+        req.set_flush(flush);
+        req.set_topic(topic.into());
+        if let Some((producer_id, sequence)) = producer {
+            req.set_producer(producer_sequence(producer_id, sequence));
+        }
+        AppendNowFuture::new(self.head_conn.append_now_async(&req))
+    }
+
+    /// Appends a single entry with a key and headers, returning the offset
+    /// once the entry is written to the head node.
+    ///
+    /// An empty key is stored as distinct from an absent key. The result does
+    /// not indicate that the entry is replicated.
+    pub fn append_record(
+        &mut self,
+        key: Option<Bytes>,
+        headers: Vec<(String, String)>,
+        payload: Bytes,
+    ) -> AppendNowFuture {
+        let mut req = AppendRecordRequest::new();
+        req.set_client_id(OsRng::new().unwrap().next_u64());
+        if let Some(key) = key {
+            req.set_key(key);
+        }
+        for (name, value) in headers {
+            let mut header = Header::new();
+            header.set_name(name.into());
+            header.set_value(value.into());
+            req.mut_headers().push(header);
+        }
+        req.set_crc32(crc32fast::hash(&payload));
+        req.set_payload(payload);
+        AppendNowFuture::new(self.head_conn.append_record_async(&req))
+    }
+
     pub fn raw_append(
         &mut self,
         client_id: u64,
@@ -104,28 +371,276 @@ impl Connection {
         self.tail_conn.replies(&reply_req).unwrap().into()
     }
 
+    /// Opens a flow controlled reply stream. The server sends at most
+    /// `credit` client request IDs until more credit is granted with
+    /// `grant_reply_credit`, holding back the replies beyond the credit.
+    pub fn raw_replies_with_credit(&mut self, client_id: u64, credit: u64) -> ReplyStream {
+        let mut reply_req = ReplyRequest::new();
+        reply_req.set_client_id(client_id);
+        reply_req.set_initial_credit(credit);
+        self.tail_conn.replies(&reply_req).unwrap().into()
+    }
+
+    /// Grants credit for more client request IDs to a flow controlled reply
+    /// stream.
+    pub fn grant_reply_credit(&mut self, client_id: u64, credit: u64) -> CreditGrantedFuture {
+        let mut req = ReplyCredit::new();
+        req.set_client_id(client_id);
+        req.set_credit(credit);
+        CreditGrantedFuture::new(self.tail_conn.grant_reply_credit_async(&req))
+    }
+
     pub fn read(&mut self, start_offset: u64, max_bytes: u32) -> QueryFuture {
+        self.read_topic("", start_offset, max_bytes)
+    }
+
+    /// Reads a named topic from the starting offset. Fails if nothing has
+    /// been appended to the topic.
+    pub fn read_topic(&mut self, topic: &str, start_offset: u64, max_bytes: u32) -> QueryFuture {
         let mut read_req = QueryRequest::new();
         read_req.set_start_offset(start_offset);
         read_req.set_max_bytes(max_bytes);
+        read_req.set_topic(topic.into());
         QueryFuture::new(self.tail_conn.query_log_async(&read_req))
     }
 
+    /// Reads up to `max_messages` entries from the starting offset, within
+    /// `max_bytes`, returning the entries and the offset to continue reading
+    /// from.
+    pub fn read_messages(
+        &mut self,
+        start_offset: u64,
+        max_bytes: u32,
+        max_messages: u32,
+    ) -> FilteredQueryFuture {
+        let mut read_req = QueryRequest::new();
+        read_req.set_start_offset(start_offset);
+        read_req.set_max_bytes(max_bytes);
+        read_req.set_max_messages(max_messages);
+        FilteredQueryFuture::new(self.tail_conn.query_log_async(&read_req))
+    }
+
+    /// Reads the entries matching the predicate from the starting offset,
+    /// returning the entries and the offset to continue reading from.
+    ///
+    /// The next offset advances past the entries that do not match, so it
+    /// may be set when no entries are returned.
+    pub fn read_filtered(
+        &mut self,
+        start_offset: u64,
+        max_bytes: u32,
+        predicate: ReadFilterPredicate,
+    ) -> FilteredQueryFuture {
+        let mut filter = ReadFilter::new();
+        match predicate {
+            ReadFilterPredicate::KeyEquals(key) => filter.set_key_equals(key),
+            ReadFilterPredicate::KeyIn(keys) => {
+                let mut allowlist = KeyAllowlist::new();
+                allowlist.set_keys(keys.into());
+                filter.set_key_in(allowlist);
+            }
+            ReadFilterPredicate::HeaderEquals(name, value) => {
+                let mut header = Header::new();
+                header.set_name(name.into());
+                header.set_value(value.into());
+                filter.set_header_equals(header);
+            }
+        }
+
+        let mut read_req = QueryRequest::new();
+        read_req.set_start_offset(start_offset);
+        read_req.set_max_bytes(max_bytes);
+        read_req.set_filter(filter);
+        FilteredQueryFuture::new(self.tail_conn.query_log_async(&read_req))
+    }
+
+    /// Reads the log from the starting offset, stopping at the first entry
+    /// with the key, such as a checkpoint marker. The entry with the key is
+    /// returned if `inclusive`.
+    ///
+    /// Returns the entries, the offset to continue reading from, and whether
+    /// the read stopped at the key. Without the key in the entries read, the
+    /// read continues from the next offset.
+    pub fn read_until_key(
+        &mut self,
+        start_offset: u64,
+        max_bytes: u32,
+        key: Bytes,
+        inclusive: bool,
+    ) -> StopQueryFuture {
+        let mut stop = StopAtKey::new();
+        stop.set_key(key);
+        stop.set_inclusive(inclusive);
+
+        let mut read_req = QueryRequest::new();
+        read_req.set_start_offset(start_offset);
+        read_req.set_max_bytes(max_bytes);
+        read_req.set_stop_at_key(stop);
+        StopQueryFuture::new(self.tail_conn.query_log_async(&read_req))
+    }
+
+    /// Reads the entries from the starting offset without their payloads,
+    /// up to the end offset (exclusive) if set. The entries carry the
+    /// offset, payload size, key, headers and sequence of each entry.
+    pub fn read_metadata(
+        &mut self,
+        start_offset: u64,
+        end_offset: Option<u64>,
+        max_bytes: u32,
+    ) -> MetadataQueryFuture {
+        let mut read_req = QueryRequest::new();
+        read_req.set_start_offset(start_offset);
+        if let Some(end) = end_offset {
+            read_req.set_end_offset(end);
+        }
+        read_req.set_max_bytes(max_bytes);
+        read_req.set_metadata_only(true);
+        MetadataQueryFuture::new(self.tail_conn.query_log_async(&read_req))
+    }
+
+    /// Reads the log from the starting offset, waiting up to `max_wait` for
+    /// entries to be appended if none exist at the offset.
+    pub fn read_wait(
+        &mut self,
+        start_offset: u64,
+        max_bytes: u32,
+        max_wait: time::Duration,
+    ) -> QueryFuture {
+        let mut read_req = QueryRequest::new();
+        read_req.set_start_offset(start_offset);
+        read_req.set_max_bytes(max_bytes);
+        read_req.set_max_wait_ms(max_wait.as_millis() as u32);
+        QueryFuture::new(self.tail_conn.query_log_async(&read_req))
+    }
+
+    /// Reads the entries with offsets in `[start_offset, end_offset)`: the
+    /// start is inclusive and the end exclusive, so an `end_offset` at or
+    /// before `start_offset` reads no entries. The read does not wait for
+    /// entries to be appended.
+    ///
+    /// Fewer entries than the range are returned when limited by `max_bytes`.
+    pub fn read_range(
+        &mut self,
+        start_offset: u64,
+        end_offset: u64,
+        max_bytes: u32,
+    ) -> QueryFuture {
+        let mut read_req = QueryRequest::new();
+        read_req.set_start_offset(start_offset);
+        read_req.set_end_offset(end_offset);
+        read_req.set_max_bytes(max_bytes);
+        QueryFuture::new(self.tail_conn.query_log_async(&read_req))
+    }
+
+    /// Reads the log from the starting offset, returning the entries encoded
+    /// as frames suitable for forwarding without re-encoding.
+    ///
+    /// Each frame is laid out as `| offset (u64 LE) | payload length (u32 LE) | payload |`,
+    /// ordered by offset with no padding or trailer. The limits and wait
+    /// behave as for `read_wait`.
+    pub fn read_framed(
+        &mut self,
+        start_offset: u64,
+        max_bytes: u32,
+        max_wait: time::Duration,
+    ) -> FramedQueryFuture {
+        let mut read_req = QueryRequest::new();
+        read_req.set_start_offset(start_offset);
+        read_req.set_max_bytes(max_bytes);
+        read_req.set_max_wait_ms(max_wait.as_millis() as u32);
+        read_req.set_framed(true);
+        FramedQueryFuture::new(self.tail_conn.query_log_async(&read_req))
+    }
+
+    /// Reads a page of the log, starting at an offset or at the cursor
+    /// returned with the previous page. The result is the entries and the
+    /// cursor of the next page, or `None` once the page reaches the end of
+    /// the log.
+    ///
+    /// Cursors are opaque and only valid on the server that issued them.
+    pub fn read_page(&mut self, start: PageStart, max_bytes: u32) -> PageFuture {
+        self.read_topic_page("", start, max_bytes)
+    }
+
+    /// Reads a page of a named topic as with `read_page`. Cursors are only
+    /// valid for the topic of the page that returned them.
+    pub fn read_topic_page(&mut self, topic: &str, start: PageStart, max_bytes: u32) -> PageFuture {
+        let mut page_req = PageRequest::new();
+        page_req.set_topic(topic.into());
+        match start {
+            PageStart::Offset(offset) => page_req.set_start_offset(offset),
+            PageStart::Cursor(cursor) => page_req.set_cursor(cursor.into()),
+        }
+        page_req.set_max_bytes(max_bytes);
+        PageFuture::new(self.tail_conn.read_page_async(&page_req))
+    }
+
     pub fn latest_offset(&mut self) -> LatestOffsetFuture {
+        self.topic_latest_offset("")
+    }
+
+    /// Offset of the last entry flushed to disk on the tail node, which
+    /// survives a crash of the node. At or before the latest offset.
+    pub fn durable_offset(&mut self) -> DurableOffsetFuture {
+        let query = LatestOffsetQuery::new();
+        DurableOffsetFuture::new(self.tail_conn.latest_offset_async(&query))
+    }
+
+    /// Flushes the log on the tail node to disk, resolving with the offset
+    /// of the last entry durable on the node after the flush. Entries
+    /// acknowledged before the flush are durable once it resolves.
+    pub fn flush(&mut self) -> FlushFuture {
+        FlushFuture::new(self.tail_conn.flush_async(&FlushRequest::new()))
+    }
+
+    /// First offset retained in the log on the tail node. Reads of offsets
+    /// below it fail with `ErrorKind::NotFound`, carrying an `OffsetTrimmed`
+    /// with the low watermark at the time of the read.
+    pub fn low_watermark(&mut self) -> LowWatermarkFuture {
         let query = LatestOffsetQuery::new();
+        LowWatermarkFuture::new(self.tail_conn.latest_offset_async(&query))
+    }
+
+    /// Latest offset of a named topic. Fails if nothing has been appended to
+    /// the topic.
+    pub fn topic_latest_offset(&mut self, topic: &str) -> LatestOffsetFuture {
+        let mut query = LatestOffsetQuery::new();
+        query.set_topic(topic.into());
         LatestOffsetFuture::new(self.tail_conn.latest_offset_async(&query))
     }
+
+    /// Size and offsets of the log on the tail node, without listing the
+    /// log directory on the node.
+    pub fn summary(&mut self) -> SummaryFuture {
+        SummaryFuture::new(self.tail_conn.summary_async(&SummaryQuery::new()))
+    }
+
+    /// Segments of the log on the tail node, ordered by base offset, as
+    /// listed by the log thread of the node, for backup tooling.
+    pub fn segments(&mut self) -> SegmentsFuture {
+        SegmentsFuture::new(self.tail_conn.segments_async(&SegmentsQuery::new()))
+    }
 }
 
 #[derive(Debug, Clone, Hash, PartialEq)]
 pub struct Configuration {
     management_server: SocketAddr,
+    shards: Vec<SocketAddr>,
+    direct: Option<Endpoint>,
+    socket: SocketOptions,
+    max_message_bytes: usize,
+    allow_empty_payloads: bool,
 }
 
 impl Default for Configuration {
     fn default() -> Configuration {
         Configuration {
             management_server: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 5000),
+            shards: Vec::new(),
+            direct: None,
+            socket: SocketOptions::default(),
+            max_message_bytes: DEFAULT_MAX_MESSAGE_BYTES,
+            allow_empty_payloads: false,
         }
     }
 }
@@ -141,14 +656,65 @@ impl Configuration {
             .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No SocketAddress found"))?;
         Ok(self)
     }
+
+    /// Connects directly to a single storage node, which acts as both head
+    /// and tail, rather than discovering the nodes from the management server.
+    ///
+    /// Use `Endpoint::Uds` for a node on the same host.
+    pub fn direct(&mut self, endpoint: Endpoint) -> &mut Configuration {
+        self.direct = Some(endpoint);
+        self
+    }
+
+    /// Sets the socket options for connections to the storage nodes.
+    pub fn socket_options(&mut self, socket: SocketOptions) -> &mut Configuration {
+        self.socket = socket;
+        self
+    }
+
+    /// Sets the maximum payload of an append, which should match the
+    /// `message_max_bytes` of the servers. Larger appends fail without a
+    /// request to the server.
+    pub fn max_message_bytes(&mut self, bytes: usize) -> &mut Configuration {
+        self.max_message_bytes = bytes;
+        self
+    }
+
+    /// Allows appends with an empty payload, which should match the
+    /// `allow_empty_payloads` of the servers. Empty appends fail without a
+    /// request to the server by default.
+    pub fn allow_empty_payloads(&mut self, allow: bool) -> &mut Configuration {
+        self.allow_empty_payloads = allow;
+        self
+    }
+
+    /// Adds the management server of a log shard. Keyed appends are routed
+    /// to shards by the order in which they are added.
+    ///
+    /// When no shards are added, the management server is the only shard.
+    pub fn shard<A: ToSocketAddrs>(&mut self, addrs: A) -> Result<&mut Configuration, io::Error> {
+        let addr = addrs
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No SocketAddress found"))?;
+        self.shards.push(addr);
+        Ok(self)
+    }
 }
 
-fn connect(env: Arc<Environment>, addr: &str) -> LogStorageClient {
+fn producer_sequence(producer_id: u64, sequence: u64) -> ProducerSequence {
+    let mut seq = ProducerSequence::new();
+    seq.set_producer_id(producer_id);
+    seq.set_sequence(sequence);
+    seq
+}
+
+fn connect(env: Arc<Environment>, addr: &str, socket: SocketOptions) -> LogStorageClient {
     let cb = ChannelBuilder::new(env)
         .default_compression_algorithm(grpcio::CompressionAlgorithms::None)
         .max_concurrent_stream(1000)
         .http2_bdp_probe(true);
-    let conn = cb.connect(addr);
+    let conn = socket.apply(cb).connect(addr);
     LogStorageClient::new(conn)
 }
 
@@ -176,15 +742,50 @@ impl LogServerClient {
     }
 
     pub fn new_connection(&self) -> ClientConnectFuture {
-        let client = connect_management_server(self.env.clone(), &self.config.management_server);
+        match self.config.direct {
+            Some(ref endpoint) => {
+                debug!("Connecting directly to {}", endpoint);
+                let addr = endpoint.grpc_addr();
+                ClientConnectFuture {
+                    state: open_connections(self.env.clone(), &addr, &addr, self.config.socket),
+                    management_client: None,
+                    env: self.env.clone(),
+                    socket: self.config.socket,
+                    max_message_bytes: self.config.max_message_bytes,
+                    allow_empty_payloads: self.config.allow_empty_payloads,
+                }
+            }
+            None => self.connect_to(&self.config.management_server),
+        }
+    }
+
+    /// Opens a connection to each of the configured shards.
+    pub fn new_sharded_connection(&self) -> ShardedConnectFuture {
+        let conns = if self.config.shards.is_empty() {
+            vec![self.new_connection()]
+        } else {
+            self.config
+                .shards
+                .iter()
+                .map(|addr| self.connect_to(addr))
+                .collect()
+        };
+        ShardedConnectFuture(join_all(conns))
+    }
+
+    fn connect_to(&self, management_server: &SocketAddr) -> ClientConnectFuture {
+        let client = connect_management_server(self.env.clone(), management_server);
         let snapshot_future = client.snapshot_async(&protocol::ClientNodeRequest::new());
         debug!("Requesting configuration from management server");
         ClientConnectFuture {
             state: ClientConnectState::RequestingConfiguration(ClientConfigurationFuture::new(
                 snapshot_future,
             )),
-            management_client: client,
+            management_client: Some(client),
             env: self.env.clone(),
+            socket: self.config.socket,
+            max_message_bytes: self.config.max_message_bytes,
+            allow_empty_payloads: self.config.allow_empty_payloads,
         }
     }
 }
@@ -198,10 +799,33 @@ enum ClientConnectState {
     },
 }
 
+fn open_connections(
+    env: Arc<Environment>,
+    head_addr: &str,
+    tail_addr: &str,
+    socket: SocketOptions,
+) -> ClientConnectState {
+    let head = connect(env.clone(), head_addr, socket);
+    let tail = connect(env, tail_addr, socket);
+
+    // force connection open by querying for the latest offset
+    let query = LatestOffsetQuery::new();
+    let head_latest = LatestOffsetFuture::new(head.latest_offset_async(&query));
+    let query = LatestOffsetQuery::new();
+    let tail_latest = LatestOffsetFuture::new(tail.latest_offset_async(&query));
+    ClientConnectState::OpeningConnections {
+        requests: head_latest.join(tail_latest),
+        connections: Some((head, tail)),
+    }
+}
+
 pub struct ClientConnectFuture {
     state: ClientConnectState,
-    management_client: ConfigurationClient,
+    management_client: Option<ConfigurationClient>,
     env: Arc<Environment>,
+    socket: SocketOptions,
+    max_message_bytes: usize,
+    allow_empty_payloads: bool,
 }
 
 impl Future for ClientConnectFuture {
@@ -222,18 +846,7 @@ impl Future for ClientConnectFuture {
                             head_addr, tail_addr
                         );
 
-                        let head = connect(self.env.clone(), head_addr);
-                        let tail = connect(self.env.clone(), tail_addr);
-
-                        // force connection open by querying for the latest offset
-                        let query = LatestOffsetQuery::new();
-                        let head_latest = LatestOffsetFuture::new(head.latest_offset_async(&query));
-                        let query = LatestOffsetQuery::new();
-                        let tail_latest = LatestOffsetFuture::new(tail.latest_offset_async(&query));
-                        ClientConnectState::OpeningConnections {
-                            requests: head_latest.join(tail_latest),
-                            connections: Some((head, tail)),
-                        }
+                        open_connections(self.env.clone(), head_addr, tail_addr, self.socket)
                     } else {
                         debug!("No head or tail found in configuration, adding delay");
                         ClientConnectState::Backoff(Delay::new(
@@ -248,6 +861,8 @@ impl Future for ClientConnectFuture {
                     debug!("Requesting configuration from management server");
                     let snapshot_future = self
                         .management_client
+                        .as_ref()
+                        .expect("Backoff without a management server")
                         .snapshot_async(&protocol::ClientNodeRequest::new());
                     ClientConnectState::RequestingConfiguration(ClientConfigurationFuture::new(
                         snapshot_future,
@@ -267,6 +882,9 @@ impl Future for ClientConnectFuture {
                         head_conn,
                         tail_conn,
                         req_mgr,
+                        max_message_bytes: self.max_message_bytes,
+                        allow_empty_payloads: self.allow_empty_payloads,
+                        producer: None,
                     }));
                 }
             };
@@ -8,12 +8,19 @@ pub use self::manage_grpc::ConfigurationClient;
 pub use self::storage::*;
 pub use self::storage_grpc::LogStorageClient;
 use bytes::Bytes;
+use crc32fast;
 use futures::{Async, Future, Poll, Stream};
 use grpcio;
+use std::error;
+use std::fmt;
 use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 macro_rules! wrap_future {
     ($name:ident, $rpc_ty:ty, $result_ty:ty, $res_var:ident, $map:expr) => {
+        wrap_future!($name, $rpc_ty, $result_ty, $res_var, $map, server_error);
+    };
+    ($name:ident, $rpc_ty:ty, $result_ty:ty, $res_var:ident, $map:expr, $err:ident) => {
         pub struct $name(grpcio::Result<grpcio::ClientUnaryReceiver<$rpc_ty>>);
 
         impl $name {
@@ -34,27 +41,199 @@ macro_rules! wrap_future {
                             Ok(Async::Ready($map))
                         }
                         Ok(Async::NotReady) => Ok(Async::NotReady),
-                        Err(e) => {
-                            error!("Error with server: {:?}", e);
-                            Err(io::Error::new(
-                                io::ErrorKind::InvalidData,
-                                "Invalid payload",
-                            ))
-                        }
+                        Err(e) => Err($err(&e)),
                     },
-                    Err(e) => {
-                        error!("Error with server: {:?}", e);
-                        Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            "Invalid payload",
-                        ))
-                    }
+                    Err(e) => Err($err(e)),
                 }
             }
         }
     };
 }
 
+/// Error of a failed request.
+fn server_error(e: &grpcio::Error) -> io::Error {
+    error!("Error with server: {:?}", e);
+    io::Error::new(io::ErrorKind::InvalidData, "Invalid payload")
+}
+
+/// Error of a failed append. Appends with a payload larger than the maximum
+/// message size of the server fail with `ErrorKind::InvalidInput`,
+/// idempotent appends retried with a sequence before the last appended with
+/// `ErrorKind::AlreadyExists`, appends to a topic paused on the server
+/// with `ErrorKind::PermissionDenied`, and appends with a payload not
+/// matching its checksum with `ErrorKind::InvalidData`, carrying a
+/// `ChecksumMismatch`.
+fn append_error(e: &grpcio::Error) -> io::Error {
+    match *e {
+        grpcio::Error::RpcFailure(ref status)
+            if status.status == grpcio::RpcStatusCode::OutOfRange =>
+        {
+            let msg = status.details.clone().unwrap_or_default();
+            io::Error::new(io::ErrorKind::InvalidInput, msg)
+        }
+        grpcio::Error::RpcFailure(ref status)
+            if status.status == grpcio::RpcStatusCode::AlreadyExists =>
+        {
+            let msg = status.details.clone().unwrap_or_default();
+            io::Error::new(io::ErrorKind::AlreadyExists, msg)
+        }
+        grpcio::Error::RpcFailure(ref status)
+            if status.status == grpcio::RpcStatusCode::FailedPrecondition =>
+        {
+            let msg = status.details.clone().unwrap_or_default();
+            io::Error::new(io::ErrorKind::PermissionDenied, msg)
+        }
+        grpcio::Error::RpcFailure(ref status)
+            if status.status == grpcio::RpcStatusCode::DataLoss =>
+        {
+            let msg = status.details.clone().unwrap_or_default();
+            match ChecksumMismatch::parse(&msg) {
+                Some(mismatch) => io::Error::new(io::ErrorKind::InvalidData, mismatch),
+                None => io::Error::new(io::ErrorKind::InvalidData, msg),
+            }
+        }
+        _ => server_error(e),
+    }
+}
+
+/// Error of a failed read. Reads of offsets deleted by a trim or retention
+/// on the server fail with `ErrorKind::NotFound`, carrying an
+/// `OffsetTrimmed` with the low watermark to resume reading from.
+fn read_error(e: &grpcio::Error) -> io::Error {
+    match *e {
+        grpcio::Error::RpcFailure(ref status)
+            if status.status == grpcio::RpcStatusCode::OutOfRange =>
+        {
+            let msg = status.details.clone().unwrap_or_default();
+            match OffsetTrimmed::parse(&msg) {
+                Some(trimmed) => io::Error::new(io::ErrorKind::NotFound, trimmed),
+                None => io::Error::new(io::ErrorKind::NotFound, msg),
+            }
+        }
+        grpcio::Error::RpcFailure(ref status)
+            if status.status == grpcio::RpcStatusCode::InvalidArgument =>
+        {
+            let msg = status.details.clone().unwrap_or_default();
+            io::Error::new(io::ErrorKind::InvalidInput, msg)
+        }
+        _ => server_error(e),
+    }
+}
+
+/// Read of an offset deleted by a trim or retention on the server, below
+/// the low watermark of the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OffsetTrimmed {
+    /// Offset read.
+    pub offset: u64,
+    /// First offset retained in the log, where reads may resume.
+    pub low_watermark: u64,
+}
+
+impl fmt::Display for OffsetTrimmed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Offset {} has been deleted, the low watermark is {}",
+            self.offset, self.low_watermark
+        )
+    }
+}
+
+impl error::Error for OffsetTrimmed {}
+
+impl OffsetTrimmed {
+    /// The deleted offset read, if the read failed for a trim.
+    pub fn from_error(e: &io::Error) -> Option<OffsetTrimmed> {
+        e.get_ref()
+            .and_then(|e| e.downcast_ref::<OffsetTrimmed>())
+            .cloned()
+    }
+
+    /// Parses the details of the status of a read failed for a trim, which
+    /// may be followed by the trace of the request.
+    fn parse(details: &str) -> Option<OffsetTrimmed> {
+        if !details.starts_with("Offset ") {
+            return None;
+        }
+        let mut parts =
+            details["Offset ".len()..].splitn(2, " has been deleted, the low watermark is ");
+        let offset = parts.next()?.parse().ok()?;
+        let low_watermark = parts.next()?.split_whitespace().next()?.parse().ok()?;
+        Some(OffsetTrimmed {
+            offset,
+            low_watermark,
+        })
+    }
+}
+
+/// Payload not matching its CRC-32 checksum, either an append rejected by
+/// the server or an entry read from the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChecksumMismatch {
+    /// Checksum expected for the payload.
+    pub expected: u32,
+    /// Checksum of the payload received.
+    pub actual: u32,
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Checksum mismatch, expected {:08x} but payload has {:08x}",
+            self.expected, self.actual
+        )
+    }
+}
+
+impl error::Error for ChecksumMismatch {}
+
+impl ChecksumMismatch {
+    /// The mismatched checksums, if the request failed for a corrupted
+    /// payload.
+    pub fn from_error(e: &io::Error) -> Option<ChecksumMismatch> {
+        e.get_ref()
+            .and_then(|e| e.downcast_ref::<ChecksumMismatch>())
+            .cloned()
+    }
+
+    /// Parses the details of the status of an append failed for a checksum
+    /// mismatch, which may name the entry of a batch and be followed by the
+    /// trace of the request.
+    fn parse(details: &str) -> Option<ChecksumMismatch> {
+        const PREFIX: &str = "Checksum mismatch, expected ";
+        let start = details.find(PREFIX)? + PREFIX.len();
+        let mut parts = details[start..].splitn(2, " but payload has ");
+        let expected = u32::from_str_radix(parts.next()?, 16).ok()?;
+        let actual = parts.next()?.split_whitespace().next()?;
+        let actual = u32::from_str_radix(actual, 16).ok()?;
+        Some(ChecksumMismatch { expected, actual })
+    }
+}
+
+/// Offsets and payloads of the entries read, verifying each payload against
+/// the checksum sent by the server.
+fn entry_payloads(entries: Vec<LogEntry>) -> io::Result<Vec<(u64, Bytes)>> {
+    entries
+        .into_iter()
+        .map(|entry| {
+            if entry.has_crc32() {
+                let actual = crc32fast::hash(&entry.payload);
+                if actual != entry.get_crc32() {
+                    error!("Corrupted payload read at offset {}", entry.offset);
+                    let mismatch = ChecksumMismatch {
+                        expected: entry.get_crc32(),
+                        actual,
+                    };
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, mismatch));
+                }
+            }
+            Ok((entry.offset, entry.payload))
+        })
+        .collect()
+}
+
 wrap_future!(
     LatestOffsetFuture,
     LatestOffsetResult,
@@ -65,22 +244,203 @@ wrap_future!(
 );
 
 wrap_future!(
-    QueryFuture,
-    QueryResult,
-    Vec<(u64, Bytes)>,
+    LowWatermarkFuture,
+    LatestOffsetResult,
+    u64,
+    res,
+    res.low_watermark
+);
+
+wrap_future!(
+    FlushFuture,
+    FlushResult,
+    Option<u64>,
+    res,
+    if res.has_durable_offset() {
+        Some(res.get_durable_offset())
+    } else {
+        None
+    }
+);
+
+wrap_future!(
+    DurableOffsetFuture,
+    LatestOffsetResult,
+    Option<u64>,
+    res,
+    if res.has_durable_offset() {
+        Some(res.get_durable_offset())
+    } else {
+        None
+    }
+);
+
+/// Size and offsets of the log on a node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogSummary {
+    /// First offset retained in the log, if any entry has been appended.
+    pub first_offset: Option<u64>,
+    pub last_offset: Option<u64>,
+    /// Bytes of the segments of the log.
+    pub bytes: u64,
+    pub segments: u64,
+    /// Time of the last flush of the log to disk.
+    pub last_flush: SystemTime,
+}
+
+/// Segment of the log on a node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentInfo {
+    /// First offset contained in the segment.
+    pub base_offset: u64,
+    /// Last offset the segment may contain, unless the segment is empty.
+    pub last_offset: Option<u64>,
+    /// Size of the segment log file, in bytes.
+    pub bytes: u64,
+    /// Time the segment was created, if recorded by the filesystem.
+    pub created: Option<SystemTime>,
+}
+
+wrap_future!(
+    SegmentsFuture,
+    SegmentsResult,
+    Vec<SegmentInfo>,
     res,
-    res.entries
+    res.segments
         .into_vec()
         .into_iter()
-        .map(
-            |LogEntry {
-                 offset, payload, ..
-             }| (offset, payload)
-        )
+        .map(|s| SegmentInfo {
+            base_offset: s.base_offset,
+            last_offset: if s.has_last_offset() {
+                Some(s.get_last_offset())
+            } else {
+                None
+            },
+            bytes: s.bytes,
+            created: match s.created_ms {
+                0 => None,
+                ms => Some(UNIX_EPOCH + Duration::from_millis(ms)),
+            },
+        })
         .collect()
 );
 
-wrap_future!(AppendSentFuture, AppendAck, (), _res, ());
+wrap_future!(SummaryFuture, SummaryResult, LogSummary, res, {
+    LogSummary {
+        first_offset: if res.has_first_offset() {
+            Some(res.get_first_offset())
+        } else {
+            None
+        },
+        last_offset: if res.has_last_offset() {
+            Some(res.get_last_offset())
+        } else {
+            None
+        },
+        bytes: res.get_bytes(),
+        segments: res.get_segments(),
+        last_flush: UNIX_EPOCH + Duration::from_millis(res.get_last_flush_ms()),
+    }
+});
+
+wrap_future!(
+    QueryFuture,
+    QueryResult,
+    Vec<(u64, Bytes)>,
+    res,
+    entry_payloads(res.entries.into_vec())?,
+    read_error
+);
+
+wrap_future!(
+    FilteredQueryFuture,
+    QueryResult,
+    (Vec<(u64, Bytes)>, Option<u64>),
+    res,
+    {
+        let next_offset = if res.has_next_offset() {
+            Some(res.get_next_offset())
+        } else {
+            None
+        };
+        let entries = entry_payloads(res.entries.into_vec())?;
+        (entries, next_offset)
+    },
+    read_error
+);
+
+wrap_future!(
+    MetadataQueryFuture,
+    QueryResult,
+    (Vec<LogEntry>, Option<u64>),
+    res,
+    {
+        let next_offset = if res.has_next_offset() {
+            Some(res.get_next_offset())
+        } else {
+            None
+        };
+        (res.entries.into_vec(), next_offset)
+    },
+    read_error
+);
+
+wrap_future!(
+    StopQueryFuture,
+    QueryResult,
+    (Vec<(u64, Bytes)>, Option<u64>, bool),
+    res,
+    {
+        let next_offset = if res.has_next_offset() {
+            Some(res.get_next_offset())
+        } else {
+            None
+        };
+        let stopped = res.stopped;
+        let entries = entry_payloads(res.entries.into_vec())?;
+        (entries, next_offset, stopped)
+    },
+    read_error
+);
+
+wrap_future!(
+    FramedQueryFuture,
+    QueryResult,
+    Bytes,
+    res,
+    res.framed_entries,
+    read_error
+);
+
+wrap_future!(
+    PageFuture,
+    PageResult,
+    (Vec<(u64, Bytes)>, Option<String>),
+    res,
+    {
+        let entries = entry_payloads(res.entries.into_vec())?;
+        let next_cursor = if res.next_cursor.is_empty() {
+            None
+        } else {
+            Some(res.next_cursor.to_string())
+        };
+        (entries, next_cursor)
+    },
+    read_error
+);
+
+wrap_future!(AppendSentFuture, AppendAck, (), _res, (), append_error);
+
+wrap_future!(CreditGrantedFuture, ReplyCreditAck, (), _res, ());
+
+wrap_future!(
+    AppendNowFuture,
+    AppendNowResult,
+    u64,
+    res,
+    res.offset,
+    append_error
+);
 
 pub struct ReplyStream(grpcio::ClientSStreamReceiver<Reply>);
 
@@ -109,6 +469,40 @@ impl From<grpcio::ClientSStreamReceiver<Reply>> for ReplyStream {
     }
 }
 
+type AppendBatchReceiver = grpcio::Result<grpcio::ClientSStreamReceiver<AppendBatchAck>>;
+
+/// Stream of the index within the batch and the offset of each entry,
+/// in batch order, as the entries are appended.
+pub struct AppendAckStream(AppendBatchReceiver);
+
+impl Stream for AppendAckStream {
+    type Item = (usize, u64);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<(usize, u64)>, io::Error> {
+        let res = match &mut self.0 {
+            Ok(ref mut s) => s.poll(),
+            Err(e) => {
+                error!("Error with server: {:?}", e);
+                return Err(io::Error::new(io::ErrorKind::Other, "Error opening stream"));
+            }
+        };
+
+        match res {
+            Ok(Async::Ready(Some(ack))) => Ok(Async::Ready(Some((ack.index as usize, ack.offset)))),
+            Ok(Async::Ready(None)) => Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(e) => Err(append_error(&e)),
+        }
+    }
+}
+
+impl From<AppendBatchReceiver> for AppendAckStream {
+    fn from(s: AppendBatchReceiver) -> AppendAckStream {
+        AppendAckStream(s)
+    }
+}
+
 wrap_future!(
     ClientConfigurationFuture,
     ClientConfiguration,
@@ -116,3 +510,59 @@ wrap_future!(
     res,
     res
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_trimmed_read_details() {
+        let trimmed = OffsetTrimmed {
+            offset: 5,
+            low_watermark: 20,
+        };
+        assert_eq!(Some(trimmed), OffsetTrimmed::parse(&trimmed.to_string()));
+        let traced = format!("{} (trace 7)", trimmed);
+        assert_eq!(Some(trimmed), OffsetTrimmed::parse(&traced));
+        assert_eq!(None, OffsetTrimmed::parse("Offset out of range"));
+    }
+
+    #[test]
+    fn parses_checksum_mismatch_details() {
+        let mismatch = ChecksumMismatch {
+            expected: 0x0123_abcd,
+            actual: 0xffff_0000,
+        };
+        assert_eq!(
+            Some(mismatch),
+            ChecksumMismatch::parse(&mismatch.to_string())
+        );
+        let batch = format!("Entry 3: {} (trace 7)", mismatch);
+        assert_eq!(Some(mismatch), ChecksumMismatch::parse(&batch));
+        assert_eq!(None, ChecksumMismatch::parse("Invalid payload"));
+    }
+
+    #[test]
+    fn rejects_corrupted_entries_read() {
+        let mut entry = LogEntry::new();
+        entry.set_offset(4);
+        entry.set_payload(Bytes::from("foo"));
+        entry.set_crc32(crc32fast::hash(b"foo"));
+        let mut unchecked = LogEntry::new();
+        unchecked.set_offset(5);
+        unchecked.set_payload(Bytes::from("bar"));
+        let entries = entry_payloads(vec![entry.clone(), unchecked]).unwrap();
+        assert_eq!(
+            vec![(4, Bytes::from("foo")), (5, Bytes::from("bar"))],
+            entries
+        );
+
+        // a bit flipped in transit
+        entry.set_payload(Bytes::from("fo0"));
+        let err = entry_payloads(vec![entry]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        let mismatch = ChecksumMismatch::from_error(&err).unwrap();
+        assert_eq!(crc32fast::hash(b"foo"), mismatch.expected);
+        assert_eq!(crc32fast::hash(b"fo0"), mismatch.actual);
+    }
+}
@@ -0,0 +1,89 @@
+use bytes::Bytes;
+use futures::future::JoinAll;
+use futures::{Async, Future, Poll};
+use std::io;
+use {AppendFuture, ClientConnectFuture, Connection};
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Selects the shard for a key.
+///
+/// The key is hashed with 64-bit FNV-1a over the raw key bytes (no length
+/// prefix or terminator), and the shard is the hash modulo the number of
+/// shards, indexing the shards in the order they were configured.
+///
+/// This scheme is part of the client contract and will not change between
+/// client versions. Changing the number or order of shards changes the
+/// placement of keys.
+pub fn shard_for_key(key: &[u8], shards: usize) -> usize {
+    assert!(shards > 0, "No shards configured");
+    (fnv1a(key) % shards as u64) as usize
+}
+
+fn fnv1a(key: &[u8]) -> u64 {
+    key.iter().fold(FNV_OFFSET_BASIS, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// Connection to multiple independent log shards.
+///
+/// Appends with the same key are routed to the same shard, over the same
+/// connection, preserving the order of the appends for the key.
+pub struct ShardedConnection {
+    shards: Vec<Connection>,
+}
+
+impl ShardedConnection {
+    /// Appends the payload to the shard selected by the key.
+    pub fn append_keyed(&mut self, key: &[u8], payload: Bytes) -> AppendFuture {
+        self.shard_mut(key).append(payload)
+    }
+
+    /// Connection to the shard selected by the key.
+    pub fn shard_mut(&mut self, key: &[u8]) -> &mut Connection {
+        let i = shard_for_key(key, self.shards.len());
+        &mut self.shards[i]
+    }
+
+    /// Connections to each of the shards, in configured order.
+    pub fn shards_mut(&mut self) -> &mut [Connection] {
+        &mut self.shards
+    }
+}
+
+pub struct ShardedConnectFuture(pub(crate) JoinAll<Vec<ClientConnectFuture>>);
+
+impl Future for ShardedConnectFuture {
+    type Item = ShardedConnection;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let shards = try_ready!(self.0.poll());
+        debug!("Connected to {} shards", shards.len());
+        Ok(Async::Ready(ShardedConnection { shards }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fnv1a_known_values() {
+        assert_eq!(0xcbf2_9ce4_8422_2325, fnv1a(b""));
+        assert_eq!(0xaf63_dc4c_8601_ec8c, fnv1a(b"a"));
+        assert_eq!(0x8594_4171_f739_67e8, fnv1a(b"foobar"));
+    }
+
+    #[test]
+    fn shard_for_key_is_stable() {
+        assert_eq!(0, shard_for_key(b"foobar", 1));
+        for shards in 1..10 {
+            let shard = shard_for_key(b"key-123", shards);
+            assert!(shard < shards);
+            assert_eq!(shard, shard_for_key(b"key-123", shards));
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use grpcio::ChannelBuilder;
+use std::ffi::CString;
+
+// gRPC core channel arguments for the transport buffers
+const READ_CHUNK_SIZE_ARG: &str = "grpc.experimental.tcp_read_chunk_size";
+const WRITE_BUFFER_SIZE_ARG: &str = "grpc.http2.write_buffer_size";
+
+/// Socket options for the connections to the storage nodes.
+///
+/// gRPC enables `TCP_NODELAY` on all of its connections, so small appends are
+/// never delayed by Nagle's algorithm. The buffer sizes default to those of
+/// gRPC (8KB reads, 64KB writes). Larger buffers favor throughput of large
+/// appends and reads, at the cost of memory per connection; smaller buffers
+/// do little for latency.
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Size of the buffer for writes to the connection, in bytes
+    pub send_buffer_bytes: Option<usize>,
+
+    /// Size of the buffer for reads from the connection, in bytes
+    pub recv_buffer_bytes: Option<usize>,
+}
+
+impl SocketOptions {
+    /// Applies the options to the channel.
+    pub(crate) fn apply(&self, mut cb: ChannelBuilder) -> ChannelBuilder {
+        if let Some(bytes) = self.send_buffer_bytes {
+            cb = cb.raw_cfg_int(CString::new(WRITE_BUFFER_SIZE_ARG).unwrap(), bytes as i32);
+        }
+        if let Some(bytes) = self.recv_buffer_bytes {
+            cb = cb.raw_cfg_int(CString::new(READ_CHUNK_SIZE_ARG).unwrap(), bytes as i32);
+        }
+        cb
+    }
+}
@@ -1,50 +1,688 @@
-use futures::{Future, Stream};
+use asynclog::{AsyncLog, ConsumerId, LogStats, LogTuning};
+use config::{AdminConfig, FlushMode, SocketConfig};
+use drain;
+use frame;
+use futures::future::{ok, Future};
+use futures::Stream;
 use http::header;
 use hyper::server::conn::Http;
-use hyper::service::service_fn_ok;
+use hyper::service::service_fn;
 use hyper::{Body, Method, Request, Response, StatusCode};
 use prometheus::{self, Encoder, TextEncoder};
-use std::net::SocketAddr;
+use serde::Serialize;
+use serde_json;
+use socket;
+use std::io::{Error, ErrorKind};
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+use tail_reply::TailReplyRegistrar;
 use tokio;
 use tokio::net::TcpListener;
 
-fn handle(req: Request<Body>) -> Response<Body> {
-    match (req.method(), req.uri().path()) {
-        (&Method::GET, "/metrics") => {
-            let encoder = TextEncoder::new();
-            let metric_familys = prometheus::gather();
-            let mut buffer = vec![];
-            encoder.encode(&metric_familys, &mut buffer).unwrap();
-
-            let len = buffer.len().to_string().parse().unwrap();
-            let mut res = Response::new(buffer.into());
-            res.headers_mut().insert(header::CONTENT_LENGTH, len);
+type ResponseFuture = Box<Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
+fn metrics() -> Response<Body> {
+    let encoder = TextEncoder::new();
+    let metric_familys = prometheus::gather();
+    let mut buffer = vec![];
+    encoder.encode(&metric_familys, &mut buffer).unwrap();
+
+    let len = buffer.len().to_string().parse().unwrap();
+    let mut res = Response::new(buffer.into());
+    res.headers_mut().insert(header::CONTENT_LENGTH, len);
+    res.headers_mut()
+        .insert(header::CONTENT_TYPE, encoder.format_type().parse().unwrap());
+    res
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    let mut res = Response::new(Body::empty());
+    *res.status_mut() = code;
+    res
+}
+
+fn json<T: Serialize>(value: &T) -> Response<Body> {
+    match serde_json::to_vec(value) {
+        Ok(body) => {
+            let mut res = Response::new(Body::from(body));
             res.headers_mut()
-                .insert(header::CONTENT_TYPE, encoder.format_type().parse().unwrap());
+                .insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
             res
         }
-        _ => {
-            let mut res = Response::new(Body::empty());
-            *res.status_mut() = StatusCode::NOT_FOUND;
-            res
+        Err(e) => {
+            error!("Unable to serialize response: {}", e);
+            status(StatusCode::INTERNAL_SERVER_ERROR)
         }
     }
 }
 
-pub fn server(addr: &SocketAddr) -> impl Future<Item = (), Error = ()> {
-    let listener = TcpListener::bind(addr).expect("unable to bind TCP listener for admin server");
+/// Body of a request that succeeded without a result.
+#[derive(Serialize)]
+struct Done {
+    done: bool,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+fn json_error(code: StatusCode, error: String) -> Response<Body> {
+    let mut res = json(&ErrorBody { error });
+    *res.status_mut() = code;
+    res
+}
+
+/// Tests whether the request carries the bearer token, if one is required.
+/// Only the metrics are served without the token, as every other endpoint
+/// returns or changes the data of the log.
+fn authorized(token: Option<&str>, req: &Request<Body>) -> bool {
+    if *req.method() == Method::GET && req.uri().path() == "/metrics" {
+        return true;
+    }
+    match token {
+        Some(token) => req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| {
+                v.starts_with("Bearer ") && constant_time_eq(v[7..].as_bytes(), token.as_bytes())
+            })
+            .unwrap_or(false),
+        None => true,
+    }
+}
+
+/// Compares the bytes in a time that does not depend on the position of the
+/// first difference, so the token cannot be guessed byte by byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Finds a query string parameter.
+fn query_str<'a>(req: &'a Request<Body>, name: &str) -> Option<&'a str> {
+    req.uri().query().and_then(|q| {
+        q.split('&')
+            .filter_map(|pair| {
+                let mut kv = pair.splitn(2, '=');
+                match (kv.next(), kv.next()) {
+                    (Some(k), Some(v)) if k == name => Some(v),
+                    _ => None,
+                }
+            })
+            .next()
+    })
+}
+
+/// Parses a numeric query string parameter.
+fn query_param(req: &Request<Body>, name: &str) -> Option<u64> {
+    query_str(req, name).and_then(|v| v.parse().ok())
+}
+
+/// Responds with the error of a request to the log, with the status of its
+/// kind.
+fn log_error(e: &Error) -> Response<Body> {
+    let code = match e.kind() {
+        ErrorKind::InvalidInput => StatusCode::BAD_REQUEST,
+        ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        ErrorKind::AlreadyExists => StatusCode::CONFLICT,
+        ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    json_error(code, e.to_string())
+}
+
+/// Fails a request missing a parameter.
+fn missing_params(params: &str) -> ResponseFuture {
+    let error = format!("Missing or invalid {} parameter", params);
+    Box::new(ok(json_error(StatusCode::BAD_REQUEST, error)))
+}
+
+#[derive(Serialize)]
+struct Tombstoned {
+    start: u64,
+    end: u64,
+}
+
+fn tombstone(log: &mut AsyncLog, req: &Request<Body>) -> ResponseFuture {
+    let (start, end) = match (query_param(req, "start"), query_param(req, "end")) {
+        (Some(start), Some(end)) => (start, end),
+        _ => return missing_params("start or end"),
+    };
+
+    Box::new(
+        log.tombstone(start..end)
+            .then(move |res| -> Result<Response<Body>, hyper::Error> {
+                match res {
+                    Ok(()) => Ok(json(&Tombstoned { start, end })),
+                    Err(e) => {
+                        warn!("Tombstone failed: {}", e);
+                        Ok(log_error(&e))
+                    }
+                }
+            }),
+    )
+}
+
+/// Decodes the percent escapes of a query string value. `None` if an escape
+/// is invalid or the value is not UTF-8.
+fn url_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = value.get(i + 1..i + 3)?;
+                if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return None;
+                }
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            b => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Resolves the name of a snapshot to a directory under the snapshot root.
+/// `None` if the name is empty, absolute or leaves the root, such as with
+/// `..`.
+fn snapshot_path(root: &Path, name: &str) -> Option<PathBuf> {
+    let name = Path::new(name);
+    let relative = name.components().all(|c| match c {
+        Component::Normal(_) => true,
+        _ => false,
+    });
+    if name.as_os_str().is_empty() || !relative {
+        return None;
+    }
+    Some(root.join(name))
+}
+
+/// Snapshots the log to the directory named by the `name` parameter, under
+/// the snapshot directory of the configuration.
+fn snapshot(log: &mut AsyncLog, root: Option<&str>, req: &Request<Body>) -> ResponseFuture {
+    let root = match root {
+        Some(root) => Path::new(root),
+        None => {
+            let error = "Snapshots are disabled, no snapshot_dir is configured".to_string();
+            return Box::new(ok(json_error(StatusCode::FORBIDDEN, error)));
+        }
+    };
+    let dest = match query_str(req, "name").and_then(url_decode) {
+        Some(name) => match snapshot_path(root, &name) {
+            Some(dest) => dest,
+            None => {
+                let error = format!("Invalid snapshot name {}", name);
+                return Box::new(ok(json_error(StatusCode::BAD_REQUEST, error)));
+            }
+        },
+        None => return missing_params("name"),
+    };
+
+    Box::new(
+        log.snapshot(dest)
+            .then(|res| -> Result<Response<Body>, hyper::Error> {
+                match res {
+                    Ok(info) => Ok(json(&info)),
+                    Err(e) => {
+                        warn!("Snapshot failed: {}", e);
+                        Ok(log_error(&e))
+                    }
+                }
+            }),
+    )
+}
+
+#[derive(Serialize)]
+struct ConsumerOffset {
+    consumer: ConsumerId,
+    offset: u64,
+}
+
+fn commit_offset(log: &mut AsyncLog, req: &Request<Body>) -> ResponseFuture {
+    let (consumer, offset) = match (query_param(req, "consumer"), query_param(req, "offset")) {
+        (Some(consumer), Some(offset)) => (consumer, offset),
+        _ => return missing_params("consumer or offset"),
+    };
+
+    Box::new(log.commit_offset(consumer, offset).then(
+        move |res| -> Result<Response<Body>, hyper::Error> {
+            match res {
+                Ok(()) => Ok(json(&ConsumerOffset { consumer, offset })),
+                Err(e) => {
+                    warn!("Commit offset failed: {}", e);
+                    Ok(log_error(&e))
+                }
+            }
+        },
+    ))
+}
+
+/// Lists the committed offset of each consumer.
+fn consumer_offsets(log: &mut AsyncLog) -> ResponseFuture {
+    Box::new(
+        log.consumer_offsets()
+            .then(|res| -> Result<Response<Body>, hyper::Error> {
+                match res {
+                    Ok(offsets) => {
+                        let offsets: Vec<_> = offsets
+                            .into_iter()
+                            .map(|(consumer, offset)| ConsumerOffset { consumer, offset })
+                            .collect();
+                        Ok(json(&offsets))
+                    }
+                    Err(e) => Ok(log_error(&e)),
+                }
+            }),
+    )
+}
+
+#[derive(Serialize)]
+struct ConsumerLag {
+    consumer: ConsumerId,
+    lag: u64,
+}
+
+fn consumer_lag(log: &mut AsyncLog, req: &Request<Body>) -> ResponseFuture {
+    let consumer = match query_param(req, "consumer") {
+        Some(consumer) => consumer,
+        None => return missing_params("consumer"),
+    };
+
+    Box::new(
+        log.consumer_lag(consumer)
+            .then(move |res| -> Result<Response<Body>, hyper::Error> {
+                match res {
+                    Ok(lag) => Ok(json(&ConsumerLag { consumer, lag })),
+                    Err(e) => Ok(log_error(&e)),
+                }
+            }),
+    )
+}
+
+/// Reads the raw bytes `[start, end)` of the segment with the base offset in
+/// the `segment` parameter.
+fn read_raw(log: &mut AsyncLog, req: &Request<Body>) -> ResponseFuture {
+    let (segment, start, end) = match (
+        query_param(req, "segment"),
+        query_param(req, "start"),
+        query_param(req, "end"),
+    ) {
+        (Some(segment), Some(start), Some(end)) => (segment, start, end),
+        _ => return missing_params("segment, start or end"),
+    };
+
+    Box::new(log.read_raw(segment, start..end).then(
+        |res| -> Result<Response<Body>, hyper::Error> {
+            match res {
+                Ok(bytes) => {
+                    let mut res = Response::new(Body::from(bytes));
+                    res.headers_mut().insert(
+                        header::CONTENT_TYPE,
+                        "application/octet-stream".parse().unwrap(),
+                    );
+                    Ok(res)
+                }
+                Err(e) => {
+                    warn!("Raw segment read failed: {}", e);
+                    Ok(log_error(&e))
+                }
+            }
+        },
+    ))
+}
+
+/// Reads the last `n` entries of the log, encoded as frames.
+fn tail(log: &mut AsyncLog, req: &Request<Body>) -> ResponseFuture {
+    let n = match query_param(req, "n") {
+        Some(n) => n as usize,
+        None => return missing_params("n"),
+    };
+
+    Box::new(
+        log.tail(n)
+            .then(|res| -> Result<Response<Body>, hyper::Error> {
+                match res {
+                    Ok(msgs) => {
+                        let mut res = Response::new(Body::from(frame::encode(&msgs)));
+                        res.headers_mut().insert(
+                            header::CONTENT_TYPE,
+                            "application/octet-stream".parse().unwrap(),
+                        );
+                        Ok(res)
+                    }
+                    Err(e) => {
+                        warn!("Tail read failed: {}", e);
+                        Ok(log_error(&e))
+                    }
+                }
+            }),
+    )
+}
+
+/// Lists the active reply subscriptions, with the client, the offset of the
+/// last delivered entry and the lag of each.
+fn subscriptions(tail: &TailReplyRegistrar) -> ResponseFuture {
+    Box::new(
+        tail.subscriptions()
+            .then(|res| -> Result<Response<Body>, hyper::Error> {
+                match res {
+                    Ok(subs) => Ok(json(&subs)),
+                    Err(()) => Ok(json_error(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "Reply subscriptions are unavailable".to_string(),
+                    )),
+                }
+            }),
+    )
+}
+
+/// Responds with the log stats mapped to a JSON value.
+fn stats<T, F>(log: &mut AsyncLog, f: F) -> ResponseFuture
+where
+    T: Serialize,
+    F: FnOnce(LogStats) -> T + Send + 'static,
+{
+    Box::new(
+        log.stats()
+            .then(|res| -> Result<Response<Body>, hyper::Error> {
+                match res {
+                    Ok(stats) => Ok(json(&f(stats))),
+                    Err(e) => Ok(log_error(&e)),
+                }
+            }),
+    )
+}
+
+/// Responds with the size of the log, without listing the segments on disk.
+fn summary(log: &mut AsyncLog) -> ResponseFuture {
+    Box::new(
+        log.summary()
+            .then(|res| -> Result<Response<Body>, hyper::Error> {
+                match res {
+                    Ok(summary) => Ok(json(&summary)),
+                    Err(e) => Ok(log_error(&e)),
+                }
+            }),
+    )
+}
+
+#[derive(Serialize)]
+struct RetentionStatus {
+    segments: usize,
+    deletable_segments: usize,
+}
+
+#[derive(Serialize)]
+struct Flushed {
+    flushed_offset: Option<u64>,
+}
+
+fn flush(log: &mut AsyncLog) -> ResponseFuture {
+    Box::new(
+        log.flush()
+            .then(|res| -> Result<Response<Body>, hyper::Error> {
+                match res {
+                    Ok(flushed_offset) => Ok(json(&Flushed { flushed_offset })),
+                    Err(e) => Ok(log_error(&e)),
+                }
+            }),
+    )
+}
+
+#[derive(Serialize)]
+struct Truncated {
+    offset: u64,
+}
+
+/// Removes the entries after the offset in the `offset` parameter.
+fn truncate(log: &mut AsyncLog, req: &Request<Body>) -> ResponseFuture {
+    let offset = match query_param(req, "offset") {
+        Some(offset) => offset,
+        None => return missing_params("offset"),
+    };
+
+    Box::new(
+        log.truncate(offset)
+            .then(move |res| -> Result<Response<Body>, hyper::Error> {
+                match res {
+                    Ok(()) => Ok(json(&Truncated { offset })),
+                    Err(e) => Ok(log_error(&e)),
+                }
+            }),
+    )
+}
+
+/// Changes the flush and retention settings from the `flush_mode`
+/// (`every_append`, `interval` or `never`), `flush_interval_ms`,
+/// `retention_max_age_secs`, `retention_max_bytes` and
+/// `retention_check_interval_secs` parameters. Missing parameters are
+/// unchanged.
+fn tune(log: &mut AsyncLog, req: &Request<Body>) -> ResponseFuture {
+    let flush_mode = match query_str(req, "flush_mode") {
+        None => None,
+        Some("every_append") => Some(FlushMode::EveryAppend),
+        Some("interval") => Some(FlushMode::Interval),
+        Some("never") => Some(FlushMode::Never),
+        Some(mode) => {
+            let error = format!("Unknown flush mode {}", mode);
+            return Box::new(ok(json_error(StatusCode::BAD_REQUEST, error)));
+        }
+    };
+    let tuning = LogTuning {
+        flush_mode,
+        flush_interval: query_param(req, "flush_interval_ms").map(Duration::from_millis),
+        retention_max_age_secs: query_param(req, "retention_max_age_secs"),
+        retention_max_bytes: query_param(req, "retention_max_bytes"),
+        retention_check_interval_secs: query_param(req, "retention_check_interval_secs"),
+    };
+
+    Box::new(
+        log.tune(tuning)
+            .then(|res| -> Result<Response<Body>, hyper::Error> {
+                match res {
+                    Ok(()) => Ok(json(&Done { done: true })),
+                    Err(e) => Ok(log_error(&e)),
+                }
+            }),
+    )
+}
+
+#[derive(Serialize)]
+struct TopicState {
+    topic: String,
+    paused: bool,
+}
+
+/// Pauses or resumes the appends to the topic in the `topic` parameter,
+/// leaving reads and the other topics unaffected.
+fn pause_topic(log: &AsyncLog, req: &Request<Body>, paused: bool) -> ResponseFuture {
+    let topic = match query_str(req, "topic") {
+        Some(topic) => topic.to_string(),
+        None => return missing_params("topic"),
+    };
+
+    Box::new(ok(match log.topic(&topic) {
+        Ok(log) => {
+            if paused {
+                log.pause();
+            } else {
+                log.resume();
+            }
+            json(&TopicState { topic, paused })
+        }
+        Err(e) => log_error(&e),
+    }))
+}
+
+fn drain_subscriptions(log: AsyncLog, tail: &TailReplyRegistrar) -> ResponseFuture {
+    Box::new(
+        drain::drain(tail, log).then(|res| -> Result<Response<Body>, hyper::Error> {
+            match res {
+                Ok(()) => Ok(json(&Done { done: true })),
+                Err(()) => Ok(json_error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Unable to drain the subscriptions".to_string(),
+                )),
+            }
+        }),
+    )
+}
+
+fn handle(
+    mut log: AsyncLog,
+    tail: &TailReplyRegistrar,
+    cfg: &AdminConfig,
+    req: Request<Body>,
+) -> ResponseFuture {
+    if !authorized(cfg.token.as_ref().map(|t| t.as_str()), &req) {
+        let error = "Missing or invalid bearer token".to_string();
+        return Box::new(ok(json_error(StatusCode::UNAUTHORIZED, error)));
+    }
+
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => Box::new(ok(metrics())),
+        (&Method::POST, "/tombstone") => tombstone(&mut log, &req),
+        (&Method::POST, "/snapshot") => snapshot(
+            &mut log,
+            cfg.snapshot_dir.as_ref().map(|d| d.as_str()),
+            &req,
+        ),
+        (&Method::GET, "/consumers") => consumer_offsets(&mut log),
+        (&Method::GET, "/consumers/lag") => consumer_lag(&mut log, &req),
+        (&Method::POST, "/consumers/commit") => commit_offset(&mut log, &req),
+        (&Method::GET, "/subscriptions") => subscriptions(tail),
+        (&Method::GET, "/segments/raw") => read_raw(&mut log, &req),
+        (&Method::GET, "/tail") => tail(&mut log, &req),
+        (&Method::GET, "/stats") => stats(&mut log, |stats| stats),
+        (&Method::GET, "/stats/queues") => Box::new(ok(json(&log.queue_stats()))),
+        (&Method::GET, "/stats/summary") => summary(&mut log),
+        (&Method::GET, "/segments") => stats(&mut log, |stats| stats.segments),
+        (&Method::GET, "/retention") => stats(&mut log, |stats| RetentionStatus {
+            segments: stats.segments.len(),
+            deletable_segments: stats.deletable_segments,
+        }),
+        (&Method::POST, "/flush") => flush(&mut log),
+        (&Method::POST, "/truncate") => truncate(&mut log, &req),
+        (&Method::POST, "/tune") => tune(&mut log, &req),
+        (&Method::POST, "/topics/pause") => pause_topic(&log, &req, true),
+        (&Method::POST, "/topics/resume") => pause_topic(&log, &req, false),
+        (&Method::POST, "/drain") => drain_subscriptions(log, tail),
+        (method, path) => {
+            let error = format!("No route for {} {}", method, path);
+            Box::new(ok(json_error(StatusCode::NOT_FOUND, error)))
+        }
+    }
+}
+
+pub fn server(
+    cfg: &AdminConfig,
+    socket_cfg: &SocketConfig,
+    log: AsyncLog,
+    tail: TailReplyRegistrar,
+) -> impl Future<Item = (), Error = ()> {
+    let socket_cfg = socket_cfg.clone();
+    let cfg = cfg.clone();
+    let listener =
+        TcpListener::bind(&cfg.server_addr).expect("unable to bind TCP listener for admin server");
     listener
         .incoming()
         .map_err(|e| error!("accept failed = {:?}", e))
         .for_each(move |sock| {
-            if let Err(e) = sock.set_nodelay(true) {
-                warn!("Unable to set nodelay on socket: {}", e);
-            }
+            socket::apply(&sock, &socket_cfg);
 
+            let log = log.clone();
+            let tail = tail.clone();
+            let cfg = cfg.clone();
             let http = Http::new();
+            let service = service_fn(move |req| handle(log.clone(), &tail, &cfg, req));
             let handle_conn = http
-                .serve_connection(sock, service_fn_ok(handle))
+                .serve_connection(sock, service)
                 .map_err(|e| error!("{}", e));
             tokio::spawn(handle_conn)
         })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(auth: Option<&str>) -> Request<Body> {
+        let mut req = Request::post("/flush");
+        if let Some(auth) = auth {
+            req.header(header::AUTHORIZATION, auth);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    fn get(path: &str) -> Request<Body> {
+        Request::get(path).body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn requires_bearer_token() {
+        assert!(authorized(None, &request(None)));
+        assert!(authorized(None, &request(Some("Bearer foo"))));
+
+        assert!(authorized(Some("foo"), &request(Some("Bearer foo"))));
+        assert!(!authorized(Some("foo"), &request(None)));
+        assert!(!authorized(Some("foo"), &request(Some("Bearer bar"))));
+        assert!(!authorized(Some("foo"), &request(Some("foo"))));
+        assert!(!authorized(Some("foo"), &request(Some("Bearer fo"))));
+        assert!(!authorized(Some("foo"), &request(Some("Bearer fooo"))));
+    }
+
+    #[test]
+    fn reads_of_log_data_require_bearer_token() {
+        for path in &[
+            "/segments/raw",
+            "/tail",
+            "/consumers",
+            "/consumers/lag",
+            "/stats",
+        ] {
+            assert!(!authorized(Some("foo"), &get(path)));
+            assert!(authorized(None, &get(path)));
+        }
+        assert!(!authorized(Some("foo"), &get("/subscriptions")));
+        assert!(authorized(Some("foo"), &get("/metrics")));
+    }
+
+    #[test]
+    fn errors_are_json() {
+        let res = log_error(&Error::new(ErrorKind::NotFound, "No committed offset"));
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+        let body = res.into_body().concat2().wait().unwrap();
+        assert_eq!(&b"{\"error\":\"No committed offset\"}"[..], &body[..]);
+    }
+
+    #[test]
+    fn snapshot_names_stay_under_the_root() {
+        let root = Path::new("/snapshots");
+        assert_eq!(
+            Some(PathBuf::from("/snapshots/nightly/1")),
+            snapshot_path(root, "nightly/1")
+        );
+        assert_eq!(None, snapshot_path(root, ""));
+        assert_eq!(None, snapshot_path(root, "/etc"));
+        assert_eq!(None, snapshot_path(root, ".."));
+        assert_eq!(None, snapshot_path(root, "nightly/../../etc"));
+
+        assert_eq!(Some("../etc".to_string()), url_decode("%2E%2e%2Fetc"));
+        assert_eq!(Some("a b".to_string()), url_decode("a+b"));
+        assert_eq!(None, url_decode("%2"));
+        assert_eq!(None, url_decode("%+1"));
+        let decoded = url_decode("%2E%2e%2Fetc").unwrap();
+        assert_eq!(None, snapshot_path(root, &decoded));
+    }
+}
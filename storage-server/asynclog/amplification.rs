@@ -0,0 +1,150 @@
+use super::DEFAULT_TOPIC;
+use prometheus::{Counter, Gauge, GaugeVec};
+
+/// Size of the pages written to disk by a flush.
+const PAGE_BYTES: u64 = 4096;
+
+/// Size of the index entry written for each entry.
+const INDEX_ENTRY_BYTES: u64 = 8;
+
+lazy_static! {
+    static ref LOGICAL_BYTES: Counter = register_counter!(opts!(
+        "log_logical_bytes",
+        "Payload bytes appended to the log.",
+        labels! {"mod" => "log",}
+    ))
+    .unwrap();
+    static ref PHYSICAL_BYTES: Counter = register_counter!(opts!(
+        "log_physical_bytes",
+        "Estimated bytes written to disk by flushes.",
+        labels! {"mod" => "log",}
+    ))
+    .unwrap();
+    static ref WRITE_AMPLIFICATION: GaugeVec = register_gauge_vec!(
+        "log_write_amplification",
+        "Ratio of the bytes written to disk to the payload bytes appended.",
+        &["topic"]
+    )
+    .unwrap();
+}
+
+/// Estimates the write amplification of the log: the bytes written to disk
+/// for each payload byte appended.
+///
+/// The bytes written to disk are estimated from the appended entries,
+/// including the entry headers and metadata and the index entries, with each
+/// flush writing whole pages. A partial page at the end of a flush is written
+/// again by the next flush, so frequent flushes of small appends show as
+/// high amplification.
+pub struct WriteAmplification {
+    logical: u64,
+    physical: u64,
+    // end of the stored bytes appended, and at the last flush
+    appended_to: u64,
+    flushed_to: u64,
+    pending_index: u64,
+    ratio: Gauge,
+}
+
+impl Default for WriteAmplification {
+    fn default() -> WriteAmplification {
+        WriteAmplification::new(DEFAULT_TOPIC)
+    }
+}
+
+impl WriteAmplification {
+    /// Estimates the write amplification of the log of the topic.
+    pub fn new(topic: &str) -> WriteAmplification {
+        WriteAmplification {
+            logical: 0,
+            physical: 0,
+            appended_to: 0,
+            flushed_to: 0,
+            pending_index: 0,
+            ratio: WRITE_AMPLIFICATION.with_label_values(&[topic]),
+        }
+    }
+
+    /// Records an append of entries with the payload bytes and the bytes
+    /// stored in the log.
+    pub fn append(&mut self, entries: usize, payload_bytes: u64, stored_bytes: u64) {
+        self.logical += payload_bytes;
+        self.appended_to += stored_bytes;
+        self.pending_index += entries as u64 * INDEX_ENTRY_BYTES;
+        LOGICAL_BYTES.inc_by(payload_bytes as f64);
+    }
+
+    /// Records a flush of the appended bytes, updating the ratio.
+    pub fn flushed(&mut self) {
+        if self.appended_to == self.flushed_to {
+            return;
+        }
+
+        let first_page = self.flushed_to / PAGE_BYTES;
+        let end_page = (self.appended_to + PAGE_BYTES - 1) / PAGE_BYTES;
+        let written = (end_page - first_page) * PAGE_BYTES + self.pending_index;
+        self.physical += written;
+        self.flushed_to = self.appended_to;
+        self.pending_index = 0;
+
+        PHYSICAL_BYTES.inc_by(written as f64);
+        if let Some(ratio) = self.ratio() {
+            self.ratio.set(ratio);
+        }
+    }
+
+    /// Bytes written to disk for each payload byte appended, or `None` if
+    /// nothing has been appended.
+    pub fn ratio(&self) -> Option<f64> {
+        if self.logical == 0 {
+            None
+        } else {
+            Some(self.physical as f64 / self.logical as f64)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_flushes_rewrite_pages() {
+        let mut amp = WriteAmplification::default();
+        assert_eq!(None, amp.ratio());
+
+        // 100 byte payloads stored in 130 bytes, flushed after each append
+        for _ in 0..10 {
+            amp.append(1, 100, 130);
+            amp.flushed();
+        }
+        assert_eq!(10 * (4096 + 8), amp.physical);
+
+        // the same appends flushed together
+        let mut batched = WriteAmplification::default();
+        for _ in 0..10 {
+            batched.append(1, 100, 130);
+        }
+        batched.flushed();
+        assert_eq!(4096 + 10 * 8, batched.physical);
+        assert!(batched.ratio() < amp.ratio());
+    }
+
+    #[test]
+    fn flush_spanning_pages() {
+        let mut amp = WriteAmplification::default();
+        amp.append(2, 8000, 8192);
+        amp.flushed();
+        assert_eq!(8192 + 16, amp.physical);
+        assert!(amp.ratio().unwrap() > 1.0);
+
+        // continues on a fresh page
+        amp.append(1, 10, 20);
+        amp.flushed();
+        assert_eq!(8192 + 16 + 4096 + 8, amp.physical);
+
+        // nothing appended since the last flush
+        amp.flushed();
+        assert_eq!(8192 + 16 + 4096 + 8, amp.physical);
+    }
+}
@@ -0,0 +1,114 @@
+use commitlog::AppendError;
+use libc;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+/// Retries appends to the log that fail with transient errors.
+///
+/// The log thread has no timer, so the retry delay blocks the thread. The
+/// delay should be kept short.
+pub struct AppendRetry {
+    retries: usize,
+    delay: Duration,
+}
+
+impl AppendRetry {
+    pub fn new(retries: usize, delay: Duration) -> AppendRetry {
+        AppendRetry { retries, delay }
+    }
+
+    /// Runs the append, retrying transient failures up to the retry limit.
+    pub fn run<T, F>(&self, mut append: F) -> Result<T, AppendError>
+    where
+        F: FnMut() -> Result<T, AppendError>,
+    {
+        let mut attempt = 0;
+        loop {
+            match append() {
+                Ok(v) => return Ok(v),
+                Err(e) => {
+                    if attempt >= self.retries || !is_transient(&e) {
+                        return Err(e);
+                    }
+                    attempt += 1;
+                    warn!(
+                        "Transient append error, retry {} of {}: {}",
+                        attempt, self.retries, e
+                    );
+                    thread::sleep(self.delay);
+                }
+            }
+        }
+    }
+}
+
+/// Whether the append may succeed if retried. I/O errors are transient,
+/// unless the disk is out of space, read-only or access is denied.
+fn is_transient(e: &AppendError) -> bool {
+    match *e {
+        AppendError::Io(ref e) => is_transient_io(e),
+        _ => false,
+    }
+}
+
+fn is_transient_io(e: &io::Error) -> bool {
+    if e.kind() == io::ErrorKind::PermissionDenied {
+        return false;
+    }
+
+    match e.raw_os_error() {
+        Some(libc::ENOSPC) | Some(libc::EDQUOT) | Some(libc::EROFS) | Some(libc::EFBIG) => false,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transient_then_success() {
+        let retry = AppendRetry::new(2, Duration::from_millis(0));
+        let mut calls = 0;
+        let res = retry.run(|| {
+            calls += 1;
+            if calls == 1 {
+                Err(AppendError::Io(io::Error::new(
+                    io::ErrorKind::Interrupted,
+                    "interrupted",
+                )))
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(2, res.unwrap());
+    }
+
+    #[test]
+    fn gives_up_after_retries() {
+        let retry = AppendRetry::new(2, Duration::from_millis(0));
+        let mut calls = 0;
+        let res: Result<(), AppendError> = retry.run(|| {
+            calls += 1;
+            Err(AppendError::Io(io::Error::new(
+                io::ErrorKind::Other,
+                "blip",
+            )))
+        });
+        assert!(res.is_err());
+        assert_eq!(3, calls);
+    }
+
+    #[test]
+    fn permanent_errors_not_retried() {
+        let retry = AppendRetry::new(2, Duration::from_millis(0));
+        let mut calls = 0;
+        let res: Result<(), AppendError> = retry.run(|| {
+            calls += 1;
+            Err(AppendError::Io(io::Error::from_raw_os_error(libc::ENOSPC)))
+        });
+        assert!(res.is_err());
+        assert_eq!(1, calls);
+    }
+}
@@ -7,10 +7,16 @@ use futures::{Async, Poll, Stream};
 use std::cell::RefCell;
 use std::rc::Rc;
 
+/// Batches the messages of the stream into pooled buffers. A batch is bound
+/// in bytes by the capacity of the buffers, and in entries by the maximum
+/// entries; messages past either bound start the next batch. Messages larger
+/// than the buffer capacity or the maximum message size are dropped.
 pub struct BatchMessageStream<S> {
     stream: S,
     buf_pool: Rc<RefCell<BytesPool>>,
     blocked_message: Option<SingleMessage>,
+    max_entries: usize,
+    max_message_bytes: usize,
 }
 
 impl<S> BatchMessageStream<S>
@@ -18,12 +24,30 @@ where
     S: Stream<Item = SingleMessage>,
 {
     pub fn new(stream: S, buf_pool: Rc<RefCell<BytesPool>>) -> BatchMessageStream<S> {
+        BatchMessageStream::with_max_entries(stream, buf_pool, usize::max_value())
+    }
+
+    /// Batches with at most `max_entries` entries in each batch.
+    pub fn with_max_entries(
+        stream: S,
+        buf_pool: Rc<RefCell<BytesPool>>,
+        max_entries: usize,
+    ) -> BatchMessageStream<S> {
         BatchMessageStream {
             stream,
             buf_pool,
             blocked_message: None,
+            max_entries: max_entries.max(1),
+            max_message_bytes: usize::max_value(),
         }
     }
+
+    /// Drops messages with a payload larger than `max_message_bytes`, which
+    /// the log would reject along with the rest of the batch.
+    pub fn max_message_bytes(mut self, max_message_bytes: usize) -> BatchMessageStream<S> {
+        self.max_message_bytes = max_message_bytes;
+        self
+    }
 }
 
 impl<S> Stream for BatchMessageStream<S>
@@ -47,7 +71,16 @@ where
         };
 
         // try to push the first message
-        if rare!(buf.push(client, req, &payload).is_err()) {
+        let mut entries = 0;
+        if rare!(payload.len() > self.max_message_bytes) {
+            warn!(
+                "Ignoring message clientId={}, reqId={} due to size {} > max size {}",
+                client,
+                req,
+                payload.len(),
+                self.max_message_bytes
+            );
+        } else if rare!(buf.push(client, req, &payload).is_err()) {
             warn!(
                 "Ignoring message clientId={}, reqId={} due to size {} > buffer capacity {}",
                 client,
@@ -55,16 +88,29 @@ where
                 payload.len(),
                 capacity
             );
+        } else {
+            entries += 1;
         }
 
-        // add more messages to the buffer, up to the capacity
-        loop {
+        // add more messages to the buffer, up to the capacity and the
+        // maximum entries
+        while entries < self.max_entries {
             match self.stream.poll()? {
                 Async::Ready(Some((client, req, payload))) => {
                     if rare!(payload.len() > capacity) {
                         warn!("Ignoring message clientId={}, reqId={} due to size {} > buffer capacity {}", client, req, payload.len(), capacity);
                         continue;
                     }
+                    if rare!(payload.len() > self.max_message_bytes) {
+                        warn!(
+                            "Ignoring message clientId={}, reqId={} due to size {} > max size {}",
+                            client,
+                            req,
+                            payload.len(),
+                            self.max_message_bytes
+                        );
+                        continue;
+                    }
 
                     // try to push the message, if there is no capacity
                     // save the message for another round of poll
@@ -78,6 +124,7 @@ where
                         self.blocked_message = Some((client, req, payload));
                         break;
                     }
+                    entries += 1;
                 }
                 Async::Ready(None) => return Ok(Async::Ready(None)),
                 Async::NotReady => {
@@ -173,6 +220,24 @@ mod tests {
         assert_eq!(2, v.len());
     }
 
+    #[test]
+    fn ignores_messages_over_max_message_bytes() {
+        let pool = Rc::new(RefCell::new(BytesPool::new(1024)));
+
+        let msgs = vec![
+            Bytes::from("12345"),
+            Bytes::from("456"),
+            Bytes::from("78901"),
+            Bytes::from("789"),
+        ];
+        let mut batch_stream =
+            BatchMessageStream::new(FakeStream(msgs.into()), pool).max_message_bytes(4);
+
+        let v = unwrap_async!(batch_stream.poll());
+        let payloads: Vec<&[u8]> = v.iter().map(|m| m.payload()).collect();
+        assert_eq!(vec![&b"456"[..], &b"789"[..]], payloads);
+    }
+
     #[test]
     fn batches_up_to_capacity() {
         let pool = Rc::new(RefCell::new(BytesPool::new(1024)));
@@ -205,6 +270,45 @@ mod tests {
         assert_eq!(2, v.len());
     }
 
+    #[test]
+    fn batches_up_to_max_entries() {
+        let pool = Rc::new(RefCell::new(BytesPool::new(16_384)));
+
+        let msgs: VecDeque<Bytes> = (0..10_000).map(|i| Bytes::from(format!("{}", i))).collect();
+        let mut batch_stream = BatchMessageStream::with_max_entries(FakeStream(msgs), pool, 100);
+
+        let mut total = 0;
+        while total < 10_000 {
+            let v = unwrap_async!(batch_stream.poll());
+            assert!(v.len() <= 100, "batch of {} entries", v.len());
+            assert!(
+                v.bytes().len() <= 16_384,
+                "batch of {} bytes",
+                v.bytes().len()
+            );
+            total += v.len();
+        }
+        assert_eq!(10_000, total);
+        assert!(batch_stream.poll().unwrap().is_not_ready());
+    }
+
+    #[test]
+    fn batches_up_to_capacity_before_max_entries() {
+        let pool = Rc::new(RefCell::new(BytesPool::new(1024)));
+
+        let msgs: VecDeque<Bytes> = (0..10_000).map(|_| Bytes::from("12345678")).collect();
+        let mut batch_stream = BatchMessageStream::with_max_entries(FakeStream(msgs), pool, 100);
+
+        let mut total = 0;
+        while total < 10_000 {
+            let v = unwrap_async!(batch_stream.poll());
+            assert!(v.len() < 100);
+            assert!(v.bytes().len() <= 1024);
+            total += v.len();
+        }
+        assert_eq!(10_000, total);
+    }
+
     struct FakeStream(VecDeque<Bytes>);
 
     impl Stream for FakeStream {
@@ -218,5 +322,4 @@ mod tests {
             }
         }
     }
-
 }
@@ -0,0 +1,299 @@
+use super::{open, AppendListener, AsyncLog, Messages, Priority};
+use bytes::Bytes;
+use commitlog::message::MessageSet;
+use config::LogConfig;
+use futures::{Future, Stream};
+use histogram::Histogram;
+use replication::FileSliceMessageReader;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::{env, process};
+
+/// Size of each read of the concurrent bulk read.
+const BULK_READ_BYTES: usize = 1_048_576;
+
+/// How the entries of the append workload are submitted to the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppendPath {
+    /// Batches of entries, with `AsyncLog::append_stream`.
+    Stream,
+
+    /// Single entries through the append queue and batching, with
+    /// `AsyncLog::append`.
+    Queued,
+
+    /// Single entries bypassing the append queue and batching, with
+    /// `AsyncLog::append_now`.
+    Now,
+}
+
+/// Append workload for `benchmark_append`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BenchConfig {
+    /// Total number of entries appended.
+    pub entries: usize,
+
+    /// Size of the payload of each entry, in bytes.
+    pub payload_bytes: usize,
+
+    /// Number of entries appended in each batch. Only used with
+    /// `AppendPath::Stream`, as the other paths append single entries.
+    pub batch_size: usize,
+
+    /// How the entries are submitted to the log.
+    pub path: AppendPath,
+
+    /// Bytes appended before the workload and read back repeatedly while it
+    /// runs, to measure the effect of bulk reads on appends. Zero reads
+    /// nothing.
+    pub bulk_read_bytes: usize,
+
+    /// Read worker threads of the log, as `LogConfig::read_threads`.
+    pub read_threads: usize,
+}
+
+impl Default for BenchConfig {
+    fn default() -> BenchConfig {
+        BenchConfig {
+            entries: 100_000,
+            payload_bytes: 100,
+            batch_size: 100,
+            path: AppendPath::Stream,
+            bulk_read_bytes: 0,
+            read_threads: 0,
+        }
+    }
+}
+
+/// Throughput and latency of the append workload.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchResult {
+    /// Entries appended per second.
+    pub appends_per_sec: f64,
+
+    /// Payload bytes appended per second.
+    pub bytes_per_sec: f64,
+
+    /// Median latency of an append, from the submission of the batch until
+    /// the entry is written to the log, in microseconds.
+    pub p50_us: u64,
+
+    /// 99th percentile append latency, in microseconds.
+    pub p99_us: u64,
+
+    /// 99.9th percentile append latency, in microseconds.
+    pub p999_us: u64,
+
+    /// Maximum append latency, in microseconds.
+    pub max_us: u64,
+}
+
+// sends the number of entries of each append to the benchmark
+struct CountListener(mpsc::Sender<usize>);
+
+impl AppendListener for CountListener {
+    fn notify_append(&mut self, appended: Messages) {
+        self.0.send(appended.len()).unwrap_or_default();
+    }
+
+    fn notify_duplicate(&mut self, _client_id: u64, _client_req_id: u64) {}
+}
+
+/// Runs a fixed append workload against a log in a temporary directory.
+///
+/// Intended to detect performance regressions, such as by asserting a
+/// minimum throughput in a test. The log is deleted after the workload.
+pub fn benchmark_append(cfg: &BenchConfig) -> BenchResult {
+    let dir = env::temp_dir().join(format!("log-bench-append-{:?}-{}", cfg.path, process::id()));
+    let mut log_cfg = LogConfig::default();
+    log_cfg.dir = dir.to_string_lossy().into_owned();
+    log_cfg.read_threads = cfg.read_threads;
+    if cfg.bulk_read_bytes > 0 {
+        // the bulk read spans several segments closed by a roll
+        log_cfg.segment_max_bytes = (cfg.bulk_read_bytes / 4).max(BULK_READ_BYTES);
+    }
+
+    let (notify, notified) = mpsc::channel();
+    let (mut log, _) = open(&log_cfg, CountListener(notify), FileSliceMessageReader)
+        .expect("Unable to open benchmark log");
+
+    let stop_reads = Arc::new(AtomicBool::new(false));
+    let bulk_reader = if cfg.bulk_read_bytes > 0 {
+        Some(spawn_bulk_reader(
+            &mut log,
+            cfg.bulk_read_bytes,
+            &stop_reads,
+        ))
+    } else {
+        None
+    };
+    // the acks of the bulk read entries are not part of the workload
+    while notified.try_recv().is_ok() {}
+
+    let payload = Bytes::from(vec![b'x'; cfg.payload_bytes]);
+    let mut latency = Histogram::default();
+    let mut appended = 0usize;
+
+    let start = Instant::now();
+    while appended < cfg.entries {
+        let append_start = Instant::now();
+        match cfg.path {
+            AppendPath::Stream => {
+                let n = cfg.batch_size.min(cfg.entries - appended).max(1);
+                for _ in log
+                    .append_stream(0, vec![payload.clone(); n])
+                    .wait()
+                    .map(|ack| ack.expect("Append failed"))
+                {
+                    latency
+                        .increment(to_us(append_start.elapsed()))
+                        .unwrap_or_default();
+                }
+                appended += n;
+                continue;
+            }
+            AppendPath::Queued => {
+                log.append(0, appended as u64, payload.clone(), Priority::High)
+                    .expect("Append failed");
+                notified.recv().expect("Log thread failed");
+            }
+            AppendPath::Now => {
+                log.append_now(0, payload.clone(), false)
+                    .wait()
+                    .expect("Append failed");
+            }
+        }
+        latency
+            .increment(to_us(append_start.elapsed()))
+            .unwrap_or_default();
+        appended += 1;
+    }
+    let elapsed = start.elapsed();
+
+    stop_reads.store(true, Ordering::Release);
+    if let Some(reader) = bulk_reader {
+        reader.join().expect("Bulk reader panicked");
+    }
+    drop(log);
+    if let Err(e) = fs::remove_dir_all(&dir) {
+        warn!("Unable to remove benchmark log {:?}: {}", dir, e);
+    }
+
+    let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+    BenchResult {
+        appends_per_sec: appended as f64 / secs,
+        bytes_per_sec: (appended * cfg.payload_bytes) as f64 / secs,
+        p50_us: latency.percentile(50.0).unwrap_or(0),
+        p99_us: latency.percentile(99.0).unwrap_or(0),
+        p999_us: latency.percentile(99.9).unwrap_or(0),
+        max_us: latency.maximum().unwrap_or(0),
+    }
+}
+
+/// Appends the bytes of entries, then spawns a thread reading them from the
+/// start, over and over, until stopped.
+fn spawn_bulk_reader(
+    log: &mut AsyncLog,
+    bytes: usize,
+    stop: &Arc<AtomicBool>,
+) -> thread::JoinHandle<()> {
+    let payload = Bytes::from(vec![b'r'; 1024]);
+    for _ in 0..(bytes / payload.len() / 1000).max(1) {
+        log.append_batch(0, vec![payload.clone(); 1000])
+            .wait()
+            .expect("Append failed");
+    }
+    log.flush().wait().expect("Flush failed");
+    let end = log
+        .last_offset()
+        .wait()
+        .expect("Read failed")
+        .map(|off| off + 1)
+        .unwrap_or(0);
+
+    let mut log = log.clone();
+    let stop = stop.clone();
+    thread::spawn(move || {
+        let mut offset = 0;
+        while !stop.load(Ordering::Acquire) {
+            let msgs = log
+                .read(offset, BULK_READ_BYTES)
+                .wait()
+                .expect("Bulk read failed");
+            offset = match msgs.next_offset() {
+                Some(next) if next < end => next,
+                _ => 0,
+            };
+        }
+    })
+}
+
+#[inline]
+fn to_us(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + u64::from(d.subsec_micros())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn append_throughput() {
+        let res = benchmark_append(&BenchConfig {
+            entries: 10_000,
+            ..BenchConfig::default()
+        });
+
+        // a generous floor, catching only severe regressions
+        assert!(
+            res.appends_per_sec > 10_000.0,
+            "append throughput regressed: {:?}",
+            res
+        );
+        assert!(res.p50_us <= res.p99_us);
+    }
+
+    #[test]
+    fn single_append_latency() {
+        let bench = |path| {
+            benchmark_append(&BenchConfig {
+                entries: 2_000,
+                path,
+                ..BenchConfig::default()
+            })
+        };
+        let queued = bench(AppendPath::Queued);
+        let now = bench(AppendPath::Now);
+
+        // generous ceilings, catching only severe regressions
+        for res in &[queued, now] {
+            assert!(
+                res.p50_us < 5_000,
+                "single append latency regressed: {:?}",
+                res
+            );
+        }
+    }
+
+    #[test]
+    fn bulk_reads_do_not_delay_appends() {
+        let res = benchmark_append(&BenchConfig {
+            entries: 2_000,
+            path: AppendPath::Now,
+            bulk_read_bytes: 16 * 1_048_576,
+            read_threads: 2,
+            ..BenchConfig::default()
+        });
+
+        // the reads of sealed segments are off the log thread, so a
+        // generous ceiling catches appends waiting behind them
+        assert!(
+            res.p99_us < 20_000,
+            "append latency under bulk reads: {:?}",
+            res
+        );
+    }
+}
@@ -13,15 +13,26 @@ lazy_static! {
 /// Pool for writable bytes
 pub struct BytesPool {
     buf_capacity: usize,
+    max_buffers: usize,
     unused: VecDeque<Bytes>,
+    misses: u64,
 }
 
 impl BytesPool {
     /// Creates a new pool for byte buffers with a given side capacity per byte buffer.
     pub fn new(buf_capacity: usize) -> BytesPool {
+        BytesPool::bounded(buf_capacity, usize::max_value())
+    }
+
+    /// Creates a pool holding at most `max_buffers` buffers, for buffers
+    /// that may be referenced for a long time, such as cached reads.
+    /// Buffers returned to a full pool are dropped.
+    pub fn bounded(buf_capacity: usize, max_buffers: usize) -> BytesPool {
         BytesPool {
             buf_capacity,
+            max_buffers,
             unused: VecDeque::new(),
+            misses: 0,
         }
     }
 
@@ -31,6 +42,12 @@ impl BytesPool {
         self.buf_capacity
     }
 
+    /// Number of takes that created a buffer rather than reusing one.
+    #[inline]
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
     /// Pull a buffer from the pool, or create one.
     pub fn take(&mut self) -> BytesMut {
         // try to pull from the front, if that doesn't
@@ -43,18 +60,25 @@ impl BytesPool {
                 bytes_mut
             }
             Some(Err(bytes)) => {
-                UNPOOLED_BUFFER_CREATE.inc();
                 self.unused.push_front(bytes);
-                BytesMut::with_capacity(self.buf_capacity)
+                self.create()
             }
-            None => BytesMut::with_capacity(self.buf_capacity),
+            None => self.create(),
         }
     }
 
+    fn create(&mut self) -> BytesMut {
+        UNPOOLED_BUFFER_CREATE.inc();
+        self.misses += 1;
+        BytesMut::with_capacity(self.buf_capacity)
+    }
+
     /// Return an unused buffer, which may have outstanding references.
     #[inline]
     pub fn push(&mut self, buf: Bytes) {
-        self.unused.push_back(buf);
+        if self.unused.len() < self.max_buffers {
+            self.unused.push_back(buf);
+        }
     }
 }
 
@@ -108,4 +132,35 @@ mod tests {
         assert_eq!(0, buf.len());
         assert!(data_ptr != buf.as_ptr());
     }
+
+    #[test]
+    fn bounded_drops_excess_buffers() {
+        let mut pool = BytesPool::bounded(1024, 2);
+        let held: Vec<_> = (0..3).map(|_| pool.take().freeze()).collect();
+        for buf in &held {
+            pool.push(buf.clone());
+        }
+        assert_eq!(2, pool.unused.len());
+    }
+
+    #[test]
+    fn no_misses_with_enough_buffers() {
+        // each round holds the buffers of 8 concurrent batches
+        let rounds = |pool: &mut BytesPool| {
+            for _ in 0..10 {
+                let held: Vec<_> = (0..8).map(|_| pool.take().freeze()).collect();
+                for buf in &held {
+                    pool.push(buf.clone());
+                }
+            }
+        };
+
+        let mut pool = BytesPool::bounded(1024, 8);
+        rounds(&mut pool);
+        assert_eq!(8, pool.misses());
+
+        let mut pool = BytesPool::bounded(1024, 4);
+        rounds(&mut pool);
+        assert_eq!(8 + 9 * 4, pool.misses());
+    }
 }
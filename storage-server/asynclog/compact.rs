@@ -0,0 +1,392 @@
+use super::log_options;
+use super::messages::Messages;
+use super::record::RecordMeta;
+use super::retention::{deletable_segments, policies, segments};
+use super::tombstone::Tombstones;
+use commitlog::message::{MessageBuf, MessageSet};
+use commitlog::{CommitLog, Offset, ReadLimit};
+use config::LogConfig;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+const READ_BYTES: usize = 4 * 1024 * 1024;
+
+/// Size of the log before and after an offline compaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactionReport {
+    /// Bytes of the segment log files before compaction.
+    pub bytes_before: u64,
+
+    /// Bytes of the segment log files after compaction.
+    pub bytes_after: u64,
+
+    /// Entries in the log before compaction.
+    pub records_before: u64,
+
+    /// Entries retained by the compaction.
+    pub records_after: u64,
+}
+
+/// Work done by a key compaction of the open log.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionStats {
+    /// Inactive segments read by the compaction.
+    pub segments_scanned: usize,
+
+    /// Entries dropped for a later entry with the same key.
+    pub messages_dropped: u64,
+
+    /// Bytes of segment log files removed by the compaction.
+    pub bytes_reclaimed: u64,
+}
+
+/// Rewrites the log directory applying the retention policies of the
+/// configuration. The log must not be open, such as by a running server.
+///
+/// Segments deleted by the retention policies are dropped and, with
+/// compaction enabled, so are the tombstoned entries. The retained entries
+/// keep their offsets and are rewritten into full segments. Every entry is
+/// verified as it is read, failing the compaction on corruption.
+///
+/// The compacted log is written to a temporary directory, then swapped with
+/// the original. The original is removed only once the swap completes.
+pub fn compact_offline(cfg: &LogConfig) -> Result<CompactionReport, Error> {
+    let dir = PathBuf::from(&cfg.dir);
+    let tmp_dir = sibling(&dir, "compacting");
+    let old_dir = sibling(&dir, "compacted-old");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir)?;
+    }
+
+    let before = segments(&dir)?;
+    let bytes_before = before.iter().map(|s| s.bytes).sum();
+    let deletable = deletable_segments(&policies(&cfg.retention), &before, SystemTime::now());
+    let start_offset = before.get(deletable).map(|s| s.base_offset).unwrap_or(0);
+    let tombstones = Tombstones::open(&dir)?;
+    let drop_tombstoned = cfg.retention.compact;
+
+    let src = CommitLog::new(log_options(cfg))?;
+    let mut tmp_cfg = cfg.clone();
+    tmp_cfg.dir = tmp_dir.to_string_lossy().into_owned();
+    let mut dst = CommitLog::new(log_options(&tmp_cfg))?;
+
+    let mut records_before = 0;
+    let mut records_after = 0;
+    let mut offset = before.first().map(|s| s.base_offset).unwrap_or(0);
+    loop {
+        let buf = read_verified(&src, offset)?;
+        records_before += buf.len() as u64;
+        offset = match buf.iter().last() {
+            Some(last) => last.offset() + 1,
+            None => break,
+        };
+
+        let retained = Messages::copy_filtered(&buf, |off| {
+            off >= start_offset && !(drop_tombstoned && tombstones.contains(off))
+        });
+        if retained.len() > 0 {
+            records_after += retained.len() as u64;
+            dst.append_with_offsets(&retained)
+                .map_err(|e| Error::new(ErrorKind::Other, format!("Append error: {}", e)))?;
+        }
+    }
+    dst.flush()?;
+    drop(dst);
+    drop(src);
+
+    // carry over the files other than segments, such as tombstones
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+        let is_segment = path
+            .extension()
+            .map(|ext| ext == "log" || ext == "index")
+            .unwrap_or(false);
+        if !is_segment && path.is_file() {
+            fs::copy(&path, tmp_dir.join(path.file_name().unwrap()))?;
+        }
+    }
+
+    let bytes_after = segments(&tmp_dir)?.iter().map(|s| s.bytes).sum();
+
+    // a crash between the renames leaves both logs intact, with the
+    // original at the old directory
+    fs::rename(&dir, &old_dir)?;
+    fs::rename(&tmp_dir, &dir)?;
+    fs::remove_dir_all(&old_dir)?;
+
+    let report = CompactionReport {
+        bytes_before,
+        bytes_after,
+        records_before,
+        records_after,
+    };
+    info!("Compacted log {:?}: {:?}", dir, report);
+    Ok(report)
+}
+
+/// Rewrites the inactive segments of the open log, keeping only the latest
+/// entry of each key. Entries without a key are kept, as is the active
+/// segment. The retained entries keep their offsets.
+///
+/// The compacted segments are written to a temporary directory, then renamed
+/// over the originals, and the originals left over are removed last. A crash
+/// during the swap leaves original segments with the entries of the compacted
+/// ones, so retained entries are never lost. The log must be reopened with
+/// `reopen` once the segments are swapped.
+pub fn compact_keys(log: &CommitLog, cfg: &LogConfig) -> Result<CompactionStats, Error> {
+    let dir = PathBuf::from(&cfg.dir);
+    let before = segments(&dir)?;
+    let (active_base, inactive) = match before.split_last() {
+        Some((active, inactive)) if !inactive.is_empty() => (active.base_offset, inactive),
+        _ => return Ok(CompactionStats::default()),
+    };
+
+    // entries superseded by a later entry of the key, including later
+    // entries in the active segment
+    let mut latest: HashMap<Vec<u8>, Offset> = HashMap::new();
+    let mut dropped = HashSet::new();
+    let mut offset = inactive[0].base_offset;
+    loop {
+        let buf = read_verified(log, offset)?;
+        for msg in buf.iter() {
+            let key = match RecordMeta::parse(msg.metadata()) {
+                Ok(RecordMeta { key: Some(key), .. }) => key.to_vec(),
+                _ => continue,
+            };
+            if let Some(prev) = latest.insert(key, msg.offset()) {
+                if prev < active_base {
+                    dropped.insert(prev);
+                }
+            }
+        }
+        offset = match buf.iter().last() {
+            Some(last) => last.offset() + 1,
+            None => break,
+        };
+    }
+
+    let mut stats = CompactionStats {
+        segments_scanned: inactive.len(),
+        ..CompactionStats::default()
+    };
+    if dropped.is_empty() {
+        return Ok(stats);
+    }
+
+    let tmp_dir = sibling(&dir, "compacting");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir)?;
+    }
+    let mut tmp_cfg = cfg.clone();
+    tmp_cfg.dir = tmp_dir.to_string_lossy().into_owned();
+    let mut dst = CommitLog::new(log_options(&tmp_cfg))?;
+    let mut offset = inactive[0].base_offset;
+    while offset < active_base {
+        let buf = read_verified(log, offset)?;
+        offset = match buf.iter().last() {
+            Some(last) => last.offset() + 1,
+            None => break,
+        };
+        let retained =
+            Messages::copy_filtered(&buf, |off| off < active_base && !dropped.contains(&off));
+        if retained.len() > 0 {
+            dst.append_with_offsets(&retained)
+                .map_err(|e| Error::new(ErrorKind::Other, format!("Append error: {}", e)))?;
+        }
+    }
+    dst.flush()?;
+    drop(dst);
+
+    let after = segments(&tmp_dir)?;
+    for segment in &after {
+        for ext in &["log", "index"] {
+            let name = format!("{:020}.{}", segment.base_offset, ext);
+            fs::rename(tmp_dir.join(&name), dir.join(&name))?;
+        }
+    }
+    for segment in inactive {
+        if after.iter().all(|s| s.base_offset != segment.base_offset) {
+            for ext in &["log", "index"] {
+                let path = dir.join(format!("{:020}.{}", segment.base_offset, ext));
+                if path.exists() {
+                    fs::remove_file(path)?;
+                }
+            }
+        }
+    }
+
+    let bytes_before: u64 = inactive.iter().map(|s| s.bytes).sum();
+    let bytes_after: u64 = after.iter().map(|s| s.bytes).sum();
+    stats.messages_dropped = dropped.len() as u64;
+    stats.bytes_reclaimed = bytes_before.saturating_sub(bytes_after);
+    info!("Compacted keys of log {:?}: {:?}", dir, stats);
+    Ok(stats)
+}
+
+/// Replaces the log with the log reopened from its directory, such as after
+/// its segments are swapped by `compact_keys`.
+///
+/// The log is closed before it is reopened, as closing it writes the index of
+/// the active segment shared with the reopened log. It is held by a log in
+/// a temporary directory meanwhile, which is left in place of the log if the
+/// reopen fails.
+pub fn reopen(log: &mut CommitLog, cfg: &LogConfig) -> Result<(), Error> {
+    let tmp_dir = sibling(Path::new(&cfg.dir), "reopening");
+    let mut tmp_cfg = cfg.clone();
+    tmp_cfg.dir = tmp_dir.to_string_lossy().into_owned();
+    drop(mem::replace(log, CommitLog::new(log_options(&tmp_cfg))?));
+    drop(mem::replace(log, CommitLog::new(log_options(cfg))?));
+    fs::remove_dir_all(&tmp_dir)
+}
+
+/// Removes the segments of the open log, reopening it empty.
+///
+/// The log is closed before the segments are removed, so that closing it
+/// does not write the index of the active segment back. It is held by a log
+/// in a temporary directory meanwhile, as with `reopen`.
+pub fn reset(log: &mut CommitLog, cfg: &LogConfig) -> Result<(), Error> {
+    let dir = Path::new(&cfg.dir);
+    let tmp_dir = sibling(dir, "resetting");
+    let mut tmp_cfg = cfg.clone();
+    tmp_cfg.dir = tmp_dir.to_string_lossy().into_owned();
+    drop(mem::replace(log, CommitLog::new(log_options(&tmp_cfg))?));
+    for segment in segments(dir)? {
+        for ext in &["log", "index"] {
+            let path = dir.join(format!("{:020}.{}", segment.base_offset, ext));
+            match fs::remove_file(&path) {
+                Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+                res => res?,
+            }
+        }
+    }
+    drop(mem::replace(log, CommitLog::new(log_options(cfg))?));
+    fs::remove_dir_all(&tmp_dir)
+}
+
+/// Reads entries from the offset, failing if an entry is corrupt.
+fn read_verified(log: &CommitLog, offset: Offset) -> Result<MessageBuf, Error> {
+    let buf = log
+        .read(offset, ReadLimit::max_bytes(READ_BYTES))
+        .map_err(|e| Error::new(ErrorKind::Other, format!("Read error: {:?}", e)))?;
+    for msg in buf.iter() {
+        if !msg.verify_hash() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Corrupt entry at offset {}", msg.offset()),
+            ));
+        }
+    }
+    Ok(buf)
+}
+
+/// Path next to the directory, with the suffix appended to the name.
+fn sibling(dir: &Path, suffix: &str) -> PathBuf {
+    let mut name = dir
+        .file_name()
+        .map(|n| n.to_os_string())
+        .unwrap_or_else(|| OsString::from("log"));
+    name.push(".");
+    name.push(suffix);
+    dir.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asynclog::messages::MessagesMut;
+    use asynclog::record::RecordMeta;
+    use bytes::BytesMut;
+    use commitlog::message::set_offsets;
+    use std::{env, process};
+
+    #[test]
+    fn compacts_tombstoned_entries() {
+        let dir = env::temp_dir().join(format!("log-compact-test-{}", process::id()));
+        let mut cfg = LogConfig::default();
+        cfg.dir = dir.to_string_lossy().into_owned();
+        cfg.retention.compact = true;
+
+        {
+            let mut log = CommitLog::new(log_options(&cfg)).unwrap();
+            for i in 0..10 {
+                log.append_msg(format!("entry-{}", i)).unwrap();
+            }
+            log.flush().unwrap();
+            let mut tombstones = Tombstones::open(&dir).unwrap();
+            tombstones.insert(2..5).unwrap();
+        }
+
+        let report = compact_offline(&cfg).unwrap();
+        assert_eq!(10, report.records_before);
+        assert_eq!(7, report.records_after);
+        assert!(report.bytes_after < report.bytes_before);
+        assert!(!sibling(&dir, "compacting").exists());
+        assert!(!sibling(&dir, "compacted-old").exists());
+
+        let log = CommitLog::new(log_options(&cfg)).unwrap();
+        let offsets: Vec<_> = log
+            .read(0, ReadLimit::max_bytes(4096))
+            .unwrap()
+            .iter()
+            .map(|m| m.offset())
+            .collect();
+        assert_eq!(vec![0, 1, 5, 6, 7, 8, 9], offsets);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_record_sequences() {
+        let dir = env::temp_dir().join(format!("log-compact-seq-test-{}", process::id()));
+        let mut cfg = LogConfig::default();
+        cfg.dir = dir.to_string_lossy().into_owned();
+        cfg.retention.compact = true;
+
+        {
+            let mut log = CommitLog::new(log_options(&cfg)).unwrap();
+            let mut buf: MessagesMut = BytesMut::with_capacity(4096).into();
+            for i in 0..10 {
+                let key = format!("key-{}", i);
+                buf.push_sequenced_record(0, i, Some(100 + i), Some(key.as_bytes()), &[], b"v")
+                    .unwrap();
+            }
+            set_offsets(&mut buf, 0);
+            log.append_with_offsets(&buf.freeze()).unwrap();
+            log.flush().unwrap();
+            let mut tombstones = Tombstones::open(&dir).unwrap();
+            tombstones.insert(0..3).unwrap();
+        }
+
+        compact_offline(&cfg).unwrap();
+
+        // the offsets of the records may change, the sequences do not
+        let log = CommitLog::new(log_options(&cfg)).unwrap();
+        let buf = log.read(0, ReadLimit::max_bytes(4096)).unwrap();
+        let records: Vec<_> = buf
+            .iter()
+            .map(|m| {
+                let meta = RecordMeta::parse(m.metadata()).unwrap();
+                (meta.sequence.unwrap(), meta.key.unwrap().to_vec())
+            })
+            .collect();
+        let expected: Vec<_> = (3..10)
+            .map(|i| (100 + i, format!("key-{}", i).into_bytes()))
+            .collect();
+        assert_eq!(expected, records);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sibling_paths() {
+        assert_eq!(
+            PathBuf::from("/var/log.compacting"),
+            sibling(Path::new("/var/log"), "compacting")
+        );
+    }
+}
@@ -0,0 +1,168 @@
+use super::persist::write_atomically;
+use super::DEFAULT_TOPIC;
+use byteorder::{ByteOrder, LittleEndian};
+use commitlog::Offset;
+use prometheus::GaugeVec;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+const CONSUMER_OFFSETS_FILE: &str = "consumer_offsets";
+const CONSUMER_OFFSETS_TMP_FILE: &str = "consumer_offsets.tmp";
+const ENTRY_SIZE: usize = 16;
+
+lazy_static! {
+    static ref CONSUMER_LAG: GaugeVec = register_gauge_vec!(
+        "log_consumer_lag",
+        "Number of entries appended after the consumer committed offset.",
+        &["topic", "consumer"]
+    )
+    .unwrap();
+}
+
+/// Identifier of a consumer committing offsets.
+pub type ConsumerId = u64;
+
+/// Offsets committed by consumers of the log, persisted in the log directory.
+pub struct ConsumerOffsets {
+    path: PathBuf,
+    offsets: BTreeMap<ConsumerId, Offset>,
+    // topic of the log, labelling the lag metric
+    topic: String,
+}
+
+impl ConsumerOffsets {
+    /// Loads the consumer offsets persisted in the log directory.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<ConsumerOffsets> {
+        let path = dir.as_ref().join(CONSUMER_OFFSETS_FILE);
+        let offsets = match File::open(&path) {
+            Ok(mut f) => {
+                let mut bytes = vec![];
+                f.read_to_end(&mut bytes)?;
+                decode(&bytes)?
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(ConsumerOffsets {
+            path,
+            offsets,
+            topic: DEFAULT_TOPIC.to_string(),
+        })
+    }
+
+    /// Reports the lag of the consumers as that of the log of the topic.
+    pub fn with_topic(mut self, topic: &str) -> ConsumerOffsets {
+        self.topic = topic.to_string();
+        self
+    }
+
+    /// Records the committed offset of the consumer, persisting the offsets to disk.
+    pub fn commit(&mut self, consumer: ConsumerId, offset: Offset) -> io::Result<()> {
+        self.offsets.insert(consumer, offset);
+        self.persist()
+    }
+
+    /// Committed offsets, ordered by consumer.
+    pub fn offsets(&self) -> Vec<(ConsumerId, Offset)> {
+        self.offsets.iter().map(|(c, o)| (*c, *o)).collect()
+    }
+
+    /// Number of entries after the committed offset of the consumer, or `None`
+    /// if the consumer has not committed an offset.
+    pub fn lag(&self, consumer: ConsumerId, last_offset: Option<Offset>) -> Option<u64> {
+        self.offsets
+            .get(&consumer)
+            .map(|committed| lag(*committed, last_offset))
+    }
+
+    /// Updates the lag metric for each of the consumers.
+    pub fn update_metrics(&self, last_offset: Option<Offset>) {
+        for (consumer, committed) in &self.offsets {
+            CONSUMER_LAG
+                .with_label_values(&[&self.topic, &consumer.to_string()])
+                .set(lag(*committed, last_offset) as f64);
+        }
+    }
+
+    fn persist(&self) -> io::Result<()> {
+        let mut bytes = vec![0u8; self.offsets.len() * ENTRY_SIZE];
+        for ((consumer, offset), buf) in self.offsets.iter().zip(bytes.chunks_mut(ENTRY_SIZE)) {
+            LittleEndian::write_u64(&mut buf[0..8], *consumer);
+            LittleEndian::write_u64(&mut buf[8..16], *offset);
+        }
+
+        write_atomically(&self.path, CONSUMER_OFFSETS_TMP_FILE, &bytes)
+    }
+}
+
+#[inline]
+fn lag(committed: Offset, last_offset: Option<Offset>) -> u64 {
+    last_offset
+        .map(|last| last.saturating_sub(committed))
+        .unwrap_or(0)
+}
+
+fn decode(bytes: &[u8]) -> io::Result<BTreeMap<ConsumerId, Offset>> {
+    if bytes.len() % ENTRY_SIZE != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid consumer offsets file length",
+        ));
+    }
+
+    Ok(bytes
+        .chunks(ENTRY_SIZE)
+        .map(|buf| {
+            (
+                LittleEndian::read_u64(&buf[0..8]),
+                LittleEndian::read_u64(&buf[8..16]),
+            )
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn commit_offsets_and_lag() {
+        let dir = env::temp_dir().join(format!("log-consumers-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        {
+            let mut c = ConsumerOffsets::open(&dir).unwrap();
+            c.commit(2, 40).unwrap();
+            c.commit(1, 95).unwrap();
+            c.commit(2, 50).unwrap();
+        }
+
+        let c = ConsumerOffsets::open(&dir).unwrap();
+        assert_eq!(vec![(1, 95), (2, 50)], c.offsets());
+        assert_eq!(Some(5), c.lag(1, Some(100)));
+        assert_eq!(Some(50), c.lag(2, Some(100)));
+        assert_eq!(None, c.lag(3, Some(100)));
+        assert_eq!(Some(0), c.lag(1, None));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn decode_encoded() {
+        let mut bytes = vec![0u8; 32];
+        LittleEndian::write_u64(&mut bytes[0..8], 1);
+        LittleEndian::write_u64(&mut bytes[8..16], 10);
+        LittleEndian::write_u64(&mut bytes[16..24], 2);
+        LittleEndian::write_u64(&mut bytes[24..32], 20);
+        let offsets = decode(&bytes).unwrap();
+        assert_eq!(Some(&10), offsets.get(&1));
+        assert_eq!(Some(&20), offsets.get(&2));
+        assert!(decode(&bytes[0..10]).is_err());
+    }
+}
@@ -0,0 +1,163 @@
+use super::Messages;
+use commitlog::Offset;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Error, ErrorKind};
+
+/// Length of a cursor token: the offset and the signature, each as 16 hex digits.
+const TOKEN_LEN: usize = 32;
+
+lazy_static! {
+    // keys signing the cursors issued by this process
+    static ref CURSOR_KEYS: RandomState = RandomState::new();
+}
+
+/// Position of a paged read.
+///
+/// Cursors are returned to clients as opaque tokens signed by the server.
+/// The signature keys are generated when the server starts, so a token is
+/// only valid on the server that issued it, until the server restarts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cursor {
+    offset: Offset,
+}
+
+impl Cursor {
+    /// Cursor for the first page of a read starting at the offset.
+    pub fn start(offset: Offset) -> Cursor {
+        Cursor { offset }
+    }
+
+    /// Parses a token issued by the server, rejecting tokens that were not
+    /// issued by this server or were modified.
+    pub fn parse(token: &str) -> Result<Cursor, Error> {
+        let invalid = || Error::new(ErrorKind::InvalidInput, "Invalid cursor");
+        if token.len() != TOKEN_LEN || !token.is_ascii() {
+            return Err(invalid());
+        }
+
+        let offset = u64::from_str_radix(&token[..16], 16).map_err(|_| invalid())?;
+        let signature = u64::from_str_radix(&token[16..], 16).map_err(|_| invalid())?;
+        if signature != sign(offset) {
+            return Err(invalid());
+        }
+        Ok(Cursor { offset })
+    }
+
+    /// Opaque token for the cursor.
+    pub fn token(&self) -> String {
+        format!("{:016x}{:016x}", self.offset, sign(self.offset))
+    }
+
+    #[inline]
+    pub fn offset(&self) -> Offset {
+        self.offset
+    }
+}
+
+fn sign(offset: Offset) -> u64 {
+    let mut hasher = CURSOR_KEYS.build_hasher();
+    hasher.write_u64(offset);
+    hasher.finish()
+}
+
+/// Reads a page at the cursor with `read`, returning the cursor of the next
+/// page, or `None` if the page reaches the end of the log.
+///
+/// Cursors before the first offset of the log are stale, as the entries have
+/// since been removed by retention.
+pub fn read_page<F>(
+    cursor: Cursor,
+    first_offset: Option<Offset>,
+    next_offset: Offset,
+    read: F,
+) -> Result<(Messages, Option<Cursor>), Error>
+where
+    F: FnOnce(Offset) -> Result<Messages, Error>,
+{
+    let pos = cursor.offset;
+    if pos > next_offset {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Cursor is past the end of the log",
+        ));
+    }
+    if first_offset.map(|first| pos < first).unwrap_or(false) {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            "Stale cursor, the entries have been removed from the log",
+        ));
+    }
+    if pos == next_offset {
+        return Ok((Messages::empty(), None));
+    }
+
+    let msgs = read(pos)?;
+    let next = match msgs.next_offset() {
+        Some(off) if off < next_offset => Some(Cursor::start(off)),
+        _ => None,
+    };
+    Ok((msgs, next))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use commitlog::message::MessageSet;
+    use commitlog::{CommitLog, LogOptions, ReadLimit};
+    use std::{env, fs, process};
+
+    #[test]
+    fn pages_continue_from_cursor() {
+        let dir = env::temp_dir().join(format!("log-cursor-test-{}", process::id()));
+        let mut log = CommitLog::new(LogOptions::new(&dir)).unwrap();
+        for i in 0..10 {
+            log.append_msg(format!("{}", i)).unwrap();
+        }
+
+        let mut offsets = Vec::new();
+        let mut pages = 0;
+        let mut cursor = Some(Cursor::start(0));
+        while let Some(c) = cursor {
+            // the client only holds the token between pages
+            let c = Cursor::parse(&c.token()).unwrap();
+            let (msgs, next) = read_page(c, Some(0), log.next_offset(), |pos| {
+                let msgs = log.read(pos, ReadLimit::max_bytes(100)).unwrap();
+                Ok(Messages::copy_from(&msgs))
+            })
+            .unwrap();
+            offsets.extend(msgs.iter().map(|m| m.offset()));
+            pages += 1;
+            cursor = next;
+        }
+        assert_eq!((0..10).collect::<Vec<_>>(), offsets);
+        assert!(pages > 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_cursors() {
+        let token = Cursor::start(12).token();
+        assert_eq!(Cursor::start(12), Cursor::parse(&token).unwrap());
+
+        // offset changed without the signature
+        let tampered = format!("{:016x}{}", 13, &token[16..]);
+        let err = Cursor::parse(&tampered).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+
+        assert!(Cursor::parse("").is_err());
+        assert!(Cursor::parse("not a cursor").is_err());
+        assert!(Cursor::parse(&format!("{}0", token)).is_err());
+
+        let read = |_| -> Result<Messages, Error> { panic!("Unexpected read") };
+        let err = read_page(Cursor::start(2), Some(5), 10, read).unwrap_err();
+        assert_eq!(ErrorKind::NotFound, err.kind());
+        let err = read_page(Cursor::start(11), Some(5), 10, read).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+
+        let (msgs, next) = read_page(Cursor::start(10), Some(5), 10, read).unwrap();
+        assert_eq!(0, msgs.len());
+        assert!(next.is_none());
+    }
+}
@@ -0,0 +1,127 @@
+use bytes::{Bytes, BytesMut};
+use commitlog::message::MessageSet;
+use commitlog::Offset;
+
+/// Log entry without the payload, for scans that need only the offsets,
+/// sizes, keys and headers of entries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryMeta {
+    /// Offset of the entry.
+    pub offset: Offset,
+
+    /// Size of the payload, in bytes.
+    pub payload_len: usize,
+
+    /// Metadata of the entry, parsed with `RecordMeta::parse`.
+    pub metadata: Bytes,
+}
+
+/// Metadata of the entries of a read, without the payloads.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetadataRead {
+    pub entries: Vec<EntryMeta>,
+
+    /// Offset after the last entry read, whether or not the entry was
+    /// retained, as `Messages::next_offset`.
+    pub next_offset: Option<Offset>,
+}
+
+impl MetadataRead {
+    /// Copies the metadata of the entries with offsets accepted by the
+    /// filter. The payloads are not copied.
+    pub fn copy_filtered<M, F>(set: &M, mut filter: F) -> MetadataRead
+    where
+        M: MessageSet,
+        F: FnMut(Offset) -> bool,
+    {
+        let meta_bytes = set.iter().map(|m| m.metadata().len()).sum::<usize>();
+        let mut buf = BytesMut::with_capacity(meta_bytes);
+        let mut read = MetadataRead::default();
+        for msg in set.iter() {
+            read.next_offset = Some(msg.offset() + 1);
+            if !filter(msg.offset()) {
+                continue;
+            }
+
+            buf.extend_from_slice(msg.metadata());
+            read.entries.push(EntryMeta {
+                offset: msg.offset(),
+                payload_len: msg.payload().len(),
+                metadata: buf.split_to(msg.metadata().len()).freeze(),
+            });
+        }
+        read
+    }
+
+    /// Copies the metadata of the entries. The payloads are not copied.
+    pub fn copy_from<M: MessageSet>(set: &M) -> MetadataRead {
+        MetadataRead::copy_filtered(set, |_| true)
+    }
+
+    /// Retains only the entries before the end offset (exclusive). If any
+    /// entries are removed, the next offset is the end offset.
+    pub fn take_until(mut self, end: Offset) -> MetadataRead {
+        match self.next_offset {
+            Some(next) if next > end => {
+                self.entries.retain(|e| e.offset < end);
+                self.next_offset = Some(end);
+                self
+            }
+            _ => self,
+        }
+    }
+
+    /// Retains only the first `max_entries` entries. If any entries are
+    /// removed, the next offset is the offset after the last entry retained.
+    pub fn take(mut self, max_entries: usize) -> MetadataRead {
+        if self.entries.len() > max_entries {
+            self.next_offset = Some(match max_entries {
+                0 => self.entries[0].offset,
+                n => self.entries[n - 1].offset + 1,
+            });
+            self.entries.truncate(max_entries);
+        }
+        self
+    }
+
+    /// Bytes held by the read, excluding the payloads.
+    pub fn retained_bytes(&self) -> usize {
+        self.entries.iter().map(|e| e.metadata.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asynclog::MessagesMut;
+    use commitlog::message::set_offsets;
+
+    #[test]
+    fn copies_metadata_without_payloads() {
+        let mut buf: MessagesMut = BytesMut::with_capacity(8192).into();
+        for i in 0..4 {
+            buf.push(1, i, vec![0u8; 1000 + i as usize]).unwrap();
+        }
+        set_offsets(&mut buf, 20);
+
+        let read = MetadataRead::copy_filtered(&buf, |off| off != 21);
+        let offsets: Vec<_> = read.entries.iter().map(|e| e.offset).collect();
+        assert_eq!(vec![20, 22, 23], offsets);
+        let sizes: Vec<_> = read.entries.iter().map(|e| e.payload_len).collect();
+        assert_eq!(vec![1000, 1002, 1003], sizes);
+        assert_eq!(Some(24), read.next_offset);
+        assert_eq!(
+            &buf.iter().nth(2).unwrap().metadata()[..],
+            &read.entries[1].metadata[..]
+        );
+        assert_eq!(3 * 16, read.retained_bytes());
+
+        let read = read.take_until(23);
+        assert_eq!(2, read.entries.len());
+        assert_eq!(Some(23), read.next_offset);
+
+        let read = read.take(1);
+        assert_eq!(1, read.entries.len());
+        assert_eq!(Some(21), read.next_offset);
+    }
+}
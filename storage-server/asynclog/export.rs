@@ -0,0 +1,261 @@
+//! Portable file of a range of log entries, for moving entries between
+//! clusters.
+//!
+//! The file starts with a header of a magic number and the format version,
+//! followed by an entry for each message, in offset order, up to the end of
+//! the file:
+//!
+//! ```text
+//! header: | magic (8 bytes) | version (u16 LE) |
+//! entry:  | offset (u64 LE) | CRC-32 of payload (u32 LE) | payload length (u32 LE) | payload |
+//! ```
+use super::AsyncLog;
+use byteorder::{ByteOrder, LittleEndian};
+use bytes::Bytes;
+use commitlog::message::MessageSet;
+use commitlog::Offset;
+use crc32fast;
+use futures::Future;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Error, ErrorKind, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+
+/// Magic number starting an export file.
+pub const EXPORT_MAGIC: &[u8; 8] = b"LOGEXPRT";
+
+/// Version of the export format written.
+pub const EXPORT_VERSION: u16 = 1;
+
+const HEADER_SIZE: usize = 10;
+const ENTRY_HEADER_SIZE: usize = 16;
+
+/// Result of an export of a range of the log.
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ExportSummary {
+    /// Number of messages written.
+    pub messages: u64,
+
+    /// Bytes written to the file, including the header.
+    pub bytes: u64,
+}
+
+/// Entry read from an export file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportEntry {
+    pub offset: Offset,
+    pub payload: Bytes,
+}
+
+/// Writes messages to an export file.
+pub struct ExportWriter<W> {
+    out: W,
+    summary: ExportSummary,
+}
+
+impl<W: Write> ExportWriter<W> {
+    /// Starts the export file with the header.
+    pub fn new(mut out: W) -> io::Result<ExportWriter<W>> {
+        let mut header = [0u8; HEADER_SIZE];
+        header[0..8].copy_from_slice(EXPORT_MAGIC);
+        LittleEndian::write_u16(&mut header[8..10], EXPORT_VERSION);
+        out.write_all(&header)?;
+        Ok(ExportWriter {
+            out,
+            summary: ExportSummary {
+                messages: 0,
+                bytes: HEADER_SIZE as u64,
+            },
+        })
+    }
+
+    /// Writes an entry for each of the messages.
+    pub fn write<M: MessageSet>(&mut self, msgs: &M) -> io::Result<()> {
+        for msg in msgs.iter() {
+            let payload = msg.payload();
+            let mut header = [0u8; ENTRY_HEADER_SIZE];
+            LittleEndian::write_u64(&mut header[0..8], msg.offset());
+            LittleEndian::write_u32(&mut header[8..12], crc32fast::hash(payload));
+            LittleEndian::write_u32(&mut header[12..16], payload.len() as u32);
+            self.out.write_all(&header)?;
+            self.out.write_all(payload)?;
+            self.summary.messages += 1;
+            self.summary.bytes += (ENTRY_HEADER_SIZE + payload.len()) as u64;
+        }
+        Ok(())
+    }
+
+    /// Flushes the entries written, returning the output and the summary.
+    pub fn finish(mut self) -> io::Result<(W, ExportSummary)> {
+        self.out.flush()?;
+        Ok((self.out, self.summary))
+    }
+}
+
+/// Reads the entries of an export file, verifying the checksum of each.
+pub struct ExportReader<R> {
+    input: R,
+    version: u16,
+}
+
+impl<R: Read> ExportReader<R> {
+    /// Reads the header of the export file, failing with
+    /// `ErrorKind::InvalidData` if not an export file of a known version.
+    pub fn new(mut input: R) -> io::Result<ExportReader<R>> {
+        let mut header = [0u8; HEADER_SIZE];
+        input.read_exact(&mut header)?;
+        if &header[0..8] != EXPORT_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a log export file"));
+        }
+        let version = LittleEndian::read_u16(&header[8..10]);
+        if version != EXPORT_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported log export version {}", version),
+            ));
+        }
+        Ok(ExportReader { input, version })
+    }
+
+    /// Version of the export format of the file.
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// Reads the next entry, or `None` at the end of the file.
+    pub fn read_entry(&mut self) -> io::Result<Option<ExportEntry>> {
+        let mut header = [0u8; ENTRY_HEADER_SIZE];
+        // the file ends between entries
+        match self.input.read(&mut header[..1])? {
+            0 => return Ok(None),
+            _ => self.input.read_exact(&mut header[1..])?,
+        }
+        let offset = LittleEndian::read_u64(&header[0..8]);
+        let crc32 = LittleEndian::read_u32(&header[8..12]);
+        let len = LittleEndian::read_u32(&header[12..16]) as usize;
+
+        let mut payload = vec![0u8; len];
+        self.input.read_exact(&mut payload)?;
+        if crc32fast::hash(&payload) != crc32 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Checksum mismatch for the entry at offset {}", offset),
+            ));
+        }
+        Ok(Some(ExportEntry {
+            offset,
+            payload: Bytes::from(payload),
+        }))
+    }
+}
+
+impl<R: Read> Iterator for ExportReader<R> {
+    type Item = io::Result<ExportEntry>;
+
+    fn next(&mut self) -> Option<io::Result<ExportEntry>> {
+        match self.read_entry() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Writes the entries of the range to a new file at the path, reading from
+/// the log in chunks of `read_bytes` so that no single read holds the log
+/// for long. Entries appended while exporting are included up to the end of
+/// the range.
+pub(super) fn export_range(
+    log: &mut AsyncLog,
+    range: Range<Offset>,
+    path: &Path,
+    read_bytes: usize,
+) -> io::Result<ExportSummary> {
+    let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    let mut writer = ExportWriter::new(BufWriter::new(file))?;
+
+    let mut pos = range.start;
+    while pos < range.end {
+        let msgs = log.read_range(pos, Some(range.end), read_bytes).wait()?;
+        writer.write(&msgs)?;
+        match msgs.next_offset() {
+            Some(next) if next > pos => pos = next,
+            // reached the end of the log
+            _ => break,
+        }
+    }
+
+    let (out, summary) = writer.finish()?;
+    out.into_inner()?.sync_all()?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asynclog::MessagesMut;
+    use bytes::BytesMut;
+    use commitlog::message::set_offsets;
+
+    fn messages(base: Offset, n: u64) -> MessagesMut {
+        let mut buf: MessagesMut = BytesMut::with_capacity(4096).into();
+        for i in 0..n {
+            buf.push(1, i, format!("entry-{}", base + i)).unwrap();
+        }
+        set_offsets(&mut buf, base);
+        buf
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut writer = ExportWriter::new(Vec::new()).unwrap();
+        writer.write(&messages(10, 3)).unwrap();
+        writer.write(&messages(20, 2)).unwrap();
+        let (file, summary) = writer.finish().unwrap();
+        assert_eq!(5, summary.messages);
+        assert_eq!(file.len() as u64, summary.bytes);
+
+        let reader = ExportReader::new(&file[..]).unwrap();
+        assert_eq!(EXPORT_VERSION, reader.version());
+        let entries = reader.collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(
+            vec![10, 11, 12, 20, 21],
+            entries.iter().map(|e| e.offset).collect::<Vec<_>>()
+        );
+        assert_eq!(&b"entry-21"[..], &entries[4].payload[..]);
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        let mut writer = ExportWriter::new(Vec::new()).unwrap();
+        writer.write(&messages(0, 2)).unwrap();
+        let (file, _) = writer.finish().unwrap();
+
+        let err = ExportReader::new(&b"NOTANEXPORT"[..]).err().unwrap();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+
+        let mut newer = file.clone();
+        LittleEndian::write_u16(&mut newer[8..10], EXPORT_VERSION + 1);
+        let err = ExportReader::new(&newer[..]).err().unwrap();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+
+        // a corrupt payload fails the entry
+        let mut corrupt = file.clone();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0xff;
+        let entries = ExportReader::new(&corrupt[..]).unwrap().collect::<Vec<_>>();
+        assert!(entries[0].is_ok());
+        assert_eq!(
+            ErrorKind::InvalidData,
+            entries[1].as_ref().unwrap_err().kind()
+        );
+
+        // as does a truncated entry
+        let truncated = &file[..file.len() - 3];
+        let entries = ExportReader::new(truncated).unwrap().collect::<Vec<_>>();
+        assert_eq!(
+            ErrorKind::UnexpectedEof,
+            entries[1].as_ref().unwrap_err().kind()
+        );
+    }
+}
@@ -0,0 +1,166 @@
+use super::record::RecordMeta;
+use super::Messages;
+use bytes::Bytes;
+use commitlog::message::MessageSet;
+use std::io::{Error, ErrorKind};
+
+/// Maximum number of keys in a `Predicate::KeyIn` allowlist.
+pub const MAX_ALLOWED_KEYS: usize = 1024;
+
+/// Predicate selecting the records returned by a read, evaluated against the
+/// key and headers of each entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Predicate {
+    /// Records with the key.
+    KeyEquals(Bytes),
+
+    /// Records with any of the keys.
+    KeyIn(Vec<Bytes>),
+
+    /// Records with a header of the name and value.
+    HeaderEquals(String, String),
+}
+
+impl Predicate {
+    /// Rejects allowlists above `MAX_ALLOWED_KEYS`, bounding the cost of
+    /// evaluating the predicate.
+    pub fn validate(&self) -> Result<(), Error> {
+        match *self {
+            Predicate::KeyIn(ref keys) if keys.len() > MAX_ALLOWED_KEYS => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Filter allows more than {} keys", MAX_ALLOWED_KEYS),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Tests whether the entry with the metadata matches. Entries without
+    /// a key or headers, or with invalid metadata, do not match.
+    pub fn matches(&self, metadata: &[u8]) -> bool {
+        let meta = match RecordMeta::parse(metadata) {
+            Ok(meta) => meta,
+            Err(_) => return false,
+        };
+
+        match *self {
+            Predicate::KeyEquals(ref key) => meta.key == Some(&key[..]),
+            Predicate::KeyIn(ref keys) => meta
+                .key
+                .map(|k| keys.iter().any(|key| &key[..] == k))
+                .unwrap_or(false),
+            Predicate::HeaderEquals(ref name, ref value) => {
+                meta.headers.iter().any(|&(n, v)| n == name && v == value)
+            }
+        }
+    }
+
+    /// Retains the matching entries. The next offset is unchanged, so a
+    /// reader advances past the entries that do not match.
+    pub fn apply(&self, msgs: &Messages) -> Messages {
+        let mut matches = msgs
+            .iter()
+            .map(|m| self.matches(m.metadata()))
+            .collect::<Vec<_>>()
+            .into_iter();
+        Messages::copy_filtered(msgs, |_| matches.next().unwrap_or(false))
+    }
+}
+
+/// Retains the entries before the first entry with the key, and the entry
+/// itself if `inclusive`. The next offset is where the read stopped.
+///
+/// Returns whether an entry with the key was found.
+pub fn stop_at_key(msgs: Messages, key: &[u8], inclusive: bool) -> (Messages, bool) {
+    let marker = msgs
+        .iter()
+        .find(|m| {
+            RecordMeta::parse(m.metadata())
+                .map(|meta| meta.key == Some(key))
+                .unwrap_or(false)
+        })
+        .map(|m| m.offset());
+    match marker {
+        Some(off) if inclusive => (msgs.take_until(off + 1), true),
+        Some(off) => (msgs.take_until(off), true),
+        None => (msgs, false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asynclog::MessagesMut;
+    use bytes::BytesMut;
+    use commitlog::message::set_offsets;
+
+    fn records() -> Messages {
+        let region = |r: &str| vec![("region".to_string(), r.to_string())];
+        let mut buf: MessagesMut = BytesMut::with_capacity(1024).into();
+        buf.push_record(0, 0, Some(&b"a"[..]), &region("us"), b"0")
+            .unwrap();
+        buf.push_record(0, 1, Some(&b"b"[..]), &region("eu"), b"1")
+            .unwrap();
+        buf.push(0, 2, b"2").unwrap();
+        buf.push_record(0, 3, Some(&b"a"[..]), &[], b"3").unwrap();
+        buf.push_record(0, 4, Some(&b"c"[..]), &region("us"), b"4")
+            .unwrap();
+        set_offsets(&mut buf, 10);
+        Messages::copy_from(&buf)
+    }
+
+    fn offsets(msgs: &Messages) -> Vec<u64> {
+        msgs.iter().map(|m| m.offset()).collect()
+    }
+
+    #[test]
+    fn filtered_reads_advance_past_skipped_records() {
+        let msgs = records();
+
+        let filtered = Predicate::KeyEquals(Bytes::from("a")).apply(&msgs);
+        assert_eq!(vec![10, 13], offsets(&filtered));
+        assert_eq!(Some(15), filtered.next_offset());
+
+        let keys = vec![Bytes::from("b"), Bytes::from("c")];
+        let filtered = Predicate::KeyIn(keys).apply(&msgs);
+        assert_eq!(vec![11, 14], offsets(&filtered));
+        assert_eq!(Some(15), filtered.next_offset());
+
+        let header = Predicate::HeaderEquals("region".to_string(), "us".to_string());
+        assert_eq!(vec![10, 14], offsets(&header.apply(&msgs)));
+
+        // no matches still advance the reader
+        let filtered = Predicate::KeyEquals(Bytes::from("z")).apply(&msgs);
+        assert_eq!(0, filtered.len());
+        assert_eq!(Some(15), filtered.next_offset());
+    }
+
+    #[test]
+    fn stops_at_marker_key() {
+        let (msgs, found) = stop_at_key(records(), b"b", false);
+        assert!(found);
+        assert_eq!(vec![10], offsets(&msgs));
+        assert_eq!(Some(11), msgs.next_offset());
+
+        let (msgs, found) = stop_at_key(records(), b"b", true);
+        assert!(found);
+        assert_eq!(vec![10, 11], offsets(&msgs));
+        assert_eq!(Some(12), msgs.next_offset());
+
+        // stops at the first of the entries with the key
+        let (msgs, _) = stop_at_key(records(), b"a", true);
+        assert_eq!(vec![10], offsets(&msgs));
+
+        let (msgs, found) = stop_at_key(records(), b"z", true);
+        assert!(!found);
+        assert_eq!(vec![10, 11, 12, 13, 14], offsets(&msgs));
+        assert_eq!(Some(15), msgs.next_offset());
+    }
+
+    #[test]
+    fn limits_allowlist() {
+        let keys = vec![Bytes::from("a"); MAX_ALLOWED_KEYS + 1];
+        let err = Predicate::KeyIn(keys).validate().unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+        assert!(Predicate::KeyIn(vec![]).validate().is_ok());
+    }
+}
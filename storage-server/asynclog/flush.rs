@@ -0,0 +1,246 @@
+use config::{FlushMode, LogConfig};
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+/// Longest time between wakeups of an idle log thread, for the periodic
+/// checks other than the flush.
+const MAX_TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Shortest time between wakeups of an idle log thread.
+const MIN_TICK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// When the log thread flushes appended entries to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Flushes each append before it is acknowledged.
+    EveryAppend,
+
+    /// Flushes once the interval has elapsed since the last flush.
+    Interval(Duration),
+
+    /// Flushes once the unflushed bytes reach the threshold, or the interval
+    /// has elapsed since the last flush.
+    BytesOrInterval { bytes: usize, interval: Duration },
+
+    /// Flushes only on request.
+    Never,
+}
+
+impl FlushPolicy {
+    pub fn from_config(cfg: &LogConfig) -> FlushPolicy {
+        match cfg.flush_mode {
+            FlushMode::EveryAppend => FlushPolicy::EveryAppend,
+            FlushMode::Interval => FlushPolicy::interval(
+                Duration::from_millis(cfg.flush_interval_ms),
+                cfg.flush_max_bytes,
+            ),
+            FlushMode::Never => FlushPolicy::Never,
+        }
+    }
+
+    /// Policy flushing at the interval, and at the byte threshold if set. A
+    /// zero interval flushes each append.
+    fn interval(interval: Duration, bytes: Option<usize>) -> FlushPolicy {
+        match bytes {
+            _ if interval == Duration::from_millis(0) => FlushPolicy::EveryAppend,
+            Some(bytes) => FlushPolicy::BytesOrInterval { bytes, interval },
+            None => FlushPolicy::Interval(interval),
+        }
+    }
+
+    /// Mode of the configuration the policy flushes as.
+    pub fn mode(&self) -> FlushMode {
+        match *self {
+            FlushPolicy::EveryAppend => FlushMode::EveryAppend,
+            FlushPolicy::Interval(_) | FlushPolicy::BytesOrInterval { .. } => FlushMode::Interval,
+            FlushPolicy::Never => FlushMode::Never,
+        }
+    }
+
+    /// Changes the mode and interval of the policy, keeping the byte
+    /// threshold of an interval policy. A policy switched to interval
+    /// flushing takes the interval and threshold unset by the change from
+    /// the configuration.
+    ///
+    /// Fails with `ErrorKind::InvalidInput` if the interval is changed on a
+    /// policy left flushing each append or never, as it would not apply.
+    pub fn tune(
+        self,
+        mode: Option<FlushMode>,
+        interval: Option<Duration>,
+        cfg: &LogConfig,
+    ) -> Result<FlushPolicy, Error> {
+        match (mode.unwrap_or_else(|| self.mode()), interval) {
+            (FlushMode::Interval, interval) => Ok(match self {
+                FlushPolicy::Interval(current) => {
+                    FlushPolicy::interval(interval.unwrap_or(current), None)
+                }
+                FlushPolicy::BytesOrInterval {
+                    bytes,
+                    interval: current,
+                } => FlushPolicy::interval(interval.unwrap_or(current), Some(bytes)),
+                FlushPolicy::EveryAppend | FlushPolicy::Never => FlushPolicy::interval(
+                    interval.unwrap_or_else(|| Duration::from_millis(cfg.flush_interval_ms)),
+                    cfg.flush_max_bytes,
+                ),
+            }),
+            (_, Some(_)) => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Flush interval only applies to interval flushing",
+            )),
+            (FlushMode::EveryAppend, None) => Ok(FlushPolicy::EveryAppend),
+            (FlushMode::Never, None) => Ok(FlushPolicy::Never),
+        }
+    }
+
+    /// Tests whether appends are flushed before they are acknowledged.
+    #[inline]
+    pub fn flushes_each_append(&self) -> bool {
+        *self == FlushPolicy::EveryAppend
+    }
+
+    /// Tests whether unflushed appends are due a flush.
+    pub fn is_due(&self, since_flush: Duration, unflushed_bytes: usize) -> bool {
+        match *self {
+            // appends left unflushed by a failed flush
+            FlushPolicy::EveryAppend => true,
+            FlushPolicy::Interval(interval) => since_flush >= interval,
+            FlushPolicy::BytesOrInterval { bytes, interval } => {
+                since_flush >= interval || unflushed_bytes >= bytes
+            }
+            FlushPolicy::Never => false,
+        }
+    }
+
+    /// Time between wakeups of an idle log thread, for the flush and the
+    /// other periodic checks.
+    pub fn tick_interval(&self) -> Duration {
+        match *self {
+            FlushPolicy::Interval(interval) | FlushPolicy::BytesOrInterval { interval, .. } => {
+                interval.min(MAX_TICK_INTERVAL).max(MIN_TICK_INTERVAL)
+            }
+            FlushPolicy::EveryAppend | FlushPolicy::Never => MAX_TICK_INTERVAL,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policy_from_config() {
+        let mut cfg = LogConfig::default();
+        assert_eq!(
+            FlushPolicy::Interval(Duration::from_secs(1)),
+            FlushPolicy::from_config(&cfg)
+        );
+
+        cfg.flush_max_bytes = Some(100);
+        assert_eq!(
+            FlushPolicy::BytesOrInterval {
+                bytes: 100,
+                interval: Duration::from_secs(1)
+            },
+            FlushPolicy::from_config(&cfg)
+        );
+
+        cfg.flush_interval_ms = 0;
+        assert_eq!(FlushPolicy::EveryAppend, FlushPolicy::from_config(&cfg));
+
+        cfg.flush_mode = FlushMode::Never;
+        assert_eq!(FlushPolicy::Never, FlushPolicy::from_config(&cfg));
+    }
+
+    #[test]
+    fn flush_due_at_threshold() {
+        let policy = FlushPolicy::BytesOrInterval {
+            bytes: 100,
+            interval: Duration::from_secs(60),
+        };
+        assert!(!policy.is_due(Duration::from_secs(1), 60));
+        assert!(policy.is_due(Duration::from_secs(1), 120));
+        assert!(policy.is_due(Duration::from_secs(60), 60));
+
+        let policy = FlushPolicy::Interval(Duration::from_secs(60));
+        assert!(!policy.is_due(Duration::from_secs(1), 1_000_000));
+        assert!(!FlushPolicy::Never.is_due(Duration::from_secs(3600), 1_000_000));
+    }
+
+    #[test]
+    fn tuning_keeps_byte_threshold() {
+        let cfg = LogConfig::default();
+        let policy = FlushPolicy::BytesOrInterval {
+            bytes: 100,
+            interval: Duration::from_secs(60),
+        };
+        assert_eq!(
+            FlushPolicy::BytesOrInterval {
+                bytes: 100,
+                interval: Duration::from_secs(1)
+            },
+            policy
+                .tune(None, Some(Duration::from_secs(1)), &cfg)
+                .unwrap()
+        );
+        assert_eq!(
+            policy,
+            policy.tune(Some(FlushMode::Interval), None, &cfg).unwrap()
+        );
+    }
+
+    #[test]
+    fn tuning_changes_mode() {
+        let mut cfg = LogConfig::default();
+        cfg.flush_max_bytes = Some(100);
+        let policy = FlushPolicy::Interval(Duration::from_secs(60));
+        assert_eq!(
+            FlushPolicy::EveryAppend,
+            policy
+                .tune(Some(FlushMode::EveryAppend), None, &cfg)
+                .unwrap()
+        );
+        assert_eq!(
+            FlushPolicy::Never,
+            policy.tune(Some(FlushMode::Never), None, &cfg).unwrap()
+        );
+
+        // switching to interval flushing takes the unset values from the config
+        assert_eq!(
+            FlushPolicy::BytesOrInterval {
+                bytes: 100,
+                interval: Duration::from_secs(1)
+            },
+            FlushPolicy::Never
+                .tune(Some(FlushMode::Interval), None, &cfg)
+                .unwrap()
+        );
+        assert_eq!(
+            FlushPolicy::BytesOrInterval {
+                bytes: 100,
+                interval: Duration::from_secs(5)
+            },
+            FlushPolicy::EveryAppend
+                .tune(
+                    Some(FlushMode::Interval),
+                    Some(Duration::from_secs(5)),
+                    &cfg
+                )
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn tuning_rejects_interval_without_interval_flushing() {
+        let cfg = LogConfig::default();
+        let interval = Some(Duration::from_secs(1));
+        let err = FlushPolicy::EveryAppend
+            .tune(None, interval, &cfg)
+            .unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+        assert!(FlushPolicy::Never.tune(None, interval, &cfg).is_err());
+        assert!(FlushPolicy::Interval(Duration::from_secs(60))
+            .tune(Some(FlushMode::Never), interval, &cfg)
+            .is_err());
+    }
+}
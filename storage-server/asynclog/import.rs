@@ -0,0 +1,85 @@
+use super::export::ExportReader;
+use super::{AsyncLog, ClientRequest};
+use bytes::Bytes;
+use commitlog::Offset;
+use futures::Future;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+
+/// Result of an import of an export file into the log.
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct ImportSummary {
+    /// Number of messages appended.
+    pub messages: u64,
+
+    /// Bytes of the payloads appended.
+    pub bytes: u64,
+
+    /// Original offset and the offset assigned by the log of each imported
+    /// message assigned a different offset than it had in the export.
+    pub remapped: Vec<(Offset, Offset)>,
+}
+
+/// Appends the entries of the export file to the log in batches of at most
+/// `batch_bytes` of payloads, each appended by a single request to the log
+/// thread.
+///
+/// The whole file is verified before the first append, so a corrupt file
+/// imports nothing. Unless `force_append`, the log must be empty and the
+/// import fails with `ErrorKind::AlreadyExists` if another append lands
+/// between the batches.
+pub(super) fn import_file(
+    log: &mut AsyncLog,
+    path: &Path,
+    force_append: bool,
+    batch_bytes: usize,
+) -> io::Result<ImportSummary> {
+    for entry in ExportReader::new(BufReader::new(File::open(path)?))? {
+        entry?;
+    }
+
+    let mut summary = ImportSummary::default();
+    let mut expect_next = if force_append { None } else { Some(0) };
+    let mut entries = ExportReader::new(BufReader::new(File::open(path)?))?;
+    let mut next = entries.read_entry()?;
+    while let Some(first) = next.take() {
+        let mut bytes = first.payload.len();
+        let mut offsets = vec![first.offset];
+        let mut payloads = vec![first.payload];
+        next = entries.read_entry()?;
+        while let Some(entry) = next.take() {
+            if bytes + entry.payload.len() > batch_bytes {
+                next = Some(entry);
+                break;
+            }
+            bytes += entry.payload.len();
+            offsets.push(entry.offset);
+            payloads.push(entry.payload);
+            next = entries.read_entry()?;
+        }
+
+        let assigned = import_batch(log, payloads, expect_next)?;
+        for (original, offset) in offsets.into_iter().zip(assigned.iter().cloned()) {
+            if original != offset {
+                summary.remapped.push((original, offset));
+            }
+        }
+        summary.messages += assigned.len() as u64;
+        summary.bytes += bytes as u64;
+        if !force_append {
+            expect_next = assigned.last().map(|offset| offset + 1);
+        }
+    }
+    Ok(summary)
+}
+
+fn import_batch(
+    log: &mut AsyncLog,
+    payloads: Vec<Bytes>,
+    expect_next: Option<Offset>,
+) -> io::Result<Vec<Offset>> {
+    log.payloads.check(&payloads)?;
+    log.send_request(|snd| ClientRequest::Import(payloads, expect_next, snd))
+        .wait()
+}
@@ -1,17 +1,34 @@
+use super::record::{self, RecordMeta};
 use byteorder::{ByteOrder, LittleEndian};
 use bytes::{Bytes, BytesMut};
 use commitlog::{
-    message::{serialize, MessageSet, MessageSetMut, HEADER_SIZE},
+    message::{serialize, Message, MessageSet, MessageSetMut, HEADER_SIZE},
     Offset,
 };
+use std::io::{Error, ErrorKind};
 
 const METADATA_SIZE: usize = 16;
 
+/// Verifies the hash of each entry, failing with `ErrorKind::InvalidData`
+/// naming the offset of the first corrupt entry.
+pub fn verify_hashes<M: MessageSet>(set: &M) -> Result<(), Error> {
+    match set.iter().find(|msg| !msg.verify_hash()) {
+        None => Ok(()),
+        Some(msg) => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Corrupt entry at offset {}", msg.offset()),
+        )),
+    }
+}
+
 /// Single message append, with client_id, client_req_id and payload
 pub type SingleMessage = (u64, u64, Bytes);
 
 /// Readonly view of messages, either via replication or as
 /// a result of appending log entries.
+///
+/// The entries are iterated with `MessageSet::iter`, or with `keyed` for
+/// their keys, whichever way the messages were built.
 #[derive(Clone)]
 pub struct Messages {
     bytes: Bytes,
@@ -83,6 +100,116 @@ impl Messages {
         }
     }
 
+    /// Empty set of messages.
+    pub fn empty() -> Messages {
+        Messages {
+            bytes: Bytes::new(),
+            len: 0,
+            next_offset: None,
+        }
+    }
+
+    /// Copies the messages from another message set.
+    pub fn copy_from<M: MessageSet>(set: &M) -> Messages {
+        let bytes = Bytes::from(set.bytes());
+        let mut len = 0;
+        let mut next_offset = None;
+        for msg in set.iter() {
+            len += 1;
+            next_offset = Some(msg.offset() + 1);
+        }
+
+        Messages {
+            bytes,
+            len,
+            next_offset,
+        }
+    }
+
+    /// Copies the messages from another message set into the buffer, such
+    /// as a pooled buffer.
+    pub fn copy_into<M: MessageSet>(set: &M, mut buf: BytesMut) -> Messages {
+        buf.extend_from_slice(set.bytes());
+        let mut len = 0;
+        let mut next_offset = None;
+        for msg in set.iter() {
+            len += 1;
+            next_offset = Some(msg.offset() + 1);
+        }
+
+        Messages {
+            bytes: buf.freeze(),
+            len,
+            next_offset,
+        }
+    }
+
+    /// Copies the messages from another message set, retaining only the
+    /// messages with offsets accepted by the filter.
+    ///
+    /// The next offset is the offset after the last message of the source,
+    /// whether or not that message was retained.
+    pub fn copy_filtered<M, F>(set: &M, mut filter: F) -> Messages
+    where
+        M: MessageSet,
+        F: FnMut(Offset) -> bool,
+    {
+        let mut buf = BytesMut::with_capacity(set.bytes().len());
+        let mut len = 0;
+        let mut next_offset = None;
+        for msg in set.iter() {
+            next_offset = Some(msg.offset() + 1);
+            if !filter(msg.offset()) {
+                continue;
+            }
+
+            if rare!(serialize(&mut buf, msg.offset(), msg.metadata(), msg.payload()).is_err()) {
+                unreachable!("Copied messages exceed the source capacity");
+            }
+            len += 1;
+        }
+
+        Messages {
+            bytes: buf.freeze(),
+            len,
+            next_offset,
+        }
+    }
+
+    /// Retains only the messages before the end offset (exclusive). If any
+    /// messages are removed, the next offset is the end offset.
+    pub fn take_until(self, end: Offset) -> Messages {
+        match self.next_offset {
+            Some(next) if next > end => {
+                let mut msgs = Messages::copy_filtered(&self, |off| off < end);
+                msgs.next_offset = Some(end);
+                msgs
+            }
+            _ => self,
+        }
+    }
+
+    /// Retains only the first `max_messages` messages, sharing the bytes of
+    /// the messages. If any messages are removed, the next offset is the
+    /// offset after the last message retained.
+    pub fn take(self, max_messages: usize) -> Messages {
+        if self.len <= max_messages {
+            return self;
+        }
+
+        let mut end = 0;
+        let mut next_offset = None;
+        for msg in self.iter().take(max_messages) {
+            end += HEADER_SIZE + msg.metadata().len() + msg.payload().len();
+            next_offset = Some(msg.offset() + 1);
+        }
+        Messages {
+            bytes: self.bytes.slice_to(end),
+            len: max_messages,
+            next_offset: next_offset.or_else(|| self.iter().next().map(|m| m.offset())),
+        }
+    }
+
     #[inline]
     pub fn into_inner(self) -> Bytes {
         self.bytes
@@ -93,6 +220,35 @@ impl Messages {
     pub fn next_offset(&self) -> Option<Offset> {
         self.next_offset
     }
+
+    /// Iterates the entries with their keys. Entries appended without a key,
+    /// including plain entries, have none.
+    pub fn keyed<'a>(&'a self) -> impl Iterator<Item = KeyedEntry<'a>> + 'a {
+        self.iter().map(KeyedEntry)
+    }
+}
+
+/// Entry of a `Messages` with access to the key of the entry.
+pub struct KeyedEntry<'a>(Message<'a>);
+
+impl<'a> KeyedEntry<'a> {
+    #[inline]
+    pub fn offset(&self) -> Offset {
+        self.0.offset()
+    }
+
+    /// Key of the entry, or `None` if the entry has no key or the record
+    /// metadata cannot be parsed.
+    pub fn key(&self) -> Option<&[u8]> {
+        RecordMeta::parse(self.0.metadata())
+            .ok()
+            .and_then(|meta| meta.key)
+    }
+
+    #[inline]
+    pub fn payload(&self) -> &[u8] {
+        self.0.payload()
+    }
 }
 
 impl AsRef<[u8]> for Messages {
@@ -162,6 +318,48 @@ impl MessagesMut {
         serialize(&mut self.0, 0, &meta, payload_bytes).map_err(|_| MessagePushError::OutOfCapacity)
     }
 
+    /// Insert a new log entry with a key and headers to the message set.
+    ///
+    /// The key and headers must be validated with `record::validate`.
+    pub fn push_record<B: AsRef<[u8]>>(
+        &mut self,
+        client_id: u64,
+        client_req_id: u64,
+        key: Option<&[u8]>,
+        headers: &[(String, String)],
+        payload: B,
+    ) -> Result<(), MessagePushError> {
+        self.push_sequenced_record(client_id, client_req_id, None, key, headers, payload)
+    }
+
+    /// Insert a new log entry with a sequence, key and headers to the
+    /// message set.
+    ///
+    /// The key and headers must be validated with `record::validate`.
+    pub fn push_sequenced_record<B: AsRef<[u8]>>(
+        &mut self,
+        client_id: u64,
+        client_req_id: u64,
+        sequence: Option<u64>,
+        key: Option<&[u8]>,
+        headers: &[(String, String)],
+        payload: B,
+    ) -> Result<(), MessagePushError> {
+        let payload_bytes = payload.as_ref();
+        let meta_len = record::metadata_len(sequence, key, headers);
+
+        if rare!(payload_bytes.len() + meta_len + HEADER_SIZE > self.0.capacity()) {
+            return Err(MessagePushError::MessageExceedsCapacity);
+        }
+
+        let mut meta = Vec::with_capacity(meta_len);
+        meta.resize(METADATA_SIZE, 0);
+        LittleEndian::write_u64(&mut meta[0..8], client_id);
+        LittleEndian::write_u64(&mut meta[8..16], client_req_id);
+        record::encode(&mut meta, sequence, key, headers);
+        serialize(&mut self.0, 0, &meta, payload_bytes).map_err(|_| MessagePushError::OutOfCapacity)
+    }
+
     /// Insert a new log entry to the message set without metadata
     #[inline]
     pub fn push_no_metadata<B: AsRef<[u8]>>(&mut self, payload: B) -> Result<(), MessagePushError> {
@@ -178,6 +376,10 @@ impl MessagesMut {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use asynclog::bufpool::BytesPool;
+    use asynclog::record::RecordParseError;
+    use commitlog::message::set_offsets;
+    use test::Bencher;
 
     #[test]
     fn message_mut_push_read() {
@@ -227,4 +429,239 @@ mod tests {
         let meta = msg.metadata();
         assert_eq!(0, meta.len());
     }
+
+    #[test]
+    fn record_round_trip() {
+        let headers = vec![
+            ("content-type".to_string(), "text/plain".to_string()),
+            ("empty".to_string(), String::new()),
+        ];
+
+        let mut buf: MessagesMut = BytesMut::with_capacity(1024).into();
+        buf.push_record(5, 10, Some(&b"user-1"[..]), &headers, b"payload")
+            .unwrap();
+        buf.push_record(5, 11, Some(&b""[..]), &[], b"").unwrap();
+        buf.push_record(5, 12, None, &headers[..1], b"no key")
+            .unwrap();
+        buf.push_record(5, 13, None, &[], b"plain record").unwrap();
+        buf.push(5, 14, b"plain").unwrap();
+        buf.push_sequenced_record(5, 15, Some(7), Some(&b"k"[..]), &headers, b"seq")
+            .unwrap();
+
+        let msgs = buf.iter().collect::<Vec<_>>();
+        assert_eq!(6, msgs.len());
+
+        let meta = RecordMeta::parse(msgs[0].metadata()).unwrap();
+        assert_eq!(None, meta.sequence);
+        assert_eq!(Some(&b"user-1"[..]), meta.key);
+        assert_eq!(
+            vec![("content-type", "text/plain"), ("empty", "")],
+            meta.headers
+        );
+        assert_eq!(b"payload", msgs[0].payload());
+        assert_eq!(5, LittleEndian::read_u64(&msgs[0].metadata()[0..8]));
+        assert_eq!(10, LittleEndian::read_u64(&msgs[0].metadata()[8..16]));
+
+        // an empty key is distinct from an absent key
+        let meta = RecordMeta::parse(msgs[1].metadata()).unwrap();
+        assert_eq!(Some(&b""[..]), meta.key);
+        assert!(meta.headers.is_empty());
+        assert_eq!(b"", msgs[1].payload());
+
+        let meta = RecordMeta::parse(msgs[2].metadata()).unwrap();
+        assert_eq!(None, meta.key);
+        assert_eq!(vec![("content-type", "text/plain")], meta.headers);
+
+        for msg in &msgs[3..5] {
+            assert_eq!(
+                RecordMeta::default(),
+                RecordMeta::parse(msg.metadata()).unwrap()
+            );
+        }
+
+        let meta = RecordMeta::parse(msgs[5].metadata()).unwrap();
+        assert_eq!(Some(7), meta.sequence);
+        assert_eq!(Some(&b"k"[..]), meta.key);
+        assert_eq!(2, meta.headers.len());
+        assert_eq!(b"seq", msgs[5].payload());
+    }
+
+    #[test]
+    fn keyed_entries() {
+        let mut buf: MessagesMut = BytesMut::with_capacity(1024).into();
+        buf.push_record(5, 10, Some(&b"user-1"[..]), &[], b"keyed")
+            .unwrap();
+        buf.push(5, 11, b"plain").unwrap();
+        buf.push_no_metadata(b"bare").unwrap();
+        set_offsets(&mut buf, 3);
+        let msgs = buf.freeze();
+
+        let entries: Vec<_> = msgs
+            .keyed()
+            .map(|e| {
+                (
+                    e.offset(),
+                    e.key().map(|k| k.to_vec()),
+                    e.payload().to_vec(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (3, Some(b"user-1".to_vec()), b"keyed".to_vec()),
+                (4, None, b"plain".to_vec()),
+                (5, None, b"bare".to_vec()),
+            ],
+            entries
+        );
+    }
+
+    #[test]
+    fn record_parse_errors() {
+        let mut meta = vec![0u8; 16];
+        meta.push(2);
+        assert_eq!(
+            Err(RecordParseError::UnknownVersion(2)),
+            RecordMeta::parse(&meta)
+        );
+
+        let mut meta = vec![0u8; 16];
+        record::encode(&mut meta, None, Some(&b"key"[..]), &[]);
+        meta.truncate(meta.len() - 3);
+        assert_eq!(Err(RecordParseError::Truncated), RecordMeta::parse(&meta));
+        assert_eq!(
+            Err(RecordParseError::Truncated),
+            RecordMeta::parse(&[0u8; 8])
+        );
+
+        let long = "x".repeat(u16::max_value() as usize + 1);
+        assert!(record::validate(Some(long.as_bytes()), &[]).is_err());
+        assert!(record::validate(None, &[(long, String::new())]).is_err());
+        assert!(record::validate(Some(&b""[..]), &[]).is_ok());
+    }
+
+    #[test]
+    fn iterates_appended_entries() {
+        let mut buf: MessagesMut = BytesMut::with_capacity(1024).into();
+        for i in 0..5 {
+            buf.push(1, i, format!("entry-{}", i)).unwrap();
+        }
+        set_offsets(&mut buf, 20);
+        let appended = buf.freeze();
+        let parsed = Messages::parse(appended.clone().into_inner()).unwrap();
+        let copied = Messages::copy_from(&appended);
+
+        for msgs in &[appended, parsed, copied] {
+            let entries: Vec<_> = msgs
+                .iter()
+                .map(|m| (m.offset(), m.payload().to_vec()))
+                .collect();
+            let expected: Vec<_> = (0..5)
+                .map(|i| (20 + i, format!("entry-{}", i).into_bytes()))
+                .collect();
+            assert_eq!(expected, entries);
+            assert_eq!(5, msgs.len());
+        }
+    }
+
+    #[test]
+    fn take_until_end_offset() {
+        let mut buf: MessagesMut = BytesMut::with_capacity(256).into();
+        for i in 0..4 {
+            buf.push(0, i, b"0123456789").unwrap();
+        }
+        set_offsets(&mut buf, 10);
+        let msgs = Messages::copy_from(&buf);
+
+        let offsets = |m: &Messages| m.iter().map(|m| m.offset()).collect::<Vec<_>>();
+
+        // end is exclusive
+        let m = msgs.clone().take_until(12);
+        assert_eq!(vec![10, 11], offsets(&m));
+        assert_eq!(Some(12), m.next_offset());
+
+        // end past the last message retains all
+        let m = msgs.clone().take_until(14);
+        assert_eq!(vec![10, 11, 12, 13], offsets(&m));
+        assert_eq!(Some(14), m.next_offset());
+        assert_eq!(4, msgs.clone().take_until(100).len());
+
+        // empty range at the first message
+        let m = msgs.clone().take_until(10);
+        assert!(offsets(&m).is_empty());
+        assert_eq!(Some(10), m.next_offset());
+
+        assert_eq!(0, Messages::empty().take_until(5).len());
+    }
+
+    #[test]
+    fn take_ends_at_message_boundary() {
+        let mut buf: MessagesMut = BytesMut::with_capacity(256).into();
+        for i in 0..4 {
+            buf.push(0, i, b"0123456789").unwrap();
+        }
+        set_offsets(&mut buf, 10);
+        let msgs = Messages::copy_from(&buf);
+
+        let m = msgs.clone().take(3);
+        assert_eq!(3, m.len());
+        assert_eq!(
+            vec![10, 11, 12],
+            m.iter().map(|m| m.offset()).collect::<Vec<_>>()
+        );
+        assert_eq!(Some(13), m.next_offset());
+        assert_eq!(msgs.clone().take_until(13).bytes(), m.bytes());
+
+        // a limit past the last message retains all
+        let m = msgs.clone().take(10);
+        assert_eq!(4, m.len());
+        assert_eq!(Some(14), m.next_offset());
+
+        let m = msgs.clone().take(0);
+        assert!(m.bytes().is_empty());
+        assert_eq!(Some(10), m.next_offset());
+    }
+
+    fn read_result() -> MessagesMut {
+        let mut buf: MessagesMut = BytesMut::with_capacity(65_536).into();
+        for i in 0..500 {
+            buf.push(0, i, &[0u8; 100][..]).unwrap();
+        }
+        set_offsets(&mut buf, 0);
+        buf
+    }
+
+    #[test]
+    fn copy_into_reuses_buffer() {
+        let set = read_result();
+        let mut pool = BytesPool::new(65_536);
+
+        let msgs = Messages::copy_into(&set, pool.take());
+        assert_eq!(500, msgs.len());
+        assert_eq!(Some(500), msgs.next_offset());
+        assert_eq!(set.bytes(), msgs.bytes());
+
+        let ptr = msgs.bytes().as_ptr();
+        pool.push(msgs.into_inner());
+        let msgs = Messages::copy_into(&set, pool.take());
+        assert_eq!(ptr, msgs.bytes().as_ptr());
+    }
+
+    #[bench]
+    fn bench_read_copy_unpooled(b: &mut Bencher) {
+        let set = read_result();
+        b.iter(|| Messages::copy_from(&set).len());
+    }
+
+    #[bench]
+    fn bench_read_copy_pooled(b: &mut Bencher) {
+        let set = read_result();
+        let mut pool = BytesPool::new(65_536);
+        b.iter(|| {
+            let msgs = Messages::copy_into(&set, pool.take());
+            let len = msgs.len();
+            pool.push(msgs.into_inner());
+            len
+        });
+    }
 }
@@ -1,28 +1,142 @@
-use bytes::Bytes;
-use commitlog::message::{set_offsets, MessageBuf, MessageSet};
+use bytes::{Bytes, BytesMut};
+use commitlog::message::MessageSet;
 use commitlog::reader::LogSliceReader;
-use commitlog::{CommitLog, LogOptions, Offset, OffsetRange, ReadError, ReadLimit};
-use config::LogConfig;
+use commitlog::{AppendError, CommitLog, LogOptions, Offset, ReadError};
+use config::{BeyondEnd, LogConfig};
 use either::Either;
 use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
-use prometheus::{exponential_buckets, linear_buckets, Gauge, Histogram};
+use prometheus::{exponential_buckets, linear_buckets, Gauge, GaugeVec, Histogram};
+use spans;
 use std::cell::RefCell;
+use std::fs;
 use std::io::{Error, ErrorKind};
+use std::mem;
+use std::ops::Range;
+use std::path::PathBuf;
 use std::rc::Rc;
+use std::sync::{Arc, RwLock};
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::timer::Delay;
 use tokio_sync::mpsc;
 
+mod amplification;
+mod append_retry;
 mod batch;
+#[cfg(feature = "bench-append")]
+mod bench;
 mod bufpool;
+mod compact;
+mod consumers;
+mod cursor;
+mod entry_meta;
+mod export;
+mod filter;
+mod flush;
+mod import;
 mod messages;
+mod offsets;
+mod payload;
+mod persist;
+mod priority;
+mod producers;
+mod qos;
+mod queue;
+mod raw;
+mod read_cache;
+mod read_only;
+mod read_workers;
+mod record;
+mod repair;
+mod retention;
+mod rollover;
+mod size;
+mod snapshot;
+mod stats;
+mod storage;
 mod sync;
+mod tail;
+mod ticker;
+mod time_index;
+mod tombstone;
+mod topics;
+mod trace;
+mod tuning;
+mod watchdog;
+mod window;
 
+use self::amplification::WriteAmplification;
+use self::append_retry::AppendRetry;
 use self::batch::BatchMessageStream;
+#[cfg(feature = "bench-append")]
+pub use self::bench::{benchmark_append, AppendPath, BenchConfig, BenchResult};
 use self::bufpool::BytesPool;
-pub use self::messages::{Messages, MessagesMut, SingleMessage};
-pub use self::sync::LogFuture;
-use self::sync::{channel, LogSender};
+pub use self::compact::{compact_offline, CompactionReport, CompactionStats};
+pub use self::consumers::ConsumerId;
+use self::consumers::ConsumerOffsets;
+use self::cursor::read_page;
+pub use self::cursor::Cursor;
+pub use self::entry_meta::{EntryMeta, MetadataRead};
+use self::export::export_range;
+pub use self::export::{ExportEntry, ExportReader, ExportSummary, ExportWriter};
+pub use self::filter::{stop_at_key, Predicate};
+use self::flush::FlushPolicy;
+use self::import::import_file;
+pub use self::import::ImportSummary;
+use self::messages::{verify_hashes, MessagePushError};
+pub use self::messages::{KeyedEntry, Messages, MessagesMut, SingleMessage};
+pub use self::offsets::{DenseOffsets, OffsetAllocator};
+pub use self::payload::MessageTooLarge;
+use self::payload::PayloadPolicy;
+use self::producers::ProducerSequences;
+pub use self::producers::{ProducerId, ProducerSequence};
+pub use self::qos::Priority;
+use self::qos::{PriorityStream, QueuedMessage};
+use self::queue::{AppendQueue, QueueStream, QueuedRead, ReadQueue};
+use self::read_cache::ReadCache;
+use self::read_only::{read_only_error, ReadOnlyLog, WriterGuard};
+use self::read_workers::{withdraw, ReadWorkers, SealedView, SharedView};
+pub use self::record::{RecordMeta, RecordParseError};
+pub use self::retention::OffsetTrimmed;
+use self::retention::Retention;
+use self::rollover::Rollover;
+use self::size::LogSize;
+pub use self::snapshot::SnapshotInfo;
+pub use self::stats::{LogQueueStats, LogSegment, LogStats, LogSummary, SegmentStats};
+pub use self::storage::{AppendRange, MemStorage, Storage};
+use self::sync::SubscriptionSender;
+use self::sync::{
+    ack_channel, channel, subscription_channel, traced_channel, AckSender, FailSender, LogSender,
+};
+pub use self::sync::{AppendAckStream, LogFuture, Subscription};
+use self::tail::read_tail;
+use self::ticker::TickStream;
+use self::time_index::TimeIndex;
+use self::tombstone::Tombstones;
+use self::topics::TopicPause;
+pub use self::topics::{TopicPaused, Topics, DEFAULT_TOPIC};
+pub use self::trace::{TraceId, TracedError};
+pub use self::tuning::LogTuning;
+use self::watchdog::{stalled_error, Progress};
+use self::window::UncommittedWindow;
+
+/// Maximum buffers held by the read buffer pool. Buffers still referenced,
+/// such as by the read cache, are skipped by the pool rather than reused.
+const READ_POOL_MAX_BUFFERS: usize = 128;
+
+/// Bytes read from the log for each chunk of an export.
+const EXPORT_READ_BYTES: usize = 1024 * 1024;
+
+/// Bytes of payloads appended by each request of an import.
+const IMPORT_BATCH_BYTES: usize = 256 * 1024;
+
+/// Batches buffered for each subscriber. A subscriber with a full buffer is
+/// not dropped, as it catches up by reading from the log once it consumes.
+const SUBSCRIPTION_BUFFER_BATCHES: usize = 16;
+
+/// Interval between entries of the time index, bounding how far before the
+/// requested time a lookup by time may start.
+const TIME_INDEX_INTERVAL_MS: u64 = 1_000;
 
 pub struct ReplicationSource<R> {
     /// Messages appended to the log
@@ -37,11 +151,11 @@ pub struct ReplicationSource<R> {
 }
 
 lazy_static! {
-    static ref LOG_LATEST_OFFSET: Gauge = register_gauge!(opts!(
+    static ref LOG_LATEST_OFFSET: GaugeVec = register_gauge_vec!(
         "log_last_offset",
         "The log offset of the last entry to the log.",
-        labels! {"mod" => "log",}
-    ))
+        &["topic"]
+    )
     .unwrap();
     static ref APPEND_COUNT_HISTOGRAM: Histogram = register_histogram!(
         "log_append_count",
@@ -77,14 +191,113 @@ lazy_static! {
 
 enum ClientRequest {
     Append(MessagesMut),
+    AppendBatch(u64, Vec<Bytes>, AckSender),
     LastOffset(LogSender<Option<Offset>>),
-    Read(Offset, ReadLimit, LogSender<MessageBuf>),
+    Read(Offset, usize, LogSender<Messages>),
+    ReadWait(Offset, usize, LogSender<Messages>),
+    ReadRange(Offset, Option<Offset>, usize, LogSender<Messages>),
+    ReadMetadata(Offset, Option<Offset>, usize, LogSender<MetadataRead>),
+    Tail(usize, LogSender<Messages>),
+    ReadPage(Cursor, usize, LogSender<(Messages, Option<Cursor>)>),
+    AppendAndFetch(u64, Vec<Bytes>, LogSender<(Range<Offset>, Messages)>),
+    AppendAtomic(u64, Vec<Bytes>, LogSender<Vec<Offset>>),
+    AppendNow(u64, Bytes, bool, LogSender<Offset>),
+    AppendSequenced(u64, ProducerSequence, Bytes, bool, LogSender<Offset>),
+    AppendIdempotent(u64, u64, ProducerSequence, Bytes),
+    AppendRecord(
+        u64,
+        Option<Bytes>,
+        Vec<(String, String)>,
+        Bytes,
+        LogSender<Offset>,
+    ),
+    Tombstone(Range<Offset>, LogSender<()>),
+    Compact(LogSender<CompactionStats>),
+    Snapshot(PathBuf, LogSender<SnapshotInfo>),
+    Import(Vec<Bytes>, Option<Offset>, LogSender<Vec<Offset>>),
+    ReadRaw(Offset, Range<u64>, LogSender<Vec<u8>>),
+    CommitOffset(ConsumerId, Offset, LogSender<()>),
+    ConsumerOffsets(LogSender<Vec<(ConsumerId, Offset)>>),
+    ConsumerLag(ConsumerId, LogSender<u64>),
+    Stats(LogSender<LogStats>),
+    Summary(LogSender<LogSummary>),
+    Segments(LogSender<Vec<LogSegment>>),
+    LowWatermark(LogSender<Offset>),
+    Flush(LogSender<Option<Offset>>),
+    FlushedOffset(LogSender<Option<Offset>>),
+    Shutdown(LogSender<()>),
+    Truncate(Offset, LogSender<()>),
+    Trim(Offset, LogSender<()>),
+    OffsetForTime(SystemTime, LogSender<Option<Offset>>),
+    Subscribe(Offset, SubscriptionSender),
+    Tune(LogTuning, LogSender<()>),
 }
 
 // TODO: remove this
 enum ReplicaRequest<R> {
     Replicate(Offset, LogSender<ReplicationSource<R>>),
-    AppendFromReplication(Messages, LogSender<OffsetRange>),
+    AppendFromReplication(Messages, LogSender<AppendRange>),
+}
+
+impl ClientRequest {
+    /// Whether the request is a read counted by the `ReadQueue`.
+    fn is_read(&self) -> bool {
+        use self::ClientRequest::*;
+        match *self {
+            LastOffset(..) | Read(..) | ReadWait(..) | ReadRange(..) | ReadMetadata(..)
+            | Tail(..) | ReadPage(..) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the request appends entries to the log.
+    fn is_append(&self) -> bool {
+        use self::ClientRequest::*;
+        match *self {
+            Append(..) | AppendBatch(..) | AppendAndFetch(..) | AppendAtomic(..)
+            | AppendNow(..) | AppendSequenced(..) | AppendIdempotent(..) | AppendRecord(..)
+            | Import(..) => true,
+            _ => false,
+        }
+    }
+
+    /// Fails an append with the error before it is sent to the log thread.
+    /// Queued appends, replied to through the `AppendListener`, are checked
+    /// before being sent instead.
+    fn fail_append(self, e: Error) {
+        use self::ClientRequest::*;
+        match self {
+            AppendBatch(_, _, acks) => acks.send_err(e),
+            AppendAndFetch(_, _, res) => res.send_err(e),
+            AppendAtomic(_, _, res) | Import(_, _, res) => res.send_err(e),
+            AppendNow(_, _, _, res) | AppendSequenced(_, _, _, _, res) => res.send_err(e),
+            AppendRecord(_, _, _, _, res) => res.send_err(e),
+            _ => unreachable!("Queued appends are checked before being sent"),
+        }
+    }
+
+    /// Whether the request is a read whose `LogFuture` has been dropped, so
+    /// the read can be skipped. Other requests are carried out regardless.
+    fn is_abandoned(&self) -> bool {
+        use self::ClientRequest::*;
+        match *self {
+            LastOffset(ref res) => res.is_canceled(),
+            Read(_, _, ref res) | ReadWait(_, _, ref res) | ReadRange(_, _, _, ref res) => {
+                res.is_canceled()
+            }
+            ReadMetadata(_, _, _, ref res) => res.is_canceled(),
+            Tail(_, ref res) => res.is_canceled(),
+            ReadPage(_, _, ref res) => res.is_canceled(),
+            _ => false,
+        }
+    }
+}
+
+/// Subscription fed by the log thread.
+struct Subscriber {
+    // next offset to send to the subscriber
+    next: Offset,
+    sender: SubscriptionSender,
 }
 
 /// Request sent through the `Sink` for the log
@@ -95,41 +308,171 @@ enum LogRequest<R> {
 
 /// `Sink` that executes commands on the log during the `start_send` phase
 /// and attempts to flush the log on the `poll_complete` phase
-struct LogSink<L, R: LogSliceReader> {
-    log: CommitLog,
+struct LogSink<S, L, R: LogSliceReader> {
+    log: S,
+    dir: PathBuf,
+    // configuration the log is reopened with
+    log_cfg: LogConfig,
     last_flush: Instant,
+    // wall clock time of the last flush, for reporting
+    last_flush_time: SystemTime,
+    flush_policy: FlushPolicy,
+    // last offset covered by a flush
+    flushed_offset: Option<Offset>,
     dirty: bool,
+    uncommitted: UncommittedWindow,
+    amplification: WriteAmplification,
+    // last offset appended, labelled with the topic of the log
+    latest_offset: Gauge,
+    tombstones: Tombstones,
+    time_index: TimeIndex,
+    consumers: ConsumerOffsets,
+    producers: ProducerSequences,
+    retention: Retention,
+    rollover: Rollover,
+    size: LogSize,
+    append_retry: AppendRetry,
+    offsets: Box<OffsetAllocator>,
+    strict_offsets: bool,
+    // first offset retained, below which entries have been deleted
+    low_watermark: Offset,
+    // error ending the log thread once the current request is handled
+    fatal: Option<Error>,
+    // next record sequence, if records are sequenced
+    next_sequence: Option<u64>,
+    progress: Arc<Progress>,
+    beyond_end: BeyondEnd,
 
     pool: Rc<RefCell<BytesPool>>,
+    read_pool: BytesPool,
+    read_queue: ReadQueue,
+    verify_reads: bool,
+    // sealed segments published to the read workers, if any
+    sealed_view: Option<SharedView>,
 
     listener: L,
     log_slice_reader: R,
     parked_replication: Option<(Offset, LogSender<ReplicationSource<R::Result>>)>,
+    parked_reads: Vec<(Offset, usize, LogSender<Messages>)>,
+    // shutdowns waiting for the queued requests to drain
+    parked_shutdowns: Vec<LogSender<()>>,
+    subscribers: Vec<Subscriber>,
+    read_cache: ReadCache,
     replication_max_bytes: usize,
 }
 
-impl<L, R> LogSink<L, R>
+/// State of a log opened by `spawn_log`, sent to the log thread to build
+/// its `LogSink`. The settings of the log are read from the configuration.
+struct LogSinkParts {
+    dir: PathBuf,
+    log_cfg: LogConfig,
+    flush_policy: FlushPolicy,
+    tombstones: Tombstones,
+    time_index: TimeIndex,
+    consumers: ConsumerOffsets,
+    producers: ProducerSequences,
+    retention: Retention,
+    rollover: Rollover,
+    size: LogSize,
+    append_retry: AppendRetry,
+    offsets: Box<OffsetAllocator>,
+    progress: Arc<Progress>,
+    read_cache: ReadCache,
+    uncommitted: UncommittedWindow,
+    read_queue: ReadQueue,
+    sealed_view: Option<SharedView>,
+}
+
+impl<S, L, R> LogSink<S, L, R>
 where
+    S: Storage,
     L: AppendListener,
     R: LogSliceReader,
 {
     fn new(
-        log: CommitLog,
-        replication_max_bytes: usize,
+        log: S,
+        topic: &str,
+        parts: LogSinkParts,
         pool: Rc<RefCell<BytesPool>>,
+        read_pool: BytesPool,
         listener: L,
         reader: R,
-    ) -> LogSink<L, R> {
-        LogSink {
+    ) -> LogSink<S, L, R> {
+        let LogSinkParts {
+            dir,
+            log_cfg,
+            flush_policy,
+            tombstones,
+            time_index,
+            consumers,
+            producers,
+            retention,
+            rollover,
+            size,
+            append_retry,
+            offsets,
+            progress,
+            read_cache,
+            uncommitted,
+            read_queue,
+            sealed_view,
+        } = parts;
+        let low_watermark = retention::low_watermark(&dir).unwrap_or_else(|e| {
+            error!("Unable to list segments: {}", e);
+            0
+        });
+        // each record takes an offset, so sequences starting from the next
+        // offset are above those assigned before a restart
+        let next_sequence = if log_cfg.record_sequence {
+            Some(log.last_offset().map(|off| off + 1).unwrap_or(0))
+        } else {
+            None
+        };
+        // the log is flushed on open
+        let flushed_offset = log.last_offset();
+        let mut sink = LogSink {
             log,
+            dir,
+            strict_offsets: log_cfg.strict_offsets,
+            beyond_end: log_cfg.read_beyond_end,
+            verify_reads: log_cfg.verify_reads,
+            replication_max_bytes: log_cfg.replication_max_bytes,
+            log_cfg,
             last_flush: Instant::now(),
+            last_flush_time: SystemTime::now(),
+            flush_policy,
+            flushed_offset,
             dirty: false,
+            uncommitted,
+            amplification: WriteAmplification::new(topic),
+            latest_offset: LOG_LATEST_OFFSET.with_label_values(&[topic]),
+            tombstones,
+            time_index,
+            consumers,
+            producers,
+            retention,
+            rollover,
+            size,
+            append_retry,
+            offsets,
+            low_watermark,
+            fatal: None,
+            next_sequence,
+            progress,
             pool,
+            read_pool,
+            read_queue,
+            sealed_view,
             listener,
             log_slice_reader: reader,
             parked_replication: None,
-            replication_max_bytes,
-        }
+            parked_reads: Vec::new(),
+            parked_shutdowns: Vec::new(),
+            subscribers: Vec::new(),
+            read_cache,
+        };
+        sink.publish_sealed();
+        sink
     }
 
     /// Trys to replicate via a log read, parking if the offset has not yet been appended.
@@ -137,7 +480,7 @@ where
         let read_res = self.log.reader(
             &mut self.log_slice_reader,
             offset,
-            ReadLimit::max_bytes(self.replication_max_bytes),
+            self.replication_max_bytes,
         );
         match read_res {
             Ok(Some(fs)) => {
@@ -164,23 +507,804 @@ where
         }
     }
 
-    fn log_append(&mut self, ms: Messages) -> Result<OffsetRange, Error> {
-        let num_bytes = ms.bytes().len() as f64;
+    fn read(&mut self, offset: Offset, max_bytes: usize) -> Result<Messages, Error> {
+        let _span = spans::log_read(offset);
+        if let Some(msgs) = self.read_cache.get(offset, max_bytes) {
+            return Ok(msgs);
+        }
+
+        // TODO: allow file slice to be sent (zero copy all the things!)
+        let read = self.log.read(offset, max_bytes);
+        if let Ok(ref v) = read {
+            self.verify(v)?;
+        }
+        let msgs = match read {
+            Ok(ref v) if self.tombstones.is_empty() => self.pooled_copy(v),
+            Ok(ref v) => {
+                let tombstones = &self.tombstones;
+                Messages::copy_filtered(v, |off| !tombstones.contains(off))
+            }
+            Err(_) => return Err(Error::new(ErrorKind::Other, "read error")),
+        };
+
+        let complete = msgs
+            .next_offset()
+            .map(|off| off < self.log.next_offset())
+            .unwrap_or(false);
+        self.read_cache
+            .insert(offset, max_bytes, msgs.clone(), complete);
+        Ok(msgs)
+    }
+
+    /// Verifies the hashes of entries read from disk, if enabled.
+    fn verify<M: MessageSet>(&self, set: &M) -> Result<(), Error> {
+        if !self.verify_reads {
+            return Ok(());
+        }
+        verify_hashes(set).map_err(|e| {
+            error!("Read error: {}", e);
+            e
+        })
+    }
+
+    /// Copies a read into a pooled buffer, if the read fits in the buffer.
+    fn pooled_copy<M: MessageSet>(&mut self, set: &M) -> Messages {
+        if set.bytes().len() > self.read_pool.buffer_capacity() {
+            return Messages::copy_from(set);
+        }
+        let msgs = Messages::copy_into(set, self.read_pool.take());
+        self.read_pool.push(msgs.clone().into_inner());
+        msgs
+    }
+
+    /// Reads the metadata of entries, skipping the payloads. The read
+    /// bypasses the read cache, which holds whole entries.
+    fn read_metadata(&mut self, offset: Offset, max_bytes: usize) -> Result<MetadataRead, Error> {
+        let _span = spans::log_read(offset);
+        match self.log.read(offset, max_bytes) {
+            Ok(ref v) => {
+                self.verify(v)?;
+                let tombstones = &self.tombstones;
+                Ok(MetadataRead::copy_filtered(v, |off| {
+                    !tombstones.contains(off)
+                }))
+            }
+            Err(_) => Err(Error::new(ErrorKind::Other, "read error")),
+        }
+    }
+
+    /// Reads a page of entries at the cursor, with the cursor of the next page.
+    fn read_page(
+        &mut self,
+        cursor: Cursor,
+        max_bytes: usize,
+    ) -> Result<(Messages, Option<Cursor>), Error> {
+        let first_offset = retention::segments(&self.dir)?
+            .first()
+            .map(|s| s.base_offset);
+        let next_offset = self.log.next_offset();
+        read_page(cursor, first_offset, next_offset, |pos| {
+            self.read(pos, max_bytes)
+        })
+    }
+
+    /// Reads from the log, parking the read if the offset has not yet been appended.
+    fn try_read(&mut self, offset: Offset, max_bytes: usize, res: LogSender<Messages>) {
+        if offset < self.log.next_offset() {
+            match self.read(offset, max_bytes) {
+                Ok(msgs) => res.send(msgs),
+                Err(e) => res.send_err(e),
+            }
+        } else {
+            trace!(
+                "[trace {}] Parking read, no offset {}",
+                res.trace_id(),
+                offset
+            );
+            self.parked_reads
+                .retain(|&(_, _, ref res)| !res.is_canceled());
+            self.parked_reads.push((offset, max_bytes, res));
+        }
+    }
+
+    /// Sends the entries after the next offset of each subscriber, while
+    /// the subscriber has room in its buffer.
+    fn feed_subscribers(&mut self) {
+        let subscribers = mem::replace(&mut self.subscribers, Vec::new());
+        for sub in subscribers {
+            if let Some(sub) = self.feed(sub) {
+                self.subscribers.push(sub);
+            }
+        }
+    }
+
+    /// Feeds the subscriber, returning it unless the subscription has ended.
+    /// With a full buffer, the log task is notified once the subscriber
+    /// consumes, so it is fed again from `poll_complete`.
+    fn feed(&mut self, mut sub: Subscriber) -> Option<Subscriber> {
+        while sub.next < self.log.next_offset() {
+            match sub.sender.poll_ready() {
+                Ok(Async::Ready(())) => {}
+                Ok(Async::NotReady) => return Some(sub),
+                Err(()) => {
+                    trace!("Subscription dropped");
+                    return None;
+                }
+            }
+
+            let max_bytes = self.replication_max_bytes;
+            match self
+                .check_trimmed(sub.next)
+                .and_then(|_| self.read(sub.next, max_bytes))
+            {
+                Ok(msgs) => match msgs.next_offset() {
+                    Some(next) => {
+                        sub.next = next;
+                        sub.sender.send(msgs);
+                    }
+                    None => break,
+                },
+                Err(e) => {
+                    sub.sender.send_err(e);
+                    return None;
+                }
+            }
+        }
+        Some(sub)
+    }
+
+    /// Copies the log to the destination directory. Appends are not processed
+    /// during the snapshot, as the log thread is busy copying.
+    fn snapshot(&mut self, dest: PathBuf) -> Result<SnapshotInfo, Error> {
+        self.flush()?;
+
+        let last_offset = self.log.last_offset();
+        let segments = snapshot::copy_segments(&self.dir, &dest)?;
+        let first_offset = match last_offset {
+            Some(_) => retention::segments(&dest)?.first().map(|s| s.base_offset),
+            None => None,
+        };
+
+        info!(
+            "Snapshot of {} segments to {:?}, offsets {:?}..={:?}",
+            segments, dest, first_offset, last_offset
+        );
+        Ok(SnapshotInfo {
+            first_offset,
+            last_offset,
+            segments,
+        })
+    }
+
+    /// Drops the entries of the inactive segments superseded by a later entry
+    /// with the same key. Requests are not processed during the compaction,
+    /// and the read workers leave reads of the segments to the log thread,
+    /// so reads see either the original or the compacted segments.
+    fn compact(&mut self) -> Result<CompactionStats, Error> {
+        self.flush()?;
+        self.withdraw_sealed(|view| view.withdraw_from(0));
+        let stats = self.log.compact_keys(&self.log_cfg)?;
+        if stats.messages_dropped == 0 {
+            self.publish_sealed();
+        } else {
+            if let Err(e) = self.log.reopen(&self.log_cfg) {
+                // the log no longer holds the segments on disk
+                self.fatal = Some(Error::new(e.kind(), format!("reopen error: {}", e)));
+                return Err(e);
+            }
+            self.read_cache.clear();
+            self.update_low_watermark();
+        }
+        Ok(stats)
+    }
+
+    /// Summarizes the log and its segments on disk.
+    fn stats(&self) -> Result<LogStats, Error> {
+        let segments = retention::segments(&self.dir)?;
+        let last_offset = self.log.last_offset();
+        Ok(LogStats {
+            first_offset: last_offset.and(segments.first().map(|s| s.base_offset)),
+            last_offset,
+            unflushed_bytes: self.uncommitted.bytes() as u64,
+            deletable_segments: self.retention.deletable(&segments),
+            segments: segments
+                .iter()
+                .map(|s| SegmentStats {
+                    base_offset: s.base_offset,
+                    bytes: s.bytes,
+                })
+                .collect(),
+        })
+    }
+
+    /// Lists the segments after a flush, reporting segment rolls and
+    /// updating the size of the log.
+    fn check_rollover(&mut self) {
+        if let Some(segments) = self.rollover.check(&self.dir) {
+            self.size.listed(&segments);
+        }
+    }
+
+    /// Lists the segments of the log. Segments roll and are deleted on the
+    /// log thread, so the list is consistent with the log.
+    fn segments(&self) -> Result<Vec<LogSegment>, Error> {
+        let segments = retention::segments(&self.dir)?;
+        let last_offset = self.log.last_offset();
+        segments
+            .iter()
+            .enumerate()
+            .map(|(i, s)| {
+                let end = match segments.get(i + 1) {
+                    Some(next) => Some(next.base_offset),
+                    None => last_offset.map(|off| off + 1),
+                };
+                let path = self.dir.join(format!("{:020}.log", s.base_offset));
+                Ok(LogSegment {
+                    base_offset: s.base_offset,
+                    last_offset: end
+                        .and_then(|end| end.checked_sub(1))
+                        .filter(|&last| last >= s.base_offset),
+                    bytes: s.bytes,
+                    created: fs::metadata(&path)?.created().ok(),
+                })
+            })
+            .collect()
+    }
+
+    /// Summarizes the size of the log from the state of the log thread.
+    fn summary(&self) -> LogSummary {
+        let last_offset = self.log.last_offset();
+        LogSummary {
+            first_offset: last_offset.map(|_| self.low_watermark),
+            last_offset,
+            bytes: self.size.bytes(),
+            segments: self.size.segments(),
+            last_flush: self.last_flush_time,
+        }
+    }
+
+    /// Removes the entries after the offset from the log.
+    fn truncate(&mut self, offset: Offset) -> Result<(), Error> {
+        match self.log.last_offset() {
+            Some(last) if offset < last => {}
+            _ => {
+                debug!(
+                    "Truncation offset {} is at or past the end of the log",
+                    offset
+                );
+                return Ok(());
+            }
+        }
+        let first_offset = retention::segments(&self.dir)?
+            .first()
+            .map(|s| s.base_offset);
+        if let Some(first) = first_offset {
+            if offset < first {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Offset {} is before the first retained offset {}",
+                        offset, first
+                    ),
+                ));
+            }
+        }
+
+        self.flush()?;
+        self.withdraw_sealed(|view| view.withdraw_from(offset + 1));
+        self.log.truncate(offset)?;
+        self.flushed_offset = self.flushed_offset.min(self.log.last_offset());
+        self.producers.truncate(self.log.next_offset());
+        self.producers.persist()?;
+        self.size.listed(&retention::segments(&self.dir)?);
+        self.read_cache.clear();
+        self.time_index
+            .retain(self.low_watermark, self.log.next_offset())?;
+        self.publish_sealed();
+        if let Some(off) = self.log.last_offset() {
+            self.latest_offset.set(off as f64);
+        }
+        warn!("Truncated the log after offset {}", offset);
+        Ok(())
+    }
+
+    /// Deletes the segments with every offset below the offset, returning
+    /// the new low watermark. The active segment is never deleted.
+    fn trim_before(&mut self, offset: Offset) -> Result<Offset, Error> {
+        let segments = retention::segments(&self.dir)?;
+        let trimmed = retention::segments_below(&segments, offset);
+        if trimmed > 0 {
+            let bytes: u64 = segments[0..trimmed].iter().map(|s| s.bytes).sum();
+            let base = segments[trimmed].base_offset;
+            self.withdraw_sealed(|view| view.withdraw_below(base));
+            self.log.trim_segments_before(base)?;
+            self.read_cache.clear();
+            info!(
+                "Trimmed {} segments before offset {}, reclaimed {} bytes",
+                trimmed, offset, bytes
+            );
+        }
+        self.update_low_watermark();
+        Ok(self.low_watermark)
+    }
+
+    fn update_low_watermark(&mut self) {
+        match retention::segments(&self.dir) {
+            Ok(segments) => {
+                self.low_watermark = segments.first().map(|s| s.base_offset).unwrap_or(0);
+                self.size.listed(&segments);
+            }
+            Err(e) => error!("Unable to list segments: {}", e),
+        }
+        // deleted segments are dropped from the time index
+        if let Err(e) = self
+            .time_index
+            .retain(self.low_watermark, self.log.next_offset())
+        {
+            error!("Unable to update the time index: {}", e);
+        }
+        self.publish_sealed();
+    }
+
+    /// Withdraws sealed segments from the read workers before they are
+    /// truncated, deleted or rewritten, so reads the workers made with the
+    /// earlier view are sent on to the log thread. `publish_sealed` publishes
+    /// the segments once changed.
+    fn withdraw_sealed<F: FnOnce(&mut SealedView)>(&self, withdraw_segments: F) {
+        if let Some(ref view) = self.sealed_view {
+            withdraw(view, withdraw_segments);
+        }
+    }
+
+    /// Publishes the sealed segments, tombstones and low watermark to the read
+    /// workers. A segment closed by a roll may hold unflushed appends, so the
+    /// segments are only listed when every append has been flushed.
+    fn publish_sealed(&mut self) {
+        let view = match self.sealed_view {
+            Some(ref view) => view,
+            None => return,
+        };
+        let mut next = SealedView::clone(&view.read().unwrap());
+        next.set_low_watermark(self.low_watermark);
+        next.set_tombstones(self.tombstones.clone());
+        if !self.dirty {
+            match retention::segments(&self.dir) {
+                Ok(segments) => next.set_segments(&segments),
+                Err(e) => error!("Unable to list segments: {}", e),
+            }
+        }
+        *view.write().unwrap() = Arc::new(next);
+    }
+
+    /// Fails reads of offsets deleted by trimming or retention.
+    fn check_trimmed(&self, offset: Offset) -> Result<(), Error> {
+        if offset < self.low_watermark {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                OffsetTrimmed {
+                    offset,
+                    low_watermark: self.low_watermark,
+                },
+            ));
+        }
+        Ok(())
+    }
+
+    /// Changes the flush and retention settings, taking effect at the next
+    /// flush or retention check.
+    fn tune(&mut self, tuning: &LogTuning) -> Result<(), Error> {
+        tuning.validate()?;
+        self.flush_policy =
+            self.flush_policy
+                .tune(tuning.flush_mode, tuning.flush_interval, &self.log_cfg)?;
+        if tuning.changes_retention() {
+            let cfg = tuning.apply_retention(self.retention.config());
+            self.retention.reconfigure(&cfg);
+        }
+        info!("Log settings changed: {:?}", tuning);
+        Ok(())
+    }
+
+    /// Assigns offsets to client appends, returning false if the allocator
+    /// assigned invalid offsets.
+    fn assign_offsets(&mut self, ms: &mut MessagesMut) -> bool {
+        let next_offset = self.log.next_offset();
+        self.offsets.assign(ms, next_offset);
+        if rare!(!offsets::valid_offsets(ms, next_offset)) {
+            error!("Offset allocator assigned non-monotone offsets, dropping append");
+            return false;
+        }
+        true
+    }
+
+    /// Appends the payloads in batches up to the buffer capacity, calling
+    /// `appended` with the index of the first entry of each batch once the
+    /// batch is appended.
+    ///
+    /// Batches appended before an error remain in the log.
+    fn append_payloads<F>(
+        &mut self,
+        client_id: u64,
+        payloads: &[Bytes],
+        mut appended: F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(usize, &Messages),
+    {
+        PayloadPolicy::from_config(&self.log_cfg).check(payloads)?;
+        let mut index = 0;
+        while index < payloads.len() {
+            let start = index;
+            let mut buf = MessagesMut(self.pool.borrow_mut().take());
+            while index < payloads.len() {
+                match buf.push(client_id, index as u64, &payloads[index]) {
+                    Ok(()) => index += 1,
+                    Err(MessagePushError::OutOfCapacity) => break,
+                    Err(MessagePushError::MessageExceedsCapacity) => {
+                        warn!("Batch entry {} exceeds the buffer capacity", index);
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            "Entry exceeds the buffer capacity",
+                        ));
+                    }
+                }
+            }
+
+            if !self.assign_offsets(&mut buf) {
+                return Err(Error::new(ErrorKind::Other, "Invalid offsets assigned"));
+            }
+            let ms = buf.freeze();
+            self.pool.borrow_mut().push(ms.clone().into_inner());
+            self.log_append(ms.clone())?;
+            appended(start, &ms);
+        }
+        Ok(())
+    }
+
+    /// Appends the payloads in batches up to the buffer capacity, sending the
+    /// acks for each batch once it is appended.
+    fn append_batch(&mut self, client_id: u64, payloads: Vec<Bytes>, mut acks: AckSender) {
+        let res = self.append_payloads(client_id, &payloads, |start, ms| {
+            for (i, m) in (start..).zip(ms.iter()) {
+                if !acks.send(i, m.offset()) {
+                    trace!("Batch ack stream dropped");
+                }
+            }
+        });
+        if let Err(e) = res {
+            acks.send_err(e);
+        }
+    }
+
+    /// Appends the payloads, returning the offsets assigned and the entries
+    /// as stored in the log.
+    fn append_and_fetch(
+        &mut self,
+        client_id: u64,
+        payloads: Vec<Bytes>,
+    ) -> Result<(Range<Offset>, Messages), Error> {
+        let mut buf = BytesMut::new();
+        self.append_payloads(client_id, &payloads, |_, ms| {
+            buf.extend_from_slice(ms.bytes())
+        })?;
+
+        let msgs = Messages::copy_from(&MessagesMut(buf));
+        let range = match (msgs.iter().next(), msgs.next_offset()) {
+            (Some(first), Some(next)) => first.offset()..next,
+            _ => {
+                let next = self.log.next_offset();
+                next..next
+            }
+        };
+        Ok((range, msgs))
+    }
+
+    /// Appends a batch of an import, returning the offsets in order. With
+    /// the next offset expected, fails with `ErrorKind::AlreadyExists` if the
+    /// log has moved on from it.
+    fn import_batch(
+        &mut self,
+        payloads: &[Bytes],
+        expect_next: Option<Offset>,
+    ) -> Result<Vec<Offset>, Error> {
+        match expect_next {
+            Some(0) if self.log.next_offset() != 0 => {
+                return Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    "Unable to import into a log that is not empty",
+                ));
+            }
+            Some(next) if self.log.next_offset() != next => {
+                return Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    "Log appended to during the import",
+                ));
+            }
+            _ => {}
+        }
+
+        let mut offsets = Vec::with_capacity(payloads.len());
+        self.append_payloads(0, payloads, |_, ms| {
+            offsets.extend(ms.iter().map(|m| m.offset()))
+        })?;
+        Ok(offsets)
+    }
+
+    /// Appends the payloads as a single batch, returning the offsets in
+    /// order. Fails without appending any entry if the payloads exceed the
+    /// buffer capacity, or if the append to the log fails.
+    fn append_atomic(&mut self, client_id: u64, payloads: &[Bytes]) -> Result<Vec<Offset>, Error> {
+        PayloadPolicy::from_config(&self.log_cfg).check(payloads)?;
+        let mut buf = MessagesMut(self.pool.borrow_mut().take());
+        for (i, payload) in payloads.iter().enumerate() {
+            if let Err(e) = buf.push(client_id, i as u64, payload) {
+                warn!("Unable to append batch entry {}: {:?}", i, e);
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "Batch exceeds the buffer capacity",
+                ));
+            }
+        }
+
+        if !self.assign_offsets(&mut buf) {
+            return Err(Error::new(ErrorKind::Other, "Invalid offsets assigned"));
+        }
+        let ms = buf.freeze();
+        self.pool.borrow_mut().push(ms.clone().into_inner());
+        let range = self.log_append(ms)?;
+        Ok(range.iter().collect())
+    }
+
+    /// Appends a single entry outside of a batch, optionally flushing the
+    /// log before returning the offset of the entry.
+    fn append_now(&mut self, client_id: u64, payload: Bytes, flush: bool) -> Result<Offset, Error> {
+        let offset = self.append_single(client_id, payload)?;
+        if flush {
+            self.flush()?;
+        }
+        Ok(offset)
+    }
+
+    /// Appends a single entry, returning its offset.
+    fn append_single(&mut self, client_id: u64, payload: Bytes) -> Result<Offset, Error> {
+        let mut offset = None;
+        self.append_payloads(client_id, &[payload], |_, ms| {
+            offset = ms.iter().next().map(|m| m.offset());
+        })?;
+        offset.ok_or_else(|| Error::new(ErrorKind::Other, "Entry not appended"))
+    }
+
+    /// Appends an entry from an idempotent producer as `append_now`, unless
+    /// the producer has appended the sequence, returning the offset of the
+    /// entry appended with the sequence.
+    fn append_sequenced(
+        &mut self,
+        client_id: u64,
+        seq: ProducerSequence,
+        payload: Bytes,
+        flush: bool,
+    ) -> Result<Offset, Error> {
+        if let Some(offset) = self.producers.appended(seq)? {
+            debug!(
+                "Sequence {} of producer {} already appended",
+                seq.sequence, seq.producer_id
+            );
+            return Ok(offset);
+        }
+        // the sequence is recorded before the flush, so a retry after a
+        // failed flush returns the offset rather than appending again
+        let offset = self.append_single(client_id, payload)?;
+        self.producers.record(seq, offset);
+        if flush {
+            self.flush()?;
+        }
+        Ok(offset)
+    }
+
+    /// Appends a queued entry from an idempotent producer on its own, unless
+    /// the producer has appended the sequence. A duplicate is acknowledged
+    /// to the listener as appended.
+    fn append_idempotent(
+        &mut self,
+        client_id: u64,
+        client_req_id: u64,
+        seq: ProducerSequence,
+        payload: &Bytes,
+    ) -> Result<(), Error> {
+        match self.producers.appended(seq) {
+            Ok(None) => {}
+            // sequences are increasing, so an older sequence has been
+            // appended even if its offset is no longer known
+            Ok(Some(_)) | Err(_) => {
+                debug!(
+                    "Sequence {} of producer {} already appended",
+                    seq.sequence, seq.producer_id
+                );
+                self.listener.notify_duplicate(client_id, client_req_id);
+                return Ok(());
+            }
+        }
+
+        PayloadPolicy::from_config(&self.log_cfg).check(Some(payload))?;
+        let mut buf = MessagesMut(self.pool.borrow_mut().take());
+        if let Err(e) = buf.push(client_id, client_req_id, payload) {
+            warn!(
+                "Unable to append entry of producer {}: {:?}",
+                seq.producer_id, e
+            );
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Entry exceeds the buffer capacity",
+            ));
+        }
+        if !self.assign_offsets(&mut buf) {
+            return Err(Error::new(ErrorKind::Other, "Invalid offsets assigned"));
+        }
+        let ms = buf.freeze();
+        self.pool.borrow_mut().push(ms.clone().into_inner());
+        let range = self.log_append(ms)?;
+        self.producers.record(seq, range.first());
+        Ok(())
+    }
+
+    /// Appends a single entry with a key and headers, returning the offset
+    /// of the entry.
+    fn append_record(
+        &mut self,
+        client_id: u64,
+        key: Option<Bytes>,
+        headers: &[(String, String)],
+        payload: Bytes,
+    ) -> Result<Offset, Error> {
+        PayloadPolicy::from_config(&self.log_cfg).check(Some(&payload))?;
+        let mut buf = MessagesMut(self.pool.borrow_mut().take());
+        let key = key.as_ref().map(|k| &k[..]);
+        let sequence = self.next_sequence;
+        if let Err(e) = buf.push_sequenced_record(client_id, 0, sequence, key, headers, &payload) {
+            warn!("Unable to append record: {:?}", e);
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Entry exceeds the buffer capacity",
+            ));
+        }
+
+        if !self.assign_offsets(&mut buf) {
+            return Err(Error::new(ErrorKind::Other, "Invalid offsets assigned"));
+        }
+        let ms = buf.freeze();
+        self.pool.borrow_mut().push(ms.clone().into_inner());
+        let range = self.log_append(ms)?;
+        if let Some(seq) = sequence {
+            self.next_sequence = Some(seq + 1);
+        }
+        Ok(range.first())
+    }
+
+    /// Flushes the log to disk.
+    fn flush(&mut self) -> Result<(), Error> {
+        let _span = spans::log_flush();
+        let start = Instant::now();
+        {
+            let _op = self.progress.begin();
+            self.log.flush()?;
+        }
+        self.last_flush = start;
+        self.last_flush_time = SystemTime::now();
+        self.flushed_offset = self.log.last_offset();
+        self.producers.persist()?;
+        self.dirty = false;
+        self.uncommitted.flushed();
+        self.amplification.flushed();
+        self.publish_sealed();
+        trace!("Flushed");
+
+        let elapsed = start.elapsed().subsec_nanos() as f64;
+        FLUSH_TIME_HISTOGRAM.observe(elapsed);
+        Ok(())
+    }
+
+    /// Removes the entries of an append that failed after the entries were
+    /// written, ending the log thread if they cannot be removed.
+    fn discard_append(&mut self, next_offset: Offset) {
+        if let Err(e) = rollback(&mut self.log, &self.log_cfg, next_offset) {
+            error!("Unable to remove the entries of the failed append: {}", e);
+            self.fatal = Some(Error::new(e.kind(), format!("rollback error: {}", e)));
+            return;
+        }
+        self.flushed_offset = self.flushed_offset.min(self.log.last_offset());
+        match retention::segments(&self.dir) {
+            Ok(segments) => self.size.listed(&segments),
+            Err(e) => error!("Unable to list segments: {}", e),
+        }
+        if let Err(e) = self
+            .time_index
+            .retain(self.low_watermark, self.log.next_offset())
+        {
+            error!("Unable to update the time index: {}", e);
+        }
+        self.publish_sealed();
+    }
+
+    fn log_append(&mut self, ms: Messages) -> Result<AppendRange, Error> {
+        let _span = spans::log_append(ms.len());
+        let num_bytes = ms.bytes().len();
 
+        // block the append until the unflushed data is within the window
+        if self.uncommitted.requires_flush(num_bytes) {
+            debug!("Uncommitted window full, flushing before append");
+            self.flush().map_err(|e| {
+                error!("Log flush error: {}", e);
+                e
+            })?;
+        }
+
+        let next_offset = self.log.next_offset();
         let start = Instant::now();
-        let range = self.log.append_with_offsets(&ms).map_err(|e| {
+        let appended = {
+            let _op = self.progress.begin();
+            let log = &mut self.log;
+            let log_cfg = &self.log_cfg;
+            self.append_retry.run(|| {
+                // entries of a failed attempt are removed, so the messages
+                // are appended entirely or not at all
+                rollback(log, log_cfg, next_offset).map_err(AppendError::Io)?;
+                log.append(&ms)
+            })
+        };
+        let range = appended.map_err(|e| {
             error!("Unable to append to the log {}", e);
+            if let Err(e) = rollback(&mut self.log, &self.log_cfg, next_offset) {
+                error!("Unable to remove the entries of the failed append: {}", e);
+            }
+            // an I/O error persisting past the retries leaves the log unusable
+            if let AppendError::Io(ref e) = e {
+                self.fatal = Some(Error::new(e.kind(), format!("append error: {}", e)));
+            }
             Error::new(ErrorKind::Other, "append error")
         })?;
         let elapsed = start.elapsed().subsec_nanos() as f64;
         APPEND_TIME_HISTOGRAM.observe(elapsed);
 
+        // the entries of a gap are removed before they are flushed or read
+        let contiguous = offsets::is_contiguous(next_offset, ms.len(), range.first(), range.len());
+        if self.strict_offsets && rare!(!contiguous) {
+            error!(
+                "Offset gap: expected {} offsets from {}, appended {} from {}",
+                ms.len(),
+                next_offset,
+                range.len(),
+                range.first()
+            );
+            if let Err(e) = rollback(&mut self.log, &self.log_cfg, next_offset) {
+                error!("Unable to remove the entries of the offset gap: {}", e);
+            }
+            return Err(Error::new(ErrorKind::InvalidData, "Offset gap"));
+        }
+
         self.dirty = true;
+        self.uncommitted.append(num_bytes);
+        self.size.appended(num_bytes);
+        if let Err(e) = self.time_index.append(SystemTime::now(), range.first()) {
+            error!("Unable to update the time index: {}", e);
+        }
+        let payload_bytes = ms.iter().map(|m| m.payload().len() as u64).sum();
+        self.amplification
+            .append(ms.len(), payload_bytes, num_bytes as u64);
+
+        // the append is acknowledged once flushed, so the entries of an
+        // append failing to flush are removed before they are exposed
+        if self.flush_policy.flushes_each_append() {
+            if let Err(e) = self.flush() {
+                error!("Log flush error: {}", e);
+                self.discard_append(next_offset);
+                return Err(e);
+            }
+        }
 
         let latest_offset = range.iter().next_back().unwrap();
 
-        APPEND_BYTES_HISTOGRAM.observe(num_bytes);
-        LOG_LATEST_OFFSET.set(latest_offset as f64);
+        APPEND_BYTES_HISTOGRAM.observe(num_bytes as f64);
+        self.latest_offset.set(latest_offset as f64);
         APPEND_COUNT_HISTOGRAM.observe(range.len() as f64);
 
         // TODO: figure out whether the listener should be notified via roles/config
@@ -204,27 +1328,56 @@ where
             self.listener.notify_append(ms);
         }
 
+        self.read_cache.invalidate_incomplete();
+        if !self.parked_reads.is_empty() {
+            debug!(
+                "Sending messages to {} parked reads",
+                self.parked_reads.len()
+            );
+            let parked = mem::replace(&mut self.parked_reads, Vec::new());
+            for (offset, max_bytes, res) in parked {
+                self.try_read(offset, max_bytes, res);
+            }
+        }
+        self.feed_subscribers();
+
         Ok(range)
     }
 }
 
-impl<L, R> Sink for LogSink<L, R>
+impl<S, L, R> Sink for LogSink<S, L, R>
 where
+    S: Storage,
     L: AppendListener,
     R: LogSliceReader,
 {
     type SinkItem = LogRequest<R::Result>;
-    type SinkError = ();
+    type SinkError = Error;
 
-    fn start_send(&mut self, item: LogRequest<R::Result>) -> StartSend<LogRequest<R::Result>, ()> {
+    fn start_send(
+        &mut self,
+        item: LogRequest<R::Result>,
+    ) -> StartSend<LogRequest<R::Result>, Error> {
         use self::ClientRequest::*;
         use self::LogRequest::*;
         use self::ReplicaRequest::*;
 
         trace!("start_send from log");
+        if let Client(ref req) = item {
+            if req.is_read() {
+                self.read_queue.pop();
+                if req.is_abandoned() {
+                    trace!("Skipping read abandoned by the requester");
+                    self.read_queue.abandon();
+                    return Ok(AsyncSink::Ready);
+                }
+            }
+        }
         match item {
             Client(Append(mut ms)) => {
-                set_offsets(&mut ms, self.log.next_offset());
+                if !self.assign_offsets(&mut ms) {
+                    return Ok(AsyncSink::Ready);
+                }
                 let ms = ms.freeze();
                 self.pool.borrow_mut().push(ms.clone().into_inner());
                 self.log_append(ms).map(|_| ()).unwrap_or_default();
@@ -268,191 +1421,3677 @@ where
                     }
                 }
             }
+            Client(AppendBatch(client_id, payloads, acks)) => {
+                self.append_batch(client_id, payloads, acks);
+            }
+            Client(AppendAndFetch(client_id, payloads, res)) => {
+                match self.append_and_fetch(client_id, payloads) {
+                    Ok(appended) => res.send(appended),
+                    Err(e) => res.send_err(e),
+                }
+            }
+            Client(AppendAtomic(client_id, payloads, res)) => {
+                match self.append_atomic(client_id, &payloads) {
+                    Ok(offsets) => res.send(offsets),
+                    Err(e) => res.send_err(e),
+                }
+            }
+            Client(AppendNow(client_id, payload, flush, res)) => {
+                match self.append_now(client_id, payload, flush) {
+                    Ok(offset) => {
+                        trace!("[trace {}] Appended offset {}", res.trace_id(), offset);
+                        res.send(offset)
+                    }
+                    Err(e) => res.send_err(e),
+                }
+            }
+            Client(AppendSequenced(client_id, seq, payload, flush, res)) => {
+                match self.append_sequenced(client_id, seq, payload, flush) {
+                    Ok(offset) => res.send(offset),
+                    Err(e) => res.send_err(e),
+                }
+            }
+            Client(AppendIdempotent(client_id, client_req_id, seq, payload)) => {
+                if let Err(e) = self.append_idempotent(client_id, client_req_id, seq, &payload) {
+                    error!(
+                        "Unable to append entry of producer {}: {}",
+                        seq.producer_id, e
+                    );
+                }
+            }
+            Client(AppendRecord(client_id, key, headers, payload, res)) => {
+                match self.append_record(client_id, key, &headers, payload) {
+                    Ok(offset) => res.send(offset),
+                    Err(e) => res.send_err(e),
+                }
+            }
             Client(LastOffset(res)) => {
                 res.send(self.log.last_offset());
             }
-            Client(Read(pos, lim, res)) => {
-                // TODO: allow file slice to be sent (zero copy all the things!)
-                match self.log.read(pos, lim) {
-                    Ok(v) => res.send(v),
-                    Err(_) => res.send_err_with(ErrorKind::Other, "read error"),
+            Client(Read(pos, max_bytes, res)) => {
+                trace!("[trace {}] Reading offset {}", res.trace_id(), pos);
+                if let Err(e) = self.check_trimmed(pos) {
+                    res.send_err(e);
+                } else if pos < self.log.next_offset() {
+                    match self.read(pos, max_bytes) {
+                        Ok(msgs) => res.send(msgs),
+                        Err(e) => res.send_err(e),
+                    }
+                } else {
+                    match self.beyond_end {
+                        BeyondEnd::Empty => res.send(Messages::empty()),
+                        BeyondEnd::Error => {
+                            res.send_err_with(ErrorKind::InvalidInput, "Offset out of range")
+                        }
+                        BeyondEnd::Wait => self.try_read(pos, max_bytes, res),
+                    }
                 }
             }
-            Replica(Replicate(offset, res)) => {
-                self.try_replicate(offset, res);
+            Client(ReadWait(pos, max_bytes, res)) => {
+                self.try_read(pos, max_bytes, res);
             }
-        }
-
-        Ok(AsyncSink::Ready)
-    }
-
-    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
-        if self.dirty {
-            trace!("Log poll_complete, flushing");
-            let now = Instant::now();
-            if (now - self.last_flush) > Duration::from_secs(1) {
-                trace!("Attempting flush");
-
-                match self.log.flush() {
-                    Err(e) => {
-                        error!("Log flush error: {}", e);
+            Client(ReadRange(start, end, max_bytes, res)) => {
+                trace!("[trace {}] Reading offsets from {}", res.trace_id(), start);
+                match end {
+                    Some(end) if end <= start => res.send(Messages::empty()),
+                    _ if start < self.low_watermark => {
+                        res.send_err(self.check_trimmed(start).unwrap_err())
                     }
-                    _ => {
-                        self.last_flush = now;
-                        self.dirty = false;
-                        trace!("Flushed");
+                    _ if start == self.log.next_offset() => res.send(Messages::empty()),
+                    _ if start > self.log.next_offset() => {
+                        res.send_err_with(ErrorKind::InvalidInput, "Offset out of range")
                     }
-                };
-
-                let elapsed = now.elapsed().subsec_nanos() as f64;
-                FLUSH_TIME_HISTOGRAM.observe(elapsed);
+                    _ => match self.read(start, max_bytes) {
+                        Ok(msgs) => res.send(match end {
+                            Some(end) => msgs.take_until(end),
+                            None => msgs,
+                        }),
+                        Err(e) => res.send_err(e),
+                    },
+                }
             }
-        }
-        Ok(Async::Ready(()))
-    }
-}
-
-/// `AsyncLog` allows asynchronous operations against the `CommitLog`.
-#[derive(Clone)]
-pub struct AsyncLog {
-    req_sink: mpsc::UnboundedSender<ClientRequest>,
-    append_sink: mpsc::UnboundedSender<SingleMessage>,
-}
+            Client(ReadMetadata(start, end, max_bytes, res)) => match end {
+                Some(end) if end <= start => res.send(MetadataRead::default()),
+                _ if start < self.low_watermark => {
+                    res.send_err(self.check_trimmed(start).unwrap_err())
+                }
+                _ => match self.read_metadata(start, max_bytes) {
+                    Ok(read) => res.send(match end {
+                        Some(end) => read.take_until(end),
+                        None => read,
+                    }),
+                    Err(e) => res.send_err(e),
+                },
+            },
+            Client(Tail(n, res)) => {
+                let next_offset = self.log.next_offset();
+                match read_tail(next_offset, n, |pos, max_bytes| self.read(pos, max_bytes)) {
+                    Ok(msgs) => res.send(msgs),
+                    Err(e) => res.send_err(e),
+                }
+            }
+            Client(ReadPage(cursor, max_bytes, res)) => match self.read_page(cursor, max_bytes) {
+                Ok(page) => res.send(page),
+                Err(e) => res.send_err(e),
+            },
+            Client(Tombstone(range, res)) => {
+                if range.start >= range.end || range.end > self.log.next_offset() {
+                    res.send_err_with(ErrorKind::InvalidInput, "Invalid tombstone range");
+                    return Ok(AsyncSink::Ready);
+                }
 
-pub fn open<L, R>(
-    cfg: &LogConfig,
-    listener: L,
-    reader: R,
-) -> (AsyncLog, ReplicatorAsyncLog<R::Result>)
-where
+                info!("Tombstoning offsets {}..{}", range.start, range.end);
+                self.read_cache.clear();
+                match self.tombstones.insert(range) {
+                    Ok(()) => {
+                        self.publish_sealed();
+                        res.send(())
+                    }
+                    Err(e) => {
+                        error!("Unable to persist tombstones: {}", e);
+                        res.send_err(e);
+                    }
+                }
+            }
+            Client(Import(payloads, expect_next, res)) => {
+                match self.import_batch(&payloads, expect_next) {
+                    Ok(offsets) => res.send(offsets),
+                    Err(e) => res.send_err(e),
+                }
+            }
+            Client(Compact(res)) => match self.compact() {
+                Ok(stats) => res.send(stats),
+                Err(e) => {
+                    error!("Unable to compact the log: {}", e);
+                    res.send_err(e);
+                }
+            },
+            Client(Snapshot(dest, res)) => match self.snapshot(dest) {
+                Ok(info) => res.send(info),
+                Err(e) => {
+                    error!("Unable to snapshot the log: {}", e);
+                    res.send_err(e);
+                }
+            },
+            Client(ReadRaw(segment_base, range, res)) => {
+                let unflushed = self.uncommitted.bytes() as u64;
+                match raw::read_segment_range(&self.dir, segment_base, range, unflushed) {
+                    Ok(bytes) => res.send(bytes),
+                    Err(e) => res.send_err(e),
+                }
+            }
+            Client(CommitOffset(consumer, offset, res)) => {
+                if offset > self.log.next_offset() {
+                    res.send_err_with(ErrorKind::InvalidInput, "Offset not yet appended");
+                    return Ok(AsyncSink::Ready);
+                }
+
+                match self.consumers.commit(consumer, offset) {
+                    Ok(()) => {
+                        self.consumers.update_metrics(self.log.last_offset());
+                        res.send(());
+                    }
+                    Err(e) => {
+                        error!("Unable to persist consumer offsets: {}", e);
+                        res.send_err(e);
+                    }
+                }
+            }
+            Client(ConsumerOffsets(res)) => {
+                res.send(self.consumers.offsets());
+            }
+            Client(ConsumerLag(consumer, res)) => {
+                match self.consumers.lag(consumer, self.log.last_offset()) {
+                    Some(lag) => res.send(lag),
+                    None => res.send_err_with(ErrorKind::NotFound, "No committed offset"),
+                }
+            }
+            Client(Stats(res)) => match self.stats() {
+                Ok(stats) => res.send(stats),
+                Err(e) => res.send_err(e),
+            },
+            Client(Summary(res)) => res.send(self.summary()),
+            Client(LowWatermark(res)) => res.send(self.low_watermark),
+            Client(Segments(res)) => match self.segments() {
+                Ok(segments) => res.send(segments),
+                Err(e) => res.send_err(e),
+            },
+            Client(Shutdown(res)) => {
+                info!("Shutting down the log, draining queued requests");
+                self.parked_shutdowns.push(res);
+            }
+            Client(Flush(res)) => match self.flush() {
+                Ok(()) => {
+                    self.check_rollover();
+                    res.send(self.flushed_offset)
+                }
+                Err(e) => {
+                    error!("Log flush error: {}", e);
+                    res.send_err(e)
+                }
+            },
+            Client(FlushedOffset(res)) => {
+                res.send(self.flushed_offset);
+            }
+            Client(Subscribe(from, sender)) => {
+                let sub = Subscriber { next: from, sender };
+                if let Some(sub) = self.feed(sub) {
+                    self.subscribers.push(sub);
+                }
+            }
+            Client(OffsetForTime(time, res)) => {
+                res.send(
+                    self.time_index
+                        .offset_for_time(time, self.log.next_offset()),
+                );
+            }
+            Client(Trim(offset, res)) => match self.trim_before(offset) {
+                Ok(_) => res.send(()),
+                Err(e) => {
+                    error!("Log trim error: {}", e);
+                    res.send_err(e)
+                }
+            },
+            Client(Truncate(offset, res)) => match self.truncate(offset) {
+                Ok(()) => res.send(()),
+                Err(e) => {
+                    error!("Log truncate error: {}", e);
+                    res.send_err(e)
+                }
+            },
+            Client(Tune(tuning, res)) => match self.tune(&tuning) {
+                Ok(()) => res.send(()),
+                Err(e) => res.send_err(e),
+            },
+            Replica(Replicate(offset, res)) => {
+                self.try_replicate(offset, res);
+            }
+        }
+
+        // ends the log thread, failing later requests fast as read-only
+        match self.fatal.take() {
+            Some(e) => Err(e),
+            None => Ok(AsyncSink::Ready),
+        }
+    }
+
+    /// Runs the periodic flush and retention checks. The sink is polled to
+    /// completion whenever the request streams are idle, and the ticker of
+    /// the request stream wakes an idle log, so the checks run at least every
+    /// tick of the flush policy without incoming requests.
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        // the request streams are drained before the sink is polled to
+        // completion, so the queued appends are in the log
+        if !self.parked_shutdowns.is_empty() {
+            let res = self.flush();
+            for snd in self.parked_shutdowns.drain(..) {
+                match res {
+                    Ok(()) => snd.send(()),
+                    Err(ref e) => snd.send_err(Error::new(e.kind(), e.to_string())),
+                }
+            }
+            match res {
+                Ok(()) => info!("Log flushed for shutdown"),
+                Err(e) => error!("Log flush error on shutdown: {}", e),
+            }
+        }
+
+        // subscribers that have consumed from a full buffer notify the task
+        self.feed_subscribers();
+
+        let now = Instant::now();
+        if self.dirty {
+            trace!("Log poll_complete, flushing");
+            let unflushed = self.uncommitted.bytes();
+            if self.flush_policy.is_due(now - self.last_flush, unflushed) {
+                trace!("Attempting flush");
+                if let Err(e) = self.flush() {
+                    error!("Log flush error: {}", e);
+                }
+                self.consumers.update_metrics(self.log.last_offset());
+                self.check_rollover();
+            }
+        }
+
+        if self.retention.is_due(now) {
+            trace!("Enforcing retention");
+            let enforced = {
+                let sealed_view = &self.sealed_view;
+                self.retention.enforce(&mut self.log, |base| {
+                    if let Some(ref view) = *sealed_view {
+                        withdraw(view, |view| view.withdraw_below(base));
+                    }
+                })
+            };
+            if let Err(e) = enforced {
+                error!("Error enforcing retention: {}", e);
+            }
+            self.read_cache.clear();
+            self.update_low_watermark();
+        }
+        Ok(Async::Ready(()))
+    }
+
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        // every handle to the log is dropped, so flush the remaining appends
+        if self.dirty {
+            if let Err(e) = self.flush() {
+                error!("Log flush error on close: {}", e);
+            }
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+/// `AsyncLog` allows asynchronous operations against the `CommitLog`.
+#[derive(Clone)]
+pub struct AsyncLog {
+    req_sink: mpsc::UnboundedSender<ClientRequest>,
+    high_sink: mpsc::UnboundedSender<QueuedMessage>,
+    bulk_sink: mpsc::UnboundedSender<QueuedMessage>,
+    append_queue: AppendQueue,
+    read_queue: ReadQueue,
+    read_only: Arc<ReadOnlyLog>,
+    progress: Arc<Progress>,
+    // appends are rejected before being sent while paused
+    pause: Arc<TopicPause>,
+    // serve reads of sealed segments, if configured
+    read_workers: Option<ReadWorkers>,
+    // payloads accepted by appends
+    payloads: PayloadPolicy,
+    // trace of the requests sent through this handle
+    trace_id: TraceId,
+    // logs of the topics besides the default, if served
+    topics: Option<Topics>,
+}
+
+/// Truncates the log back to the next offset before an append, removing the
+/// entries of an append that failed part way. The log is cleared if the
+/// append was the first to the log.
+fn rollback<S: Storage>(log: &mut S, cfg: &LogConfig, next_offset: Offset) -> Result<(), Error> {
+    if log.next_offset() <= next_offset {
+        return Ok(());
+    }
+    warn!(
+        "Removing entries {}..{} of a failed append",
+        next_offset,
+        log.next_offset()
+    );
+    if next_offset == 0 {
+        log.clear(cfg)
+    } else {
+        log.truncate(next_offset - 1)
+    }
+}
+
+fn log_options(cfg: &LogConfig) -> LogOptions {
+    let mut opts = LogOptions::new(&cfg.dir);
+    opts.message_max_bytes(cfg.message_max_bytes);
+    opts.index_max_items(cfg.index_max_items);
+    opts.segment_max_bytes(cfg.segment_max_bytes);
+    opts
+}
+
+/// Opens the log in the directory of the configuration, creating the
+/// directory if missing, and spawns the log thread.
+///
+/// Fails if the directory or the files of the log cannot be opened, such as
+/// when the directory is not writable. A torn write at the end of the newest
+/// segment is truncated, or fails the open with `ErrorKind::InvalidData` if
+/// the log is not configured to repair it.
+pub fn open<L, R>(
+    cfg: &LogConfig,
+    listener: L,
+    reader: R,
+) -> Result<(AsyncLog, ReplicatorAsyncLog<R::Result>), Error>
+where
+    L: AppendListener + Send + 'static,
+    R: LogSliceReader + Send + 'static,
+    R::Result: Send + 'static,
+{
+    open_with_offsets(cfg, listener, reader, Box::new(DenseOffsets))
+}
+
+/// Opens the log of a topic other than the default as with `open`.
+///
+/// The metrics of the log are labelled with the topic.
+pub fn open_topic<L, R>(
+    topic: &str,
+    cfg: &LogConfig,
+    listener: L,
+    reader: R,
+) -> Result<(AsyncLog, ReplicatorAsyncLog<R::Result>), Error>
+where
+    L: AppendListener + Send + 'static,
+    R: LogSliceReader + Send + 'static,
+    R::Result: Send + 'static,
+{
+    open_log(topic, cfg, listener, reader, Box::new(DenseOffsets))
+}
+
+/// Opens the log as with `open`, assigning the offsets of client appends
+/// with the allocator rather than densely.
+///
+/// `strict_offsets` expects dense offsets, so fails every append after a
+/// gap left by the allocator.
+pub fn open_with_offsets<L, R>(
+    cfg: &LogConfig,
+    listener: L,
+    reader: R,
+    offsets: Box<OffsetAllocator>,
+) -> Result<(AsyncLog, ReplicatorAsyncLog<R::Result>), Error>
+where
+    L: AppendListener + Send + 'static,
+    R: LogSliceReader + Send + 'static,
+    R::Result: Send + 'static,
+{
+    open_log(DEFAULT_TOPIC, cfg, listener, reader, offsets)
+}
+
+fn open_log<L, R>(
+    topic: &str,
+    cfg: &LogConfig,
+    listener: L,
+    reader: R,
+    offsets: Box<OffsetAllocator>,
+) -> Result<(AsyncLog, ReplicatorAsyncLog<R::Result>), Error>
+where
+    L: AppendListener + Send + 'static,
+    R: LogSliceReader + Send + 'static,
+    R::Result: Send + 'static,
+{
+    let open_err = |what: &str, e: Error| open_error(cfg, what, e);
+    cfg.validate().map_err(|e| open_err("log", e))?;
+    fs::create_dir_all(&cfg.dir).map_err(|e| open_err("log directory", e))?;
+    let torn = repair::scan_tail(&cfg.dir).map_err(|e| open_err("segments", e))?;
+    if let Some(ref torn) = torn {
+        if !cfg.repair {
+            return Err(open_err("log", torn.error()));
+        }
+        torn.truncate().map_err(|e| open_err("log", e))?;
+        warn!(
+            "Discarded {} bytes of a torn write at the end of {}",
+            torn.discarded_bytes,
+            torn.path.display()
+        );
+    }
+    let mut log = CommitLog::new(log_options(cfg)).map_err(|e| open_err("log", e))?;
+    if let Some(torn) = torn {
+        // the index may cover an entry of the torn write
+        if log.next_offset() > torn.next_offset {
+            if torn.next_offset == 0 {
+                return Err(open_err("log", torn.error()));
+            }
+            log.truncate(torn.next_offset - 1)
+                .map_err(|e| open_err("log", e))?;
+        }
+    }
+    // the entries recovered on open are durable once flushed
+    log.flush().map_err(|e| open_err("log", e))?;
+    spawn_log(topic, cfg, log, listener, reader, offsets)
+}
+
+/// Opens the log as with `open`, holding the entries in the storage rather
+/// than the segments of the log directory, such as a `MemStorage` in tests.
+///
+/// The tombstones, consumer offsets and other state of the log are still
+/// kept in the log directory.
+pub fn open_with_storage<S, L, R>(
+    cfg: &LogConfig,
+    storage: S,
+    listener: L,
+    reader: R,
+) -> Result<(AsyncLog, ReplicatorAsyncLog<R::Result>), Error>
+where
+    S: Storage + Send + 'static,
+    L: AppendListener + Send + 'static,
+    R: LogSliceReader + Send + 'static,
+    R::Result: Send + 'static,
+{
+    cfg.validate().map_err(|e| open_error(cfg, "log", e))?;
+    fs::create_dir_all(&cfg.dir).map_err(|e| open_error(cfg, "log directory", e))?;
+    spawn_log(
+        DEFAULT_TOPIC,
+        cfg,
+        storage,
+        listener,
+        reader,
+        Box::new(DenseOffsets),
+    )
+}
+
+fn open_error(cfg: &LogConfig, what: &str, e: Error) -> Error {
+    Error::new(
+        e.kind(),
+        format!("Unable to open {} in {}: {}", what, cfg.dir, e),
+    )
+}
+
+/// Spawns the log thread appending to the storage, opening the state of the
+/// log kept in the log directory.
+fn spawn_log<S, L, R>(
+    topic: &str,
+    cfg: &LogConfig,
+    log: S,
+    listener: L,
+    reader: R,
+    offsets: Box<OffsetAllocator>,
+) -> Result<(AsyncLog, ReplicatorAsyncLog<R::Result>), Error>
+where
+    S: Storage + Send + 'static,
     L: AppendListener + Send + 'static,
     R: LogSliceReader + Send + 'static,
     R::Result: Send + 'static,
 {
     let (client_req_sink, client_req_stream) = mpsc::unbounded_channel::<ClientRequest>();
     let (repl_req_sink, repl_req_stream) = mpsc::unbounded_channel::<LogRequest<R::Result>>();
-    let (append_sink, append_stream) = mpsc::unbounded_channel::<SingleMessage>();
-
-    let log = {
-        let mut opts = LogOptions::new(&cfg.dir);
-        opts.message_max_bytes(cfg.message_max_bytes);
-        opts.index_max_items(cfg.index_max_items);
-        opts.segment_max_bytes(cfg.segment_max_bytes);
-        CommitLog::new(opts).expect("Unable to open log")
-    };
+    let (high_sink, high_stream) = mpsc::unbounded_channel::<QueuedMessage>();
+    let (bulk_sink, bulk_stream) = mpsc::unbounded_channel::<QueuedMessage>();
+    let append_queue = AppendQueue::new(cfg.append_queue_max).with_topic(topic);
+
+    let open_err = |what: &str, e: Error| open_error(cfg, what, e);
+    let read_only = Arc::new(ReadOnlyLog::new(cfg));
+    let tombstones = Tombstones::open(&cfg.dir).map_err(|e| open_err("tombstones", e))?;
+    let first_offset = retention::low_watermark(&cfg.dir).map_err(|e| open_err("segments", e))?;
+    let time_index = TimeIndex::open(
+        &cfg.dir,
+        Duration::from_millis(TIME_INDEX_INTERVAL_MS),
+        first_offset,
+        log.next_offset(),
+    )
+    .map_err(|e| open_err("time index", e))?;
+    let consumers = ConsumerOffsets::open(&cfg.dir)
+        .map(|consumers| consumers.with_topic(topic))
+        .map_err(|e| open_err("consumer offsets", e))?;
+    let producers =
+        ProducerSequences::open(&cfg.dir).map_err(|e| open_err("producer sequences", e))?;
+    let retention = Retention::new(&cfg.dir, &cfg.retention);
+    let rollover = Rollover::new(&cfg.dir);
+    let size = retention::segments(&cfg.dir)
+        .map(|segments| LogSize::new(&segments))
+        .map_err(|e| open_err("segments", e))?;
+    let read_cache = ReadCache::new(cfg.read_cache_entries);
+    let uncommitted = UncommittedWindow::new(cfg.max_uncommitted_bytes);
+    let append_retry = AppendRetry::new(
+        cfg.append_retries,
+        Duration::from_millis(cfg.append_retry_delay_ms),
+    );
 
     // start the metric for latest offset, if not already appended
     if let Some(off) = log.last_offset() {
-        LOG_LATEST_OFFSET.set(off as f64);
+        LOG_LATEST_OFFSET
+            .with_label_values(&[topic])
+            .set(off as f64);
     }
 
     trace!("Spawning log sink...");
 
-    // TODO: revisit this
-    let message_buffer_bytes = cfg.message_max_bytes;
-    let replication_max_bytes = cfg.replication_max_bytes;
+    let topic = topic.to_string();
+    let message_max_bytes = cfg.message_max_bytes;
+    let message_buffer_bytes = cfg.message_buffer_bytes.max(message_max_bytes);
+    let message_pool_buffers = cfg.message_pool_buffers.unwrap_or(usize::max_value());
+    let batch_max_entries = cfg.append_batch_max_entries.unwrap_or(usize::max_value());
+    let read_buffer_bytes = cfg.read_buffer_bytes;
+    let drained_queue = append_queue.clone();
+    let read_queue = ReadQueue::new(
+        cfg.read_queue_max,
+        Duration::from_millis(cfg.read_queue_timeout_ms),
+    );
+    let thread_priority = cfg.thread_priority.clone();
+    let progress = Arc::new(Progress::new(cfg.stall_fail_fast));
+    let pause = Arc::new(TopicPause::new(&topic));
+    if let Some(threshold_ms) = cfg.stall_threshold_ms {
+        watchdog::spawn(&progress, Duration::from_millis(threshold_ms));
+    }
+    let flush_policy = FlushPolicy::from_config(cfg);
+    // wakes an idle log for the flush and retention checks
+    let tick_interval = flush_policy.tick_interval();
+    let sealed_view = if cfg.read_threads > 0 {
+        Some(Arc::new(RwLock::new(Arc::new(SealedView::new(
+            tombstones.clone(),
+        )))))
+    } else {
+        None
+    };
+    let read_workers = sealed_view.as_ref().map(|view| {
+        ReadWorkers::spawn(
+            cfg.read_threads,
+            PathBuf::from(&cfg.dir),
+            view.clone(),
+            cfg.verify_reads,
+            client_req_sink.clone(),
+            read_queue.clone(),
+            read_only.clone(),
+        )
+    });
+    let parts = LogSinkParts {
+        dir: PathBuf::from(&cfg.dir),
+        log_cfg: cfg.clone(),
+        flush_policy,
+        tombstones,
+        time_index,
+        consumers,
+        producers,
+        retention,
+        rollover,
+        size,
+        append_retry,
+        offsets,
+        progress: progress.clone(),
+        read_cache,
+        uncommitted,
+        read_queue: read_queue.clone(),
+        sealed_view,
+    };
+    let writer_guard = WriterGuard(read_only.clone());
     thread::spawn(move || {
-        let pool = Rc::new(RefCell::new(BytesPool::new(message_buffer_bytes)));
+        let writer_guard = writer_guard;
+        if let Some(ref priority) = thread_priority {
+            priority::elevate_current_thread(priority);
+        }
+        let pool = BytesPool::bounded(message_buffer_bytes, message_pool_buffers);
+        let pool = Rc::new(RefCell::new(pool));
+        let read_pool = BytesPool::bounded(read_buffer_bytes, READ_POOL_MAX_BUFFERS);
+        let append_stream = PriorityStream::new(high_stream, bulk_stream);
+        let append_stream = QueueStream::new(append_stream, drained_queue);
         let append_stream =
-            BatchMessageStream::new(append_stream, pool.clone()).map(ClientRequest::Append);
-        LogSink::new(log, replication_max_bytes, pool, listener, reader)
-            .send_all(
+            BatchMessageStream::with_max_entries(append_stream, pool.clone(), batch_max_entries)
+                .max_message_bytes(message_max_bytes)
+                .map(ClientRequest::Append);
+        let res = LogSink::new(log, &topic, parts, pool, read_pool, listener, reader)
+            .send_all(TickStream::new(
                 client_req_stream
                     .select(append_stream)
                     .map(LogRequest::Client)
                     .select(repl_req_stream)
-                    .map_err(|_| ()),
-            )
+                    .map_err(|_| Error::new(ErrorKind::Other, "log request stream failed")),
+                tick_interval,
+            ))
             .map(|_| error!("Log sink completed"))
-            .wait()
-            .unwrap()
+            .wait();
+        if let Err(e) = res {
+            writer_guard.fail(&e);
+        }
     });
 
-    (
+    Ok((
         AsyncLog {
             req_sink: client_req_sink,
-            append_sink,
+            high_sink,
+            bulk_sink,
+            append_queue,
+            read_queue,
+            read_only,
+            progress,
+            pause,
+            read_workers,
+            payloads: PayloadPolicy::from_config(cfg),
+            trace_id: 0,
+            topics: None,
         },
         ReplicatorAsyncLog {
             req_sink: repl_req_sink,
         },
-    )
+    ))
 }
 
 impl AsyncLog {
-    pub fn append(&mut self, client_id: u64, client_req_id: u64, payload: Bytes) {
-        self.append_sink
-            .try_send((client_id, client_req_id, payload))
-            .map_err(|_| ())
-            .expect("unable to append to the log");
+    /// Sends the requests of the handle with the trace, which the log thread
+    /// logs the requests with and names in their errors.
+    pub fn with_trace(mut self, trace_id: TraceId) -> AsyncLog {
+        self.trace_id = trace_id;
+        self
     }
 
-    pub fn last_offset(&mut self) -> LogFuture<Option<Offset>> {
-        let (snd, f) = channel::<Option<Offset>>();
-        self.req_sink
-            .try_send(ClientRequest::LastOffset(snd))
-            .map_err(|_| ())
-            .expect("unable to read latest offset from the log");
-        f
+    /// Serves the topics besides the default through the handle, so that
+    /// `topic` and `topic_or_create` route to the logs of the registry.
+    pub fn with_topics(mut self, topics: Topics) -> AsyncLog {
+        self.topics = Some(topics);
+        self
     }
 
-    pub fn read(&mut self, position: Offset, limit: ReadLimit) -> LogFuture<MessageBuf> {
-        let (snd, f) = channel::<MessageBuf>();
-        self.req_sink
-            .try_send(ClientRequest::Read(position, limit, snd))
-            .map_err(|_| ())
-            .expect("unable to read from the log");
-        f
+    /// Handle to the log of an existing topic, sending its requests with the
+    /// trace of this handle. An empty topic is the default topic, the log of
+    /// this handle.
+    ///
+    /// Fails with `ErrorKind::NotFound` if nothing has been appended to the
+    /// topic or topics are not served, and with `ErrorKind::PermissionDenied`
+    /// while the node is in a chain with other nodes.
+    pub fn topic(&self, topic: &str) -> Result<AsyncLog, Error> {
+        self.route(topic, false)
     }
-}
 
-// TODO: remove replication-specific logic
-pub struct ReplicatorAsyncLog<R> {
-    req_sink: mpsc::UnboundedSender<LogRequest<R>>,
-}
+    /// Handle to the log of the topic as with `topic`, creating the log if
+    /// missing.
+    pub fn topic_or_create(&self, topic: &str) -> Result<AsyncLog, Error> {
+        self.route(topic, true)
+    }
 
-impl<R> Clone for ReplicatorAsyncLog<R> {
-    fn clone(&self) -> ReplicatorAsyncLog<R> {
-        ReplicatorAsyncLog {
-            req_sink: self.req_sink.clone(),
+    fn route(&self, topic: &str, create: bool) -> Result<AsyncLog, Error> {
+        if topics::is_default(topic) {
+            return Ok(self.clone());
         }
+        let topics = self.topics.as_ref().ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("Topic {} does not exist, topics are not served", topic),
+            )
+        })?;
+        let log = if create {
+            topics.log_or_create(topic)?
+        } else {
+            topics.log(topic)?
+        };
+        Ok(log.with_trace(self.trace_id))
     }
-}
 
-impl<R> ReplicatorAsyncLog<R> {
-    pub fn replicate_from(&mut self, offset: Offset) -> LogFuture<ReplicationSource<R>> {
-        let (snd, f) = channel::<ReplicationSource<R>>();
-        self.req_sink
-            .try_send(LogRequest::Replica(ReplicaRequest::Replicate(offset, snd)))
-            .map_err(|_| ())
-            .expect("cannot sent replicate_from to the log");
-        f
+    /// Queues an append to the log. High priority appends are batched ahead
+    /// of queued bulk appends, while bulk appends still make progress.
+    ///
+    /// Fails with `ErrorKind::WouldBlock` if the append queue is bounded
+    /// and full. See `append_wait` to wait for the queue instead. Payloads
+    /// larger than `message_max_bytes` fail with `ErrorKind::InvalidInput`
+    /// carrying `MessageTooLarge`, as do empty payloads unless
+    /// `allow_empty_payloads` is set, here and in the other appends. Appends
+    /// to a paused log fail with `ErrorKind::PermissionDenied` carrying
+    /// `TopicPaused`.
+    ///
+    /// An append from an idempotent producer is not appended again when
+    /// retried with the sequence of an earlier append, but acknowledged to
+    /// the listener as appended. Such appends are appended on their own
+    /// rather than batched, so bypass the append queue and its priorities.
+    pub fn append(
+        &mut self,
+        client_id: u64,
+        client_req_id: u64,
+        payload: Bytes,
+        priority: Priority,
+        producer: Option<ProducerSequence>,
+    ) -> Result<(), Error> {
+        self.payloads.check(Some(&payload))?;
+
+        if rare!(self.read_only.is_active()) {
+            return Err(read_only_error());
+        }
+
+        if rare!(self.progress.is_stalled()) {
+            return Err(stalled_error());
+        }
+
+        self.pause.check()?;
+
+        if let Some(seq) = producer {
+            return self
+                .req_sink
+                .try_send(ClientRequest::AppendIdempotent(
+                    client_id,
+                    client_req_id,
+                    seq,
+                    payload,
+                ))
+                .map_err(|_| read_only_error());
+        }
+
+        if rare!(!self.append_queue.try_push()) {
+            return Err(Error::new(
+                ErrorKind::WouldBlock,
+                format!("Append queue is full, {} pending", self.append_queue.len()),
+            ));
+        }
+
+        self.send_queued(client_id, client_req_id, payload, priority)
     }
 
-    pub fn append_from_replication(&mut self, buf: Messages) -> LogFuture<OffsetRange> {
-        let (snd, f) = channel::<OffsetRange>();
-        self.req_sink
-            .try_send(LogRequest::Replica(ReplicaRequest::AppendFromReplication(
-                buf, snd,
-            )))
-            .map_err(|_| ())
-            .expect("cannot sent append_from_replication to the log");
-        f
+    /// Queues an append to the log as `append`, waiting for a slot if the
+    /// append queue is bounded and full rather than failing. The future
+    /// resolves once the append is queued.
+    pub fn append_wait(
+        &self,
+        client_id: u64,
+        client_req_id: u64,
+        payload: Bytes,
+        priority: Priority,
+    ) -> QueuedAppendFuture {
+        QueuedAppendFuture {
+            log: self.clone(),
+            append: Some((client_id, client_req_id, payload, priority)),
+        }
     }
 
-    pub fn last_offset(&mut self) -> LogFuture<Option<Offset>> {
-        let (snd, f) = channel::<Option<Offset>>();
-        self.req_sink
-            .try_send(LogRequest::Client(ClientRequest::LastOffset(snd)))
-            .map_err(|_| ())
-            .expect("cannot send last_offset to the log");
+    /// Sends an append to the log thread, once a slot in the append queue
+    /// is reserved.
+    fn send_queued(
+        &mut self,
+        client_id: u64,
+        client_req_id: u64,
+        payload: Bytes,
+        priority: Priority,
+    ) -> Result<(), Error> {
+        let sink = match priority {
+            Priority::High => &mut self.high_sink,
+            Priority::Bulk => &mut self.bulk_sink,
+        };
+        sink.try_send((
+            Instant::now(),
+            self.trace_id,
+            (client_id, client_req_id, payload),
+        ))
+        .map_err(|_| read_only_error())
+    }
+
+    /// Appends a batch of entries in order, yielding the index and offset of
+    /// each entry as it is appended.
+    ///
+    /// Fails with the first ack if the log thread has failed.
+    pub fn append_stream(&mut self, client_id: u64, payloads: Vec<Bytes>) -> AppendAckStream {
+        let checked = self.payloads.check(&payloads);
+        self.send_checked(checked, ack_channel, |snd| {
+            ClientRequest::AppendBatch(client_id, payloads, snd)
+        })
+    }
+
+    /// Subscribes to the entries from the offset, first those already in the
+    /// log and then those appended afterwards, as they are appended.
+    ///
+    /// Each subscriber buffers a few batches. A slow subscriber is never
+    /// dropped and does not hold up appends: once its buffer is full the
+    /// log thread stops sending, and resumes by reading from the log as the
+    /// subscriber consumes. Should retention delete entries the subscriber
+    /// has yet to receive, the subscription fails with `ErrorKind::NotFound`.
+    ///
+    /// The subscription ends once every handle to the log is dropped.
+    pub fn subscribe(&self, from: Offset) -> Subscription {
+        let (snd, s) = subscription_channel(SUBSCRIPTION_BUFFER_BATCHES);
+        if rare!(self.progress.is_stalled()) {
+            snd.send_err(stalled_error());
+            return s;
+        }
+
+        if !self.read_only.is_active()
+            && self
+                .req_sink
+                .clone()
+                .try_send(ClientRequest::Subscribe(from, snd))
+                .is_ok()
+        {
+            return s;
+        }
+
+        let (snd, s) = subscription_channel(1);
+        snd.send_err(read_only_error());
+        s
+    }
+
+    /// Appends the payloads in order, returning the offsets assigned and the
+    /// entries exactly as stored in the log, without a separate read.
+    ///
+    /// Entries are appended in batches up to the buffer capacity. On failure,
+    /// batches appended before the failure remain in the log.
+    pub fn append_and_fetch(
+        &mut self,
+        client_id: u64,
+        payloads: Vec<Bytes>,
+    ) -> LogFuture<(Range<Offset>, Messages)> {
+        let checked = self.payloads.check(&payloads);
+        let trace_id = self.trace_id;
+        self.send_checked(
+            checked,
+            || traced_channel(trace_id),
+            |snd| ClientRequest::AppendAndFetch(client_id, payloads, snd),
+        )
+    }
+
+    /// Appends the payloads as a single batch in one buffer, returning the
+    /// offsets assigned in order. The batch is appended as a unit: either
+    /// every entry is appended or the future fails, including when the
+    /// payloads exceed the buffer capacity. Entries of an append to the log
+    /// that fails part way, or fails to flush when flushing each append, are
+    /// removed before the future fails.
+    ///
+    /// Readers never see part of a batch: the batch is appended by a single
+    /// request to the log thread, which serves reads between requests.
+    ///
+    /// An empty batch resolves immediately without a request to the log
+    /// thread.
+    pub fn append_batch(&mut self, client_id: u64, payloads: Vec<Bytes>) -> LogFuture<Vec<Offset>> {
+        if payloads.is_empty() {
+            let (snd, f) = traced_channel(self.trace_id);
+            snd.send(Vec::new());
+            return f;
+        }
+        let checked = self.payloads.check(&payloads);
+        let trace_id = self.trace_id;
+        self.send_checked(
+            checked,
+            || traced_channel(trace_id),
+            |snd| ClientRequest::AppendAtomic(client_id, payloads, snd),
+        )
+    }
+
+    /// Appends a single entry directly on the log thread, without waiting in
+    /// the append queue or for a batch to fill, returning the offset of the
+    /// entry. If `flush` is set, the log is flushed before the offset is
+    /// returned.
+    ///
+    /// This trades throughput for latency, so is intended for rare, urgent
+    /// entries such as control records. Queued appends are not affected.
+    pub fn append_now(&mut self, client_id: u64, payload: Bytes, flush: bool) -> LogFuture<Offset> {
+        let checked = self.payloads.check(Some(&payload));
+        let trace_id = self.trace_id;
+        self.send_checked(
+            checked,
+            || traced_channel(trace_id),
+            |snd| ClientRequest::AppendNow(client_id, payload, flush, snd),
+        )
+    }
+
+    /// Appends a single entry from an idempotent producer as `append_now`.
+    /// An append retried with the sequence of an earlier append by the
+    /// producer, such as after a timeout, is not appended again; the offset
+    /// of the earlier append is returned instead.
+    ///
+    /// Sequences are increasing for each producer. The offsets of the latest
+    /// sequences of each producer are kept, so a retry of a sequence before
+    /// them fails with `ErrorKind::AlreadyExists`.
+    pub fn append_sequenced(
+        &mut self,
+        client_id: u64,
+        seq: ProducerSequence,
+        payload: Bytes,
+        flush: bool,
+    ) -> LogFuture<Offset> {
+        let checked = self.payloads.check(Some(&payload));
+        let trace_id = self.trace_id;
+        self.send_checked(
+            checked,
+            || traced_channel(trace_id),
+            |snd| ClientRequest::AppendSequenced(client_id, seq, payload, flush, snd),
+        )
+    }
+
+    /// Appends a single entry with a key, returning the offset of the entry.
+    /// The key is returned with the entry on reads, with `Messages::keyed`.
+    pub fn append_with_key(
+        &mut self,
+        client_id: u64,
+        key: Bytes,
+        payload: Bytes,
+    ) -> LogFuture<Offset> {
+        self.append_record(client_id, Some(key), Vec::new(), payload)
+    }
+
+    /// Appends a single entry with a key and headers, returning the offset of
+    /// the entry. The key and headers are returned with the entry on reads,
+    /// with `RecordMeta::parse`.
+    ///
+    /// Records are appended directly on the log thread, as with `append_now`.
+    pub fn append_record(
+        &mut self,
+        client_id: u64,
+        key: Option<Bytes>,
+        headers: Vec<(String, String)>,
+        payload: Bytes,
+    ) -> LogFuture<Offset> {
+        let checked = record::validate(key.as_ref().map(|k| &k[..]), &headers)
+            .and_then(|()| self.payloads.check(Some(&payload)));
+        let trace_id = self.trace_id;
+        self.send_checked(
+            checked,
+            || traced_channel(trace_id),
+            |snd| ClientRequest::AppendRecord(client_id, key, headers, payload, snd),
+        )
+    }
+
+    /// Tests whether the log thread has failed, leaving the log read-only.
+    ///
+    /// Once read-only, appends fail immediately while reads are served from
+    /// a separate handle to the log.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.is_active()
+    }
+
+    /// The error that ended the log thread, such as an append failing with
+    /// an I/O error past the retries, or `None` while the log is running.
+    /// Writes to a read-only log fail with `ErrorKind::BrokenPipe`.
+    pub fn failure(&self) -> Option<Error> {
+        self.read_only.failure()
+    }
+
+    /// Pauses the appends to the log, for maintenance of a topic while the
+    /// other topics keep appending. Appends made until resumed fail with
+    /// `TopicPaused`, while appends already sent to the log thread before the
+    /// pause are still appended and reads are served as usual.
+    pub fn pause(&self) {
+        self.pause.set(true);
+    }
+
+    /// Resumes the appends to the log paused by `pause`.
+    pub fn resume(&self) {
+        self.pause.set(false);
+    }
+
+    /// Whether appends to the log are paused.
+    pub fn is_paused(&self) -> bool {
+        self.pause.is_paused()
+    }
+
+    /// Sends a read to the log thread, or serves the read from the read-only
+    /// log if the log thread has failed. Fails if the log thread is stalled.
+    fn send_read<T, F, G>(&mut self, req: F, read_only: G) -> LogFuture<T>
+    where
+        F: FnOnce(LogSender<T>) -> ClientRequest,
+        G: FnOnce(&ReadOnlyLog) -> Result<T, Error>,
+    {
+        let (snd, f) = traced_channel::<T>(self.trace_id);
+        if rare!(self.progress.is_stalled()) {
+            snd.send_err(stalled_error());
+            return f;
+        }
+
+        if !self.read_only.is_active() {
+            if let Some(f) = self.enqueue_read(req(snd), f) {
+                return f;
+            }
+        }
+
+        let (snd, f) = traced_channel::<T>(self.trace_id);
+        match read_only(&self.read_only) {
+            Ok(v) => snd.send(v),
+            Err(e) => snd.send_err(e),
+        }
         f
     }
-}
 
-/// Notifies a listener of log append operations.
-pub trait AppendListener {
-    /// Notifies the listener that the log has been mutated with the
-    /// offset range specified.
-    fn notify_append(&mut self, appended: Messages);
+    /// Sends a read to the log thread if the read queue has a slot, or else
+    /// returns a future that sends the read once a slot is released and
+    /// fails with `ErrorKind::WouldBlock` if none is in time. `None` if the
+    /// log thread has exited.
+    fn enqueue_read<T>(&mut self, req: ClientRequest, f: LogFuture<T>) -> Option<LogFuture<T>> {
+        if !self.read_queue.try_push() {
+            let read = QueuedRead::new(self.read_queue.clone(), self.req_sink.clone(), req);
+            return Some(f.queued(read));
+        }
+
+        if self.req_sink.try_send(req).is_ok() {
+            Some(f)
+        } else {
+            self.read_queue.cancel();
+            None
+        }
+    }
+
+    /// Sends a request to the log thread as `send_checked`, with nothing of
+    /// the request to check.
+    fn send_request<T, F>(&mut self, req: F) -> LogFuture<T>
+    where
+        F: FnOnce(LogSender<T>) -> ClientRequest,
+    {
+        let trace_id = self.trace_id;
+        self.send_checked(Ok(()), || traced_channel(trace_id), req)
+    }
+
+    /// Sends a request to the log thread with the sender of a new channel,
+    /// returning the receiving end. The request fails without reaching the
+    /// log thread with the error of `checked`, such as rejected payloads, if
+    /// the log thread is stalled and failing fast, if an append and the log
+    /// is paused, and with `ErrorKind::BrokenPipe` if the log is read-only or
+    /// the log thread has exited.
+    fn send_checked<S, T, C, F>(&mut self, checked: Result<(), Error>, channel: C, req: F) -> T
+    where
+        S: FailSender,
+        C: Fn() -> (S, T),
+        F: FnOnce(S) -> ClientRequest,
+    {
+        let (snd, res) = channel();
+        let checked = checked.and_then(|()| {
+            if rare!(self.progress.is_stalled()) {
+                Err(stalled_error())
+            } else if rare!(self.read_only.is_active()) {
+                Err(read_only_error())
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(e) = checked {
+            snd.send_err(e);
+            return res;
+        }
+        let req = req(snd);
+        if req.is_append() {
+            if let Err(e) = self.pause.check() {
+                req.fail_append(e);
+                return res;
+            }
+        }
+        if self.req_sink.try_send(req).is_ok() {
+            return res;
+        }
+
+        let (snd, res) = channel();
+        snd.send_err(read_only_error());
+        res
+    }
+
+    /// Offset of the last entry in the log, or `None` if the log is empty,
+    /// distinguishing an empty log from one with a single entry at offset 0.
+    pub fn last_offset(&mut self) -> LogFuture<Option<Offset>> {
+        self.send_read(ClientRequest::LastOffset, |log| log.last_offset())
+    }
+
+    /// Reads from the log starting at the position, inclusive, up to
+    /// `max_bytes` of entries.
+    ///
+    /// With `read_threads` configured, reads of segments closed by a roll are
+    /// served by the read workers rather than the log thread, so they are
+    /// neither delayed by appends nor delay them.
+    pub fn read(&mut self, position: Offset, max_bytes: usize) -> LogFuture<Messages> {
+        if let Some(ref workers) = self.read_workers {
+            if workers.is_sealed(position) {
+                return workers.read(position, max_bytes);
+            }
+        }
+        self.send_read(
+            |snd| ClientRequest::Read(position, max_bytes, snd),
+            |log| log.read(position, max_bytes),
+        )
+    }
+
+    /// Reads from the log as `read`, up to `max_messages` entries as well as
+    /// `max_bytes` of entries, for consumers processing batches of a fixed
+    /// number of entries. The next offset of the result is where to continue.
+    pub fn read_messages(
+        &mut self,
+        position: Offset,
+        max_bytes: usize,
+        max_messages: usize,
+    ) -> impl Future<Item = Messages, Error = Error> {
+        self.read(position, max_bytes)
+            .map(move |msgs| msgs.take(max_messages))
+    }
+
+    /// Reads the entries with offsets in `[start, end)`, up to `max_bytes` of
+    /// entries. The start is inclusive and the end is exclusive, so a range
+    /// with `end <= start` is empty. Without an end, the range continues to
+    /// the end of the log.
+    ///
+    /// The read may return fewer entries than the range when limited by
+    /// `max_bytes`; the next offset of the result is where to continue.
+    ///
+    /// A range starting at the next offset to be appended is empty, while a
+    /// start past it fails with `ErrorKind::InvalidInput`, and a start below
+    /// the low watermark with `ErrorKind::NotFound`.
+    pub fn read_range(
+        &mut self,
+        start: Offset,
+        end: Option<Offset>,
+        max_bytes: usize,
+    ) -> LogFuture<Messages> {
+        self.send_read(
+            |snd| ClientRequest::ReadRange(start, end, max_bytes, snd),
+            |log| match end {
+                Some(end) if end <= start => Ok(Messages::empty()),
+                Some(end) => log.read(start, max_bytes).map(|msgs| msgs.take_until(end)),
+                None => log.read(start, max_bytes),
+            },
+        )
+    }
+
+    /// Reads the metadata of the entries with offsets in `[start, end)`, as
+    /// `read_range`, without the payloads. For scans over the offsets, sizes,
+    /// keys and headers of entries, the payloads are skipped on the log
+    /// thread rather than copied.
+    pub fn read_metadata(
+        &mut self,
+        start: Offset,
+        end: Option<Offset>,
+        max_bytes: usize,
+    ) -> LogFuture<MetadataRead> {
+        self.send_read(
+            |snd| ClientRequest::ReadMetadata(start, end, max_bytes, snd),
+            |log| {
+                let read = match end {
+                    Some(end) if end <= start => return Ok(MetadataRead::default()),
+                    _ => MetadataRead::copy_from(&log.read(start, max_bytes)?),
+                };
+                Ok(match end {
+                    Some(end) => read.take_until(end),
+                    None => read,
+                })
+            },
+        )
+    }
+
+    /// Reads the last `n` entries of the log, in offset order. The entries
+    /// are resolved against the last offset at the time of the read, so
+    /// concurrent appends do not leave gaps in the result.
+    ///
+    /// Fewer than `n` entries are returned if the log is shorter.
+    pub fn tail(&mut self, n: usize) -> LogFuture<Messages> {
+        self.send_read(|snd| ClientRequest::Tail(n, snd), |log| log.tail(n))
+    }
+
+    /// Reads a page of up to `max_bytes` of entries at the cursor, returning
+    /// the cursor of the next page, or `None` once the page reaches the end
+    /// of the log.
+    ///
+    /// Fails with `ErrorKind::NotFound` if the cursor is stale, as the
+    /// entries have been removed by retention.
+    pub fn read_page(
+        &mut self,
+        cursor: Cursor,
+        max_bytes: usize,
+    ) -> LogFuture<(Messages, Option<Cursor>)> {
+        self.send_read(
+            |snd| ClientRequest::ReadPage(cursor, max_bytes, snd),
+            |log| log.read_page(cursor, max_bytes),
+        )
+    }
+
+    /// Reads from the log, waiting up to `max_wait` for entries to be appended
+    /// if there are none at the position.
+    ///
+    /// The read resolves as soon as any entries are available, up to the limit,
+    /// or with an empty result once the wait elapses.
+    pub fn read_wait(
+        &mut self,
+        position: Offset,
+        max_bytes: usize,
+        max_wait: Duration,
+    ) -> ReadWaitFuture {
+        // no appends are made once read-only or stalled, so there is nothing
+        // to wait for
+        if max_wait == Duration::from_millis(0)
+            || self.read_only.is_active()
+            || self.progress.is_stalled()
+        {
+            return ReadWaitFuture {
+                read: self.read(position, max_bytes),
+                delay: None,
+            };
+        }
+
+        let (snd, f) = traced_channel::<Messages>(self.trace_id);
+        match self.enqueue_read(ClientRequest::ReadWait(position, max_bytes, snd), f) {
+            Some(f) => ReadWaitFuture {
+                read: f,
+                delay: Some(Delay::new(Instant::now() + max_wait)),
+            },
+            None => ReadWaitFuture {
+                read: self.read(position, max_bytes),
+                delay: None,
+            },
+        }
+    }
+
+    /// Marks a range of offsets as deleted.
+    ///
+    /// The entries are logically gone as soon as the future resolves: reads
+    /// skip over tombstoned offsets. The bytes are physically removed after
+    /// the next compaction of the segments containing them.
+    pub fn tombstone(&mut self, range: Range<Offset>) -> LogFuture<()> {
+        self.send_request(|snd| ClientRequest::Tombstone(range, snd))
+    }
+
+    /// Records the offset committed by the consumer.
+    pub fn commit_offset(&mut self, consumer: ConsumerId, offset: Offset) -> LogFuture<()> {
+        self.send_request(|snd| ClientRequest::CommitOffset(consumer, offset, snd))
+    }
+
+    /// Offsets committed by each of the consumers.
+    pub fn consumer_offsets(&mut self) -> LogFuture<Vec<(ConsumerId, Offset)>> {
+        self.send_request(ClientRequest::ConsumerOffsets)
+    }
+
+    /// Number of entries appended after the offset committed by the consumer.
+    ///
+    /// Fails with `ErrorKind::NotFound` if the consumer has not committed an offset.
+    pub fn consumer_lag(&mut self, consumer: ConsumerId) -> LogFuture<u64> {
+        self.send_request(|snd| ClientRequest::ConsumerLag(consumer, snd))
+    }
+
+    /// Copies the log to the destination directory, which must not exist.
+    ///
+    /// The snapshot is consistent: it contains every entry up to the last
+    /// offset in the result and no entries after it. Appends are delayed
+    /// until the snapshot completes.
+    pub fn snapshot<P: Into<PathBuf>>(&mut self, dest: P) -> LogFuture<SnapshotInfo> {
+        self.send_request(|snd| ClientRequest::Snapshot(dest.into(), snd))
+    }
+
+    /// Exports the entries with offsets in the range to a new file at the
+    /// path, in the portable format of `ExportReader`, for loading into
+    /// another log. Tombstoned entries are skipped, and a range past the end
+    /// of the log is exported up to the end.
+    ///
+    /// The export runs on its own thread, reading the range in chunks, so
+    /// the log thread serves appends and reads in between.
+    pub fn export(&self, range: Range<Offset>, path: PathBuf) -> LogFuture<ExportSummary> {
+        let (snd, f) = traced_channel(self.trace_id);
+        let mut log = self.clone();
+        // a chunk always holds at least the largest entry
+        let read_bytes = EXPORT_READ_BYTES.max(2 * self.payloads.max_bytes);
+        thread::Builder::new()
+            .name("log-export".to_string())
+            .spawn(
+                move || match export_range(&mut log, range, &path, read_bytes) {
+                    Ok(summary) => snd.send(summary),
+                    Err(e) => snd.send_err(e),
+                },
+            )
+            .expect("Unable to spawn log export thread");
+        f
+    }
+
+    /// Appends the entries of a file written by `export`, verifying the
+    /// checksum of every entry before appending any. The entries are
+    /// appended on a thread of the import in batches, each a single request
+    /// to the log thread, so appends and reads are served in between.
+    ///
+    /// The log must be empty, and stay free of other appends until the
+    /// import completes, unless `force_append`, which appends the entries
+    /// after any already in the log. The summary lists the entries assigned
+    /// an offset other than their offset in the export.
+    pub fn import(&self, path: PathBuf, force_append: bool) -> LogFuture<ImportSummary> {
+        let (snd, f) = traced_channel(self.trace_id);
+        let mut log = self.clone();
+        thread::Builder::new()
+            .name("log-import".to_string())
+            .spawn(
+                move || match import_file(&mut log, &path, force_append, IMPORT_BATCH_BYTES) {
+                    Ok(summary) => snd.send(summary),
+                    Err(e) => snd.send_err(e),
+                },
+            )
+            .expect("Unable to spawn log import thread");
+        f
+    }
+
+    /// Reads the bytes `[range.start, range.end)` of the segment starting at
+    /// the base offset verbatim, without decoding the entries.
+    ///
+    /// The bytes are not aligned to entries: callers are responsible for
+    /// requesting ranges on entry boundaries. Reads of the active segment
+    /// fail if the range includes bytes not yet flushed to disk.
+    pub fn read_raw(&mut self, segment_base: Offset, range: Range<u64>) -> LogFuture<Vec<u8>> {
+        self.send_request(|snd| ClientRequest::ReadRaw(segment_base, range, snd))
+    }
+
+    /// Drops the entries of the inactive segments superseded by a later entry
+    /// with the same key, keeping the offsets of the retained entries. The
+    /// log serves no other request until the compaction completes.
+    pub fn compact(&mut self) -> LogFuture<CompactionStats> {
+        self.send_request(ClientRequest::Compact)
+    }
+
+    /// Summarizes the log and its segments on disk.
+    pub fn stats(&mut self) -> LogFuture<LogStats> {
+        self.send_request(ClientRequest::Stats)
+    }
+
+    /// Size of the log, answered by the log thread without listing the
+    /// segments on disk as `stats` does. Segments created by a roll are
+    /// counted once the appends are flushed.
+    pub fn summary(&mut self) -> LogFuture<LogSummary> {
+        self.send_request(ClientRequest::Summary)
+    }
+
+    /// First offset retained in the log, below which entries have been
+    /// deleted by a trim or the retention policies. Zero until the first
+    /// segment is deleted.
+    pub fn low_watermark(&mut self) -> LogFuture<Offset> {
+        self.send_request(ClientRequest::LowWatermark)
+    }
+
+    /// Segments of the log, ordered by base offset, listed by the log thread
+    /// so the list never shows a segment part way through a roll or
+    /// deletion.
+    pub fn segments(&mut self) -> LogFuture<Vec<LogSegment>> {
+        self.send_request(ClientRequest::Segments)
+    }
+
+    /// Depth of the append and read queues in front of the log thread. The
+    /// counts are read without a request to the log thread, so remain
+    /// available while it is busy.
+    pub fn queue_stats(&self) -> LogQueueStats {
+        LogQueueStats {
+            append_queue_depth: self.append_queue.len(),
+            read_queue_depth: self.read_queue.len(),
+            appends_total: self.append_queue.total(),
+            reads_total: self.read_queue.total(),
+            reads_abandoned: self.read_queue.abandoned(),
+        }
+    }
+
+    /// Flushes the log to disk now, regardless of the flush policy,
+    /// resolving with the offset of the last entry durable after the flush,
+    /// or `None` if the log is empty. Appends acknowledged before the flush
+    /// was requested are durable once it resolves.
+    pub fn flush(&mut self) -> LogFuture<Option<Offset>> {
+        self.send_request(ClientRequest::Flush)
+    }
+
+    /// Offset of the last entry flushed to disk, or `None` if no entries
+    /// have been flushed. Entries up to the offset survive a crash, while
+    /// those after it may be lost until the next flush.
+    ///
+    /// The log is flushed when opened, so after a restart every entry
+    /// recovered from disk is durable.
+    pub fn flushed_offset(&mut self) -> LogFuture<Option<Offset>> {
+        self.send_request(ClientRequest::FlushedOffset)
+    }
+
+    /// Shuts down the log cleanly, resolving once the requests queued before
+    /// the shutdown, including queued appends, are processed and the log is
+    /// flushed.
+    ///
+    /// Other handles to the log remain usable, so callers should stop
+    /// appending before shutting down for the flush to cover every append.
+    pub fn shutdown(mut self) -> LogFuture<()> {
+        let (snd, f) = traced_channel::<()>(self.trace_id);
+        if !self.read_only.is_active()
+            && self.req_sink.try_send(ClientRequest::Shutdown(snd)).is_ok()
+        {
+            return f;
+        }
+
+        let (snd, f) = traced_channel::<()>(self.trace_id);
+        snd.send_err(read_only_error());
+        f
+    }
+
+    /// Deletes the segments with every offset below the offset, reclaiming
+    /// their disk space. Entries in the segment containing the offset are
+    /// kept, and so is the active segment.
+    ///
+    /// Reads of deleted offsets fail with `ErrorKind::NotFound`, carrying an
+    /// `OffsetTrimmed` with the low watermark, the first offset retained.
+    /// The low watermark is derived from the segments, so holds across
+    /// restarts.
+    pub fn trim_before(&mut self, offset: Offset) -> LogFuture<()> {
+        self.send_request(|snd| ClientRequest::Trim(offset, snd))
+    }
+
+    /// First offset appended at or after the time, or the next offset if
+    /// nothing has been appended since. `None` if the time is in the future.
+    ///
+    /// Append times are indexed sparsely, so the offset may be of an entry
+    /// appended up to a second before the time, but never after an entry
+    /// appended at or after the time. Entries appended before the time index
+    /// existed are not found by time.
+    pub fn offset_for_time(&mut self, time: SystemTime) -> LogFuture<Option<Offset>> {
+        self.send_request(|snd| ClientRequest::OffsetForTime(time, snd))
+    }
+
+    /// Removes the entries after the offset from the log. Downstream
+    /// replicas are not truncated. Appends continue from the offset after
+    /// the truncation offset.
+    ///
+    /// Truncating at or past the last offset is a no-op. Fails with
+    /// `ErrorKind::InvalidInput` if the offset is before the first offset
+    /// retained, rather than removing every entry.
+    pub fn truncate(&mut self, offset: Offset) -> LogFuture<()> {
+        self.send_request(|snd| ClientRequest::Truncate(offset, snd))
+    }
+
+    /// Changes the flush and retention settings of the running log. Fails
+    /// with `ErrorKind::InvalidInput` if a value is invalid, leaving the
+    /// settings unchanged.
+    pub fn tune(&mut self, tuning: LogTuning) -> LogFuture<()> {
+        self.send_request(|snd| ClientRequest::Tune(tuning, snd))
+    }
+}
+
+/// Read that resolves with whatever is available once the maximum wait elapses.
+pub struct ReadWaitFuture {
+    read: LogFuture<Messages>,
+    delay: Option<Delay>,
+}
+
+impl Future for ReadWaitFuture {
+    type Item = Messages;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Messages, Error> {
+        if let Async::Ready(msgs) = self.read.poll()? {
+            return Ok(Async::Ready(msgs));
+        }
+
+        match self.delay {
+            Some(ref mut delay) => match delay.poll() {
+                Ok(Async::Ready(())) => {
+                    trace!("Read wait elapsed");
+                    Ok(Async::Ready(Messages::empty()))
+                }
+                Ok(Async::NotReady) => Ok(Async::NotReady),
+                Err(e) => Err(Error::new(ErrorKind::Other, e)),
+            },
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// Append waiting for a slot in the append queue.
+pub struct QueuedAppendFuture {
+    log: AsyncLog,
+    append: Option<(u64, u64, Bytes, Priority)>,
+}
+
+impl Future for QueuedAppendFuture {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        if let Some((_, _, ref payload, _)) = self.append {
+            self.log.payloads.check(Some(payload))?;
+        }
+        if rare!(self.log.read_only.is_active()) {
+            return Err(read_only_error());
+        }
+        if rare!(self.log.progress.is_stalled()) {
+            return Err(stalled_error());
+        }
+        self.log.pause.check()?;
+
+        if let Async::NotReady = self.log.append_queue.poll_push() {
+            return Ok(Async::NotReady);
+        }
+        let (client_id, client_req_id, payload, priority) = self
+            .append
+            .take()
+            .expect("Queued append polled after completion");
+        self.log
+            .send_queued(client_id, client_req_id, payload, priority)
+            .map(Async::Ready)
+    }
+}
+
+// TODO: remove replication-specific logic
+pub struct ReplicatorAsyncLog<R> {
+    req_sink: mpsc::UnboundedSender<LogRequest<R>>,
+}
+
+impl<R> Clone for ReplicatorAsyncLog<R> {
+    fn clone(&self) -> ReplicatorAsyncLog<R> {
+        ReplicatorAsyncLog {
+            req_sink: self.req_sink.clone(),
+        }
+    }
+}
+
+impl<R> ReplicatorAsyncLog<R> {
+    pub fn replicate_from(&mut self, offset: Offset) -> LogFuture<ReplicationSource<R>> {
+        self.send_request(|snd| LogRequest::Replica(ReplicaRequest::Replicate(offset, snd)))
+    }
+
+    pub fn append_from_replication(&mut self, buf: Messages) -> LogFuture<AppendRange> {
+        self.send_request(|snd| {
+            LogRequest::Replica(ReplicaRequest::AppendFromReplication(buf, snd))
+        })
+    }
+
+    pub fn last_offset(&mut self) -> LogFuture<Option<Offset>> {
+        self.send_request(|snd| LogRequest::Client(ClientRequest::LastOffset(snd)))
+    }
+
+    /// Sends a request to the log thread, failing the request with
+    /// `ErrorKind::BrokenPipe` if the log thread has exited.
+    fn send_request<T, F>(&mut self, req: F) -> LogFuture<T>
+    where
+        F: FnOnce(LogSender<T>) -> LogRequest<R>,
+    {
+        let (snd, f) = channel::<T>();
+        if self.req_sink.try_send(req(snd)).is_ok() {
+            return f;
+        }
+
+        let (snd, f) = channel::<T>();
+        snd.send_err(read_only_error());
+        f
+    }
+}
+
+/// Notifies a listener of log append operations.
+pub trait AppendListener {
+    /// Notifies the listener that the log has been mutated with the
+    /// offset range specified.
+    fn notify_append(&mut self, appended: Messages);
+
+    /// Notifies the listener that the append of the client request was a
+    /// retry of an entry already appended, so was not appended again.
+    fn notify_duplicate(&mut self, client_id: u64, client_req_id: u64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{ByteOrder, LittleEndian};
+    use commitlog::message::{set_offsets, HEADER_SIZE};
+    use commitlog::ReadLimit;
+    use config::{FlushMode, TopicConfig};
+    use futures::stream;
+    use replication::FileSliceMessageReader;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+    use std::{env, fs, process};
+    use tokio::runtime::current_thread::block_on_all;
+
+    struct NoopListener;
+
+    impl AppendListener for NoopListener {
+        fn notify_append(&mut self, _appended: Messages) {}
+
+        fn notify_duplicate(&mut self, _client_id: u64, _client_req_id: u64) {}
+    }
+
+    fn open_test_log(name: &str, cfg: &mut LogConfig) -> (AsyncLog, PathBuf) {
+        let dir = env::temp_dir().join(format!("log-{}-test-{}", name, process::id()));
+        cfg.dir = dir.to_string_lossy().into_owned();
+        let (log, _) = open(cfg, NoopListener, FileSliceMessageReader).unwrap();
+        (log, dir)
+    }
+
+    /// Opens a test log on the commit log and one on a `MemStorage`, for
+    /// the behavior shared by the storage of the log.
+    fn open_test_storages(name: &str) -> Vec<(AsyncLog, PathBuf)> {
+        let commit_log = open_test_log(name, &mut LogConfig::default());
+
+        let dir = env::temp_dir().join(format!("log-{}-mem-test-{}", name, process::id()));
+        let mut cfg = LogConfig::default();
+        cfg.dir = dir.to_string_lossy().into_owned();
+        let (log, _) = open_with_storage(
+            &cfg,
+            MemStorage::new(),
+            NoopListener,
+            FileSliceMessageReader,
+        )
+        .unwrap();
+        vec![commit_log, (log, dir)]
+    }
+
+    #[test]
+    fn topics_are_created_on_append() {
+        let mut cfg = LogConfig::default();
+        cfg.max_topics = 1;
+        let (log, dir) = open_test_log("topics", &mut cfg);
+        let topics = Topics::new(&cfg, |topic, cfg| {
+            open_topic(topic, cfg, NoopListener, FileSliceMessageReader).map(|(log, _)| log)
+        });
+        let mut log = log.with_topics(topics.clone());
+
+        log.append_and_fetch(1, vec![Bytes::from("default")])
+            .wait()
+            .unwrap();
+        assert_eq!(
+            ErrorKind::NotFound,
+            log.topic("orders").err().unwrap().kind()
+        );
+
+        let mut orders = log.topic_or_create("orders").unwrap();
+        orders
+            .append_and_fetch(1, vec![Bytes::from("a"), Bytes::from("b")])
+            .wait()
+            .unwrap();
+        assert!(dir.join("topics").join("orders").is_dir());
+        assert_eq!(
+            Some(1),
+            log.topic("orders").unwrap().last_offset().wait().unwrap()
+        );
+        assert_eq!(
+            Some(0),
+            log.topic("").unwrap().last_offset().wait().unwrap()
+        );
+        assert_eq!(
+            Some(0),
+            log.topic(DEFAULT_TOPIC)
+                .unwrap()
+                .last_offset()
+                .wait()
+                .unwrap()
+        );
+
+        assert_eq!(
+            ErrorKind::InvalidInput,
+            log.topic_or_create("../orders").err().unwrap().kind()
+        );
+        assert_eq!(
+            ErrorKind::Other,
+            log.topic_or_create("refunds").err().unwrap().kind()
+        );
+
+        // topics are not replicated, so are rejected in a chain
+        topics.set_chained(true);
+        assert_eq!(
+            ErrorKind::PermissionDenied,
+            log.topic("orders").err().unwrap().kind()
+        );
+        assert_eq!(
+            Some(0),
+            log.topic("").unwrap().last_offset().wait().unwrap()
+        );
+        topics.set_chained(false);
+        assert!(log.topic("orders").is_ok());
+
+        drop(orders);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn topics_flush_with_their_own_mode() {
+        let mut cfg = LogConfig::default();
+        cfg.flush_interval_ms = 3_600_000;
+        cfg.topics.insert(
+            "orders".to_string(),
+            TopicConfig {
+                flush_mode: Some(FlushMode::EveryAppend),
+                flush_interval_ms: None,
+            },
+        );
+        cfg.topics.insert(
+            "metrics".to_string(),
+            TopicConfig {
+                flush_mode: Some(FlushMode::Never),
+                flush_interval_ms: None,
+            },
+        );
+        let (log, dir) = open_test_log("topics-flush", &mut cfg);
+        let topics = Topics::new(&cfg, |topic, cfg| {
+            open_topic(topic, cfg, NoopListener, FileSliceMessageReader).map(|(log, _)| log)
+        });
+        let log = log.with_topics(topics);
+
+        let mut orders = log.topic_or_create("orders").unwrap();
+        let mut metrics = log.topic_or_create("metrics").unwrap();
+        for _ in 0..3 {
+            orders
+                .append_and_fetch(1, vec![Bytes::from("order")])
+                .wait()
+                .unwrap();
+            metrics
+                .append_and_fetch(1, vec![Bytes::from("metric")])
+                .wait()
+                .unwrap();
+        }
+        thread::sleep(Duration::from_millis(50));
+
+        assert_eq!(0, orders.stats().wait().unwrap().unflushed_bytes);
+        assert_eq!(Some(2), orders.flushed_offset().wait().unwrap());
+        assert!(metrics.stats().wait().unwrap().unflushed_bytes > 0);
+        assert_eq!(None, metrics.flushed_offset().wait().unwrap());
+
+        // flushing one topic leaves the other to its own mode
+        metrics.flush().wait().unwrap();
+        assert_eq!(Some(2), metrics.flushed_offset().wait().unwrap());
+        orders
+            .append_and_fetch(1, vec![Bytes::from("order")])
+            .wait()
+            .unwrap();
+        metrics
+            .append_and_fetch(1, vec![Bytes::from("metric")])
+            .wait()
+            .unwrap();
+        assert_eq!(Some(3), orders.flushed_offset().wait().unwrap());
+        assert_eq!(Some(2), metrics.flushed_offset().wait().unwrap());
+
+        drop(orders);
+        drop(metrics);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn paused_topic_rejects_appends() {
+        let mut cfg = LogConfig::default();
+        let (log, dir) = open_test_log("topics-pause", &mut cfg);
+        let topics = Topics::new(&cfg, |topic, cfg| {
+            open_topic(topic, cfg, NoopListener, FileSliceMessageReader).map(|(log, _)| log)
+        });
+        let log = log.with_topics(topics);
+
+        let mut orders = log.topic_or_create("orders").unwrap();
+        let mut metrics = log.topic_or_create("metrics").unwrap();
+        orders
+            .append_and_fetch(1, vec![Bytes::from("a")])
+            .wait()
+            .unwrap();
+
+        log.topic("orders").unwrap().pause();
+        assert!(orders.is_paused());
+        assert!(!metrics.is_paused());
+
+        let err = orders
+            .append_and_fetch(1, vec![Bytes::from("b")])
+            .wait()
+            .unwrap_err();
+        assert_eq!(ErrorKind::PermissionDenied, err.kind());
+        assert_eq!(
+            Some(TopicPaused {
+                topic: "orders".to_string()
+            }),
+            TopicPaused::from_error(&err)
+        );
+        let err = orders
+            .append(1, 1, Bytes::from("b"), Priority::High, None)
+            .unwrap_err();
+        assert!(TopicPaused::from_error(&err).is_some());
+        let err = orders
+            .append_now(1, Bytes::from("b"), false)
+            .wait()
+            .unwrap_err();
+        assert!(TopicPaused::from_error(&err).is_some());
+
+        // the other topic appends, and the paused topic is still read
+        let (range, _) = metrics
+            .append_and_fetch(1, vec![Bytes::from("m")])
+            .wait()
+            .unwrap();
+        assert_eq!(0..1, range);
+        assert_eq!(Some(0), orders.last_offset().wait().unwrap());
+        assert_eq!(1, orders.read(0, 4096).wait().unwrap().len());
+
+        orders.resume();
+        let (range, _) = orders
+            .append_and_fetch(1, vec![Bytes::from("b")])
+            .wait()
+            .unwrap();
+        assert_eq!(1..2, range);
+
+        drop(orders);
+        drop(metrics);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn appends_queued_before_a_pause_are_appended() {
+        let mut cfg = LogConfig::default();
+        let dir = env::temp_dir().join(format!("log-queued-pause-test-{}", process::id()));
+        cfg.dir = dir.to_string_lossy().into_owned();
+        let replies = Arc::new(Mutex::new(Vec::new()));
+        let listener = ReplyListener(replies.clone());
+        let (mut log, _) = open(&cfg, listener, FileSliceMessageReader).unwrap();
+        let seq = ProducerSequence {
+            producer_id: 7,
+            sequence: 0,
+        };
+
+        // sent to the log thread, which may not have handled them yet
+        log.append(1, 10, Bytes::from("foo"), Priority::High, Some(seq))
+            .unwrap();
+        let now = log.append_now(1, Bytes::from("bar"), false);
+        log.pause();
+        let err = log
+            .append(1, 11, Bytes::from("baz"), Priority::High, None)
+            .unwrap_err();
+        assert!(TopicPaused::from_error(&err).is_some());
+
+        // replied to through the listener and the future alike
+        assert_eq!(1, now.wait().unwrap());
+        assert_eq!(vec![10], *replies.lock().unwrap());
+        assert_eq!(Some(1), log.last_offset().wait().unwrap());
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn open_creates_directory() {
+        let base = env::temp_dir().join(format!("log-open-nested-test-{}", process::id()));
+        let mut cfg = LogConfig::default();
+        cfg.dir = base.join("node-1").to_string_lossy().into_owned();
+        let (mut log, _) = open(&cfg, NoopListener, FileSliceMessageReader).unwrap();
+        log.append_and_fetch(1, vec![Bytes::from("foo")])
+            .wait()
+            .unwrap();
+        assert!(base.join("node-1").is_dir());
+        drop(log);
+        fs::remove_dir_all(&base).unwrap();
+
+        // a file in place of the directory
+        let file = env::temp_dir().join(format!("log-open-file-test-{}", process::id()));
+        fs::write(&file, b"not a directory").unwrap();
+        cfg.dir = file.to_string_lossy().into_owned();
+        let err = open(&cfg, NoopListener, FileSliceMessageReader)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains(&cfg.dir));
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn append_and_fetch_matches_read() {
+        for (mut log, dir) in open_test_storages("append-fetch") {
+            log.append_and_fetch(1, vec![Bytes::from("first")])
+                .wait()
+                .unwrap();
+
+            let payloads = vec![Bytes::from("foo"), Bytes::from("bar"), Bytes::from("baz")];
+            let (range, fetched) = log.append_and_fetch(7, payloads).wait().unwrap();
+            assert_eq!(1..4, range);
+            assert_eq!(Some(4), fetched.next_offset());
+            assert_eq!(
+                vec![b"foo".to_vec(), b"bar".to_vec(), b"baz".to_vec()],
+                fetched
+                    .iter()
+                    .map(|m| m.payload().to_vec())
+                    .collect::<Vec<_>>()
+            );
+
+            let read = log.read(range.start, 4096).wait().unwrap();
+            assert_eq!(read.bytes(), fetched.bytes());
+
+            let (range, fetched) = log.append_and_fetch(7, vec![]).wait().unwrap();
+            assert_eq!(4..4, range);
+            assert_eq!(0, fetched.len());
+
+            drop(log);
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn append_wait_parks_when_queue_full() {
+        let mut cfg = LogConfig::default();
+        cfg.append_queue_max = Some(8);
+        let (mut log, dir) = open_test_log("append-wait", &mut cfg);
+
+        for i in 0..200 {
+            let payload = Bytes::from(format!("entry-{}", i));
+            log.append_wait(1, i, payload, Priority::Bulk)
+                .wait()
+                .unwrap();
+            assert!(log.append_queue.len() <= 8);
+        }
+        // drains the queued appends
+        log.clone().shutdown().wait().unwrap();
+
+        let msgs = log.read(0, 1_048_576).wait().unwrap();
+        assert_eq!(Some(200), msgs.next_offset());
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn shutdown_flushes_queued_appends() {
+        let mut cfg = LogConfig::default();
+        cfg.flush_interval_ms = 3_600_000;
+        let (mut log, dir) = open_test_log("shutdown", &mut cfg);
+
+        for i in 0..100 {
+            log.append(
+                1,
+                i,
+                Bytes::from(format!("entry-{}", i)),
+                Priority::Bulk,
+                None,
+            )
+            .unwrap();
+        }
+        log.shutdown().wait().unwrap();
+
+        // reopened as after a crash, from the flushed files
+        let (mut log, _) = open(&cfg, NoopListener, FileSliceMessageReader).unwrap();
+        let msgs = log.read(0, 1_048_576).wait().unwrap();
+        assert_eq!(Some(100), msgs.next_offset());
+        assert_eq!(b"entry-99", msgs.iter().last().unwrap().payload());
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn topic_logs_are_flushed_on_shutdown() {
+        let mut cfg = LogConfig::default();
+        cfg.topics.insert(
+            "metrics".to_string(),
+            TopicConfig {
+                flush_mode: Some(FlushMode::Never),
+                flush_interval_ms: None,
+            },
+        );
+        let (log, dir) = open_test_log("topics-shutdown", &mut cfg);
+        let topics = Topics::new(&cfg, |topic, cfg| {
+            open_topic(topic, cfg, NoopListener, FileSliceMessageReader).map(|(log, _)| log)
+        });
+        let log = log.with_topics(topics.clone());
+
+        let mut metrics = log.topic_or_create("metrics").unwrap();
+        metrics
+            .append_and_fetch(1, vec![Bytes::from("metric")])
+            .wait()
+            .unwrap();
+        assert_eq!(None, metrics.flushed_offset().wait().unwrap());
+        topics.shutdown_all().wait().unwrap();
+
+        // reopened as after a crash, from the flushed files
+        let mut topic_cfg = cfg.clone();
+        topic_cfg.dir = dir
+            .join("topics")
+            .join("metrics")
+            .to_string_lossy()
+            .into_owned();
+        let (mut reopened, _) =
+            open_topic("metrics", &topic_cfg, NoopListener, FileSliceMessageReader).unwrap();
+        assert_eq!(Some(0), reopened.last_offset().wait().unwrap());
+
+        drop(reopened);
+        drop(metrics);
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn trim_before_deletes_segments() {
+        let mut cfg = LogConfig::default();
+        cfg.segment_max_bytes = 1024;
+        let (mut log, dir) = open_test_log("trim", &mut cfg);
+        let payloads = (0..50).map(|_| Bytes::from(vec![0u8; 100])).collect();
+        log.append_and_fetch(1, payloads).wait().unwrap();
+        let segments = || retention::segments(&dir).unwrap().len();
+        let before = segments();
+        assert!(before > 3);
+
+        log.trim_before(25).wait().unwrap();
+        assert!(segments() < before);
+        let low = retention::low_watermark(&dir).unwrap();
+        assert!(low > 0 && low <= 25);
+
+        assert_eq!(low, log.low_watermark().wait().unwrap());
+
+        let err = log.read(0, 4096).wait().unwrap_err();
+        assert_eq!(ErrorKind::NotFound, err.kind());
+        assert!(err
+            .to_string()
+            .contains(&format!("low watermark is {}", low)));
+        let trimmed = OffsetTrimmed::from_error(&err).unwrap();
+        assert_eq!(0, trimmed.offset);
+        assert_eq!(low, trimmed.low_watermark);
+
+        // errors of traced requests name the trace
+        let err = log.clone().with_trace(7).read(0, 4096).wait().unwrap_err();
+        assert_eq!(Some(7), TracedError::trace_id(&err));
+        assert_eq!(Some(trimmed), OffsetTrimmed::from_error(&err));
+        assert_eq!(
+            low,
+            log.read(low, 4096)
+                .wait()
+                .unwrap()
+                .iter()
+                .next()
+                .unwrap()
+                .offset()
+        );
+
+        // the low watermark holds across restarts
+        log.clone().shutdown().wait().unwrap();
+        drop(log);
+        let (mut log, _) = open(&cfg, NoopListener, FileSliceMessageReader).unwrap();
+        assert_eq!(low, log.low_watermark().wait().unwrap());
+        let err = log.read_range(0, None, 4096).wait().unwrap_err();
+        assert_eq!(ErrorKind::NotFound, err.kind());
+        assert_eq!(
+            Some(low),
+            OffsetTrimmed::from_error(&err).map(|t| t.low_watermark)
+        );
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reads_across_segment_boundaries() {
+        let mut cfg = LogConfig::default();
+        cfg.segment_max_bytes = 256;
+        cfg.index_max_items = 16;
+        let (mut log, dir) = open_test_log("tiny-segments", &mut cfg);
+        let payloads: Vec<_> = (0..30)
+            .map(|i| Bytes::from(format!("entry-{:02}", i)))
+            .collect();
+        for chunk in payloads.chunks(4) {
+            log.append_batch(1, chunk.to_vec()).wait().unwrap();
+        }
+        assert!(retention::segments(&dir).unwrap().len() > 3);
+
+        let mut read = Vec::new();
+        loop {
+            let msgs = log
+                .read_range(read.len() as u64, None, 4096)
+                .wait()
+                .unwrap();
+            if msgs.len() == 0 {
+                break;
+            }
+            read.extend(msgs.iter().map(|m| Bytes::from(m.payload())));
+        }
+        assert_eq!(payloads, read);
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_segment_options() {
+        let mut cfg = LogConfig::default();
+        cfg.segment_max_bytes = 0;
+        let res = open(&cfg, NoopListener, FileSliceMessageReader);
+        let err = res.err().unwrap();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+        assert!(err.to_string().contains("segment_max_bytes"));
+    }
+
+    #[test]
+    fn read_workers_serve_sealed_segments() {
+        let mut cfg = LogConfig::default();
+        cfg.segment_max_bytes = 1024;
+        cfg.read_threads = 2;
+        let (mut log, dir) = open_test_log("read-workers", &mut cfg);
+        let payloads: Vec<_> = (0..50)
+            .map(|i| Bytes::from(format!("{:0100}", i)))
+            .collect();
+        log.append_batch(1, payloads.clone()).wait().unwrap();
+        log.flush().wait().unwrap();
+        let active_base = retention::segments(&dir)
+            .unwrap()
+            .last()
+            .unwrap()
+            .base_offset;
+        assert!(active_base > 10);
+
+        let reads_on_log_thread = log.queue_stats().reads_total;
+        let mut read = Vec::new();
+        while (read.len() as u64) < active_base {
+            let msgs = log.read(read.len() as u64, 4096).wait().unwrap();
+            read.extend(msgs.iter().map(|m| Bytes::from(m.payload())));
+        }
+        assert_eq!(&payloads[..read.len()], &read[..]);
+        assert_eq!(reads_on_log_thread, log.queue_stats().reads_total);
+
+        // tombstones are hidden as by the log thread
+        log.tombstone(1..3).wait().unwrap();
+        let msgs = log.read(0, 4096).wait().unwrap();
+        let offsets: Vec<_> = msgs.iter().map(|m| m.offset()).take(2).collect();
+        assert_eq!(vec![0, 3], offsets);
+
+        // deleted offsets are left to the log thread, which fails the read
+        log.trim_before(active_base).wait().unwrap();
+        let err = log.read(0, 4096).wait().unwrap_err();
+        assert_eq!(ErrorKind::NotFound, err.kind());
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compact_keeps_latest_entry_per_key() {
+        let mut cfg = LogConfig::default();
+        cfg.segment_max_bytes = 1024;
+        let (mut log, dir) = open_test_log("compact-keys", &mut cfg);
+        log.append_batch(1, vec![Bytes::from("plain")])
+            .wait()
+            .unwrap();
+        for i in 0..40 {
+            let key = Bytes::from(format!("key-{}", i % 4));
+            log.append_with_key(1, key, Bytes::from(vec![0u8; 100]))
+                .wait()
+                .unwrap();
+        }
+        let active_base = retention::segments(&dir)
+            .unwrap()
+            .last()
+            .unwrap()
+            .base_offset;
+        assert!(active_base > 10);
+
+        let stats = log.compact().wait().unwrap();
+        assert!(stats.segments_scanned > 1);
+        assert!(stats.messages_dropped > 0);
+        assert!(stats.bytes_reclaimed > 0);
+
+        let mut entries = Vec::new();
+        let mut next = 0;
+        loop {
+            let msgs = log.read_range(next, None, 4096).wait().unwrap();
+            if msgs.len() == 0 {
+                break;
+            }
+            for e in msgs.keyed() {
+                entries.push((e.offset(), e.key().map(|k| k.to_vec())));
+                next = e.offset() + 1;
+            }
+        }
+        assert_eq!((0, None), entries[0]);
+        assert_eq!(41 - stats.messages_dropped as usize, entries.len());
+        // entries of the inactive segments are the latest of their key
+        for (i, &(offset, ref key)) in entries.iter().enumerate().skip(1) {
+            if offset < active_base {
+                assert!(entries[i + 1..].iter().all(|e| e.1 != *key));
+            }
+        }
+
+        assert_eq!(Some(40), log.last_offset().wait().unwrap());
+        let offset = log
+            .append_with_key(1, Bytes::from("key-0"), Bytes::from("v"))
+            .wait()
+            .unwrap();
+        assert_eq!(41, offset);
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_range_continues_from_next_offset() {
+        let mut cfg = LogConfig::default();
+        let (mut log, dir) = open_test_log("read-range", &mut cfg);
+        let payloads = (0..20).map(|_| Bytes::from(vec![0u8; 100])).collect();
+        log.append_and_fetch(1, payloads).wait().unwrap();
+
+        // a byte budget smaller than the range truncates at an entry
+        let mut offsets = vec![];
+        let mut start = 5;
+        while start < 15 {
+            let msgs = log.read_range(start, Some(15), 512).wait().unwrap();
+            assert!(msgs.iter().count() < 10);
+            offsets.extend(msgs.iter().map(|m| m.offset()));
+            start = msgs.next_offset().unwrap();
+        }
+        assert_eq!((5..15).collect::<Vec<_>>(), offsets);
+
+        let msgs = log.read_range(20, Some(30), 4096).wait().unwrap();
+        assert_eq!(0, msgs.iter().count());
+        let err = log.read_range(21, Some(30), 4096).wait().unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn retention_deletes_segments_of_idle_log() {
+        let mut cfg = LogConfig::default();
+        cfg.segment_max_bytes = 1024;
+        cfg.retention.max_age_secs = Some(1);
+        cfg.retention.check_interval_secs = 0;
+        let (mut log, dir) = open_test_log("retention-idle", &mut cfg);
+        let payloads = (0..50).map(|_| Bytes::from(vec![0u8; 100])).collect();
+        let (range, _) = log.append_and_fetch(1, payloads).wait().unwrap();
+        assert!(retention::segments(&dir).unwrap().len() > 3);
+
+        // no requests are sent while the segments age
+        thread::sleep(Duration::from_millis(3_000));
+        assert_eq!(1, retention::segments(&dir).unwrap().len());
+
+        let err = log.read(0, 4096).wait().unwrap_err();
+        assert_eq!(ErrorKind::NotFound, err.kind());
+        let last = range.end - 1;
+        let msgs = log.read(last, 4096).wait().unwrap();
+        assert_eq!(last, msgs.iter().next().unwrap().offset());
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn subscribe_replays_then_follows_appends() {
+        let (mut log, dir) = open_test_log("subscribe", &mut LogConfig::default());
+        log.append_and_fetch(1, vec![Bytes::from("a"), Bytes::from("b")])
+            .wait()
+            .unwrap();
+
+        let payloads = |msgs: Messages| {
+            let payloads: Vec<_> = msgs.iter().map(|m| m.payload().to_vec()).collect();
+            stream::iter_ok(payloads)
+        };
+        let mut entries = log.subscribe(1).map(payloads).flatten().wait();
+        assert_eq!(b"b".to_vec(), entries.next().unwrap().unwrap());
+
+        let payloads = (0..100).map(|i| Bytes::from(format!("{}", i))).collect();
+        log.append_and_fetch(1, payloads).wait().unwrap();
+        for i in 0..100 {
+            assert_eq!(
+                format!("{}", i).into_bytes(),
+                entries.next().unwrap().unwrap()
+            );
+        }
+
+        drop(log);
+        assert!(entries.next().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn last_offset_of_empty_log() {
+        for (mut log, dir) in open_test_storages("last-offset") {
+            assert_eq!(None, log.last_offset().wait().unwrap());
+
+            log.append_batch(1, vec![Bytes::from("first")])
+                .wait()
+                .unwrap();
+            assert_eq!(Some(0), log.last_offset().wait().unwrap());
+
+            // the first entry is kept by truncation
+            log.append_batch(1, vec![Bytes::from("second")])
+                .wait()
+                .unwrap();
+            log.truncate(0).wait().unwrap();
+            assert_eq!(Some(0), log.last_offset().wait().unwrap());
+
+            drop(log);
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn summary_tracks_segments_without_listing() {
+        let mut cfg = LogConfig::default();
+        cfg.segment_max_bytes = 1024;
+        let (mut log, dir) = open_test_log("summary", &mut cfg);
+        let summary = log.summary().wait().unwrap();
+        assert_eq!(None, summary.first_offset);
+        assert_eq!(None, summary.last_offset);
+        assert_eq!(1, summary.segments);
+
+        for _ in 0..10 {
+            let payloads = vec![Bytes::from(vec![0; 200]), Bytes::from(vec![1; 200])];
+            log.append_batch(1, payloads).wait().unwrap();
+        }
+        // appends are counted before the segments are listed
+        let summary = log.summary().wait().unwrap();
+        assert_eq!(Some(19), summary.last_offset);
+        assert!(summary.bytes > 4000);
+
+        log.flush().wait().unwrap();
+        let summary = log.summary().wait().unwrap();
+        let stats = log.stats().wait().unwrap();
+        assert_eq!(stats.first_offset, summary.first_offset);
+        assert_eq!(stats.segments.len(), summary.segments);
+        assert!(summary.segments > 1);
+        assert_eq!(
+            stats.segments.iter().map(|s| s.bytes).sum::<u64>(),
+            summary.bytes
+        );
+        assert!(summary.last_flush <= SystemTime::now());
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn segments_cover_the_log() {
+        let mut cfg = LogConfig::default();
+        cfg.segment_max_bytes = 1024;
+        let (mut log, dir) = open_test_log("segments", &mut cfg);
+        assert_eq!(None, log.segments().wait().unwrap()[0].last_offset);
+
+        for _ in 0..10 {
+            let payloads = vec![Bytes::from(vec![0; 200]), Bytes::from(vec![1; 200])];
+            log.append_batch(1, payloads).wait().unwrap();
+        }
+        let segments = log.segments().wait().unwrap();
+        assert!(segments.len() > 1);
+        assert_eq!(0, segments[0].base_offset);
+        for pair in segments.windows(2) {
+            assert_eq!(Some(pair[1].base_offset - 1), pair[0].last_offset);
+        }
+        assert_eq!(Some(19), segments.last().unwrap().last_offset);
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn exports_a_range_to_a_file() {
+        let (mut log, dir) = open_test_log("export", &mut LogConfig::default());
+        let payloads: Vec<_> = (0..20)
+            .map(|i| Bytes::from(format!("entry-{}", i)))
+            .collect();
+        log.append_batch(1, payloads).wait().unwrap();
+
+        let path = dir.with_extension("export");
+        let summary = log.export(5..15, path.clone()).wait().unwrap();
+        assert_eq!(10, summary.messages);
+        assert_eq!(fs::metadata(&path).unwrap().len(), summary.bytes);
+
+        let reader = ExportReader::new(fs::File::open(&path).unwrap()).unwrap();
+        let entries = reader.collect::<Result<Vec<_>, _>>().unwrap();
+        let offsets: Vec<_> = entries.iter().map(|e| e.offset).collect();
+        assert_eq!((5..15).collect::<Vec<_>>(), offsets);
+        assert_eq!(&b"entry-14"[..], &entries[9].payload[..]);
+
+        // the file is never overwritten
+        let err = log.export(0..5, path.clone()).wait().unwrap_err();
+        assert_eq!(ErrorKind::AlreadyExists, err.kind());
+
+        drop(log);
+        fs::remove_file(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn imports_an_export_into_an_empty_log() {
+        let (mut log, dir) = open_test_log("import-src", &mut LogConfig::default());
+        for batch in 0..10 {
+            let payloads: Vec<_> = (0..1000)
+                .map(|i| Bytes::from(format!("entry-{}", batch * 1000 + i)))
+                .collect();
+            log.append_batch(1, payloads).wait().unwrap();
+        }
+        let path = dir.with_extension("export");
+        let exported = log.export(0..10_000, path.clone()).wait().unwrap();
+        assert_eq!(10_000, exported.messages);
+
+        let (mut copy, copy_dir) = open_test_log("import-dst", &mut LogConfig::default());
+        let summary = copy.import(path.clone(), false).wait().unwrap();
+        assert_eq!(10_000, summary.messages);
+        assert!(summary.remapped.is_empty());
+
+        let mut pos = 0;
+        while pos < 10_000 {
+            let expected = log.read_range(pos, None, 64 * 1024).wait().unwrap();
+            let actual = copy.read_range(pos, None, 64 * 1024).wait().unwrap();
+            assert!(expected.len() > 0);
+            for (e, a) in expected.iter().zip(actual.iter()) {
+                assert_eq!(e.offset(), a.offset());
+                assert_eq!(e.payload(), a.payload());
+            }
+            pos = expected.next_offset().unwrap();
+        }
+
+        // the log is no longer empty
+        let err = copy.import(path.clone(), false).wait().unwrap_err();
+        assert_eq!(ErrorKind::AlreadyExists, err.kind());
+
+        let summary = copy.import(path.clone(), true).wait().unwrap();
+        assert_eq!(10_000, summary.messages);
+        assert_eq!((0, 10_000), summary.remapped[0]);
+        assert_eq!((9_999, 19_999), summary.remapped[9_999]);
+        assert_eq!(Some(19_999), copy.last_offset().wait().unwrap());
+
+        drop(log);
+        drop(copy);
+        fs::remove_file(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&copy_dir).unwrap();
+    }
+
+    #[test]
+    fn queue_stats_count_drained_requests() {
+        let (mut log, dir) = open_test_log("queue-stats", &mut LogConfig::default());
+        for i in 0..10 {
+            log.append(1, i, Bytes::from("foo"), Priority::High, None)
+                .unwrap();
+        }
+        for _ in 0..1000 {
+            if log.queue_stats().appends_total == 10 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        log.read(0, 4096).wait().unwrap();
+        log.last_offset().wait().unwrap();
+
+        let stats = log.queue_stats();
+        assert_eq!(0, stats.append_queue_depth);
+        assert_eq!(0, stats.read_queue_depth);
+        assert_eq!(10, stats.appends_total);
+        assert_eq!(2, stats.reads_total);
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn skips_reads_abandoned_before_the_log_thread() {
+        let (mut log, dir) = open_test_log("abandoned-reads", &mut LogConfig::default());
+        log.append_batch(1, vec![Bytes::from("foo")])
+            .wait()
+            .unwrap();
+
+        // the future is dropped before the log thread receives the read
+        let (snd, f) = channel();
+        drop(f);
+        log.read_queue.push();
+        log.req_sink
+            .try_send(ClientRequest::Read(0, 4096, snd))
+            .unwrap();
+        log.last_offset().wait().unwrap();
+
+        let stats = log.queue_stats();
+        assert_eq!(2, stats.reads_total);
+        assert_eq!(1, stats.reads_abandoned);
+
+        // appends are carried out without a requester
+        drop(log.append_now(1, Bytes::from("bar"), false));
+        assert_eq!(Some(1), log.last_offset().wait().unwrap());
+        assert_eq!(1, log.queue_stats().reads_abandoned);
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reads_fail_busy_once_the_read_queue_stays_full() {
+        let mut cfg = LogConfig::default();
+        cfg.read_queue_max = Some(1);
+        cfg.read_queue_timeout_ms = 50;
+        let (mut log, dir) = open_test_log("busy-reads", &mut cfg);
+        log.append_batch(1, vec![Bytes::from("foo")])
+            .wait()
+            .unwrap();
+
+        // a read that is never received holds the only slot
+        assert!(log.read_queue.try_push());
+        let read = log.read(0, 4096);
+        let start = Instant::now();
+        let err = block_on_all(read).unwrap_err();
+        assert_eq!(ErrorKind::WouldBlock, err.kind());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // a read waiting for the slot is sent once the slot is released
+        let read = log.read(0, 4096);
+        let released = log.read_queue.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            released.cancel();
+        });
+        let msgs = block_on_all(read).unwrap();
+        assert_eq!(1, msgs.len());
+        assert_eq!(0, log.queue_stats().read_queue_depth);
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fails_fast_after_log_thread_failure() {
+        let (mut log, dir) = open_test_log("thread-failure", &mut LogConfig::default());
+        log.append_batch(1, vec![Bytes::from("foo")])
+            .wait()
+            .unwrap();
+        assert!(log.failure().is_none());
+
+        log.read_only.fail("append error: disk failure".to_string());
+        let err = log
+            .append(1, 1, Bytes::from("bar"), Priority::High, None)
+            .unwrap_err();
+        assert_eq!(ErrorKind::BrokenPipe, err.kind());
+        let err = log
+            .append_batch(1, vec![Bytes::from("bar")])
+            .wait()
+            .unwrap_err();
+        assert_eq!(ErrorKind::BrokenPipe, err.kind());
+        assert!(log.failure().unwrap().to_string().contains("disk failure"));
+
+        // reads are still served
+        assert_eq!(1, log.read(0, 4096).wait().unwrap().len());
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn requests_fail_after_log_thread_exits() {
+        let (mut log, dir) = open_test_log("thread-exit", &mut LogConfig::default());
+        log.append_batch(1, vec![Bytes::from("foo")])
+            .wait()
+            .unwrap();
+
+        // a handle to the log without a log thread receiving its requests
+        let mut cfg = LogConfig::default();
+        cfg.dir = dir.to_string_lossy().into_owned();
+        let (req_sink, _) = mpsc::unbounded_channel();
+        let (high_sink, _) = mpsc::unbounded_channel();
+        let (bulk_sink, _) = mpsc::unbounded_channel();
+        let mut log = AsyncLog {
+            req_sink,
+            high_sink,
+            bulk_sink,
+            append_queue: AppendQueue::new(None),
+            read_queue: ReadQueue::default(),
+            read_only: Arc::new(ReadOnlyLog::new(&cfg)),
+            progress: Arc::new(Progress::new(false)),
+            pause: Arc::new(TopicPause::new(DEFAULT_TOPIC)),
+            read_workers: None,
+            payloads: PayloadPolicy::from_config(&cfg),
+            trace_id: 0,
+            topics: None,
+        };
+
+        let err = log
+            .append(1, 1, Bytes::from("bar"), Priority::High, None)
+            .unwrap_err();
+        assert_eq!(ErrorKind::BrokenPipe, err.kind());
+        assert_eq!(
+            ErrorKind::BrokenPipe,
+            log.flush().wait().unwrap_err().kind()
+        );
+        assert_eq!(
+            ErrorKind::BrokenPipe,
+            log.stats().wait().unwrap_err().kind()
+        );
+        assert_eq!(
+            ErrorKind::BrokenPipe,
+            log.truncate(0).wait().unwrap_err().kind()
+        );
+
+        // reads are served without the log thread
+        assert_eq!(Some(0), log.last_offset().wait().unwrap());
+        assert_eq!(1, log.read(0, 4096).wait().unwrap().len());
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn append_with_key_round_trip() {
+        let (mut log, dir) = open_test_log("append-key", &mut LogConfig::default());
+        log.append_batch(1, vec![Bytes::from("plain")])
+            .wait()
+            .unwrap();
+        let offset = log
+            .append_with_key(1, Bytes::from("user-1"), Bytes::from("keyed"))
+            .wait()
+            .unwrap();
+        assert_eq!(1, offset);
+
+        let msgs = log.read(0, 4096).wait().unwrap();
+        let entries: Vec<_> = msgs
+            .keyed()
+            .map(|e| {
+                (
+                    e.offset(),
+                    e.key().map(|k| k.to_vec()),
+                    e.payload().to_vec(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (0, None, b"plain".to_vec()),
+                (1, Some(b"user-1".to_vec()), b"keyed".to_vec()),
+            ],
+            entries
+        );
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_detects_corrupt_entry() {
+        let (mut log, dir) = open_test_log("corrupt-read", &mut LogConfig::default());
+        let payloads = (0..10)
+            .map(|i| Bytes::from(format!("entry-{}", i)))
+            .collect();
+        log.append_batch(1, payloads).wait().unwrap();
+        log.flush().wait().unwrap();
+
+        // flip the last byte of the payload of the last entry
+        let segment = dir.join(format!("{:020}.log", 0));
+        let mut bytes = fs::read(&segment).unwrap();
+        *bytes.last_mut().unwrap() ^= 0xff;
+        fs::write(&segment, &bytes).unwrap();
+
+        let err = log.read(0, 4096).wait().unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+        assert!(err.to_string().contains("offset 9"));
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn offset_for_time_after_restart() {
+        let mut cfg = LogConfig::default();
+        let (mut log, dir) = open_test_log("offset-for-time", &mut cfg);
+        let start = SystemTime::now();
+        log.append_batch(1, vec![Bytes::from("a"), Bytes::from("b")])
+            .wait()
+            .unwrap();
+        thread::sleep(Duration::from_millis(TIME_INDEX_INTERVAL_MS + 100));
+        let between = SystemTime::now();
+        log.append_batch(1, vec![Bytes::from("c")]).wait().unwrap();
+        thread::sleep(Duration::from_millis(10));
+        let after = SystemTime::now();
+
+        let future = after + Duration::from_secs(3600);
+        assert_eq!(None, log.offset_for_time(future).wait().unwrap());
+        assert_eq!(Some(0), log.offset_for_time(start).wait().unwrap());
+        // within the interval of the first append
+        assert_eq!(Some(0), log.offset_for_time(between).wait().unwrap());
+        // nothing appended since
+        assert_eq!(Some(3), log.offset_for_time(after).wait().unwrap());
+
+        log.clone().shutdown().wait().unwrap();
+        drop(log);
+        let (mut log, _) = open(&cfg, NoopListener, FileSliceMessageReader).unwrap();
+        assert_eq!(Some(0), log.offset_for_time(start).wait().unwrap());
+        // entries may have been appended within the interval of the last
+        // index entry before the restart
+        assert_eq!(Some(2), log.offset_for_time(after).wait().unwrap());
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncate_then_append() {
+        for (mut log, dir) in open_test_storages("truncate") {
+            let payloads = (0..10)
+                .map(|i| Bytes::from(format!("entry-{}", i)))
+                .collect();
+            log.append_batch(1, payloads).wait().unwrap();
+
+            // past the end
+            log.truncate(20).wait().unwrap();
+            assert_eq!(Some(9), log.last_offset().wait().unwrap());
+
+            log.truncate(4).wait().unwrap();
+            assert_eq!(Some(4), log.last_offset().wait().unwrap());
+            let offsets = log
+                .append_batch(1, vec![Bytes::from("next")])
+                .wait()
+                .unwrap();
+            assert_eq!(vec![5], offsets);
+
+            drop(log);
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn append_batch_as_unit() {
+        let (mut log, dir) = open_test_log("append-batch", &mut LogConfig::default());
+
+        assert!(log.append_batch(1, vec![]).wait().unwrap().is_empty());
+
+        let payloads = (0..100)
+            .map(|i| Bytes::from(format!("entry-{}", i)))
+            .collect();
+        let offsets = log.append_batch(1, payloads).wait().unwrap();
+        assert_eq!((0..100).collect::<Vec<_>>(), offsets);
+
+        // too large for one buffer, so nothing is appended
+        let payloads = (0..2000).map(|_| Bytes::from(vec![0u8; 1024])).collect();
+        let err = log.append_batch(1, payloads).wait().unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+
+        let msgs = log.read(0, 1_048_576).wait().unwrap();
+        assert_eq!(Some(100), msgs.next_offset());
+        assert_eq!(b"entry-42", msgs.iter().nth(42).unwrap().payload());
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn readers_never_see_part_of_a_batch() {
+        let (mut log, dir) = open_test_log("append-batch-reader", &mut LogConfig::default());
+        let done = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let mut log = log.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    let msgs = log.read(0, 1_048_576).wait().unwrap();
+                    assert_eq!(0, msgs.len() % 10, "read {} entries", msgs.len());
+                }
+            })
+        };
+
+        for _ in 0..50 {
+            let payloads = (0..10)
+                .map(|i| Bytes::from(format!("entry-{}", i)))
+                .collect();
+            log.append_batch(1, payloads).wait().unwrap();
+        }
+        done.store(true, Ordering::SeqCst);
+        reader.join().unwrap();
+        assert_eq!(Some(499), log.last_offset().wait().unwrap());
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Storage in memory failing its first appends after writing the first
+    /// entry, as a write failing part way, and failing its first flushes.
+    struct FailingStorage {
+        mem: MemStorage,
+        append_failures: usize,
+        flush_failures: usize,
+    }
+
+    impl FailingStorage {
+        fn new(append_failures: usize, flush_failures: usize) -> FailingStorage {
+            FailingStorage {
+                mem: MemStorage::new(),
+                append_failures,
+                flush_failures,
+            }
+        }
+    }
+
+    impl Storage for FailingStorage {
+        type Set = <MemStorage as Storage>::Set;
+
+        fn append<M: MessageSet>(&mut self, msgs: &M) -> Result<AppendRange, AppendError> {
+            if self.append_failures == 0 {
+                return self.mem.append(msgs);
+            }
+            self.append_failures -= 1;
+            let first = msgs.iter().next().unwrap();
+            let len = HEADER_SIZE + first.metadata().len() + first.payload().len();
+            self.mem
+                .append(&MessagesMut(BytesMut::from(&msgs.bytes()[..len])))?;
+            Err(AppendError::Io(Error::new(
+                ErrorKind::Interrupted,
+                "write interrupted",
+            )))
+        }
+
+        fn read(&self, offset: Offset, max_bytes: usize) -> Result<Self::Set, ReadError> {
+            self.mem.read(offset, max_bytes)
+        }
+
+        fn reader<R: LogSliceReader>(
+            &mut self,
+            reader: &mut R,
+            offset: Offset,
+            max_bytes: usize,
+        ) -> Result<Option<R::Result>, ReadError> {
+            self.mem.reader(reader, offset, max_bytes)
+        }
+
+        fn last_offset(&self) -> Option<Offset> {
+            self.mem.last_offset()
+        }
+
+        fn next_offset(&self) -> Offset {
+            self.mem.next_offset()
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            if self.flush_failures == 0 {
+                return self.mem.flush();
+            }
+            self.flush_failures -= 1;
+            Err(Error::new(ErrorKind::Other, "flush failed"))
+        }
+
+        fn truncate(&mut self, offset: Offset) -> Result<(), Error> {
+            self.mem.truncate(offset)
+        }
+
+        fn trim_segments_before(&mut self, offset: Offset) -> Result<(), Error> {
+            self.mem.trim_segments_before(offset)
+        }
+
+        fn compact_keys(&self, cfg: &LogConfig) -> Result<CompactionStats, Error> {
+            self.mem.compact_keys(cfg)
+        }
+
+        fn reopen(&mut self, cfg: &LogConfig) -> Result<(), Error> {
+            self.mem.reopen(cfg)
+        }
+
+        fn clear(&mut self, cfg: &LogConfig) -> Result<(), Error> {
+            self.mem.clear(cfg)
+        }
+    }
+
+    fn open_failing_log(name: &str, cfg: &mut LogConfig, storage: FailingStorage) -> AsyncLog {
+        let dir = env::temp_dir().join(format!("log-{}-test-{}", name, process::id()));
+        cfg.dir = dir.to_string_lossy().into_owned();
+        open_with_storage(cfg, storage, NoopListener, FileSliceMessageReader)
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn failed_first_batch_leaves_no_entries() {
+        let mut cfg = LogConfig::default();
+        let mut log = open_failing_log("first-batch", &mut cfg, FailingStorage::new(1, 0));
+
+        // the entry written by the failed attempt is removed before the retry
+        let payloads: Vec<_> = vec![Bytes::from("a"), Bytes::from("b"), Bytes::from("c")];
+        assert_eq!(
+            vec![0, 1, 2],
+            log.append_batch(1, payloads.clone()).wait().unwrap()
+        );
+        let msgs = log.read(0, 4096).wait().unwrap();
+        let read: Vec<_> = msgs.iter().map(|m| Bytes::from(m.payload())).collect();
+        assert_eq!(payloads, read);
+
+        drop(log);
+        fs::remove_dir_all(&cfg.dir).unwrap();
+    }
+
+    #[test]
+    fn failed_flush_of_append_leaves_no_entries() {
+        let mut cfg = LogConfig::default();
+        cfg.flush_mode = FlushMode::EveryAppend;
+        let mut log = open_failing_log("append-flush", &mut cfg, FailingStorage::new(0, 1));
+
+        let err = log
+            .append_batch(1, vec![Bytes::from("lost")])
+            .wait()
+            .unwrap_err();
+        assert_eq!(ErrorKind::Other, err.kind());
+        assert_eq!(None, log.last_offset().wait().unwrap());
+
+        assert_eq!(
+            vec![0],
+            log.append_batch(1, vec![Bytes::from("kept")])
+                .wait()
+                .unwrap()
+        );
+        let msgs = log.read(0, 4096).wait().unwrap();
+        assert_eq!(b"kept", msgs.iter().next().unwrap().payload());
+        assert_eq!(Some(1), msgs.next_offset());
+
+        drop(log);
+        fs::remove_dir_all(&cfg.dir).unwrap();
+    }
+
+    #[test]
+    fn retried_sequenced_append_is_not_duplicated() {
+        let mut cfg = LogConfig::default();
+        let (mut log, dir) = open_test_log("sequenced", &mut cfg);
+        let seq = ProducerSequence {
+            producer_id: 7,
+            sequence: 0,
+        };
+        let first = log.append_sequenced(1, seq, Bytes::from("foo"), false);
+        let retry = log.append_sequenced(1, seq, Bytes::from("foo"), false);
+        assert_eq!(0, first.wait().unwrap());
+        assert_eq!(0, retry.wait().unwrap());
+        assert_eq!(Some(0), log.last_offset().wait().unwrap());
+
+        let next = ProducerSequence {
+            producer_id: 7,
+            sequence: 1,
+        };
+        assert_eq!(
+            1,
+            log.append_sequenced(1, next, Bytes::from("bar"), false)
+                .wait()
+                .unwrap()
+        );
+        // a retry of an earlier sequence returns the offset of its append
+        assert_eq!(
+            0,
+            log.append_sequenced(1, seq, Bytes::from("foo"), false)
+                .wait()
+                .unwrap()
+        );
+        assert_eq!(Some(1), log.last_offset().wait().unwrap());
+
+        // the sequences survive a restart once flushed
+        log.clone().shutdown().wait().unwrap();
+        drop(log);
+        let (mut log, _) = open(&cfg, NoopListener, FileSliceMessageReader).unwrap();
+        assert_eq!(
+            1,
+            log.append_sequenced(1, next, Bytes::from("bar"), false)
+                .wait()
+                .unwrap()
+        );
+        assert_eq!(Some(1), log.last_offset().wait().unwrap());
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Records the client request IDs appended or acknowledged as retries.
+    struct ReplyListener(Arc<Mutex<Vec<u64>>>);
+
+    impl AppendListener for ReplyListener {
+        fn notify_append(&mut self, appended: Messages) {
+            let mut replies = self.0.lock().unwrap();
+            replies.extend(
+                appended
+                    .iter()
+                    .map(|m| LittleEndian::read_u64(&m.metadata()[8..16])),
+            );
+        }
+
+        fn notify_duplicate(&mut self, _client_id: u64, client_req_id: u64) {
+            self.0.lock().unwrap().push(client_req_id);
+        }
+    }
+
+    #[test]
+    fn retried_idempotent_append_is_acknowledged() {
+        let mut cfg = LogConfig::default();
+        let dir = env::temp_dir().join(format!("log-idempotent-test-{}", process::id()));
+        cfg.dir = dir.to_string_lossy().into_owned();
+        let replies = Arc::new(Mutex::new(Vec::new()));
+        let listener = ReplyListener(replies.clone());
+        let (mut log, _) = open(&cfg, listener, FileSliceMessageReader).unwrap();
+        let seq = ProducerSequence {
+            producer_id: 7,
+            sequence: 0,
+        };
+
+        log.append(1, 10, Bytes::from("foo"), Priority::High, Some(seq))
+            .unwrap();
+        log.append(1, 11, Bytes::from("foo"), Priority::High, Some(seq))
+            .unwrap();
+        // requests are handled in order, so both appends are handled
+        assert_eq!(Some(0), log.last_offset().wait().unwrap());
+        assert_eq!(vec![10, 11], *replies.lock().unwrap());
+        assert_eq!(1, log.read(0, 4096).wait().unwrap().len());
+
+        // the sequence is shared with appends returning the offset
+        let retry = log.append_sequenced(1, seq, Bytes::from("foo"), false);
+        assert_eq!(0, retry.wait().unwrap());
+        assert_eq!(Some(0), log.last_offset().wait().unwrap());
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn append_now_optionally_flushes() {
+        let mut cfg = LogConfig::default();
+        cfg.flush_interval_ms = 3_600_000;
+        let (mut log, dir) = open_test_log("append-now", &mut cfg);
+        let unflushed = |log: &mut AsyncLog| log.stats().wait().unwrap().unflushed_bytes;
+
+        assert_eq!(
+            0,
+            log.append_now(1, Bytes::from("foo"), false).wait().unwrap()
+        );
+        assert!(unflushed(&mut log) > 0);
+
+        assert_eq!(
+            1,
+            log.append_now(1, Bytes::from("bar"), true).wait().unwrap()
+        );
+        assert_eq!(0, unflushed(&mut log));
+
+        let msgs = log.read(0, 4096).wait().unwrap();
+        assert_eq!(
+            vec![(0, b"foo".to_vec()), (1, b"bar".to_vec())],
+            msgs.iter()
+                .map(|m| (m.offset(), m.payload().to_vec()))
+                .collect::<Vec<_>>()
+        );
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_payloads_over_message_max_bytes() {
+        let mut cfg = LogConfig::default();
+        cfg.message_max_bytes = 1024;
+        let (mut log, dir) = open_test_log("message-max-bytes", &mut cfg);
+        let too_large = Bytes::from(vec![0; 1025]);
+        let expected = Some(MessageTooLarge {
+            bytes: 1025,
+            max_bytes: 1024,
+        });
+
+        let err = log
+            .append(1, 1, too_large.clone(), Priority::High, None)
+            .unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+        assert_eq!(expected, MessageTooLarge::from_error(&err));
+
+        let err = log
+            .append_now(1, too_large.clone(), false)
+            .wait()
+            .unwrap_err();
+        assert_eq!(expected, MessageTooLarge::from_error(&err));
+        let payloads = vec![Bytes::from("foo"), too_large.clone()];
+        let err = log.append_batch(1, payloads).wait().unwrap_err();
+        assert_eq!(expected, MessageTooLarge::from_error(&err));
+
+        // a payload of the maximum size is appended
+        assert_eq!(
+            0,
+            log.append_now(1, Bytes::from(vec![0; 1024]), false)
+                .wait()
+                .unwrap()
+        );
+        assert_eq!(1, log.read(0, 4096).wait().unwrap().len());
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_empty_payloads_unless_allowed() {
+        let (mut log, dir) = open_test_log("empty-rejected", &mut LogConfig::default());
+        let err = log
+            .append(1, 1, Bytes::new(), Priority::High, None)
+            .unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+        let err = log.append_now(1, Bytes::new(), false).wait().unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+
+        // a batch with a single empty payload appends none of its entries
+        let payloads = vec![Bytes::from("foo"), Bytes::new(), Bytes::from("bar")];
+        let err = log.append_batch(1, payloads.clone()).wait().unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+        let err = log.append_stream(1, payloads).collect().wait().unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+        assert_eq!(None, log.last_offset().wait().unwrap());
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut cfg = LogConfig::default();
+        cfg.allow_empty_payloads = true;
+        let (mut log, dir) = open_test_log("empty-allowed", &mut cfg);
+        let payloads = vec![Bytes::from("foo"), Bytes::new(), Bytes::from("bar")];
+        assert_eq!(vec![0, 1, 2], log.append_batch(1, payloads).wait().unwrap());
+        assert_eq!(3, log.append_now(1, Bytes::new(), false).wait().unwrap());
+
+        let msgs = log.read(0, 4096).wait().unwrap();
+        assert_eq!(
+            vec![3, 0, 3, 0],
+            msgs.iter().map(|m| m.payload().len()).collect::<Vec<_>>()
+        );
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn append_record_round_trip() {
+        let (mut log, dir) = open_test_log("append-record", &mut LogConfig::default());
+
+        let headers = vec![("type".to_string(), "control".to_string())];
+        let key = Some(Bytes::from("key-1"));
+        log.append_record(1, key, headers, Bytes::from("foo"))
+            .wait()
+            .unwrap();
+        log.append_and_fetch(1, vec![Bytes::from("bar")])
+            .wait()
+            .unwrap();
+        let offset = log
+            .append_record(1, None, vec![], Bytes::from("baz"))
+            .wait()
+            .unwrap();
+        assert_eq!(2, offset);
+
+        let msgs = log.read(0, 4096).wait().unwrap();
+        let records = msgs
+            .iter()
+            .map(|m| {
+                let meta = RecordMeta::parse(m.metadata()).unwrap();
+                let key = meta.key.map(|k| k.to_vec());
+                let headers = meta
+                    .headers
+                    .iter()
+                    .map(|&(name, value)| (name.to_string(), value.to_string()))
+                    .collect::<Vec<_>>();
+                (key, headers, m.payload().to_vec())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (
+                    Some(b"key-1".to_vec()),
+                    vec![("type".to_string(), "control".to_string())],
+                    b"foo".to_vec()
+                ),
+                (None, vec![], b"bar".to_vec()),
+                (None, vec![], b"baz".to_vec()),
+            ],
+            records
+        );
+
+        let long_key = Some(Bytes::from(vec![0u8; 70_000]));
+        let err = log
+            .append_record(1, long_key, vec![], Bytes::new())
+            .wait()
+            .unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_metadata_skips_payloads() {
+        let (mut log, dir) = open_test_log("read-metadata", &mut LogConfig::default());
+
+        let payloads = (0..100).map(|i| Bytes::from(vec![0u8; 1000 + i])).collect();
+        log.append_and_fetch(1, payloads).wait().unwrap();
+        let key = Some(Bytes::from("key"));
+        log.append_record(1, key, vec![], Bytes::from("record"))
+            .wait()
+            .unwrap();
+
+        let full = log.read(0, 1_048_576).wait().unwrap();
+        let read = log.read_metadata(0, None, 1_048_576).wait().unwrap();
+        assert_eq!(101, read.entries.len());
+        assert_eq!(Some(101), read.next_offset);
+        for (i, entry) in read.entries.iter().take(100).enumerate() {
+            assert_eq!(i as u64, entry.offset);
+            assert_eq!(1000 + i, entry.payload_len);
+        }
+        let record = RecordMeta::parse(&read.entries[100].metadata).unwrap();
+        assert_eq!(Some(&b"key"[..]), record.key);
+        assert_eq!(6, read.entries[100].payload_len);
+        assert!(read.retained_bytes() * 20 < full.bytes().len());
+
+        let read = log.read_metadata(10, Some(20), 1_048_576).wait().unwrap();
+        assert_eq!(10, read.entries.len());
+        assert_eq!(Some(20), read.next_offset);
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_beyond_end() {
+        let payloads = || vec![Bytes::from("foo")];
+
+        let mut cfg = LogConfig::default();
+        cfg.read_beyond_end = BeyondEnd::Empty;
+        let (mut log, dir) = open_test_log("beyond-end-empty", &mut cfg);
+        log.append_and_fetch(1, payloads()).wait().unwrap();
+        assert_eq!(0, log.read(1, 4096).wait().unwrap().len());
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+
+        cfg.read_beyond_end = BeyondEnd::Error;
+        let (mut log, dir) = open_test_log("beyond-end-error", &mut cfg);
+        log.append_and_fetch(1, payloads()).wait().unwrap();
+        let err = log.read(1, 4096).wait().unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+
+        cfg.read_beyond_end = BeyondEnd::Wait;
+        let (mut log, dir) = open_test_log("beyond-end-wait", &mut cfg);
+        log.append_and_fetch(1, payloads()).wait().unwrap();
+        // the read is sent to the log thread ahead of the append
+        let read = log.read(1, 4096);
+        log.append_and_fetch(1, vec![Bytes::from("bar")])
+            .wait()
+            .unwrap();
+        let msgs = read.wait().unwrap();
+        assert_eq!(
+            vec![(1, b"bar".to_vec())],
+            msgs.iter()
+                .map(|m| (m.offset(), m.payload().to_vec()))
+                .collect::<Vec<_>>()
+        );
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn appends_durable_within_flush_interval() {
+        // reopens the log while the log thread runs, as after a crash
+        let durable = |cfg: &LogConfig| {
+            let log = CommitLog::new(log_options(cfg)).unwrap();
+            let msgs = log.read(0, ReadLimit::max_bytes(4096)).unwrap();
+            msgs.iter()
+                .map(|m| m.payload().to_vec())
+                .collect::<Vec<_>>()
+        };
+
+        let mut cfg = LogConfig::default();
+        cfg.flush_interval_ms = 0;
+        let (mut log, dir) = open_test_log("flush-each-append", &mut cfg);
+        log.append_and_fetch(1, vec![Bytes::from("foo")])
+            .wait()
+            .unwrap();
+        assert_eq!(0, log.stats().wait().unwrap().unflushed_bytes);
+        assert_eq!(vec![b"foo".to_vec()], durable(&cfg));
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+
+        cfg.flush_interval_ms = 100;
+        let (mut log, dir) = open_test_log("flush-interval", &mut cfg);
+        log.append_and_fetch(1, vec![Bytes::from("bar")])
+            .wait()
+            .unwrap();
+
+        // the idle log is flushed without further requests, as the stats
+        // are read before the log thread would flush after the request
+        thread::sleep(Duration::from_millis(250));
+        assert_eq!(0, log.stats().wait().unwrap().unflushed_bytes);
+        assert_eq!(vec![b"bar".to_vec()], durable(&cfg));
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn flush_modes() {
+        // reopens the log while the log thread runs, as after a crash
+        let durable = |cfg: &LogConfig| {
+            let log = CommitLog::new(log_options(cfg)).unwrap();
+            log.read(0, ReadLimit::max_bytes(4096)).unwrap().len()
+        };
+
+        let mut cfg = LogConfig::default();
+        cfg.flush_mode = FlushMode::EveryAppend;
+        cfg.flush_interval_ms = 3_600_000;
+        let (mut log, dir) = open_test_log("flush-mode-every-append", &mut cfg);
+        for i in 0..3 {
+            log.append_and_fetch(1, vec![Bytes::from("foo")])
+                .wait()
+                .unwrap();
+            assert_eq!(i + 1, durable(&cfg));
+        }
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+
+        cfg.flush_mode = FlushMode::Never;
+        cfg.flush_interval_ms = 0;
+        cfg.flush_max_bytes = Some(1);
+        let (mut log, dir) = open_test_log("flush-mode-never", &mut cfg);
+        log.append_and_fetch(1, vec![Bytes::from("bar")])
+            .wait()
+            .unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(log.stats().wait().unwrap().unflushed_bytes > 0);
+
+        log.flush().wait().unwrap();
+        assert_eq!(0, log.stats().wait().unwrap().unflushed_bytes);
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn flushed_offset_lags_until_flush() {
+        let mut cfg = LogConfig::default();
+        cfg.flush_interval_ms = 3_600_000;
+        let (mut log, dir) = open_test_log("flushed-offset", &mut cfg);
+        assert_eq!(None, log.flushed_offset().wait().unwrap());
+
+        log.append_and_fetch(1, vec![Bytes::from("foo"), Bytes::from("bar")])
+            .wait()
+            .unwrap();
+        assert_eq!(Some(1), log.last_offset().wait().unwrap());
+        assert_eq!(None, log.flushed_offset().wait().unwrap());
+
+        log.flush().wait().unwrap();
+        assert_eq!(Some(1), log.flushed_offset().wait().unwrap());
+
+        log.append_and_fetch(1, vec![Bytes::from("baz")])
+            .wait()
+            .unwrap();
+        assert_eq!(Some(2), log.last_offset().wait().unwrap());
+        assert_eq!(Some(1), log.flushed_offset().wait().unwrap());
+
+        // the recovered entries are durable after a restart
+        log.clone().shutdown().wait().unwrap();
+        drop(log);
+        let (mut log, _) = open(&cfg, NoopListener, FileSliceMessageReader).unwrap();
+        assert_eq!(Some(2), log.flushed_offset().wait().unwrap());
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Leaves a gap of the given size before each append.
+    struct SpacedOffsets(Offset);
+
+    impl OffsetAllocator for SpacedOffsets {
+        fn assign(&mut self, messages: &mut MessagesMut, next_offset: Offset) {
+            set_offsets(messages, next_offset + self.0);
+        }
+    }
+
+    #[test]
+    fn appends_with_the_offsets_of_the_allocator() {
+        let mut cfg = LogConfig::default();
+        let dir = env::temp_dir().join(format!("log-spaced-test-{}", process::id()));
+        cfg.dir = dir.to_string_lossy().into_owned();
+        let offsets = Box::new(SpacedOffsets(10));
+        let (mut log, _) =
+            open_with_offsets(&cfg, NoopListener, FileSliceMessageReader, offsets).unwrap();
+
+        let first = log.append_batch(1, vec![Bytes::from("a"), Bytes::from("b")]);
+        assert_eq!(vec![10, 11], first.wait().unwrap());
+        let second = log.append_batch(1, vec![Bytes::from("c")]);
+        assert_eq!(vec![22], second.wait().unwrap());
+        assert_eq!(Some(22), log.last_offset().wait().unwrap());
+
+        let msgs = log.read(10, 4096).wait().unwrap();
+        assert_eq!(
+            vec![
+                (10, b"a".to_vec()),
+                (11, b"b".to_vec()),
+                (22, b"c".to_vec())
+            ],
+            msgs.iter()
+                .map(|m| (m.offset(), m.payload().to_vec()))
+                .collect::<Vec<_>>()
+        );
+        let msgs = log.read(22, 4096).wait().unwrap();
+        assert_eq!(
+            vec![22],
+            msgs.iter().map(|m| m.offset()).collect::<Vec<_>>()
+        );
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Leaves a gap before each append once the log reaches the offset.
+    struct GapFrom(Offset);
+
+    impl OffsetAllocator for GapFrom {
+        fn assign(&mut self, messages: &mut MessagesMut, next_offset: Offset) {
+            let gap = if next_offset < self.0 { 0 } else { 10 };
+            set_offsets(messages, next_offset + gap);
+        }
+    }
+
+    #[test]
+    fn strict_offsets_fail_appends_leaving_a_gap() {
+        let mut cfg = LogConfig::default();
+        cfg.strict_offsets = true;
+        let dir = env::temp_dir().join(format!("log-strict-test-{}", process::id()));
+        cfg.dir = dir.to_string_lossy().into_owned();
+        let offsets = Box::new(GapFrom(2));
+        let (mut log, _) =
+            open_with_offsets(&cfg, NoopListener, FileSliceMessageReader, offsets).unwrap();
+
+        let appended = log.append_batch(1, vec![Bytes::from("a"), Bytes::from("b")]);
+        assert_eq!(vec![0, 1], appended.wait().unwrap());
+        let err = log
+            .append_batch(1, vec![Bytes::from("c")])
+            .wait()
+            .unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+
+        // the entries of the gap are removed
+        assert_eq!(Some(1), log.last_offset().wait().unwrap());
+        let msgs = log.read(0, 4096).wait().unwrap();
+        assert_eq!(
+            vec![b"a".to_vec(), b"b".to_vec()],
+            msgs.iter()
+                .map(|m| m.payload().to_vec())
+                .collect::<Vec<_>>()
+        );
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn flush_returns_the_durable_offset() {
+        let mut cfg = LogConfig::default();
+        cfg.flush_interval_ms = 3_600_000;
+        let (mut log, dir) = open_test_log("flush-offset", &mut cfg);
+        assert_eq!(None, log.flush().wait().unwrap());
+
+        let mut last_durable = None;
+        for i in 0..20 {
+            let payloads = (0..i % 4 + 1)
+                .map(|j| Bytes::from(format!("{}-{}", i, j)))
+                .collect();
+            let offsets = log.append_batch(1, payloads).wait().unwrap();
+            let last_appended = offsets.last().cloned();
+
+            // a flush racing with an append of another handle still covers
+            // everything acknowledged before it
+            let pending = log.clone().append_batch(2, vec![Bytes::from("other")]);
+            let durable = log.flush().wait().unwrap();
+            pending.wait().unwrap();
+
+            assert!(durable >= last_appended);
+            assert!(durable <= log.last_offset().wait().unwrap());
+            assert!(durable >= last_durable);
+            assert_eq!(durable, log.flushed_offset().wait().unwrap());
+            last_durable = durable;
+        }
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reads_up_to_message_count() {
+        let (mut log, dir) = open_test_log("read-count", &mut LogConfig::default());
+        let payloads = (0..10).map(|i| Bytes::from(format!("{}", i))).collect();
+        log.append_and_fetch(1, payloads).wait().unwrap();
+
+        let msgs = log.read_messages(0, 4096, 4).wait().unwrap();
+        assert_eq!(4, msgs.len());
+        assert_eq!(Some(4), msgs.next_offset());
+
+        // the byte limit applies as well
+        let one = log.read_messages(0, 4096, 1).wait().unwrap().bytes().len();
+        let msgs = log.read_messages(4, one * 2 + one / 2, 4).wait().unwrap();
+        assert_eq!(
+            vec![4, 5],
+            msgs.iter().map(|m| m.offset()).collect::<Vec<_>>()
+        );
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn appends_messages_parsed_from_replication() {
+        let mut cfg = LogConfig::default();
+        let (mut upstream, upstream_dir) = open_test_log("replication-upstream", &mut cfg);
+        upstream
+            .append_and_fetch(1, vec![Bytes::from("foo"), Bytes::from("bar")])
+            .wait()
+            .unwrap();
+        let sent = upstream.read(0, 4096).wait().unwrap();
+
+        // messages arrive from the network as the bytes read upstream
+        let mut cfg = LogConfig::default();
+        cfg.dir = env::temp_dir()
+            .join(format!("log-replication-replica-test-{}", process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let (mut replica, mut replicator) =
+            open(&cfg, NoopListener, FileSliceMessageReader).unwrap();
+        let received = Messages::parse(Bytes::from(sent.bytes())).unwrap();
+        let range = replicator
+            .append_from_replication(received.clone())
+            .wait()
+            .unwrap();
+        assert_eq!(0, range.first());
+        assert_eq!(2, range.len());
+
+        let msgs = replica.read(0, 4096).wait().unwrap();
+        assert_eq!(sent.bytes(), msgs.bytes());
+
+        // a batch out of sequence with the replica is rejected
+        let err = replicator
+            .append_from_replication(received)
+            .wait()
+            .unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+
+        drop(upstream);
+        drop(replica);
+        drop(replicator);
+        fs::remove_dir_all(&upstream_dir).unwrap();
+        fs::remove_dir_all(&cfg.dir).unwrap();
+    }
+
+    #[test]
+    fn repairs_torn_write_at_tail() {
+        let mut cfg = LogConfig::default();
+        let (mut log, dir) = open_test_log("torn-tail", &mut cfg);
+        log.append_and_fetch(1, vec![Bytes::from("foo"), Bytes::from("bar")])
+            .wait()
+            .unwrap();
+        log.clone().shutdown().wait().unwrap();
+        drop(log);
+
+        // junk left by a killed append at the end of the newest segment
+        let segment = dir.join(format!("{:020}.log", 0));
+        let mut file = fs::OpenOptions::new().append(true).open(&segment).unwrap();
+        file.write_all(&[0xff; 13]).unwrap();
+        drop(file);
+
+        cfg.repair = false;
+        let err = open(&cfg, NoopListener, FileSliceMessageReader)
+            .err()
+            .unwrap();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+
+        cfg.repair = true;
+        let (mut log, _) = open(&cfg, NoopListener, FileSliceMessageReader).unwrap();
+        assert_eq!(Some(1), log.last_offset().wait().unwrap());
+        let msgs = log.read(0, 4096).wait().unwrap();
+        assert_eq!(
+            vec![b"foo".to_vec(), b"bar".to_vec()],
+            msgs.iter()
+                .map(|m| m.payload().to_vec())
+                .collect::<Vec<_>>()
+        );
+
+        // appends continue after the last valid entry
+        let (range, _) = log
+            .append_and_fetch(1, vec![Bytes::from("baz")])
+            .wait()
+            .unwrap();
+        assert_eq!(2, range.start);
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn flushes_at_byte_threshold() {
+        let mut cfg = LogConfig::default();
+        cfg.flush_interval_ms = 3_600_000;
+        cfg.flush_max_bytes = Some(100);
+        let (mut log, dir) = open_test_log("flush-bytes", &mut cfg);
+        let unflushed = |log: &mut AsyncLog| log.stats().wait().unwrap().unflushed_bytes;
+
+        log.append_and_fetch(1, vec![Bytes::from("foo")])
+            .wait()
+            .unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert!(unflushed(&mut log) > 0);
+
+        log.append_and_fetch(1, vec![Bytes::from(vec![0u8; 200])])
+            .wait()
+            .unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(0, unflushed(&mut log));
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tune_flush_interval() {
+        let mut cfg = LogConfig::default();
+        cfg.flush_interval_ms = 3_600_000;
+        let (mut log, dir) = open_test_log("tune-flush", &mut cfg);
+        let unflushed = |log: &mut AsyncLog| log.stats().wait().unwrap().unflushed_bytes;
+
+        log.append_and_fetch(1, vec![Bytes::from("foo")])
+            .wait()
+            .unwrap();
+        assert!(unflushed(&mut log) > 0);
+        assert_eq!(None, log.flushed_offset().wait().unwrap());
+
+        let invalid = LogTuning {
+            retention_check_interval_secs: Some(0),
+            ..LogTuning::default()
+        };
+        assert!(log.tune(invalid).wait().is_err());
+
+        let tuning = LogTuning {
+            flush_interval: Some(Duration::from_millis(10)),
+            ..LogTuning::default()
+        };
+        log.tune(tuning).wait().unwrap();
+
+        // the flush runs at the next tick of the idle log thread
+        let mut tries = 0;
+        while log.flushed_offset().wait().unwrap() != Some(0) {
+            tries += 1;
+            assert!(tries < 500, "log not flushed at the tuned interval");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(0, unflushed(&mut log));
+
+        // the interval only applies to interval flushing
+        let tuning = LogTuning {
+            flush_mode: Some(FlushMode::EveryAppend),
+            ..LogTuning::default()
+        };
+        log.tune(tuning).wait().unwrap();
+        let tuning = LogTuning {
+            flush_interval: Some(Duration::from_millis(10)),
+            ..LogTuning::default()
+        };
+        assert!(log.tune(tuning).wait().is_err());
+        log.append_and_fetch(1, vec![Bytes::from("bar")])
+            .wait()
+            .unwrap();
+        assert_eq!(0, unflushed(&mut log));
+        assert_eq!(Some(1), log.flushed_offset().wait().unwrap());
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,97 @@
+use super::MessagesMut;
+use commitlog::message::{set_offsets, MessageSet};
+use commitlog::Offset;
+
+/// Assigns offsets to client appends, called from the log thread before
+/// each append.
+///
+/// Implementations must assign offsets that are:
+///
+/// * **Monotone**: each message has a greater offset than the message before
+///   it, and the first message has an offset of at least `next_offset`.
+/// * **Unique**: no offset is assigned twice, including across restarts.
+///
+/// Offsets may leave gaps to embed metadata such as an epoch or shard bits.
+/// Replicas verify that replicated appends are contiguous, so allocators
+/// leaving gaps are only supported without replication.
+pub trait OffsetAllocator: Send {
+    /// Assigns offsets to the messages. The next offset is the offset after
+    /// the last message in the log.
+    fn assign(&mut self, messages: &mut MessagesMut, next_offset: Offset);
+}
+
+/// Dense, monotonic offsets, as assigned by the commit log.
+pub struct DenseOffsets;
+
+impl OffsetAllocator for DenseOffsets {
+    #[inline]
+    fn assign(&mut self, messages: &mut MessagesMut, next_offset: Offset) {
+        set_offsets(messages, next_offset);
+    }
+}
+
+/// Tests whether the assigned offsets meet the constraints of the allocator.
+pub fn valid_offsets<M: MessageSet>(messages: &M, next_offset: Offset) -> bool {
+    let mut min = next_offset;
+    for msg in messages.iter() {
+        if msg.offset() < min {
+            return false;
+        }
+        min = msg.offset() + 1;
+    }
+    true
+}
+
+/// Tests whether an append was assigned the offsets directly following the
+/// previous end of the log, one for each message.
+#[inline]
+pub fn is_contiguous(next_offset: Offset, num_msgs: usize, first: Offset, len: usize) -> bool {
+    first == next_offset && len == num_msgs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+
+    fn messages(n: usize) -> MessagesMut {
+        let mut buf = MessagesMut(BytesMut::with_capacity(1024));
+        for i in 0..n {
+            buf.push(0, i as u64, b"0123456789").unwrap();
+        }
+        buf
+    }
+
+    #[test]
+    fn dense_offsets() {
+        let mut buf = messages(3);
+        DenseOffsets.assign(&mut buf, 10);
+        assert_eq!(
+            vec![10, 11, 12],
+            buf.iter().map(|m| m.offset()).collect::<Vec<_>>()
+        );
+        assert!(valid_offsets(&buf, 10));
+        assert!(!valid_offsets(&buf, 11));
+    }
+
+    #[test]
+    fn validates_offsets() {
+        let mut buf = messages(2);
+        set_offsets(&mut buf, 5);
+        assert!(valid_offsets(&buf, 0));
+        assert!(!valid_offsets(&buf, 6));
+
+        // gaps are allowed
+        let mut sparse = messages(1);
+        set_offsets(&mut sparse, 1 << 32);
+        assert!(valid_offsets(&sparse, 7));
+    }
+
+    #[test]
+    fn contiguous_offsets() {
+        assert!(is_contiguous(10, 3, 10, 3));
+        assert!(!is_contiguous(10, 3, 11, 3));
+        assert!(!is_contiguous(10, 3, 9, 3));
+        assert!(!is_contiguous(10, 3, 10, 2));
+    }
+}
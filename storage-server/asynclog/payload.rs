@@ -0,0 +1,125 @@
+use super::trace::untraced;
+use bytes::Bytes;
+use config::LogConfig;
+use std::error;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+/// Payload of an append larger than the maximum message size of the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageTooLarge {
+    /// Size of the payload, in bytes.
+    pub bytes: usize,
+
+    /// Maximum size of a payload, the `message_max_bytes` of the log.
+    pub max_bytes: usize,
+}
+
+impl fmt::Display for MessageTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Entry of {} bytes exceeds the maximum message size of {} bytes",
+            self.bytes, self.max_bytes
+        )
+    }
+}
+
+impl error::Error for MessageTooLarge {}
+
+impl MessageTooLarge {
+    /// The payload too large, if the append failed for its size rather than
+    /// any other invalid input.
+    pub fn from_error(e: &Error) -> Option<MessageTooLarge> {
+        untraced(e)
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<MessageTooLarge>())
+            .cloned()
+    }
+}
+
+/// Payloads accepted by the appends of a log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PayloadPolicy {
+    pub max_bytes: usize,
+    pub allow_empty: bool,
+}
+
+impl PayloadPolicy {
+    pub fn from_config(cfg: &LogConfig) -> PayloadPolicy {
+        PayloadPolicy {
+            max_bytes: cfg.message_max_bytes,
+            allow_empty: cfg.allow_empty_payloads,
+        }
+    }
+
+    /// Fails with `ErrorKind::InvalidInput` at the first payload that is
+    /// empty, unless allowed, or larger than the maximum message size, the
+    /// latter carrying `MessageTooLarge`.
+    pub fn check<'a, I>(&self, payloads: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = &'a Bytes>,
+    {
+        for (i, payload) in payloads.into_iter().enumerate() {
+            if payload.is_empty() && !self.allow_empty {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Entry {} has an empty payload", i),
+                ));
+            }
+            if payload.len() > self.max_bytes {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    MessageTooLarge {
+                        bytes: payload.len(),
+                        max_bytes: self.max_bytes,
+                    },
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allow_empty: bool) -> PayloadPolicy {
+        PayloadPolicy {
+            max_bytes: 10,
+            allow_empty,
+        }
+    }
+
+    #[test]
+    fn rejects_payloads_over_max() {
+        let payloads = vec![Bytes::from(vec![0; 10]), Bytes::from(vec![0; 11])];
+        assert!(policy(false).check(&payloads[..1]).is_ok());
+
+        let err = policy(false).check(&payloads).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+        assert_eq!(
+            Some(MessageTooLarge {
+                bytes: 11,
+                max_bytes: 10,
+            }),
+            MessageTooLarge::from_error(&err)
+        );
+
+        // other invalid input is not mistaken for a payload too large
+        let err = Error::new(ErrorKind::InvalidInput, "Key too large");
+        assert_eq!(None, MessageTooLarge::from_error(&err));
+    }
+
+    #[test]
+    fn rejects_empty_payloads_unless_allowed() {
+        let payloads = vec![Bytes::from("foo"), Bytes::new(), Bytes::from("bar")];
+
+        let err = policy(false).check(&payloads).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+        assert_eq!(None, MessageTooLarge::from_error(&err));
+
+        assert!(policy(true).check(&payloads).is_ok());
+    }
+}
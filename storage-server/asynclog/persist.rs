@@ -0,0 +1,36 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Replaces the file at the path with the bytes. The bytes are written and
+/// synced to `tmp_name` in the same directory, then renamed over the file,
+/// so a crash never leaves a partial file.
+pub fn write_atomically(path: &Path, tmp_name: &str, bytes: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_file_name(tmp_name);
+    {
+        let mut f = File::create(&tmp_path)?;
+        f.write_all(bytes)?;
+        f.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    #[test]
+    fn replaces_the_file() {
+        let dir = env::temp_dir().join(format!("log-persist-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state");
+
+        write_atomically(&path, "state.tmp", b"first").unwrap();
+        write_atomically(&path, "state.tmp", b"second").unwrap();
+        assert_eq!(b"second".to_vec(), fs::read(&path).unwrap());
+        assert!(!dir.join("state.tmp").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
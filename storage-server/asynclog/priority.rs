@@ -0,0 +1,48 @@
+use config::ThreadPriority;
+use libc;
+use std::io;
+
+/// Requests elevated scheduling for the calling thread. Best-effort: if the
+/// OS denies the request, a warning is logged and the thread continues at
+/// normal priority.
+pub fn elevate_current_thread(priority: &ThreadPriority) {
+    match set_priority(priority) {
+        Ok(()) => info!("Log thread running with {:?} scheduling", priority),
+        Err(e) => warn!(
+            "Unable to set {:?} scheduling for the log thread, continuing at normal priority: {}",
+            priority, e
+        ),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_priority(priority: &ThreadPriority) -> io::Result<()> {
+    let ret = match *priority {
+        // on Linux, the nice value of PRIO_PROCESS with the thread id
+        // applies to the single thread
+        ThreadPriority::Nice(nice) => unsafe {
+            let tid = libc::syscall(libc::SYS_gettid) as libc::id_t;
+            libc::setpriority(libc::PRIO_PROCESS, tid, nice)
+        },
+        ThreadPriority::Fifo(prio) => unsafe {
+            let param = libc::sched_param {
+                sched_priority: prio,
+            };
+            libc::sched_setscheduler(0, libc::SCHED_FIFO, &param)
+        },
+    };
+
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_priority(_priority: &ThreadPriority) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Thread priority is only supported on Linux",
+    ))
+}
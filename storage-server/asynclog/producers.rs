@@ -0,0 +1,255 @@
+use super::persist::write_atomically;
+use byteorder::{ByteOrder, LittleEndian};
+use commitlog::Offset;
+use prometheus::Counter;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+const PRODUCERS_FILE: &str = "producer_sequences";
+const PRODUCERS_TMP_FILE: &str = "producer_sequences.tmp";
+const ENTRY_SIZE: usize = 24;
+
+/// Number of the latest sequences of each producer kept with the offset of
+/// their entry, bounding the retries acknowledged with their offset.
+const MAX_SEQUENCES: usize = 32;
+
+lazy_static! {
+    static ref DUPLICATE_APPENDS: Counter = register_counter!(opts!(
+        "log_duplicate_appends",
+        "Number of idempotent appends acknowledged with the offset of an earlier append.",
+        labels! {"mod" => "log",}
+    ))
+    .unwrap();
+}
+
+/// Identifier of an idempotent producer, chosen by the producer.
+pub type ProducerId = u64;
+
+/// Sequence of an append from an idempotent producer. Each producer numbers
+/// its appends with increasing sequences, and retries an append with the
+/// sequence of the original.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProducerSequence {
+    pub producer_id: ProducerId,
+    pub sequence: u64,
+}
+
+/// Latest sequences appended by each idempotent producer and the offsets of
+/// their entries, persisted in the log directory as the log is flushed.
+///
+/// Sequences recorded after the last flush are lost with a crash, as are
+/// the entries not yet flushed.
+pub struct ProducerSequences {
+    path: PathBuf,
+    // latest sequences of each producer with their offsets, oldest first
+    sequences: BTreeMap<ProducerId, VecDeque<(u64, Offset)>>,
+    dirty: bool,
+}
+
+impl ProducerSequences {
+    /// Loads the producer sequences persisted in the log directory.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<ProducerSequences> {
+        let path = dir.as_ref().join(PRODUCERS_FILE);
+        let sequences = match File::open(&path) {
+            Ok(mut f) => {
+                let mut bytes = vec![];
+                f.read_to_end(&mut bytes)?;
+                decode(&bytes)?
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(ProducerSequences {
+            path,
+            sequences,
+            dirty: false,
+        })
+    }
+
+    /// Offset of the entry appended with the sequence, or `None` if the
+    /// sequence is after the last appended by the producer.
+    ///
+    /// Fails with `ErrorKind::AlreadyExists` for a sequence before the last
+    /// appended by the producer that is not among the latest kept, as the
+    /// offset of its entry is no longer known.
+    pub fn appended(&self, seq: ProducerSequence) -> io::Result<Option<Offset>> {
+        let appended = match self.sequences.get(&seq.producer_id) {
+            Some(appended) => appended,
+            None => return Ok(None),
+        };
+        match appended.back() {
+            Some(&(last, _)) if last < seq.sequence => return Ok(None),
+            None => return Ok(None),
+            _ => {}
+        }
+
+        match appended
+            .iter()
+            .find(|&&(sequence, _)| sequence == seq.sequence)
+        {
+            Some(&(_, offset)) => {
+                DUPLICATE_APPENDS.inc();
+                Ok(Some(offset))
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "Sequence {} of producer {} is before the latest {} appended",
+                    seq.sequence, seq.producer_id, MAX_SEQUENCES
+                ),
+            )),
+        }
+    }
+
+    /// Records the offset of the entry appended with the sequence.
+    pub fn record(&mut self, seq: ProducerSequence, offset: Offset) {
+        let appended = self
+            .sequences
+            .entry(seq.producer_id)
+            .or_insert_with(VecDeque::new);
+        if appended.len() == MAX_SEQUENCES {
+            appended.pop_front();
+        }
+        appended.push_back((seq.sequence, offset));
+        self.dirty = true;
+    }
+
+    /// Forgets the sequences of entries at or after the offset, removed from
+    /// the log by a truncation.
+    pub fn truncate(&mut self, next_offset: Offset) {
+        let mut emptied = Vec::new();
+        for (producer, appended) in self.sequences.iter_mut() {
+            let len = appended.len();
+            appended.retain(|&(_, offset)| offset < next_offset);
+            self.dirty |= appended.len() != len;
+            if appended.is_empty() {
+                emptied.push(*producer);
+            }
+        }
+        for producer in emptied {
+            self.sequences.remove(&producer);
+        }
+    }
+
+    /// Persists the sequences recorded since the last call.
+    pub fn persist(&mut self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let entries = self.sequences.iter().flat_map(|(producer, appended)| {
+            appended
+                .iter()
+                .map(move |&(seq, offset)| (*producer, seq, offset))
+        });
+        let mut bytes = Vec::with_capacity(self.sequences.len() * ENTRY_SIZE);
+        for (producer, seq, offset) in entries {
+            let mut buf = [0u8; ENTRY_SIZE];
+            LittleEndian::write_u64(&mut buf[0..8], producer);
+            LittleEndian::write_u64(&mut buf[8..16], seq);
+            LittleEndian::write_u64(&mut buf[16..24], offset);
+            bytes.extend_from_slice(&buf);
+        }
+
+        write_atomically(&self.path, PRODUCERS_TMP_FILE, &bytes)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+/// Decodes the sequences of each producer, persisted oldest first.
+fn decode(bytes: &[u8]) -> io::Result<BTreeMap<ProducerId, VecDeque<(u64, Offset)>>> {
+    if bytes.len() % ENTRY_SIZE != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid producer sequences file length",
+        ));
+    }
+
+    let mut sequences = BTreeMap::new();
+    for buf in bytes.chunks(ENTRY_SIZE) {
+        sequences
+            .entry(LittleEndian::read_u64(&buf[0..8]))
+            .or_insert_with(VecDeque::new)
+            .push_back((
+                LittleEndian::read_u64(&buf[8..16]),
+                LittleEndian::read_u64(&buf[16..24]),
+            ));
+    }
+    Ok(sequences)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn seq(producer_id: ProducerId, sequence: u64) -> ProducerSequence {
+        ProducerSequence {
+            producer_id,
+            sequence,
+        }
+    }
+
+    #[test]
+    fn duplicate_sequences_and_persist() {
+        let dir = env::temp_dir().join(format!("log-producers-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        {
+            let mut p = ProducerSequences::open(&dir).unwrap();
+            assert_eq!(None, p.appended(seq(1, 0)).unwrap());
+            p.record(seq(1, 0), 10);
+            p.record(seq(1, 1), 11);
+            p.record(seq(2, 5), 12);
+            p.persist().unwrap();
+        }
+
+        let mut p = ProducerSequences::open(&dir).unwrap();
+        assert_eq!(Some(11), p.appended(seq(1, 1)).unwrap());
+        assert_eq!(Some(10), p.appended(seq(1, 0)).unwrap());
+        assert_eq!(None, p.appended(seq(1, 2)).unwrap());
+        assert_eq!(Some(12), p.appended(seq(2, 5)).unwrap());
+        assert_eq!(
+            io::ErrorKind::AlreadyExists,
+            p.appended(seq(2, 4)).unwrap_err().kind()
+        );
+
+        p.truncate(11);
+        assert_eq!(None, p.appended(seq(2, 5)).unwrap());
+        assert_eq!(None, p.appended(seq(1, 1)).unwrap());
+        assert_eq!(Some(10), p.appended(seq(1, 0)).unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_latest_sequences() {
+        let dir = env::temp_dir().join(format!("log-producers-window-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut p = ProducerSequences::open(&dir).unwrap();
+        let n = MAX_SEQUENCES as u64 + 1;
+        for i in 0..n {
+            p.record(seq(1, i), 100 + i);
+        }
+        assert_eq!(Some(100 + n - 1), p.appended(seq(1, n - 1)).unwrap());
+        assert_eq!(Some(101), p.appended(seq(1, 1)).unwrap());
+        assert_eq!(
+            io::ErrorKind::AlreadyExists,
+            p.appended(seq(1, 0)).unwrap_err().kind()
+        );
+
+        p.persist().unwrap();
+        let p = ProducerSequences::open(&dir).unwrap();
+        assert_eq!(Some(101), p.appended(seq(1, 1)).unwrap());
+        assert!(p.appended(seq(1, 0)).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
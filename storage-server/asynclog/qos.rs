@@ -0,0 +1,212 @@
+use super::messages::SingleMessage;
+use super::trace::TraceId;
+use futures::{Async, Poll, Stream};
+use prometheus::{exponential_buckets, HistogramVec};
+use std::time::Instant;
+
+/// Number of consecutive high priority appends drained while bulk appends
+/// are waiting before a bulk append is drained.
+const BULK_INTERVAL: usize = 8;
+
+lazy_static! {
+    static ref APPEND_QUEUE_WAIT_HISTOGRAM: HistogramVec = register_histogram_vec!(
+        "log_append_queue_wait_us",
+        "Micros an append waits in the queue before batching, by priority.",
+        &["priority"],
+        exponential_buckets(10f64, 2f64, 16usize).unwrap()
+    )
+    .unwrap();
+}
+
+/// Priority class of an append.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Priority {
+    /// Latency sensitive appends, batched ahead of bulk appends.
+    High,
+
+    /// Throughput oriented appends, which yield to high priority appends.
+    Bulk,
+}
+
+impl Priority {
+    fn label(self) -> &'static str {
+        match self {
+            Priority::High => "high",
+            Priority::Bulk => "bulk",
+        }
+    }
+}
+
+/// Append queued with the time it was sent to the log thread and the trace
+/// of the request.
+pub type QueuedMessage = (Instant, TraceId, SingleMessage);
+
+/// Drains the appends of each priority class, preferring high priority
+/// appends.
+///
+/// Bulk appends are not starved: after `BULK_INTERVAL` consecutive high
+/// priority appends, a waiting bulk append is drained.
+pub struct PriorityStream<S> {
+    high: Option<S>,
+    bulk: Option<S>,
+    high_run: usize,
+}
+
+impl<S> PriorityStream<S>
+where
+    S: Stream<Item = QueuedMessage>,
+{
+    pub fn new(high: S, bulk: S) -> PriorityStream<S> {
+        PriorityStream {
+            high: Some(high),
+            bulk: Some(bulk),
+            high_run: 0,
+        }
+    }
+
+    fn poll_class(&mut self, priority: Priority) -> Result<Option<SingleMessage>, S::Error> {
+        let stream = match priority {
+            Priority::High => &mut self.high,
+            Priority::Bulk => &mut self.bulk,
+        };
+
+        let res = match *stream {
+            Some(ref mut s) => s.poll()?,
+            None => return Ok(None),
+        };
+
+        match res {
+            Async::Ready(Some((queued, trace_id, msg))) => {
+                let wait = queued.elapsed();
+                let wait_us = wait.as_secs() * 1_000_000 + u64::from(wait.subsec_micros());
+                APPEND_QUEUE_WAIT_HISTOGRAM
+                    .with_label_values(&[priority.label()])
+                    .observe(wait_us as f64);
+                trace!(
+                    "[trace {}] Batching append {} of client {} after {}us",
+                    trace_id,
+                    msg.1,
+                    msg.0,
+                    wait_us
+                );
+                Ok(Some(msg))
+            }
+            Async::Ready(None) => {
+                *stream = None;
+                Ok(None)
+            }
+            Async::NotReady => Ok(None),
+        }
+    }
+}
+
+impl<S> Stream for PriorityStream<S>
+where
+    S: Stream<Item = QueuedMessage>,
+{
+    type Item = SingleMessage;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<SingleMessage>, S::Error> {
+        let order = if self.high_run >= BULK_INTERVAL {
+            [Priority::Bulk, Priority::High]
+        } else {
+            [Priority::High, Priority::Bulk]
+        };
+
+        for &priority in &order {
+            if let Some(msg) = self.poll_class(priority)? {
+                self.high_run = match priority {
+                    Priority::High => self.high_run.saturating_add(1),
+                    Priority::Bulk => 0,
+                };
+                return Ok(Async::Ready(Some(msg)));
+            }
+        }
+
+        if self.high.is_none() && self.bulk.is_none() {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use futures::stream;
+
+    fn queued(client: u64, n: u64) -> Vec<QueuedMessage> {
+        let now = Instant::now();
+        (0..n)
+            .map(|i| (now, 0, (client, i, Bytes::new())))
+            .collect()
+    }
+
+    #[test]
+    fn high_priority_drained_first_without_starvation() {
+        const HIGH: u64 = 1;
+        const BULK: u64 = 2;
+
+        let mut s = PriorityStream::new(
+            stream::iter_ok::<_, ()>(queued(HIGH, 40)),
+            stream::iter_ok::<_, ()>(queued(BULK, 40)),
+        );
+
+        let mut drained = Vec::new();
+        while let Ok(Async::Ready(Some((client, req, _)))) = s.poll() {
+            drained.push((client, req));
+        }
+        assert_eq!(80, drained.len());
+        let clients = drained.iter().map(|&(c, _)| c).collect::<Vec<_>>();
+
+        // position in the drain order stands in for the queue latency
+        let mean_position = |client| {
+            let positions = clients
+                .iter()
+                .enumerate()
+                .filter(|&(_, &c)| c == client)
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>();
+            positions.iter().sum::<usize>() / positions.len()
+        };
+        assert!(mean_position(HIGH) < mean_position(BULK));
+
+        // bulk appends make progress under a steady load of high priority appends
+        assert_eq!(BULK, clients[BULK_INTERVAL]);
+        assert_eq!(
+            vec![HIGH; BULK_INTERVAL],
+            clients[BULK_INTERVAL + 1..2 * BULK_INTERVAL + 1].to_vec()
+        );
+
+        // order within a class is retained
+        for &class in &[HIGH, BULK] {
+            let reqs = drained
+                .iter()
+                .filter(|&&(c, _)| c == class)
+                .map(|&(_, r)| r)
+                .collect::<Vec<_>>();
+            assert_eq!((0..40).collect::<Vec<_>>(), reqs);
+        }
+    }
+
+    #[test]
+    fn drains_bulk_when_no_high_priority() {
+        let mut s = PriorityStream::new(
+            stream::iter_ok::<_, ()>(Vec::new()),
+            stream::iter_ok::<_, ()>(queued(2, 3)),
+        );
+        for i in 0..3 {
+            match s.poll() {
+                Ok(Async::Ready(Some((2, req, _)))) => assert_eq!(i, req),
+                _ => panic!("Expected bulk append"),
+            }
+        }
+        assert!(match s.poll() {
+            Ok(Async::Ready(None)) => true,
+            _ => false,
+        });
+    }
+}
@@ -0,0 +1,498 @@
+use super::read_only::read_only_error;
+use super::{ClientRequest, DEFAULT_TOPIC};
+use futures::executor::{self, Notify};
+use futures::future;
+use futures::task::{self, Task};
+use futures::{Async, Future, Poll, Stream};
+use prometheus::{Counter, Gauge, GaugeVec};
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+use tokio_sync::mpsc;
+
+lazy_static! {
+    static ref APPEND_QUEUE_LENGTH: GaugeVec = register_gauge_vec!(
+        "log_append_queue_length",
+        "Number of appends queued for the log thread.",
+        &["topic"]
+    )
+    .unwrap();
+    static ref ABANDONED_READS: Counter = register_counter!(opts!(
+        "log_abandoned_reads",
+        "Number of reads skipped as the requester dropped the request.",
+        labels! {"mod" => "log",}
+    ))
+    .unwrap();
+    static ref BUSY_READS: Counter = register_counter!(opts!(
+        "log_busy_reads",
+        "Number of reads failed after waiting for a slot in the full read queue.",
+        labels! {"mod" => "log",}
+    ))
+    .unwrap();
+}
+
+/// Tracks the number of appends sent to the log thread that have not yet
+/// been drained into a batch, optionally bounding the queue.
+#[derive(Clone)]
+pub struct AppendQueue {
+    len: Arc<AtomicUsize>,
+    // appends drained into a batch
+    total: Arc<AtomicU64>,
+    max: Option<usize>,
+    // tasks waiting for a slot in a bounded queue
+    waiters: Arc<Mutex<Vec<Task>>>,
+    length: Gauge,
+}
+
+impl AppendQueue {
+    pub fn new(max: Option<usize>) -> AppendQueue {
+        AppendQueue {
+            len: Arc::new(AtomicUsize::new(0)),
+            total: Arc::new(AtomicU64::new(0)),
+            max,
+            waiters: Arc::new(Mutex::new(Vec::new())),
+            length: APPEND_QUEUE_LENGTH.with_label_values(&[DEFAULT_TOPIC]),
+        }
+    }
+
+    /// Reports the length of the queue as that of the log of the topic.
+    pub fn with_topic(mut self, topic: &str) -> AppendQueue {
+        self.length = APPEND_QUEUE_LENGTH.with_label_values(&[topic]);
+        self
+    }
+
+    /// Number of appends pending in the queue.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    /// Number of appends drained from the queue into a batch.
+    #[inline]
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// Reserves a slot in the queue, returning false if the queue is full.
+    pub fn try_push(&self) -> bool {
+        match self.max {
+            None => {
+                self.len.fetch_add(1, Ordering::AcqRel);
+                true
+            }
+            Some(max) => {
+                let mut len = self.len.load(Ordering::Acquire);
+                loop {
+                    if len >= max {
+                        return false;
+                    }
+                    match self.len.compare_exchange_weak(
+                        len,
+                        len + 1,
+                        Ordering::AcqRel,
+                        Ordering::Acquire,
+                    ) {
+                        Ok(_) => return true,
+                        Err(actual) => len = actual,
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reserves a slot in the queue, parking the current task until a slot
+    /// is released if the queue is full.
+    pub fn poll_push(&self) -> Async<()> {
+        if self.try_push() {
+            return Async::Ready(());
+        }
+
+        self.waiters.lock().unwrap().push(task::current());
+        // a slot released before the task was registered has no task to notify
+        if self.try_push() {
+            Async::Ready(())
+        } else {
+            Async::NotReady
+        }
+    }
+
+    /// Releases a slot after the append has been drained from the queue.
+    #[inline]
+    fn pop(&self) {
+        let prev = self.len.fetch_sub(1, Ordering::AcqRel);
+        debug_assert!(prev > 0, "Append queue length underflow");
+        self.total.fetch_add(1, Ordering::Relaxed);
+        self.length.set((prev - 1) as f64);
+
+        if self.max.is_some() {
+            for waiter in self.waiters.lock().unwrap().drain(..) {
+                waiter.notify();
+            }
+        }
+    }
+}
+
+/// Counts the reads sent to the log thread that have not yet been received
+/// by the log thread, optionally bounding the queue.
+#[derive(Clone, Default)]
+pub struct ReadQueue {
+    // a read may be received before it is counted as sent, so the depth is
+    // the difference of the totals rather than a counter that may underflow
+    sent: Arc<AtomicU64>,
+    received: Arc<AtomicU64>,
+    abandoned: Arc<AtomicU64>,
+    max: Option<usize>,
+    // time a read waits for a slot in a bounded queue
+    timeout: Duration,
+    // tasks waiting for a slot in a bounded queue
+    waiters: Arc<Mutex<Vec<Task>>>,
+}
+
+impl ReadQueue {
+    pub fn new(max: Option<usize>, timeout: Duration) -> ReadQueue {
+        ReadQueue {
+            max,
+            timeout,
+            ..ReadQueue::default()
+        }
+    }
+
+    /// Number of reads pending in the queue.
+    #[inline]
+    pub fn len(&self) -> usize {
+        let received = self.received.load(Ordering::Relaxed);
+        self.sent.load(Ordering::Relaxed).saturating_sub(received) as usize
+    }
+
+    /// Number of reads received by the log thread.
+    #[inline]
+    pub fn total(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    /// Time a read waits for a slot in a bounded queue.
+    #[inline]
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Counts a read sent to the log thread.
+    #[inline]
+    pub fn push(&self) {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Reserves a slot for a read before it is sent, returning false if the
+    /// queue is full.
+    pub fn try_push(&self) -> bool {
+        let max = match self.max {
+            Some(max) => max as u64,
+            None => {
+                self.push();
+                return true;
+            }
+        };
+        let mut sent = self.sent.load(Ordering::Acquire);
+        loop {
+            // reads received since the load only free more slots
+            if sent.saturating_sub(self.received.load(Ordering::Acquire)) >= max {
+                return false;
+            }
+            match self.sent.compare_exchange_weak(
+                sent,
+                sent + 1,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return true,
+                Err(actual) => sent = actual,
+            }
+        }
+    }
+
+    /// Reserves a slot in the queue, parking the current task until a slot
+    /// is released if the queue is full.
+    pub fn poll_push(&self) -> Async<()> {
+        if self.try_push() {
+            return Async::Ready(());
+        }
+
+        self.waiters.lock().unwrap().push(task::current());
+        // a slot released before the task was registered has no task to notify
+        if self.try_push() {
+            Async::Ready(())
+        } else {
+            Async::NotReady
+        }
+    }
+
+    /// Reserves a slot as `poll_push`, blocking the current thread until a
+    /// slot is released, for threads outside of a task such as the read
+    /// workers. Fails with `ErrorKind::WouldBlock` if no slot is released
+    /// by the deadline.
+    pub fn push_until(&self, deadline: Instant) -> Result<(), Error> {
+        if self.try_push() {
+            return Ok(());
+        }
+
+        let notify = Arc::new(ThreadNotify(thread::current()));
+        let mut push = executor::spawn(future::poll_fn(|| Ok::<_, ()>(self.poll_push())));
+        loop {
+            if let Ok(Async::Ready(())) = push.poll_future_notify(&notify, 0) {
+                return Ok(());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(self.busy_error());
+            }
+            thread::park_timeout(deadline - now);
+        }
+    }
+
+    /// Error of a read that found no slot in time, as the log is busy.
+    fn busy_error(&self) -> Error {
+        BUSY_READS.inc();
+        Error::new(
+            ErrorKind::WouldBlock,
+            format!("Log is busy, {} reads pending", self.len()),
+        )
+    }
+
+    /// Releases the slot of a read that could not be sent.
+    #[inline]
+    pub fn cancel(&self) {
+        self.sent.fetch_sub(1, Ordering::AcqRel);
+        self.notify_waiters();
+    }
+
+    /// Counts a read received by the log thread.
+    #[inline]
+    pub fn pop(&self) {
+        self.received.fetch_add(1, Ordering::AcqRel);
+        self.notify_waiters();
+    }
+
+    fn notify_waiters(&self) {
+        if self.max.is_some() {
+            for waiter in self.waiters.lock().unwrap().drain(..) {
+                waiter.notify();
+            }
+        }
+    }
+
+    /// Number of reads received after the requester dropped the request,
+    /// skipped by the log thread.
+    #[inline]
+    pub fn abandoned(&self) -> u64 {
+        self.abandoned.load(Ordering::Relaxed)
+    }
+
+    /// Counts a read skipped by the log thread.
+    #[inline]
+    pub fn abandon(&self) {
+        self.abandoned.fetch_add(1, Ordering::Relaxed);
+        ABANDONED_READS.inc();
+    }
+}
+
+/// Wakes a thread blocked on a slot in the read queue.
+struct ThreadNotify(Thread);
+
+impl Notify for ThreadNotify {
+    fn notify(&self, _id: usize) {
+        self.0.unpark();
+    }
+}
+
+/// Read waiting for a slot in the full read queue before it is sent to the
+/// log thread. Fails with `ErrorKind::WouldBlock` if no slot is released
+/// within the timeout of the queue, as the log is busy.
+pub struct QueuedRead {
+    queue: ReadQueue,
+    req_sink: mpsc::UnboundedSender<ClientRequest>,
+    req: Option<ClientRequest>,
+    deadline: Delay,
+}
+
+impl QueuedRead {
+    pub fn new(
+        queue: ReadQueue,
+        req_sink: mpsc::UnboundedSender<ClientRequest>,
+        req: ClientRequest,
+    ) -> QueuedRead {
+        let deadline = Delay::new(Instant::now() + queue.timeout);
+        QueuedRead {
+            queue,
+            req_sink,
+            req: Some(req),
+            deadline,
+        }
+    }
+
+    /// Sends the read once the queue has a slot.
+    pub fn poll_send(&mut self) -> Poll<(), Error> {
+        if let Async::Ready(()) = self.queue.poll_push() {
+            let req = self
+                .req
+                .take()
+                .expect("Queued read polled after it was sent");
+            if self.req_sink.try_send(req).is_err() {
+                self.queue.cancel();
+                return Err(read_only_error());
+            }
+            return Ok(Async::Ready(()));
+        }
+
+        match self.deadline.poll() {
+            Ok(Async::Ready(())) => Err(self.queue.busy_error()),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(e) => Err(Error::new(ErrorKind::Other, e)),
+        }
+    }
+}
+
+/// Stream of queued appends that releases the queue slot of each item
+/// as it is drained.
+pub struct QueueStream<S> {
+    stream: S,
+    queue: AppendQueue,
+}
+
+impl<S: Stream> QueueStream<S> {
+    pub fn new(stream: S, queue: AppendQueue) -> QueueStream<S> {
+        QueueStream { stream, queue }
+    }
+}
+
+impl<S: Stream> Stream for QueueStream<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        let item = try_ready!(self.stream.poll());
+        if item.is_some() {
+            self.queue.pop();
+        }
+        Ok(Async::Ready(item))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::{poll_fn, Future};
+    use futures::stream;
+    use futures::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn length_is_sends_minus_drained() {
+        let queue = AppendQueue::new(None);
+        for _ in 0..5 {
+            assert!(queue.try_push());
+        }
+        assert_eq!(5, queue.len());
+
+        let mut s = QueueStream::new(stream::iter_ok::<_, ()>(0..5), queue.clone());
+        assert_eq!(Ok(Async::Ready(Some(0))), s.poll());
+        assert_eq!(Ok(Async::Ready(Some(1))), s.poll());
+        assert_eq!(3, queue.len());
+
+        assert!(queue.try_push());
+        assert_eq!(4, queue.len());
+        assert_eq!(2, queue.total());
+    }
+
+    #[test]
+    fn bounded_rejects_past_limit() {
+        let queue = AppendQueue::new(Some(2));
+        assert!(queue.try_push());
+        assert!(queue.try_push());
+        assert!(!queue.try_push());
+        assert_eq!(2, queue.len());
+
+        let mut s = QueueStream::new(stream::iter_ok::<_, ()>(0..2), queue.clone());
+        assert_eq!(Ok(Async::Ready(Some(0))), s.poll());
+        assert!(queue.try_push());
+        assert!(!queue.try_push());
+    }
+
+    #[test]
+    fn bounded_parks_producer() {
+        let queue = AppendQueue::new(Some(4));
+        let (snd, rcv) = mpsc::unbounded::<usize>();
+
+        // a slow consumer, as for a slow disk
+        let consumer = QueueStream::new(rcv, queue.clone());
+        let consumed = thread::spawn(move || {
+            consumer
+                .wait()
+                .map(|_| thread::sleep(Duration::from_millis(1)))
+                .count()
+        });
+
+        let mut max_len = 0;
+        for i in 0..200 {
+            poll_fn(|| Ok::<_, ()>(queue.poll_push())).wait().unwrap();
+            max_len = max_len.max(queue.len());
+            snd.unbounded_send(i).unwrap();
+        }
+        drop(snd);
+
+        assert_eq!(200, consumed.join().unwrap());
+        assert!(max_len <= 4);
+        assert_eq!(0, queue.len());
+    }
+
+    #[test]
+    fn bounded_reads_wait_for_a_slot() {
+        let queue = ReadQueue::new(Some(2), Duration::from_millis(100));
+        assert!(queue.try_push());
+        assert!(queue.try_push());
+        assert!(!queue.try_push());
+
+        // a slot is released as the log thread receives a read
+        let parked = queue.clone();
+        let waiter = thread::spawn(move || {
+            poll_fn(|| Ok::<_, ()>(parked.poll_push())).wait().unwrap();
+        });
+        thread::sleep(Duration::from_millis(10));
+        queue.pop();
+        waiter.join().unwrap();
+        assert_eq!(2, queue.len());
+
+        // as does a read that could not be sent
+        queue.cancel();
+        assert_eq!(1, queue.len());
+        assert!(queue.try_push());
+        assert!(!queue.try_push());
+    }
+
+    #[test]
+    fn blocked_reads_wait_until_the_deadline() {
+        let queue = ReadQueue::new(Some(1), Duration::from_millis(100));
+        assert!(queue.push_until(Instant::now()).is_ok());
+
+        let err = queue
+            .push_until(Instant::now() + Duration::from_millis(20))
+            .unwrap_err();
+        assert_eq!(ErrorKind::WouldBlock, err.kind());
+        assert_eq!(1, queue.len());
+
+        // the thread is woken as the log thread receives a read
+        let received = queue.clone();
+        let receiver = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            received.pop();
+        });
+        assert!(queue
+            .push_until(Instant::now() + Duration::from_secs(5))
+            .is_ok());
+        receiver.join().unwrap();
+        assert_eq!(1, queue.len());
+    }
+}
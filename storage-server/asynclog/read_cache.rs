@@ -0,0 +1,155 @@
+use super::Messages;
+use commitlog::message::MessageSet;
+use commitlog::Offset;
+use prometheus::Counter;
+use std::collections::VecDeque;
+
+lazy_static! {
+    static ref READ_CACHE_HITS: Counter = register_counter!(opts!(
+        "log_read_cache_hits",
+        "Number of reads served from the read cache.",
+        labels! {"mod" => "log",}
+    ))
+    .unwrap();
+    static ref READ_CACHE_MISSES: Counter = register_counter!(opts!(
+        "log_read_cache_misses",
+        "Number of reads decoded from the log.",
+        labels! {"mod" => "log",}
+    ))
+    .unwrap();
+}
+
+struct Entry {
+    offset: Offset,
+    max_bytes: usize,
+    messages: Messages,
+    /// Whether the read was bounded by the limit rather than the end of
+    /// the log. Incomplete reads may return more messages after an append.
+    complete: bool,
+}
+
+/// Cache of recently decoded reads, shared by readers of the same offsets.
+///
+/// Many subscribers tailing the log read the same offsets. The cache saves
+/// reading and decoding the segment for each of them.
+pub struct ReadCache {
+    capacity: usize,
+    entries: VecDeque<Entry>,
+}
+
+impl ReadCache {
+    /// Creates a cache with the number of entries. A capacity of zero disables
+    /// the cache.
+    pub fn new(capacity: usize) -> ReadCache {
+        ReadCache {
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn get(&self, offset: Offset, max_bytes: usize) -> Option<Messages> {
+        if self.capacity == 0 {
+            return None;
+        }
+
+        let hit = self
+            .entries
+            .iter()
+            .find(|e| e.offset == offset && e.max_bytes == max_bytes)
+            .map(|e| e.messages.clone());
+        if hit.is_some() {
+            READ_CACHE_HITS.inc();
+        } else {
+            READ_CACHE_MISSES.inc();
+        }
+        hit
+    }
+
+    /// Caches the messages read. The read is complete if there are messages
+    /// in the log after those read.
+    pub fn insert(&mut self, offset: Offset, max_bytes: usize, messages: Messages, complete: bool) {
+        if self.capacity == 0 || messages.len() == 0 {
+            return;
+        }
+
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(Entry {
+            offset,
+            max_bytes,
+            messages,
+            complete,
+        });
+    }
+
+    /// Removes the reads that ended at the end of the log, after an append.
+    pub fn invalidate_incomplete(&mut self) {
+        self.entries.retain(|e| e.complete);
+    }
+
+    /// Removes all reads, after the contents of the log have changed.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asynclog::MessagesMut;
+    use bytes::BytesMut;
+    use commitlog::message::set_offsets;
+
+    fn messages(offset: Offset, n: usize) -> Messages {
+        let mut buf = MessagesMut(BytesMut::with_capacity(1024));
+        for i in 0..n {
+            buf.push(0, i as u64, b"0123456789").unwrap();
+        }
+        set_offsets(&mut buf, offset);
+        Messages::copy_from(&buf)
+    }
+
+    #[test]
+    fn hit_by_offset_and_limit() {
+        let mut cache = ReadCache::new(4);
+        cache.insert(10, 100, messages(10, 2), true);
+
+        assert_eq!(2, cache.get(10, 100).unwrap().len());
+        assert!(cache.get(10, 200).is_none());
+        assert!(cache.get(11, 100).is_none());
+    }
+
+    #[test]
+    fn evicts_oldest() {
+        let mut cache = ReadCache::new(2);
+        cache.insert(0, 100, messages(0, 1), true);
+        cache.insert(1, 100, messages(1, 1), true);
+        cache.insert(2, 100, messages(2, 1), true);
+
+        assert!(cache.get(0, 100).is_none());
+        assert!(cache.get(1, 100).is_some());
+        assert!(cache.get(2, 100).is_some());
+    }
+
+    #[test]
+    fn invalidates_incomplete_reads() {
+        let mut cache = ReadCache::new(4);
+        cache.insert(0, 100, messages(0, 1), true);
+        cache.insert(1, 100, messages(1, 1), false);
+        cache.invalidate_incomplete();
+
+        assert!(cache.get(0, 100).is_some());
+        assert!(cache.get(1, 100).is_none());
+
+        cache.clear();
+        assert!(cache.get(0, 100).is_none());
+    }
+
+    #[test]
+    fn disabled_with_zero_capacity() {
+        let mut cache = ReadCache::new(0);
+        cache.insert(0, 100, messages(0, 1), true);
+        assert!(cache.get(0, 100).is_none());
+    }
+}
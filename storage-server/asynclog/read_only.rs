@@ -0,0 +1,193 @@
+use super::cursor::{read_page, Cursor};
+use super::retention::segments;
+use super::tail::read_tail;
+use super::tombstone::Tombstones;
+use super::{log_options, Messages};
+use commitlog::{CommitLog, Offset, ReadLimit};
+use config::LogConfig;
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Error for writes to the log once the log thread has failed. See
+/// `ReadOnlyLog::failure` for the cause.
+pub fn read_only_error() -> Error {
+    Error::new(
+        ErrorKind::BrokenPipe,
+        "Log thread failed, the log is read-only",
+    )
+}
+
+/// Serves reads once the log thread has failed, from a separate handle to
+/// the log opened on first use.
+///
+/// Reads block the calling thread, as there is no log thread to serve them.
+pub struct ReadOnlyLog {
+    cfg: LogConfig,
+    active: AtomicBool,
+    cause: Mutex<Option<String>>,
+    log: Mutex<Option<(CommitLog, Tombstones)>>,
+}
+
+impl ReadOnlyLog {
+    pub fn new(cfg: &LogConfig) -> ReadOnlyLog {
+        ReadOnlyLog {
+            cfg: cfg.clone(),
+            active: AtomicBool::new(false),
+            cause: Mutex::new(None),
+            log: Mutex::new(None),
+        }
+    }
+
+    /// Tests whether the log thread has failed.
+    #[inline]
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Acquire)
+    }
+
+    /// Marks the log read-only, keeping the first cause reported.
+    pub fn fail(&self, cause: String) {
+        {
+            let mut first = self.cause.lock().unwrap();
+            if first.is_none() {
+                error!("Log thread failed, the log is read-only: {}", cause);
+                *first = Some(cause);
+            }
+        }
+        self.active.store(true, Ordering::Release);
+    }
+
+    /// The terminal error of the log thread, once it has failed.
+    pub fn failure(&self) -> Option<Error> {
+        if !self.is_active() {
+            return None;
+        }
+
+        let cause = self.cause.lock().unwrap();
+        Some(Error::new(
+            ErrorKind::BrokenPipe,
+            format!(
+                "Log thread failed, the log is read-only: {}",
+                cause
+                    .as_ref()
+                    .map(|s| s.as_str())
+                    .unwrap_or("unknown cause")
+            ),
+        ))
+    }
+
+    pub fn read(&self, offset: Offset, max_bytes: usize) -> Result<Messages, Error> {
+        self.with_log(
+            |log, tombstones| match log.read(offset, ReadLimit::max_bytes(max_bytes)) {
+                Ok(ref v) if tombstones.is_empty() => Ok(Messages::copy_from(v)),
+                Ok(ref v) => Ok(Messages::copy_filtered(v, |off| !tombstones.contains(off))),
+                Err(_) => Err(Error::new(ErrorKind::Other, "read error")),
+            },
+        )
+    }
+
+    pub fn tail(&self, n: usize) -> Result<Messages, Error> {
+        let next_offset = self.with_log(|log, _| Ok(log.next_offset()))?;
+        read_tail(next_offset, n, |pos, max_bytes| self.read(pos, max_bytes))
+    }
+
+    pub fn read_page(
+        &self,
+        cursor: Cursor,
+        max_bytes: usize,
+    ) -> Result<(Messages, Option<Cursor>), Error> {
+        let first_offset = segments(&self.cfg.dir)?.first().map(|s| s.base_offset);
+        let next_offset = self.with_log(|log, _| Ok(log.next_offset()))?;
+        read_page(cursor, first_offset, next_offset, |pos| {
+            self.read(pos, max_bytes)
+        })
+    }
+
+    pub fn last_offset(&self) -> Result<Option<Offset>, Error> {
+        self.with_log(|log, _| Ok(log.last_offset()))
+    }
+
+    fn with_log<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&CommitLog, &Tombstones) -> Result<T, Error>,
+    {
+        let mut log = self
+            .log
+            .lock()
+            .map_err(|_| Error::new(ErrorKind::Other, "Read-only log poisoned"))?;
+        if log.is_none() {
+            info!("Opening the log read-only");
+            let commit_log = CommitLog::new(log_options(&self.cfg))?;
+            let tombstones = Tombstones::open(&self.cfg.dir)?;
+            *log = Some((commit_log, tombstones));
+        }
+
+        let (ref commit_log, ref tombstones) = *log.as_ref().unwrap();
+        f(commit_log, tombstones)
+    }
+}
+
+/// Owned by the log thread, marks the log read-only when the thread exits,
+/// including by a panic.
+pub struct WriterGuard(pub Arc<ReadOnlyLog>);
+
+impl WriterGuard {
+    /// Records the error ending the log thread.
+    pub fn fail(&self, e: &Error) {
+        self.0.fail(e.to_string());
+    }
+}
+
+impl Drop for WriterGuard {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.0.fail("log thread panicked".to_string());
+        } else {
+            self.0.fail("log thread exited".to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use commitlog::message::MessageSet;
+    use std::{env, fs, process, thread};
+
+    #[test]
+    fn reads_after_writer_failure() {
+        let dir = env::temp_dir().join(format!("log-read-only-test-{}", process::id()));
+        let mut cfg = LogConfig::default();
+        cfg.dir = dir.to_string_lossy().into_owned();
+
+        let read_only = Arc::new(ReadOnlyLog::new(&cfg));
+        let guard = WriterGuard(read_only.clone());
+        let writer_cfg = cfg.clone();
+        let res = thread::spawn(move || {
+            let _guard = guard;
+            let mut log = CommitLog::new(log_options(&writer_cfg)).unwrap();
+            log.append_msg("foo").unwrap();
+            log.append_msg("bar").unwrap();
+            log.flush().unwrap();
+            panic!("writer failure");
+        })
+        .join();
+        assert!(res.is_err());
+        assert!(read_only.is_active());
+        let err = read_only.failure().unwrap();
+        assert_eq!(ErrorKind::BrokenPipe, err.kind());
+        assert!(err.to_string().contains("log thread panicked"));
+
+        let msgs = read_only.read(0, 4096).unwrap();
+        assert_eq!(
+            vec![b"foo".to_vec(), b"bar".to_vec()],
+            msgs.iter()
+                .map(|m| m.payload().to_vec())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Some(1), read_only.last_offset().unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,715 @@
+use super::messages::{verify_hashes, Messages};
+use super::queue::ReadQueue;
+use super::read_only::ReadOnlyLog;
+use super::retention::SegmentInfo;
+use super::sync::{channel, LogFuture, LogSender};
+use super::tombstone::Tombstones;
+use super::ClientRequest;
+use commitlog::message::MessageSet;
+use commitlog::Offset;
+use prometheus::Counter;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, Error, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread;
+use std::time::Instant;
+use tokio_sync::mpsc;
+
+lazy_static! {
+    static ref COALESCED_READS: Counter = register_counter!(opts!(
+        "log_coalesced_reads",
+        "Number of reads of sealed segments served by an identical pending read.",
+        labels! {"mod" => "log",}
+    ))
+    .unwrap();
+}
+
+/// Bytes read at a time when scanning a segment for the position of an entry.
+const SCAN_BYTES: usize = 1_048_576;
+
+/// Segments closed by a roll, which the log thread no longer writes, as last
+/// published by the log thread.
+#[derive(Clone)]
+pub struct SealedView {
+    // base offsets of the sealed segments, ascending
+    bases: Vec<Offset>,
+    // base offset of the active segment, ending the sealed segments
+    end: Offset,
+    low_watermark: Offset,
+    tombstones: Tombstones,
+    // segments withdrawn before a change, or tombstones changed, so the
+    // workers discard the reads made with an earlier view that may have
+    // overlapped the change
+    withdrawals: u64,
+}
+
+/// View shared by the log thread and the read workers, replaced by the log
+/// thread as segments roll or are deleted.
+pub type SharedView = Arc<RwLock<Arc<SealedView>>>;
+
+impl SealedView {
+    /// View without sealed segments, until the segments are published.
+    pub fn new(tombstones: Tombstones) -> SealedView {
+        SealedView {
+            bases: Vec::new(),
+            end: 0,
+            low_watermark: 0,
+            tombstones,
+            withdrawals: 0,
+        }
+    }
+
+    /// Replaces the sealed segments with the segments of the log directory,
+    /// all but the last of which are sealed.
+    pub fn set_segments(&mut self, segments: &[SegmentInfo]) {
+        match segments.split_last() {
+            Some((active, sealed)) => {
+                self.bases = sealed.iter().map(|s| s.base_offset).collect();
+                self.end = active.base_offset;
+            }
+            None => {
+                self.bases.clear();
+                self.end = 0;
+            }
+        }
+    }
+
+    pub fn set_low_watermark(&mut self, low_watermark: Offset) {
+        self.low_watermark = low_watermark;
+    }
+
+    /// Replaces the tombstones, withdrawing the entries newly tombstoned
+    /// from the reads made with an earlier view.
+    pub fn set_tombstones(&mut self, tombstones: Tombstones) {
+        if tombstones != self.tombstones {
+            self.tombstones = tombstones;
+            self.withdrawals += 1;
+        }
+    }
+
+    /// Withdraws the sealed segment holding the offset and every later
+    /// sealed segment, as they are about to be truncated or rewritten.
+    pub fn withdraw_from(&mut self, offset: Offset) {
+        let keep = match self.bases.binary_search(&offset) {
+            Ok(i) => i,
+            Err(i) => i.saturating_sub(1),
+        };
+        if offset >= self.end || keep >= self.bases.len() {
+            return;
+        }
+        self.end = self.bases[keep];
+        self.bases.truncate(keep);
+        self.withdrawals += 1;
+    }
+
+    /// Withdraws the sealed segments below the offset, as they are about to
+    /// be deleted.
+    pub fn withdraw_below(&mut self, offset: Offset) {
+        if offset > self.low_watermark {
+            self.low_watermark = offset;
+            self.withdrawals += 1;
+        }
+    }
+
+    /// Tests whether segments have been withdrawn since this view, so a read
+    /// made with the view may have overlapped a change of its segment.
+    fn is_stale(&self, current: &SealedView) -> bool {
+        self.withdrawals != current.withdrawals
+    }
+
+    /// Base offset of the sealed segment containing the offset.
+    fn segment(&self, offset: Offset) -> Option<Offset> {
+        if offset < self.low_watermark || offset >= self.end {
+            return None;
+        }
+        match self.bases.binary_search(&offset) {
+            Ok(i) => Some(self.bases[i]),
+            Err(0) => None,
+            Err(i) => Some(self.bases[i - 1]),
+        }
+    }
+}
+
+/// Publishes the view with segments withdrawn before the log thread changes
+/// them. The segments are published again once changed.
+pub fn withdraw<F: FnOnce(&mut SealedView)>(view: &SharedView, withdraw: F) {
+    let mut next = SealedView::clone(&view.read().unwrap());
+    withdraw(&mut next);
+    *view.write().unwrap() = Arc::new(next);
+}
+
+/// Threads serving reads of sealed segments from the segment files, so a
+/// large read does not hold up the appends on the log thread. Appends and
+/// every other request remain ordered on the log thread.
+///
+/// Reads the workers cannot serve exactly as the log thread would, such as
+/// of a segment deleted since the view was published, or withdrawn or
+/// tombstoned during the read, are sent on to the log thread through the
+/// read queue, as reads sent by `AsyncLog::read`.
+///
+/// Reads of the same offset and size as a read pending on the workers, as
+/// when many consumers follow the same offsets, wait for the pending read
+/// rather than reading the segment again, unless the view has changed since
+/// the pending read was sent.
+#[derive(Clone)]
+pub struct ReadWorkers {
+    jobs: Arc<Jobs>,
+    view: SharedView,
+    // closes the jobs once the last handle is dropped
+    _handle: Arc<JobsHandle>,
+}
+
+impl ReadWorkers {
+    pub(super) fn spawn(
+        threads: usize,
+        dir: PathBuf,
+        view: SharedView,
+        verify_reads: bool,
+        req_sink: mpsc::UnboundedSender<ClientRequest>,
+        read_queue: ReadQueue,
+        read_only: Arc<ReadOnlyLog>,
+    ) -> ReadWorkers {
+        let jobs = Arc::new(Jobs::default());
+        for i in 0..threads {
+            let mut reader = SegmentReader::new(dir.clone(), verify_reads);
+            let mut fallback = Fallback {
+                req_sink: req_sink.clone(),
+                read_queue: read_queue.clone(),
+                read_only: read_only.clone(),
+            };
+            let jobs = jobs.clone();
+            let view = view.clone();
+            thread::Builder::new()
+                .name(format!("log-reader-{}", i))
+                .spawn(move || {
+                    while let Some(job) = jobs.take() {
+                        let sealed = view.read().unwrap().clone();
+                        let read = match reader.read(&sealed, job.offset, job.max_bytes) {
+                            // the segment may have changed during the read
+                            Ok(Some(_)) if sealed.is_stale(&view.read().unwrap()) => None,
+                            Ok(msgs) => msgs,
+                            Err(e) => {
+                                debug!("Read of offset {} left to the log: {}", job.offset, e);
+                                None
+                            }
+                        };
+                        match read {
+                            Some(msgs) => jobs.complete(job, Ok(msgs)),
+                            None => fallback.read(job, jobs.waiting(job)),
+                        }
+                    }
+                })
+                .expect("Unable to spawn log reader thread");
+        }
+
+        ReadWorkers {
+            _handle: Arc::new(JobsHandle(jobs.clone())),
+            jobs,
+            view,
+        }
+    }
+
+    /// Tests whether the offset is in a sealed segment, served by the workers.
+    pub fn is_sealed(&self, offset: Offset) -> bool {
+        self.view.read().unwrap().segment(offset).is_some()
+    }
+
+    pub fn read(&self, offset: Offset, max_bytes: usize) -> LogFuture<Messages> {
+        let (res, f) = channel();
+        let withdrawals = self.view.read().unwrap().withdrawals;
+        let job = ReadJob {
+            offset,
+            max_bytes,
+            withdrawals,
+        };
+        self.jobs.push(job, res);
+        f
+    }
+}
+
+/// Read of a sealed segment, shared by the identical reads pending on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct ReadJob {
+    offset: Offset,
+    max_bytes: usize,
+    // withdrawals of the view when the read was sent, so a read sent after
+    // a change never waits on a job the workers may have read before it
+    withdrawals: u64,
+}
+
+#[derive(Default)]
+struct JobQueue {
+    queue: VecDeque<ReadJob>,
+    // results waiting on each queued or running job
+    waiting: HashMap<ReadJob, Vec<LogSender<Messages>>>,
+    // whether the workers are to exit
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Jobs {
+    queue: Mutex<JobQueue>,
+    ready: Condvar,
+    // segment reads taken by the workers, after coalescing
+    reads: AtomicU64,
+}
+
+impl Jobs {
+    /// Queues the read, or adds the result to the identical job pending with
+    /// the same view.
+    fn push(&self, job: ReadJob, res: LogSender<Messages>) {
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(waiting) = queue.waiting.get_mut(&job) {
+            waiting.push(res);
+            COALESCED_READS.inc();
+            return;
+        }
+        queue.waiting.insert(job, vec![res]);
+        queue.queue.push_back(job);
+        self.ready.notify_one();
+    }
+
+    /// Waits for the next job, or `None` once closed.
+    fn take(&self) -> Option<ReadJob> {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            if queue.closed {
+                return None;
+            }
+            if let Some(job) = queue.queue.pop_front() {
+                self.reads.fetch_add(1, Ordering::Relaxed);
+                return Some(job);
+            }
+            queue = self.ready.wait(queue).unwrap();
+        }
+    }
+
+    /// Takes the reads waiting on the job, completing the job.
+    fn waiting(&self, job: ReadJob) -> Vec<LogSender<Messages>> {
+        self.queue
+            .lock()
+            .unwrap()
+            .waiting
+            .remove(&job)
+            .unwrap_or_default()
+    }
+
+    /// Sends the result of the job to each read waiting on it.
+    fn complete(&self, job: ReadJob, res: Result<Messages, Error>) {
+        let waiting = self.waiting(job);
+        match res {
+            Ok(msgs) => {
+                for res in waiting {
+                    res.send(msgs.clone());
+                }
+            }
+            Err(e) => {
+                for res in waiting {
+                    res.send_err(Error::new(e.kind(), e.to_string()));
+                }
+            }
+        }
+    }
+
+    fn close(&self) {
+        self.queue.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+}
+
+struct JobsHandle(Arc<Jobs>);
+
+impl Drop for JobsHandle {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+/// Sends reads the workers cannot serve to the log thread, or to the
+/// read-only log once the log thread has failed.
+struct Fallback {
+    req_sink: mpsc::UnboundedSender<ClientRequest>,
+    read_queue: ReadQueue,
+    read_only: Arc<ReadOnlyLog>,
+}
+
+impl Fallback {
+    /// Sends the reads waiting on the job to the log thread, which replies to
+    /// them directly, so the worker moves on to the next job. Each read takes
+    /// a slot in the read queue, waiting for the timeout of the queue from
+    /// the start of the job, and fails with `ErrorKind::WouldBlock` if no
+    /// slot is released in time.
+    fn read(&mut self, job: ReadJob, waiting: Vec<LogSender<Messages>>) {
+        let deadline = Instant::now() + self.read_queue.timeout();
+        for res in waiting {
+            if self.read_only.is_active() {
+                self.serve_read_only(job, res);
+                continue;
+            }
+            if let Err(e) = self.read_queue.push_until(deadline) {
+                res.send_err(e);
+                continue;
+            }
+            let req = ClientRequest::Read(job.offset, job.max_bytes, res);
+            if let Err(e) = self.req_sink.try_send(req) {
+                // the log thread has exited
+                self.read_queue.cancel();
+                if let ClientRequest::Read(_, _, res) = e.into_inner() {
+                    self.serve_read_only(job, res);
+                }
+            }
+        }
+    }
+
+    /// Serves the read from the read-only log.
+    fn serve_read_only(&self, job: ReadJob, res: LogSender<Messages>) {
+        match self.read_only.read(job.offset, job.max_bytes) {
+            Ok(msgs) => res.send(msgs),
+            Err(e) => res.send_err(e),
+        }
+    }
+}
+
+/// Bytes of a segment file read from the position of an entry. The iterator
+/// stops at a partial entry at the end of the bytes.
+struct Chunk<'a>(&'a [u8]);
+
+impl<'a> MessageSet for Chunk<'a> {
+    fn bytes(&self) -> &[u8] {
+        self.0
+    }
+}
+
+/// Positions of entries in a sealed segment file.
+#[derive(Default)]
+struct Positions {
+    // positions of the first entry at or after the offset, ascending
+    scanned: Vec<(Offset, u64)>,
+    // position of the entry after the last read, as read sequentially
+    next: Option<(Offset, u64)>,
+}
+
+/// Reads entries of sealed segments from the segment files, remembering the
+/// positions of entries so later reads of the segment skip the scan.
+struct SegmentReader {
+    dir: PathBuf,
+    verify_reads: bool,
+    view: Option<Arc<SealedView>>,
+    positions: HashMap<Offset, Positions>,
+}
+
+impl SegmentReader {
+    fn new(dir: PathBuf, verify_reads: bool) -> SegmentReader {
+        SegmentReader {
+            dir,
+            verify_reads,
+            view: None,
+            positions: HashMap::new(),
+        }
+    }
+
+    /// Reads the entries from the offset up to `max_bytes` of entries, as the
+    /// log thread would. `None` if the read is to be served by the log thread.
+    fn read(
+        &mut self,
+        view: &Arc<SealedView>,
+        offset: Offset,
+        max_bytes: usize,
+    ) -> io::Result<Option<Messages>> {
+        if !self
+            .view
+            .as_ref()
+            .map(|v| Arc::ptr_eq(v, view))
+            .unwrap_or(false)
+        {
+            // segments may have been deleted or rewritten since
+            self.positions.clear();
+            self.view = Some(view.clone());
+        }
+        let base = match view.segment(offset) {
+            Some(base) => base,
+            None => return Ok(None),
+        };
+
+        let mut file = File::open(self.dir.join(format!("{:020}.log", base)))?;
+        let pos = match self.seek(&mut file, base, offset)? {
+            Some(pos) => pos,
+            None => return Ok(None),
+        };
+        let buf = read_at(&mut file, pos, max_bytes)?;
+        let set = Chunk(&buf);
+        match set.iter().next() {
+            Some(ref msg) if msg.offset() == offset => {}
+            // an entry larger than `max_bytes`, or a gap in the offsets
+            _ => return Ok(None),
+        }
+        // corruption is reported by the log thread
+        if self.verify_reads && verify_hashes(&set).is_err() {
+            return Ok(None);
+        }
+
+        let all = Messages::copy_filtered(&set, |_| true);
+        if let Some(next) = all.next_offset() {
+            let positions = self
+                .positions
+                .entry(base)
+                .or_insert_with(Positions::default);
+            positions.next = Some((next, pos + all.bytes().len() as u64));
+        }
+        if view.tombstones.is_empty() {
+            Ok(Some(all))
+        } else {
+            let tombstones = &view.tombstones;
+            Ok(Some(Messages::copy_filtered(&set, |off| {
+                !tombstones.contains(off)
+            })))
+        }
+    }
+
+    /// Position of the entry at the offset, scanning the segment from the
+    /// closest known position. `None` if the segment has no entry at the
+    /// offset.
+    fn seek(&mut self, file: &mut File, base: Offset, offset: Offset) -> io::Result<Option<u64>> {
+        let positions = self.positions.entry(base).or_insert_with(|| Positions {
+            scanned: vec![(base, 0)],
+            next: None,
+        });
+        if let Some((next, pos)) = positions.next {
+            if next == offset {
+                return Ok(Some(pos));
+            }
+        }
+
+        let mut i = match positions
+            .scanned
+            .binary_search_by_key(&offset, |&(off, _)| off)
+        {
+            Ok(i) => return Ok(Some(positions.scanned[i].1)),
+            Err(i) => i - 1,
+        };
+        let mut pos = positions.scanned[i].1;
+        loop {
+            let buf = read_at(file, pos, SCAN_BYTES)?;
+            let set = Chunk(&buf);
+            let last = match set.iter().last() {
+                Some(msg) => msg.offset(),
+                None => return Ok(None),
+            };
+            if last >= offset {
+                let before = Messages::copy_filtered(&set, |off| off < offset);
+                return Ok(Some(pos + before.bytes().len() as u64));
+            }
+
+            pos += Messages::copy_filtered(&set, |_| true).bytes().len() as u64;
+            // positions are kept past the last scanned, bounding them by the
+            // segment size rather than the number of reads
+            if i + 1 == positions.scanned.len() {
+                positions.scanned.push((last + 1, pos));
+                i += 1;
+            }
+        }
+    }
+}
+
+fn read_at(file: &mut File, pos: u64, len: usize) -> io::Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(pos))?;
+    let mut buf = Vec::with_capacity(len);
+    file.take(len as u64).read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::LogConfig;
+    use futures::{Future, Stream};
+    use std::time::Duration;
+    use std::{env, fs, process};
+
+    fn view(bases: &[Offset], end: Offset, low_watermark: Offset) -> SealedView {
+        SealedView {
+            bases: bases.to_vec(),
+            end,
+            low_watermark,
+            tombstones: Tombstones::open("/nonexistent-log-dir").unwrap(),
+            withdrawals: 0,
+        }
+    }
+
+    #[test]
+    fn coalesces_identical_pending_reads() {
+        let jobs = Jobs::default();
+        let job = ReadJob {
+            offset: 5,
+            max_bytes: 4096,
+            withdrawals: 0,
+        };
+        let reads = (0..50)
+            .map(|_| {
+                let (res, f) = channel();
+                jobs.push(job, res);
+                f
+            })
+            .collect::<Vec<_>>();
+        let (res, other) = channel();
+        jobs.push(
+            ReadJob {
+                offset: 5,
+                max_bytes: 100,
+                withdrawals: 0,
+            },
+            res,
+        );
+
+        assert_eq!(Some(job), jobs.take());
+        let msgs = Messages::copy_filtered(&Chunk(&[]), |_| true);
+        jobs.complete(job, Ok(msgs));
+        for f in reads {
+            assert_eq!(0, f.wait().unwrap().len());
+        }
+        assert_eq!(1, jobs.reads.load(Ordering::Relaxed));
+
+        // reads of a different size are not coalesced
+        let job = jobs.take().unwrap();
+        assert_eq!(100, job.max_bytes);
+        jobs.complete(job, Err(Error::new(io::ErrorKind::NotFound, "deleted")));
+        assert_eq!(io::ErrorKind::NotFound, other.wait().unwrap_err().kind());
+        assert_eq!(2, jobs.reads.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn reads_after_a_view_change_are_not_coalesced() {
+        let jobs = Jobs::default();
+        let before = ReadJob {
+            offset: 5,
+            max_bytes: 4096,
+            withdrawals: 0,
+        };
+        let (res, f) = channel();
+        jobs.push(before, res);
+        // a worker takes the job, then the offset is tombstoned
+        assert_eq!(Some(before), jobs.take());
+
+        let after = ReadJob {
+            withdrawals: 1,
+            ..before
+        };
+        let (res, other) = channel();
+        jobs.push(after, res);
+        let msgs = Messages::copy_filtered(&Chunk(&[]), |_| true);
+        jobs.complete(before, Ok(msgs));
+        assert_eq!(0, f.wait().unwrap().len());
+
+        // the later read is read again with the view it was sent with
+        assert_eq!(Some(after), jobs.take());
+        jobs.complete(after, Err(Error::new(io::ErrorKind::NotFound, "deleted")));
+        assert_eq!(io::ErrorKind::NotFound, other.wait().unwrap_err().kind());
+        assert_eq!(2, jobs.reads.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn fallback_reads_take_a_slot_in_the_read_queue() {
+        let (req_sink, req_stream) = mpsc::unbounded_channel();
+        let read_queue = ReadQueue::new(Some(1), Duration::from_millis(20));
+        let mut fallback = Fallback {
+            req_sink,
+            read_queue: read_queue.clone(),
+            read_only: Arc::new(ReadOnlyLog::new(&LogConfig::default())),
+        };
+        let job = ReadJob {
+            offset: 5,
+            max_bytes: 4096,
+            withdrawals: 0,
+        };
+
+        // a full queue fails the reads once the timeout has passed
+        assert!(read_queue.try_push());
+        let (res, f) = channel();
+        fallback.read(job, vec![res]);
+        assert_eq!(io::ErrorKind::WouldBlock, f.wait().unwrap_err().kind());
+
+        // reads are sent on with their sender, for the log thread to reply
+        read_queue.pop();
+        let (res, _f) = channel();
+        fallback.read(job, vec![res]);
+        assert_eq!(1, read_queue.len());
+        match req_stream.wait().next() {
+            Some(Ok(ClientRequest::Read(5, 4096, _))) => {}
+            _ => panic!("Expected the read to be sent to the log thread"),
+        }
+    }
+
+    #[test]
+    fn sealed_segment_of_offset() {
+        let view = view(&[0, 10, 20], 30, 5);
+        assert_eq!(None, view.segment(3));
+        assert_eq!(Some(0), view.segment(5));
+        assert_eq!(Some(10), view.segment(10));
+        assert_eq!(Some(20), view.segment(29));
+        // the active segment is read by the log thread
+        assert_eq!(None, view.segment(30));
+        assert_eq!(None, view.segment(100));
+    }
+
+    #[test]
+    fn withdrawn_segments_are_left_to_the_log() {
+        let published = view(&[0, 10, 20], 30, 0);
+
+        let mut truncated = published.clone();
+        truncated.withdraw_from(15);
+        assert_eq!(Some(0), truncated.segment(9));
+        assert_eq!(None, truncated.segment(10));
+        assert_eq!(None, truncated.segment(25));
+        assert!(published.is_stale(&truncated));
+
+        let mut trimmed = published.clone();
+        trimmed.withdraw_below(20);
+        assert_eq!(None, trimmed.segment(15));
+        assert_eq!(Some(20), trimmed.segment(25));
+        assert!(published.is_stale(&trimmed));
+
+        // changes of the active segment leave the sealed segments
+        let mut unchanged = published.clone();
+        unchanged.withdraw_from(30);
+        unchanged.withdraw_below(0);
+        assert_eq!(Some(20), unchanged.segment(25));
+        assert!(!published.is_stale(&unchanged));
+
+        // the withdrawals are kept as the segments are published again
+        truncated.set_segments(&[]);
+        assert!(published.is_stale(&truncated));
+    }
+
+    #[test]
+    fn reads_overlapping_a_tombstone_are_left_to_the_log() {
+        let dir = env::temp_dir().join(format!("log-worker-tombstone-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut tombstones = Tombstones::open(&dir).unwrap();
+        let shared: SharedView = Arc::new(RwLock::new(Arc::new(SealedView {
+            tombstones: tombstones.clone(),
+            ..view(&[0, 10], 20, 0)
+        })));
+
+        // a worker takes the view for a read of offset 5
+        let sealed = shared.read().unwrap().clone();
+
+        // the log thread tombstones the offset and publishes the tombstones
+        // before acknowledging, while the read is running
+        tombstones.insert(3..7).unwrap();
+        let mut next = SealedView::clone(&shared.read().unwrap());
+        next.set_tombstones(tombstones.clone());
+        *shared.write().unwrap() = Arc::new(next);
+        assert!(sealed.is_stale(&shared.read().unwrap()));
+
+        // publishing the same tombstones again leaves reads in flight
+        let sealed = shared.read().unwrap().clone();
+        let mut next = SealedView::clone(&shared.read().unwrap());
+        next.set_tombstones(tombstones);
+        assert!(!sealed.is_stale(&next));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,208 @@
+//! Format of log entries carrying a key and headers.
+//!
+//! The metadata of every entry starts with the client ID and the client
+//! request ID. Plain entries carry nothing else. Records follow these with
+//! the record format version, then the sequence, the key and the headers:
+//!
+//! ```text
+//! version (u8) | flags (u8) | [sequence (u64)] | [key length (u16) | key]
+//!     | header count (u16) | (name length (u16) | name | value length (u16) | value)*
+//! ```
+//!
+//! Integers are little endian, and header names and values are UTF-8. The
+//! key is present when the lowest bit of the flags is set, so an empty key
+//! is distinct from an absent key. The sequence is present when the second
+//! bit is set.
+use byteorder::{ByteOrder, LittleEndian};
+use std::io::{Error, ErrorKind};
+use std::str;
+
+/// Size of the client ID and client request ID in the metadata.
+pub const CLIENT_METADATA_SIZE: usize = 16;
+
+/// Current version of the record format.
+pub const RECORD_VERSION: u8 = 1;
+
+const KEY_FLAG: u8 = 0b1;
+const SEQUENCE_FLAG: u8 = 0b10;
+
+/// Maximum size of the metadata of an entry.
+const METADATA_MAX_BYTES: usize = u16::max_value() as usize;
+
+/// Key and headers of a log entry, borrowed from the entry metadata. Plain
+/// entries have no key and no headers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecordMeta<'a> {
+    /// Sequence assigned by the server when the record was appended. Unlike
+    /// the offset, the sequence is kept by the record when the log is
+    /// rewritten.
+    pub sequence: Option<u64>,
+    pub key: Option<&'a [u8]>,
+    pub headers: Vec<(&'a str, &'a str)>,
+}
+
+/// Error parsing the key and headers of a log entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordParseError {
+    /// The metadata ends within a field.
+    Truncated,
+
+    /// The record format version is not supported by this server.
+    UnknownVersion(u8),
+
+    /// A header name or value is not UTF-8.
+    InvalidUtf8,
+}
+
+impl<'a> RecordMeta<'a> {
+    /// Parses the key and headers from the metadata of a log entry.
+    pub fn parse(metadata: &'a [u8]) -> Result<RecordMeta<'a>, RecordParseError> {
+        if metadata.len() < CLIENT_METADATA_SIZE {
+            return Err(RecordParseError::Truncated);
+        }
+        if metadata.len() == CLIENT_METADATA_SIZE {
+            return Ok(RecordMeta::default());
+        }
+
+        let mut rd = Reader(&metadata[CLIENT_METADATA_SIZE..]);
+        let version = rd.u8()?;
+        if version != RECORD_VERSION {
+            return Err(RecordParseError::UnknownVersion(version));
+        }
+
+        let flags = rd.u8()?;
+        let sequence = if flags & SEQUENCE_FLAG != 0 {
+            Some(rd.u64()?)
+        } else {
+            None
+        };
+        let key = if flags & KEY_FLAG != 0 {
+            Some(rd.field()?)
+        } else {
+            None
+        };
+
+        let count = rd.u16()?;
+        let mut headers = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let name = rd.str_field()?;
+            let value = rd.str_field()?;
+            headers.push((name, value));
+        }
+        Ok(RecordMeta {
+            sequence,
+            key,
+            headers,
+        })
+    }
+}
+
+/// Size of the metadata of a record with the sequence, key and headers.
+pub fn metadata_len(
+    sequence: Option<u64>,
+    key: Option<&[u8]>,
+    headers: &[(String, String)],
+) -> usize {
+    CLIENT_METADATA_SIZE
+        + 4
+        + sequence.map(|_| 8).unwrap_or(0)
+        + key.map(|k| 2 + k.len()).unwrap_or(0)
+        + headers
+            .iter()
+            .map(|(name, value)| 4 + name.len() + value.len())
+            .sum::<usize>()
+}
+
+/// Checks that the key and headers fit in the metadata of an entry.
+pub fn validate(key: Option<&[u8]>, headers: &[(String, String)]) -> Result<(), Error> {
+    let max = u16::max_value() as usize;
+    let fields_fit = key.map(|k| k.len() <= max).unwrap_or(true)
+        && headers.len() <= max
+        && headers
+            .iter()
+            .all(|(name, value)| name.len() <= max && value.len() <= max);
+    // leaves room for a sequence assigned by the server
+    if !fields_fit || metadata_len(Some(0), key, headers) > METADATA_MAX_BYTES {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Record key and headers exceed the metadata size limit",
+        ));
+    }
+    Ok(())
+}
+
+/// Writes the record metadata after the client metadata. The key and
+/// headers must be validated.
+pub fn encode(
+    buf: &mut Vec<u8>,
+    sequence: Option<u64>,
+    key: Option<&[u8]>,
+    headers: &[(String, String)],
+) {
+    fn write_field(buf: &mut Vec<u8>, field: &[u8]) {
+        let mut len = [0u8; 2];
+        LittleEndian::write_u16(&mut len, field.len() as u16);
+        buf.extend_from_slice(&len);
+        buf.extend_from_slice(field);
+    }
+
+    let mut flags = 0;
+    if key.is_some() {
+        flags |= KEY_FLAG;
+    }
+    if sequence.is_some() {
+        flags |= SEQUENCE_FLAG;
+    }
+    buf.push(RECORD_VERSION);
+    buf.push(flags);
+    if let Some(sequence) = sequence {
+        let mut seq = [0u8; 8];
+        LittleEndian::write_u64(&mut seq, sequence);
+        buf.extend_from_slice(&seq);
+    }
+    if let Some(key) = key {
+        write_field(buf, key);
+    }
+
+    let mut count = [0u8; 2];
+    LittleEndian::write_u16(&mut count, headers.len() as u16);
+    buf.extend_from_slice(&count);
+    for (name, value) in headers {
+        write_field(buf, name.as_bytes());
+        write_field(buf, value.as_bytes());
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], RecordParseError> {
+        if self.0.len() < n {
+            return Err(RecordParseError::Truncated);
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, RecordParseError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, RecordParseError> {
+        Ok(LittleEndian::read_u16(self.take(2)?))
+    }
+
+    fn u64(&mut self) -> Result<u64, RecordParseError> {
+        Ok(LittleEndian::read_u64(self.take(8)?))
+    }
+
+    fn field(&mut self) -> Result<&'a [u8], RecordParseError> {
+        let len = self.u16()?;
+        self.take(len as usize)
+    }
+
+    fn str_field(&mut self) -> Result<&'a str, RecordParseError> {
+        str::from_utf8(self.field()?).map_err(|_| RecordParseError::InvalidUtf8)
+    }
+}
@@ -0,0 +1,125 @@
+use super::retention;
+use commitlog::message::{Message, MessageSet, HEADER_SIZE};
+use commitlog::Offset;
+use std::fs::{self, OpenOptions};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// Bytes of a segment file, iterated up to a partial entry at the end.
+struct SegmentBytes(Vec<u8>);
+
+impl MessageSet for SegmentBytes {
+    fn bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Entries at the end of the newest segment that are not fully written, as
+/// left by a process killed during an append.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TornTail {
+    /// Path of the segment log file.
+    pub path: PathBuf,
+
+    /// Bytes of the segment up to the end of the last valid entry.
+    pub valid_bytes: u64,
+
+    /// Bytes after the last valid entry, discarded by a repair.
+    pub discarded_bytes: u64,
+
+    /// Offset after the last valid entry of the log.
+    pub next_offset: Offset,
+}
+
+impl TornTail {
+    /// Truncates the segment to the end of its last valid entry.
+    pub fn truncate(&self) -> Result<(), Error> {
+        let file = OpenOptions::new().write(true).open(&self.path)?;
+        file.set_len(self.valid_bytes)?;
+        file.sync_all()
+    }
+
+    /// Error opening the log without repairing the torn write.
+    pub fn error(&self) -> Error {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Torn write of {} bytes at the end of {}, after offset {}",
+                self.discarded_bytes,
+                self.path.display(),
+                self.next_offset.saturating_sub(1)
+            ),
+        )
+    }
+}
+
+/// Scans the newest segment of the log directory for bytes after the last
+/// fully written entry, validating the framing and hash of each entry.
+/// `None` if the segment ends with a valid entry.
+pub fn scan_tail<P: AsRef<Path>>(dir: P) -> Result<Option<TornTail>, Error> {
+    let segment = match retention::segments(&dir)?.pop() {
+        Some(segment) => segment,
+        None => return Ok(None),
+    };
+    let path = dir
+        .as_ref()
+        .join(format!("{:020}.log", segment.base_offset));
+    let set = SegmentBytes(fs::read(&path)?);
+
+    let mut valid_bytes = 0;
+    let mut next_offset = segment.base_offset;
+    for msg in set.iter() {
+        if msg.offset() < next_offset || !msg.verify_hash() {
+            break;
+        }
+        next_offset = msg.offset() + 1;
+        valid_bytes += (HEADER_SIZE + msg.metadata().len() + msg.payload().len()) as u64;
+    }
+
+    let len = set.0.len() as u64;
+    if valid_bytes == len {
+        return Ok(None);
+    }
+    Ok(Some(TornTail {
+        path,
+        valid_bytes,
+        discarded_bytes: len - valid_bytes,
+        next_offset,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use commitlog::{CommitLog, LogOptions};
+    use std::env;
+    use std::io::Write;
+    use std::process;
+
+    #[test]
+    fn finds_bytes_after_last_valid_entry() {
+        let dir = env::temp_dir().join(format!("log-repair-scan-test-{}", process::id()));
+        {
+            let mut log = CommitLog::new(LogOptions::new(&dir)).unwrap();
+            log.append_msg("foo").unwrap();
+            log.append_msg("bar").unwrap();
+            log.flush().unwrap();
+        }
+        assert_eq!(None, scan_tail(&dir).unwrap());
+
+        let path = dir.join(format!("{:020}.log", 0));
+        let len = fs::metadata(&path).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0xde, 0xad, 0xbe, 0xef, 1, 2, 3]).unwrap();
+        drop(file);
+
+        let torn = scan_tail(&dir).unwrap().unwrap();
+        assert_eq!(len, torn.valid_bytes);
+        assert_eq!(7, torn.discarded_bytes);
+        assert_eq!(2, torn.next_offset);
+
+        torn.truncate().unwrap();
+        assert_eq!(None, scan_tail(&dir).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,355 @@
+use super::storage::Storage;
+use super::trace::untraced;
+use commitlog::Offset;
+use config::RetentionConfig;
+use std::cmp::min;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Segment of the log on disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentInfo {
+    /// First offset contained in the segment.
+    pub base_offset: Offset,
+
+    /// Size of the segment log file, in bytes.
+    pub bytes: u64,
+
+    /// Time of the last write to the segment.
+    pub modified: SystemTime,
+}
+
+/// Lists the segments in the log directory, ordered by base offset.
+pub fn segments<P: AsRef<Path>>(dir: P) -> io::Result<Vec<SegmentInfo>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.extension().map(|ext| ext != "log").unwrap_or(true) {
+            continue;
+        }
+
+        let base_offset = match path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse::<u64>().ok())
+        {
+            Some(off) => off,
+            None => continue,
+        };
+
+        let meta = entry.metadata()?;
+        segments.push(SegmentInfo {
+            base_offset,
+            bytes: meta.len(),
+            modified: meta.modified()?,
+        });
+    }
+    segments.sort_by_key(|s| s.base_offset);
+    Ok(segments)
+}
+
+/// Policy deciding whether the oldest segments of the log may be deleted.
+pub trait RetentionPolicy: Send {
+    /// Number of the oldest segments the policy allows to be deleted, or `None`
+    /// if the policy places no constraint on deletion.
+    ///
+    /// The segments are ordered oldest to newest, including the active segment.
+    fn deletable(&self, segments: &[SegmentInfo], now: SystemTime) -> Option<usize>;
+
+    /// Whether the policy requires compaction of inactive segments.
+    fn compact(&self) -> bool {
+        false
+    }
+}
+
+/// Deletes segments that have not been written within the maximum age.
+pub struct Age(pub Duration);
+
+impl RetentionPolicy for Age {
+    fn deletable(&self, segments: &[SegmentInfo], now: SystemTime) -> Option<usize> {
+        Some(
+            segments
+                .iter()
+                .take_while(|s| {
+                    now.duration_since(s.modified)
+                        .map(|age| age > self.0)
+                        .unwrap_or(false)
+                })
+                .count(),
+        )
+    }
+}
+
+/// Deletes the oldest segments until the log is within the byte budget.
+pub struct Size(pub u64);
+
+impl RetentionPolicy for Size {
+    fn deletable(&self, segments: &[SegmentInfo], _now: SystemTime) -> Option<usize> {
+        let mut total: u64 = segments.iter().map(|s| s.bytes).sum();
+        let mut deletable = 0;
+        for s in segments {
+            if total <= self.0 {
+                break;
+            }
+            total -= s.bytes;
+            deletable += 1;
+        }
+        Some(deletable)
+    }
+}
+
+/// Compacts inactive segments. Places no constraint on deletion.
+pub struct Compact;
+
+impl RetentionPolicy for Compact {
+    fn deletable(&self, _segments: &[SegmentInfo], _now: SystemTime) -> Option<usize> {
+        None
+    }
+
+    fn compact(&self) -> bool {
+        true
+    }
+}
+
+/// Number of segments that may be deleted when all the policies are evaluated
+/// together. A segment is only deleted when every constraining policy allows
+/// it, and the active segment is never deleted.
+pub fn deletable_segments(
+    policies: &[Box<RetentionPolicy>],
+    segments: &[SegmentInfo],
+    now: SystemTime,
+) -> usize {
+    let deletable = policies
+        .iter()
+        .filter_map(|p| p.deletable(segments, now))
+        .min()
+        .unwrap_or(0);
+    min(deletable, segments.len().saturating_sub(1))
+}
+
+/// Number of the oldest segments with every offset below the offset. The
+/// active segment is never included.
+pub fn segments_below(segments: &[SegmentInfo], offset: Offset) -> usize {
+    segments
+        .windows(2)
+        .take_while(|pair| pair[1].base_offset <= offset)
+        .count()
+}
+
+/// Read of an offset deleted by a trim or the retention policies, below the
+/// low watermark of the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OffsetTrimmed {
+    /// Offset read.
+    pub offset: Offset,
+
+    /// First offset retained in the log, where reads may resume.
+    pub low_watermark: Offset,
+}
+
+impl fmt::Display for OffsetTrimmed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Offset {} has been deleted, the low watermark is {}",
+            self.offset, self.low_watermark
+        )
+    }
+}
+
+impl error::Error for OffsetTrimmed {}
+
+impl OffsetTrimmed {
+    /// The deleted offset read, if the read failed for a trim rather than
+    /// any other missing entry.
+    pub fn from_error(e: &io::Error) -> Option<OffsetTrimmed> {
+        untraced(e)
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<OffsetTrimmed>())
+            .cloned()
+    }
+}
+
+/// First offset retained in the log directory, derived from the segments,
+/// or zero for an empty log.
+pub fn low_watermark<P: AsRef<Path>>(dir: P) -> io::Result<Offset> {
+    Ok(segments(dir)?.first().map(|s| s.base_offset).unwrap_or(0))
+}
+
+/// Policies configured for the log.
+pub fn policies(cfg: &RetentionConfig) -> Vec<Box<RetentionPolicy>> {
+    let mut policies: Vec<Box<RetentionPolicy>> = Vec::new();
+    if let Some(secs) = cfg.max_age_secs {
+        policies.push(Box::new(Age(Duration::from_secs(secs))));
+    }
+    if let Some(bytes) = cfg.max_bytes {
+        policies.push(Box::new(Size(bytes)));
+    }
+    if cfg.compact {
+        policies.push(Box::new(Compact));
+    }
+    policies
+}
+
+/// Retention enforcement, run periodically from the log thread.
+pub struct Retention {
+    dir: PathBuf,
+    cfg: RetentionConfig,
+    policies: Vec<Box<RetentionPolicy>>,
+    interval: Duration,
+    last_check: Instant,
+}
+
+impl Retention {
+    pub fn new<P: AsRef<Path>>(dir: P, cfg: &RetentionConfig) -> Retention {
+        Retention {
+            dir: dir.as_ref().to_path_buf(),
+            cfg: cfg.clone(),
+            policies: policies(cfg),
+            interval: Duration::from_secs(cfg.check_interval_secs),
+            last_check: Instant::now(),
+        }
+    }
+
+    /// Configuration of the retention policies.
+    #[inline]
+    pub fn config(&self) -> &RetentionConfig {
+        &self.cfg
+    }
+
+    /// Replaces the retention policies, taking effect at the next check.
+    pub fn reconfigure(&mut self, cfg: &RetentionConfig) {
+        self.cfg = cfg.clone();
+        self.policies = policies(cfg);
+        self.interval = Duration::from_secs(cfg.check_interval_secs);
+    }
+
+    /// Whether the retention pass is due.
+    #[inline]
+    pub fn is_due(&self, now: Instant) -> bool {
+        !self.policies.is_empty() && (now - self.last_check) > self.interval
+    }
+
+    /// Number of the oldest segments the retention policies allow to be deleted.
+    pub fn deletable(&self, segments: &[SegmentInfo]) -> usize {
+        deletable_segments(&self.policies, segments, SystemTime::now())
+    }
+
+    /// Deletes the segments allowed by the retention policies, calling
+    /// `before_delete` with the base offset of the first segment kept before
+    /// the segments are deleted.
+    pub fn enforce<S, F>(&mut self, log: &mut S, before_delete: F) -> io::Result<()>
+    where
+        S: Storage,
+        F: FnOnce(Offset),
+    {
+        self.last_check = Instant::now();
+
+        let segments = segments(&self.dir)?;
+        let deletable = self.deletable(&segments);
+        if deletable == 0 {
+            trace!("No segments eligible for deletion");
+            return Ok(());
+        }
+
+        let bytes: u64 = segments[0..deletable].iter().map(|s| s.bytes).sum();
+        before_delete(segments[deletable].base_offset);
+        log.trim_segments_before(segments[deletable].base_offset)?;
+        info!(
+            "Retention deleted {} segments, reclaimed {} bytes",
+            deletable, bytes
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(base_offset: Offset, bytes: u64, age_secs: u64, now: SystemTime) -> SegmentInfo {
+        SegmentInfo {
+            base_offset,
+            bytes,
+            modified: now - Duration::from_secs(age_secs),
+        }
+    }
+
+    #[test]
+    fn age_deletes_old_segments() {
+        let now = SystemTime::now();
+        let segments = vec![
+            segment(0, 100, 50, now),
+            segment(10, 100, 40, now),
+            segment(20, 100, 10, now),
+        ];
+        let policy = Age(Duration::from_secs(30));
+        assert_eq!(Some(2), policy.deletable(&segments, now));
+    }
+
+    #[test]
+    fn size_deletes_to_budget() {
+        let now = SystemTime::now();
+        let segments = vec![
+            segment(0, 100, 0, now),
+            segment(10, 100, 0, now),
+            segment(20, 100, 0, now),
+        ];
+        assert_eq!(Some(2), Size(150).deletable(&segments, now));
+        assert_eq!(Some(0), Size(300).deletable(&segments, now));
+    }
+
+    #[test]
+    fn composed_policies_require_all() {
+        let now = SystemTime::now();
+        let segments = vec![
+            segment(0, 100, 50, now),
+            segment(10, 100, 10, now),
+            segment(20, 100, 0, now),
+        ];
+        let policies: Vec<Box<RetentionPolicy>> = vec![
+            Box::new(Age(Duration::from_secs(30))),
+            Box::new(Size(100)),
+            Box::new(Compact),
+        ];
+        assert_eq!(1, deletable_segments(&policies, &segments, now));
+    }
+
+    #[test]
+    fn segments_below_offset() {
+        let now = SystemTime::now();
+        let segments = vec![
+            segment(0, 100, 50, now),
+            segment(10, 100, 40, now),
+            segment(20, 100, 10, now),
+        ];
+        assert_eq!(0, segments_below(&segments, 9));
+        assert_eq!(1, segments_below(&segments, 10));
+        assert_eq!(1, segments_below(&segments, 19));
+        assert_eq!(2, segments_below(&segments, 20));
+        // the active segment is kept
+        assert_eq!(2, segments_below(&segments, 100));
+        assert_eq!(0, segments_below(&[], 100));
+    }
+
+    #[test]
+    fn never_deletes_active_segment() {
+        let now = SystemTime::now();
+        let segments = vec![segment(0, 100, 50, now), segment(10, 100, 50, now)];
+        let policies: Vec<Box<RetentionPolicy>> = vec![Box::new(Size(0))];
+        assert_eq!(1, deletable_segments(&policies, &segments, now));
+    }
+
+    #[test]
+    fn compact_only_deletes_nothing() {
+        let now = SystemTime::now();
+        let segments = vec![segment(0, 100, 50, now), segment(10, 100, 50, now)];
+        let policies: Vec<Box<RetentionPolicy>> = vec![Box::new(Compact)];
+        assert_eq!(0, deletable_segments(&policies, &segments, now));
+    }
+}
@@ -0,0 +1,196 @@
+use super::retention::{segments, SegmentInfo};
+use commitlog::Offset;
+use prometheus::{exponential_buckets, Counter, Histogram};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+lazy_static! {
+    static ref SEGMENT_ROLLS: Counter = register_counter!(opts!(
+        "log_segment_rolls",
+        "Number of segments closed by a segment roll.",
+        labels! {"mod" => "log",}
+    ))
+    .unwrap();
+    static ref CLOSED_SEGMENT_BYTES_HISTOGRAM: Histogram = register_histogram!(
+        "log_closed_segment_bytes",
+        "Size of segments closed by a segment roll, in bytes.",
+        exponential_buckets(1_048_576f64, 2f64, 12usize).unwrap()
+    )
+    .unwrap();
+    static ref CLOSED_SEGMENT_ACTIVE_HISTOGRAM: Histogram = register_histogram!(
+        "log_closed_segment_active_secs",
+        "Seconds a segment closed by a segment roll was the active segment.",
+        exponential_buckets(1f64, 2f64, 18usize).unwrap()
+    )
+    .unwrap();
+}
+
+/// Segment closed by a segment roll.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentRoll {
+    /// First offset contained in the segment.
+    pub base_offset: Offset,
+
+    /// Last offset contained in the segment.
+    pub last_offset: Offset,
+
+    /// Number of entries in the segment.
+    pub entries: u64,
+
+    /// Size of the segment log file, in bytes.
+    pub bytes: u64,
+
+    /// Time the segment was the active segment, from the last write to the
+    /// previous segment until the last write to the segment.
+    pub active: Duration,
+}
+
+/// Detects segment rolls from the segments in the log directory.
+///
+/// The log does not report rolls, so rolls are detected when the segments
+/// are listed, after each flush. Segments closed in between are reported
+/// together.
+pub struct Rollover {
+    // base offset of the active segment at the last check
+    active_base: Option<Offset>,
+    // estimated time the active segment was opened
+    active_since: SystemTime,
+}
+
+impl Rollover {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Rollover {
+        let mut rollover = Rollover {
+            active_base: None,
+            active_since: SystemTime::now(),
+        };
+        match segments(dir) {
+            Ok(segments) => rollover.reset(&segments),
+            Err(e) => error!("Unable to list segments: {}", e),
+        }
+        rollover
+    }
+
+    /// Lists the segments, reporting the segments closed since the last check.
+    /// Returns the segments listed, if the listing succeeded.
+    pub fn check<P: AsRef<Path>>(&mut self, dir: P) -> Option<Vec<SegmentInfo>> {
+        match segments(dir) {
+            Ok(segments) => {
+                for roll in self.observe(&segments) {
+                    info!(
+                        "Segment rolled: base_offset={}, last_offset={}, entries={}, \
+                         bytes={}, active_secs={}",
+                        roll.base_offset,
+                        roll.last_offset,
+                        roll.entries,
+                        roll.bytes,
+                        roll.active.as_secs()
+                    );
+                    SEGMENT_ROLLS.inc();
+                    CLOSED_SEGMENT_BYTES_HISTOGRAM.observe(roll.bytes as f64);
+                    CLOSED_SEGMENT_ACTIVE_HISTOGRAM.observe(roll.active.as_secs() as f64);
+                }
+                Some(segments)
+            }
+            Err(e) => {
+                error!("Unable to list segments: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Segments closed since the last observation, ordered by base offset.
+    fn observe(&mut self, segments: &[SegmentInfo]) -> Vec<SegmentRoll> {
+        let active_base = match (self.active_base, segments.last()) {
+            (Some(base), Some(last)) if base <= last.base_offset => base,
+            _ => {
+                // first segment or a truncated log, without rolls to report
+                self.reset(segments);
+                return Vec::new();
+            }
+        };
+
+        let mut rolls = Vec::new();
+        for pair in segments.windows(2) {
+            let (closed, next) = (&pair[0], &pair[1]);
+            if closed.base_offset < active_base {
+                continue;
+            }
+
+            let active = closed
+                .modified
+                .duration_since(self.active_since)
+                .unwrap_or_default();
+            rolls.push(SegmentRoll {
+                base_offset: closed.base_offset,
+                last_offset: next.base_offset - 1,
+                entries: next.base_offset - closed.base_offset,
+                bytes: closed.bytes,
+                active,
+            });
+            self.active_since = closed.modified;
+        }
+        self.active_base = segments.last().map(|s| s.base_offset);
+        rolls
+    }
+
+    fn reset(&mut self, segments: &[SegmentInfo]) {
+        self.active_base = segments.last().map(|s| s.base_offset);
+        // the active segment was opened at about the last write to its predecessor
+        if segments.len() > 1 {
+            self.active_since = segments[segments.len() - 2].modified;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(base_offset: Offset, bytes: u64, modified_secs: u64) -> SegmentInfo {
+        SegmentInfo {
+            base_offset,
+            bytes,
+            modified: SystemTime::UNIX_EPOCH + Duration::from_secs(modified_secs),
+        }
+    }
+
+    #[test]
+    fn reports_closed_segments() {
+        let mut rollover = Rollover {
+            active_base: None,
+            active_since: SystemTime::now(),
+        };
+        let mut segments = vec![segment(0, 100, 10), segment(10, 50, 20)];
+        assert!(rollover.observe(&segments).is_empty());
+        assert!(rollover.observe(&segments).is_empty());
+
+        segments[1].bytes = 100;
+        segments.push(segment(25, 100, 60));
+        segments.push(segment(30, 5, 70));
+        assert_eq!(
+            vec![
+                SegmentRoll {
+                    base_offset: 10,
+                    last_offset: 24,
+                    entries: 15,
+                    bytes: 100,
+                    active: Duration::from_secs(10),
+                },
+                SegmentRoll {
+                    base_offset: 25,
+                    last_offset: 29,
+                    entries: 5,
+                    bytes: 100,
+                    active: Duration::from_secs(40),
+                },
+            ],
+            rollover.observe(&segments)
+        );
+        assert!(rollover.observe(&segments).is_empty());
+
+        // truncated to before the active segment
+        segments.truncate(2);
+        assert!(rollover.observe(&segments).is_empty());
+        assert_eq!(Some(10), rollover.active_base);
+    }
+}
@@ -0,0 +1,72 @@
+use super::retention::SegmentInfo;
+
+/// Segment count and size of the log, kept by the log thread from the
+/// segments listed after flushes and the bytes appended since, so the size is
+/// known without listing the log directory on each request.
+///
+/// A segment created by a roll is counted from the next listing.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LogSize {
+    segments: usize,
+    // bytes of the segments at the last listing
+    listed_bytes: u64,
+    appended_bytes: u64,
+}
+
+impl LogSize {
+    pub fn new(segments: &[SegmentInfo]) -> LogSize {
+        let mut size = LogSize::default();
+        size.listed(segments);
+        size
+    }
+
+    /// Replaces the size with the segments listed from the log directory.
+    pub fn listed(&mut self, segments: &[SegmentInfo]) {
+        self.segments = segments.len();
+        self.listed_bytes = segments.iter().map(|s| s.bytes).sum();
+        self.appended_bytes = 0;
+    }
+
+    /// Counts bytes appended to the log since the last listing.
+    pub fn appended(&mut self, bytes: usize) {
+        self.appended_bytes += bytes as u64;
+    }
+
+    pub fn segments(&self) -> usize {
+        self.segments
+    }
+
+    /// Bytes of the segment log files.
+    pub fn bytes(&self) -> u64 {
+        self.listed_bytes + self.appended_bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    fn segment(base_offset: u64, bytes: u64) -> SegmentInfo {
+        SegmentInfo {
+            base_offset,
+            bytes,
+            modified: SystemTime::now(),
+        }
+    }
+
+    #[test]
+    fn counts_appends_until_listed() {
+        let mut size = LogSize::new(&[segment(0, 100), segment(10, 50)]);
+        assert_eq!(2, size.segments());
+        assert_eq!(150, size.bytes());
+
+        size.appended(25);
+        size.appended(25);
+        assert_eq!(200, size.bytes());
+
+        size.listed(&[segment(10, 100), segment(20, 0)]);
+        assert_eq!(2, size.segments());
+        assert_eq!(100, size.bytes());
+    }
+}
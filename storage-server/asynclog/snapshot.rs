@@ -0,0 +1,104 @@
+use super::retention::segments;
+use super::time_index::TIME_INDEX_FILE;
+use super::tombstone::TOMBSTONE_FILE;
+use commitlog::Offset;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Point-in-time copy of the log.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotInfo {
+    /// First offset contained in the snapshot, or `None` if the log is empty.
+    pub first_offset: Option<Offset>,
+
+    /// Last offset contained in the snapshot, or `None` if the log is empty.
+    pub last_offset: Option<Offset>,
+
+    /// Number of segments copied.
+    pub segments: usize,
+}
+
+/// Copies the segments of the log directory into the destination directory,
+/// which must not already exist.
+///
+/// The log must be flushed and must not be appended during the copy. The
+/// inactive segments are immutable and are hard linked when possible. The
+/// active segment is copied.
+pub fn copy_segments(dir: &Path, dest: &Path) -> io::Result<usize> {
+    fs::create_dir_all(dest.parent().unwrap_or(dest))?;
+    fs::create_dir(dest)?;
+
+    let segments = segments(dir)?;
+    let active = segments.len().saturating_sub(1);
+    for (i, segment) in segments.iter().enumerate() {
+        for ext in &["log", "index"] {
+            let name = format!("{:020}.{}", segment.base_offset, ext);
+            let src = dir.join(&name);
+            if !src.exists() {
+                continue;
+            }
+
+            let dst = dest.join(&name);
+            if i < active {
+                link_or_copy(&src, &dst)?;
+            } else {
+                fs::copy(&src, &dst)?;
+            }
+        }
+    }
+
+    for file in &[TOMBSTONE_FILE, TIME_INDEX_FILE] {
+        let src = dir.join(file);
+        if src.exists() {
+            fs::copy(&src, dest.join(file))?;
+        }
+    }
+
+    Ok(segments.len())
+}
+
+fn link_or_copy(src: &Path, dst: &Path) -> io::Result<()> {
+    if let Err(e) = fs::hard_link(src, dst) {
+        debug!("Unable to hard link {:?}, copying: {}", src, e);
+        fs::copy(src, dst)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn copies_segments() {
+        let root = env::temp_dir().join(format!("log-snapshot-test-{}", process::id()));
+        let dir = root.join("log");
+        let dest = root.join("snapshot");
+        fs::create_dir_all(&dir).unwrap();
+
+        for (base, content) in &[(0u64, "seg0"), (10, "seg1")] {
+            fs::write(dir.join(format!("{:020}.log", base)), content).unwrap();
+            fs::write(dir.join(format!("{:020}.index", base)), content).unwrap();
+        }
+        fs::write(dir.join(TOMBSTONE_FILE), "t").unwrap();
+
+        assert_eq!(2, copy_segments(&dir, &dest).unwrap());
+        assert_eq!(
+            "seg0",
+            fs::read_to_string(dest.join(format!("{:020}.log", 0))).unwrap()
+        );
+        assert_eq!(
+            "seg1",
+            fs::read_to_string(dest.join(format!("{:020}.index", 10))).unwrap()
+        );
+        assert!(dest.join(TOMBSTONE_FILE).exists());
+
+        // the destination must not already exist
+        assert!(copy_segments(&dir, &dest).is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,92 @@
+use commitlog::Offset;
+use std::time::SystemTime;
+
+/// Summary of the log for operators.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct LogStats {
+    /// Offset of the first entry in the log, or `None` if the log is empty.
+    pub first_offset: Option<Offset>,
+
+    /// Offset of the last entry in the log, or `None` if the log is empty.
+    pub last_offset: Option<Offset>,
+
+    /// Bytes appended to the log that have not been flushed to disk.
+    pub unflushed_bytes: u64,
+
+    /// Segments of the log, ordered by base offset.
+    pub segments: Vec<SegmentStats>,
+
+    /// Number of the oldest segments the retention policies allow to be
+    /// deleted at the next retention check.
+    pub deletable_segments: usize,
+}
+
+/// Size of the log, kept by the log thread without listing the segments on
+/// disk, so cheap enough to request frequently. Segments created by a roll
+/// are counted once the appends are flushed.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct LogSummary {
+    /// First offset retained in the log, or `None` if the log is empty.
+    pub first_offset: Option<Offset>,
+
+    /// Offset of the last entry in the log, or `None` if the log is empty.
+    pub last_offset: Option<Offset>,
+
+    /// Bytes of the segments of the log.
+    pub bytes: u64,
+
+    /// Number of segments of the log.
+    pub segments: usize,
+
+    /// Time of the last flush to disk. The log is flushed when opened.
+    pub last_flush: SystemTime,
+}
+
+/// Depth of the queues in front of the log thread, and the requests drained
+/// from them, for telling a backed up queue from a slow log.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogQueueStats {
+    /// Appends queued that have not been batched by the log thread.
+    pub append_queue_depth: usize,
+
+    /// Reads sent that have not been received by the log thread.
+    pub read_queue_depth: usize,
+
+    /// Appends batched by the log thread.
+    pub appends_total: u64,
+
+    /// Reads received by the log thread.
+    pub reads_total: u64,
+
+    /// Reads received by the log thread after the requester dropped the
+    /// request, skipped without reading the log.
+    pub reads_abandoned: u64,
+}
+
+/// Segment of the log, as listed by the log thread.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct LogSegment {
+    /// First offset contained in the segment.
+    pub base_offset: Offset,
+
+    /// Last offset the segment may contain, before the base offset of the
+    /// next segment, or the last offset of the log for the active segment.
+    /// `None` if the segment is empty.
+    pub last_offset: Option<Offset>,
+
+    /// Size of the segment log file, in bytes.
+    pub bytes: u64,
+
+    /// Time the segment was created, if recorded by the filesystem.
+    pub created: Option<SystemTime>,
+}
+
+/// Segment of the log on disk.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SegmentStats {
+    /// First offset contained in the segment.
+    pub base_offset: Offset,
+
+    /// Size of the segment log file, in bytes.
+    pub bytes: u64,
+}
@@ -0,0 +1,355 @@
+//! Storage of the entries of the log, as the operations of the log thread
+//! on the commit log, with an in-memory implementation for tests.
+//!
+//! Retention, rollover and the read workers list the segment files of the
+//! log directory, so find nothing to do for entries held in memory.
+use super::compact::{self, CompactionStats};
+use commitlog::message::{MessageBuf, MessageSet, HEADER_SIZE};
+use commitlog::reader::LogSliceReader;
+use commitlog::{AppendError, CommitLog, Offset, ReadError, ReadLimit};
+use config::LogConfig;
+use std::io::{Error, ErrorKind};
+use std::ops::Range;
+
+/// Offsets of the entries of an append.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AppendRange {
+    first: Offset,
+    len: usize,
+}
+
+impl AppendRange {
+    pub fn new(first: Offset, len: usize) -> AppendRange {
+        AppendRange { first, len }
+    }
+
+    /// Offset of the first entry appended.
+    #[inline]
+    pub fn first(&self) -> Offset {
+        self.first
+    }
+
+    /// Number of entries appended.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Offsets of the range, in order.
+    pub fn iter(&self) -> Range<Offset> {
+        self.first..self.first + self.len as u64
+    }
+}
+
+/// Entries of a log, in offset order.
+pub trait Storage {
+    /// Entries read from the storage.
+    type Set: MessageSet;
+
+    /// Appends the messages, which carry their offsets, returning the range
+    /// of offsets appended.
+    fn append<M: MessageSet>(&mut self, msgs: &M) -> Result<AppendRange, AppendError>;
+
+    /// Reads the entries from the offset, up to `max_bytes` of entries.
+    fn read(&self, offset: Offset, max_bytes: usize) -> Result<Self::Set, ReadError>;
+
+    /// Reads the entries from the offset with the slice reader, as sent to
+    /// replicas. `None` if the offset has not been appended.
+    fn reader<R: LogSliceReader>(
+        &mut self,
+        reader: &mut R,
+        offset: Offset,
+        max_bytes: usize,
+    ) -> Result<Option<R::Result>, ReadError>;
+
+    /// Offset of the last entry, or `None` if the log is empty.
+    fn last_offset(&self) -> Option<Offset>;
+
+    /// Offset assigned to the next entry appended.
+    fn next_offset(&self) -> Offset;
+
+    /// Makes the entries appended durable.
+    fn flush(&mut self) -> Result<(), Error>;
+
+    /// Removes the entries after the offset.
+    fn truncate(&mut self, offset: Offset) -> Result<(), Error>;
+
+    /// Removes the segments with every offset below the offset.
+    fn trim_segments_before(&mut self, offset: Offset) -> Result<(), Error>;
+
+    /// Drops the entries of the inactive segments superseded by a later entry
+    /// with the same key. The storage is reopened with `reopen` once entries
+    /// are dropped.
+    fn compact_keys(&self, cfg: &LogConfig) -> Result<CompactionStats, Error>;
+
+    /// Reopens the storage after a compaction.
+    fn reopen(&mut self, cfg: &LogConfig) -> Result<(), Error>;
+
+    /// Removes every entry, leaving an empty log. `truncate` keeps the entry
+    /// at the offset, so cannot remove the first entry.
+    fn clear(&mut self, cfg: &LogConfig) -> Result<(), Error>;
+}
+
+impl Storage for CommitLog {
+    type Set = MessageBuf;
+
+    fn append<M: MessageSet>(&mut self, msgs: &M) -> Result<AppendRange, AppendError> {
+        let range = self.append_with_offsets(msgs)?;
+        Ok(AppendRange::new(range.first(), range.len()))
+    }
+
+    fn read(&self, offset: Offset, max_bytes: usize) -> Result<Self::Set, ReadError> {
+        CommitLog::read(self, offset, ReadLimit::max_bytes(max_bytes))
+    }
+
+    fn reader<R: LogSliceReader>(
+        &mut self,
+        reader: &mut R,
+        offset: Offset,
+        max_bytes: usize,
+    ) -> Result<Option<R::Result>, ReadError> {
+        CommitLog::reader(self, reader, offset, ReadLimit::max_bytes(max_bytes))
+    }
+
+    fn last_offset(&self) -> Option<Offset> {
+        CommitLog::last_offset(self)
+    }
+
+    fn next_offset(&self) -> Offset {
+        CommitLog::next_offset(self)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        CommitLog::flush(self)
+    }
+
+    fn truncate(&mut self, offset: Offset) -> Result<(), Error> {
+        CommitLog::truncate(self, offset)
+    }
+
+    fn trim_segments_before(&mut self, offset: Offset) -> Result<(), Error> {
+        CommitLog::trim_segments_before(self, offset)
+    }
+
+    fn compact_keys(&self, cfg: &LogConfig) -> Result<CompactionStats, Error> {
+        compact::compact_keys(self, cfg)
+    }
+
+    fn reopen(&mut self, cfg: &LogConfig) -> Result<(), Error> {
+        compact::reopen(self, cfg)
+    }
+
+    fn clear(&mut self, cfg: &LogConfig) -> Result<(), Error> {
+        compact::reset(self, cfg)
+    }
+}
+
+/// Bytes of entries read from memory.
+pub struct EntryBytes(Vec<u8>);
+
+impl MessageSet for EntryBytes {
+    fn bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Log held in memory, lost when dropped. The log has no segments, so
+/// replication reads fail and there is nothing to compact.
+#[derive(Default)]
+pub struct MemStorage {
+    // offset and the bytes of each entry
+    entries: Vec<(Offset, Vec<u8>)>,
+    // kept when the entries are trimmed
+    next_offset: Offset,
+}
+
+impl MemStorage {
+    pub fn new() -> MemStorage {
+        MemStorage::default()
+    }
+
+    /// Index of the first entry at or after the offset.
+    fn position(&self, offset: Offset) -> usize {
+        match self.entries.binary_search_by_key(&offset, |&(off, _)| off) {
+            Ok(i) | Err(i) => i,
+        }
+    }
+}
+
+impl Storage for MemStorage {
+    type Set = EntryBytes;
+
+    fn append<M: MessageSet>(&mut self, msgs: &M) -> Result<AppendRange, AppendError> {
+        let bytes = msgs.bytes();
+        let mut pos = 0;
+        let mut appended = Vec::new();
+        let mut next = self.next_offset();
+        for msg in msgs.iter() {
+            if msg.offset() < next {
+                return Err(AppendError::Io(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Offset {} is before the end of the log", msg.offset()),
+                )));
+            }
+            let len = HEADER_SIZE + msg.metadata().len() + msg.payload().len();
+            appended.push((msg.offset(), bytes[pos..pos + len].to_vec()));
+            pos += len;
+            next = msg.offset() + 1;
+        }
+
+        let range = match appended.first() {
+            Some(&(first, _)) => AppendRange::new(first, appended.len()),
+            None => AppendRange::new(next, 0),
+        };
+        self.entries.extend(appended);
+        self.next_offset = next;
+        Ok(range)
+    }
+
+    fn read(&self, offset: Offset, max_bytes: usize) -> Result<EntryBytes, ReadError> {
+        let mut bytes = Vec::new();
+        for &(_, ref entry) in &self.entries[self.position(offset)..] {
+            if bytes.len() + entry.len() > max_bytes {
+                break;
+            }
+            bytes.extend_from_slice(entry);
+        }
+        Ok(EntryBytes(bytes))
+    }
+
+    fn reader<R: LogSliceReader>(
+        &mut self,
+        _reader: &mut R,
+        _offset: Offset,
+        _max_bytes: usize,
+    ) -> Result<Option<R::Result>, ReadError> {
+        Err(ReadError::Io(Error::new(
+            ErrorKind::Other,
+            "Replication reads need the segments of a log on disk",
+        )))
+    }
+
+    fn last_offset(&self) -> Option<Offset> {
+        self.next_offset.checked_sub(1)
+    }
+
+    fn next_offset(&self) -> Offset {
+        self.next_offset
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn truncate(&mut self, offset: Offset) -> Result<(), Error> {
+        self.entries.retain(|&(off, _)| off <= offset);
+        self.next_offset = self.next_offset.min(offset + 1);
+        Ok(())
+    }
+
+    fn trim_segments_before(&mut self, offset: Offset) -> Result<(), Error> {
+        let trimmed = self.position(offset);
+        self.entries.drain(..trimmed);
+        Ok(())
+    }
+
+    fn compact_keys(&self, _cfg: &LogConfig) -> Result<CompactionStats, Error> {
+        Ok(CompactionStats::default())
+    }
+
+    fn reopen(&mut self, _cfg: &LogConfig) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn clear(&mut self, _cfg: &LogConfig) -> Result<(), Error> {
+        self.entries.clear();
+        self.next_offset = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asynclog::{log_options, Messages, MessagesMut};
+    use bytes::BytesMut;
+    use commitlog::message::set_offsets;
+    use std::{env, fs, process};
+
+    fn messages(base: Offset, payloads: &[&str]) -> MessagesMut {
+        let mut buf: MessagesMut = BytesMut::with_capacity(4096).into();
+        for (i, payload) in payloads.iter().enumerate() {
+            buf.push(1, i as u64, payload).unwrap();
+        }
+        set_offsets(&mut buf, base);
+        buf
+    }
+
+    fn read<S: Storage>(storage: &S, offset: Offset) -> Messages {
+        Messages::copy_from(&storage.read(offset, 4096).unwrap())
+    }
+
+    fn payloads(msgs: &Messages) -> Vec<Vec<u8>> {
+        msgs.iter().map(|m| m.payload().to_vec()).collect()
+    }
+
+    fn appends_and_reads<S: Storage>(storage: &mut S, cfg: &LogConfig) {
+        assert_eq!(None, storage.last_offset());
+        assert_eq!(0, storage.next_offset());
+        assert_eq!(0, read(storage, 0).len());
+
+        let range = storage.append(&messages(0, &["foo", "bar"])).unwrap();
+        assert_eq!(0..2, range.iter());
+        assert_eq!(2..3, storage.append(&messages(2, &["baz"])).unwrap().iter());
+        storage.flush().unwrap();
+        assert_eq!(Some(2), storage.last_offset());
+        assert_eq!(3, storage.next_offset());
+
+        let msgs = read(storage, 0);
+        assert_eq!(
+            vec![b"foo".to_vec(), b"bar".to_vec(), b"baz".to_vec()],
+            payloads(&msgs)
+        );
+        assert_eq!(Some(3), msgs.next_offset());
+        let msgs = read(storage, 1);
+        assert_eq!(vec![b"bar".to_vec(), b"baz".to_vec()], payloads(&msgs));
+        assert_eq!(0, read(storage, 3).len());
+
+        storage.truncate(0).unwrap();
+        assert_eq!(Some(0), storage.last_offset());
+        assert_eq!(1..2, storage.append(&messages(1, &["qux"])).unwrap().iter());
+        let msgs = read(storage, 0);
+        assert_eq!(vec![b"foo".to_vec(), b"qux".to_vec()], payloads(&msgs));
+
+        storage.clear(cfg).unwrap();
+        assert_eq!(None, storage.last_offset());
+        assert_eq!(0, read(storage, 0).len());
+        assert_eq!(
+            0..1,
+            storage.append(&messages(0, &["quux"])).unwrap().iter()
+        );
+        assert_eq!(vec![b"quux".to_vec()], payloads(&read(storage, 0)));
+    }
+
+    #[test]
+    fn commit_log_storage() {
+        let dir = env::temp_dir().join(format!("log-storage-test-{}", process::id()));
+        let mut cfg = LogConfig::default();
+        cfg.dir = dir.to_string_lossy().into_owned();
+        {
+            let mut log = CommitLog::new(log_options(&cfg)).unwrap();
+            appends_and_reads(&mut log, &cfg);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn mem_storage() {
+        appends_and_reads(&mut MemStorage::new(), &LogConfig::default());
+    }
+}
@@ -1,9 +1,19 @@
-use futures::{Async, Future, Poll};
+use asynclog::queue::QueuedRead;
+use asynclog::trace::{traced, TraceId};
+use asynclog::Messages;
+use commitlog::Offset;
+use futures::{Async, Future, Poll, Stream};
 use std::io::{Error, ErrorKind};
-use tokio_sync::oneshot;
+use tokio_sync::{mpsc, oneshot};
 
+/// Sends the result of a request to its `LogFuture`. A result sent after the
+/// `LogFuture` is dropped is discarded, as the requester has gone away.
+///
+/// The sender carries the trace of the request, zero if untraced, which
+/// errors sent are logged and returned with.
 pub struct LogSender<T> {
     s: oneshot::Sender<Result<T, Error>>,
+    trace_id: TraceId,
 }
 
 impl<T> LogSender<T> {
@@ -14,18 +24,66 @@ impl<T> LogSender<T> {
 
     #[inline]
     pub fn send_err(self, e: Error) {
-        self.s.send(Err(e)).unwrap_or_default();
+        debug!("[trace {}] Request failed: {}", self.trace_id, e);
+        self.s
+            .send(Err(traced(e, self.trace_id)))
+            .unwrap_or_default();
+    }
+
+    /// Trace of the request.
+    #[inline]
+    pub fn trace_id(&self) -> TraceId {
+        self.trace_id
+    }
+
+    /// Tests whether the receiving `LogFuture` has been dropped.
+    #[inline]
+    pub fn is_canceled(&self) -> bool {
+        self.s.is_closed()
     }
 
     #[inline]
     pub fn send_err_with(self, k: ErrorKind, e: &'static str) {
-        self.s.send(Err(Error::new(k, e))).unwrap_or_default();
+        self.send_err(Error::new(k, e));
+    }
+}
+
+/// Sender of the result of a request, which fails the request without it
+/// reaching the log thread.
+pub trait FailSender {
+    fn send_err(self, e: Error);
+}
+
+impl<T> FailSender for LogSender<T> {
+    #[inline]
+    fn send_err(self, e: Error) {
+        LogSender::send_err(self, e)
+    }
+}
+
+impl FailSender for AckSender {
+    #[inline]
+    fn send_err(self, e: Error) {
+        AckSender::send_err(self, e)
     }
 }
 
 /// `LogFuture` waits for a response from the `CommitLog`.
 pub struct LogFuture<R> {
     f: oneshot::Receiver<Result<R, Error>>,
+    // read waiting for a slot in the read queue before it is sent
+    queued: Option<Box<QueuedRead>>,
+}
+
+impl<R> LogFuture<R> {
+    /// Future of the result of the read once it is sent from the full
+    /// read queue.
+    pub(crate) fn queued(self, read: QueuedRead) -> LogFuture<R> {
+        LogFuture {
+            f: self.f,
+            queued: Some(Box::new(read)),
+        }
+    }
 }
 
 impl<R> Future for LogFuture<R> {
@@ -33,6 +91,11 @@ impl<R> Future for LogFuture<R> {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<R, Error> {
+        if let Some(ref mut read) = self.queued {
+            try_ready!(read.poll_send());
+        }
+        self.queued = None;
+
         match self.f.poll() {
             Ok(Async::Ready(Ok(v))) => Ok(Async::Ready(v)),
             Ok(Async::Ready(Err(e))) => {
@@ -41,14 +104,136 @@ impl<R> Future for LogFuture<R> {
             }
             Ok(Async::NotReady) => Ok(Async::NotReady),
             Err(e) => {
+                // the log thread exited with the request
                 error!("Encountered cancellation: {:?}", e);
-                Err(Error::new(ErrorKind::Other, "Cancelled"))
+                Err(Error::new(
+                    ErrorKind::BrokenPipe,
+                    "Log thread dropped the request",
+                ))
             }
         }
     }
 }
 
 pub fn channel<T>() -> (LogSender<T>, LogFuture<T>) {
+    traced_channel(0)
+}
+
+/// Channel for the result of a request with the trace.
+pub fn traced_channel<T>(trace_id: TraceId) -> (LogSender<T>, LogFuture<T>) {
     let (s, f) = oneshot::channel::<Result<T, Error>>();
-    (LogSender { s }, LogFuture { f })
+    (LogSender { s, trace_id }, LogFuture { f, queued: None })
+}
+
+/// Sends the acks for entries of a batch append, as the entries are appended.
+pub struct AckSender {
+    s: mpsc::UnboundedSender<Result<(usize, Offset), Error>>,
+}
+
+impl AckSender {
+    /// Sends the ack for the entry at the index within the batch. Returns false
+    /// if the receiving `AppendAckStream` has been dropped.
+    #[inline]
+    pub fn send(&mut self, index: usize, offset: Offset) -> bool {
+        self.s.try_send(Ok((index, offset))).is_ok()
+    }
+
+    #[inline]
+    pub fn send_err(mut self, e: Error) {
+        self.s.try_send(Err(e)).unwrap_or_default();
+    }
+}
+
+/// `AppendAckStream` yields the index within the batch and the offset
+/// of each entry, in batch order, as the entries are appended.
+pub struct AppendAckStream {
+    r: mpsc::UnboundedReceiver<Result<(usize, Offset), Error>>,
+}
+
+impl Stream for AppendAckStream {
+    type Item = (usize, Offset);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<(usize, Offset)>, Error> {
+        match self.r.poll() {
+            Ok(Async::Ready(Some(Ok(v)))) => Ok(Async::Ready(Some(v))),
+            Ok(Async::Ready(Some(Err(e)))) => {
+                error!("Batch append error {}", e);
+                Err(e)
+            }
+            Ok(Async::Ready(None)) => Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(e) => {
+                error!("Encountered cancellation: {:?}", e);
+                Err(Error::new(ErrorKind::Other, "Cancelled"))
+            }
+        }
+    }
+}
+
+pub fn ack_channel() -> (AckSender, AppendAckStream) {
+    let (s, r) = mpsc::unbounded_channel::<Result<(usize, Offset), Error>>();
+    (AckSender { s }, AppendAckStream { r })
+}
+
+/// Sends the entries of a subscription as they are appended. The buffer is
+/// bounded, so the log thread reads ahead only as the subscriber consumes.
+pub struct SubscriptionSender {
+    s: mpsc::Sender<Result<Messages, Error>>,
+}
+
+impl SubscriptionSender {
+    /// Tests whether the buffer has room for another batch, registering
+    /// the current task to be notified once it does. Fails if the
+    /// receiving `Subscription` has been dropped.
+    #[inline]
+    pub fn poll_ready(&mut self) -> Poll<(), ()> {
+        self.s.poll_ready().map_err(|_| ())
+    }
+
+    /// Sends a batch of entries, which must follow a ready `poll_ready`.
+    #[inline]
+    pub fn send(&mut self, msgs: Messages) {
+        self.s.try_send(Ok(msgs)).unwrap_or_default();
+    }
+
+    #[inline]
+    pub fn send_err(mut self, e: Error) {
+        self.s.try_send(Err(e)).unwrap_or_default();
+    }
+}
+
+/// `Subscription` yields batches of entries from the starting offset, first
+/// those already in the log, then those appended after the subscription.
+///
+/// The stream ends once the log is shut down.
+pub struct Subscription {
+    r: mpsc::Receiver<Result<Messages, Error>>,
+}
+
+impl Stream for Subscription {
+    type Item = Messages;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Messages>, Error> {
+        match self.r.poll() {
+            Ok(Async::Ready(Some(Ok(v)))) => Ok(Async::Ready(Some(v))),
+            Ok(Async::Ready(Some(Err(e)))) => {
+                error!("Subscription error {}", e);
+                Err(e)
+            }
+            Ok(Async::Ready(None)) => Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(e) => {
+                error!("Encountered cancellation: {:?}", e);
+                Err(Error::new(ErrorKind::Other, "Cancelled"))
+            }
+        }
+    }
+}
+
+/// Channel for a subscription buffering up to `batches` batches of entries.
+pub fn subscription_channel(batches: usize) -> (SubscriptionSender, Subscription) {
+    let (s, r) = mpsc::channel::<Result<Messages, Error>>(batches);
+    (SubscriptionSender { s }, Subscription { r })
 }
@@ -0,0 +1,72 @@
+use super::{Messages, MessagesMut};
+use bytes::BytesMut;
+use commitlog::message::MessageSet;
+use commitlog::Offset;
+use std::io::Error;
+
+/// Bytes read from the log at a time when collecting the tail.
+const READ_BYTES: usize = 1024 * 1024;
+
+/// Reads the last `n` entries before `next_offset`, in offset order, using
+/// the `read` function to read entries from an offset up to a number of bytes.
+///
+/// Fewer than `n` entries are returned if the log is shorter or some of the
+/// entries have been tombstoned.
+pub fn read_tail<F>(next_offset: Offset, n: usize, mut read: F) -> Result<Messages, Error>
+where
+    F: FnMut(Offset, usize) -> Result<Messages, Error>,
+{
+    let mut pos = next_offset.saturating_sub(n as u64);
+    let mut buf = BytesMut::new();
+    while pos < next_offset {
+        let msgs = read(pos, READ_BYTES)?;
+        match msgs.next_offset() {
+            Some(next) if next > pos => pos = next,
+            _ => break,
+        }
+        buf.extend_from_slice(msgs.bytes());
+    }
+    Ok(Messages::copy_from(&MessagesMut(buf)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use commitlog::{CommitLog, LogOptions, ReadLimit};
+    use std::{env, fs, process};
+
+    #[test]
+    fn tail_of_short_log() {
+        let dir = env::temp_dir().join(format!("log-tail-test-{}", process::id()));
+        let mut log = CommitLog::new(LogOptions::new(&dir)).unwrap();
+        for payload in &["a", "b", "c"] {
+            log.append_msg(payload).unwrap();
+        }
+
+        let read = |pos, max_bytes| {
+            let msgs = log.read(pos, ReadLimit::max_bytes(max_bytes)).unwrap();
+            Ok(Messages::copy_from(&msgs))
+        };
+        let payloads = |msgs: Messages| {
+            msgs.iter()
+                .map(|m| (m.offset(), m.payload().to_vec()))
+                .collect::<Vec<_>>()
+        };
+
+        // fewer entries than requested
+        let msgs = read_tail(log.next_offset(), 100, read).unwrap();
+        assert_eq!(Some(3), msgs.next_offset());
+        assert_eq!(
+            vec![(0, b"a".to_vec()), (1, b"b".to_vec()), (2, b"c".to_vec())],
+            payloads(msgs)
+        );
+
+        let msgs = read_tail(log.next_offset(), 2, read).unwrap();
+        assert_eq!(vec![(1, b"b".to_vec()), (2, b"c".to_vec())], payloads(msgs));
+
+        assert_eq!(0, read_tail(log.next_offset(), 0, read).unwrap().len());
+        assert_eq!(0, read_tail(0, 10, read).unwrap().len());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,96 @@
+use futures::task::{self, Task};
+use futures::{Poll, Stream};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+/// Stream that wakes the task polling it at an interval, even while the
+/// inner stream is idle.
+///
+/// `Sink::send_all` polls the sink to completion whenever the stream is not
+/// ready, so the periodic flush and retention checks of the log run on an
+/// idle log. The stream passes through the items of the inner stream and
+/// ends with it.
+pub struct TickStream<S> {
+    stream: S,
+    task: Arc<Mutex<Option<Task>>>,
+}
+
+impl<S: Stream> TickStream<S> {
+    /// Wraps the stream, spawning the thread waking the polling task. The
+    /// thread exits once the stream is dropped.
+    pub fn new(stream: S, interval: Duration) -> TickStream<S> {
+        let task = Arc::new(Mutex::new(None));
+        let ticker: Weak<Mutex<Option<Task>>> = Arc::downgrade(&task);
+        thread::Builder::new()
+            .name("log-ticker".to_string())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                let task = match ticker.upgrade() {
+                    Some(task) => task,
+                    None => return,
+                };
+
+                if let Some(ref t) = *task.lock().unwrap() {
+                    t.notify();
+                }
+            })
+            .expect("Unable to spawn the log ticker thread");
+        TickStream { stream, task }
+    }
+}
+
+impl<S: Stream> Stream for TickStream<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        {
+            let mut task = self.task.lock().unwrap();
+            let current = match task.take() {
+                Some(ref t) if t.will_notify_current() => t.clone(),
+                _ => task::current(),
+            };
+            *task = Some(current);
+        }
+        self.stream.poll()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::{poll_fn, Future};
+    use futures::sync::mpsc;
+    use futures::Async;
+
+    #[test]
+    fn wakes_idle_task() {
+        let (_snd, rcv) = mpsc::unbounded::<()>();
+        let mut s = TickStream::new(rcv, Duration::from_millis(10));
+
+        // the receiver is never ready, so only the ticker wakes the task
+        let mut polls = 0;
+        poll_fn(|| {
+            polls += 1;
+            if polls == 3 {
+                return Ok::<_, ()>(Async::Ready(()));
+            }
+            assert_eq!(Ok(Async::NotReady), s.poll());
+            Ok(Async::NotReady)
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn ends_with_inner_stream() {
+        let (snd, rcv) = mpsc::unbounded::<u32>();
+        snd.unbounded_send(1).unwrap();
+        drop(snd);
+        let items: Vec<_> = TickStream::new(rcv, Duration::from_millis(10))
+            .wait()
+            .collect();
+        assert_eq!(vec![Ok(1)], items);
+    }
+}
@@ -0,0 +1,266 @@
+use super::persist::write_atomically;
+use byteorder::{ByteOrder, LittleEndian};
+use commitlog::Offset;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const TIME_INDEX_FILE: &str = "timeindex";
+const TIME_INDEX_TMP_FILE: &str = "timeindex.tmp";
+const ENTRY_SIZE: usize = 16;
+
+/// Sparse index from the time entries were appended to their offsets.
+///
+/// The index holds the time and offset of the first append of each interval,
+/// so an entry at or after a time is found to within the interval. Entries
+/// appended before the index existed are not found by time.
+///
+/// New index entries are appended to the index file without a sync, as the
+/// index is recovered against the segments when the log is opened.
+pub struct TimeIndex {
+    path: PathBuf,
+    interval_ms: u64,
+    /// Millis since the epoch and offset, ordered by both.
+    entries: Vec<(u64, Offset)>,
+    /// Latest time an entry may have been appended, if any.
+    last_append_ms: Option<u64>,
+    file: Option<File>,
+}
+
+impl TimeIndex {
+    /// Loads the index persisted in the log directory, recovering it against
+    /// the offsets remaining in the log.
+    pub fn open<P: AsRef<Path>>(
+        dir: P,
+        interval: Duration,
+        first_offset: Offset,
+        next_offset: Offset,
+    ) -> io::Result<TimeIndex> {
+        let path = dir.as_ref().join(TIME_INDEX_FILE);
+        let entries = match File::open(&path) {
+            Ok(mut f) => {
+                let mut bytes = vec![];
+                f.read_to_end(&mut bytes)?;
+                decode(&bytes)
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        let mut index = TimeIndex {
+            path,
+            interval_ms: millis(interval),
+            entries,
+            last_append_ms: None,
+            file: None,
+        };
+        // the file may have a partial entry or entries truncated from the log
+        index.persist()?;
+        index.retain(first_offset, next_offset)?;
+        index.reset_last_append();
+        Ok(index)
+    }
+
+    /// Records an append of entries from the offset, adding an index entry
+    /// if the interval has elapsed since the last index entry.
+    pub fn append(&mut self, now: SystemTime, offset: Offset) -> io::Result<()> {
+        // a wall clock moving backwards keeps the index ordered
+        let now = millis(now.duration_since(UNIX_EPOCH).unwrap_or_default())
+            .max(self.last_append_ms.unwrap_or(0));
+        self.last_append_ms = Some(now);
+
+        let due = match self.entries.last() {
+            Some(&(t, off)) => offset > off && now >= t + self.interval_ms,
+            None => true,
+        };
+        if !due {
+            return Ok(());
+        }
+
+        self.entries.push((now, offset));
+        if self.file.is_none() {
+            self.file = Some(
+                OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(&self.path)?,
+            );
+        }
+        let mut buf = [0u8; ENTRY_SIZE];
+        encode_entry(&mut buf, now, offset);
+        self.file.as_mut().unwrap().write_all(&buf)
+    }
+
+    /// First offset appended at or after the time, to within the interval,
+    /// or the next offset if nothing has been appended since. `None` if the
+    /// time is in the future.
+    pub fn offset_for_time(&self, time: SystemTime, next_offset: Offset) -> Option<Offset> {
+        if time > SystemTime::now() {
+            return None;
+        }
+
+        let ts = millis(time.duration_since(UNIX_EPOCH).unwrap_or_default());
+        if self.last_append_ms.map(|last| ts > last).unwrap_or(true) {
+            return Some(next_offset);
+        }
+
+        // the interval starting at or before the time holds the first entry
+        // appended at or after the time
+        match self.entries.binary_search_by_key(&ts, |&(t, _)| t) {
+            Ok(i) => Some(self.entries[i].1),
+            Err(0) => self.entries.first().map(|&(_, off)| off),
+            Err(i) => Some(self.entries[i - 1].1),
+        }
+    }
+
+    /// Removes the index entries for offsets no longer in the log. The
+    /// interval holding the first offset is kept, starting at the offset.
+    pub fn retain(&mut self, first_offset: Offset, next_offset: Offset) -> io::Result<()> {
+        let before = (self.entries.len(), self.entries.first().cloned());
+        let len = self.entries.len();
+        self.entries.retain(|&(_, off)| off < next_offset);
+        if self.entries.len() != len {
+            self.reset_last_append();
+        }
+
+        let below = self
+            .entries
+            .iter()
+            .take_while(|&&(_, off)| off <= first_offset)
+            .count();
+        if below > 0 {
+            self.entries.drain(0..below - 1);
+            self.entries[0].1 = first_offset;
+        }
+
+        if (self.entries.len(), self.entries.first().cloned()) != before {
+            self.persist()?;
+        }
+        Ok(())
+    }
+
+    fn reset_last_append(&mut self) {
+        // entries after the last index entry were appended within the interval
+        let interval_ms = self.interval_ms;
+        self.last_append_ms = self.entries.last().map(|&(t, _)| t + interval_ms);
+    }
+
+    fn persist(&mut self) -> io::Result<()> {
+        let mut bytes = vec![0u8; self.entries.len() * ENTRY_SIZE];
+        for (&(t, off), buf) in self.entries.iter().zip(bytes.chunks_mut(ENTRY_SIZE)) {
+            encode_entry(buf, t, off);
+        }
+
+        // appends reopen the file once replaced
+        self.file = None;
+        write_atomically(&self.path, TIME_INDEX_TMP_FILE, &bytes)
+    }
+}
+
+fn millis(d: Duration) -> u64 {
+    d.as_secs() * 1_000 + u64::from(d.subsec_millis())
+}
+
+fn encode_entry(buf: &mut [u8], t: u64, offset: Offset) {
+    LittleEndian::write_u64(&mut buf[0..8], t);
+    LittleEndian::write_u64(&mut buf[8..16], offset);
+}
+
+/// Decodes the index entries, skipping a partial entry at the end and any
+/// entry out of order, as from an interrupted write.
+fn decode(bytes: &[u8]) -> Vec<(u64, Offset)> {
+    let mut entries: Vec<(u64, Offset)> = Vec::with_capacity(bytes.len() / ENTRY_SIZE);
+    for buf in bytes
+        .chunks(ENTRY_SIZE)
+        .filter(|buf| buf.len() == ENTRY_SIZE)
+    {
+        let entry = (
+            LittleEndian::read_u64(&buf[0..8]),
+            LittleEndian::read_u64(&buf[8..16]),
+        );
+        match entries.last() {
+            Some(&(t, off)) if entry.0 <= t || entry.1 <= off => continue,
+            _ => entries.push(entry),
+        }
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("time-index-{}-test-{}", name, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn finds_offset_for_time() {
+        let dir = test_dir("lookup");
+        let mut index = TimeIndex::open(&dir, Duration::from_secs(10), 0, 0).unwrap();
+        assert_eq!(Some(0), index.offset_for_time(at(100), 0));
+
+        index.append(at(100), 0).unwrap();
+        index.append(at(105), 10).unwrap();
+        index.append(at(110), 20).unwrap();
+        index.append(at(125), 30).unwrap();
+        assert_eq!(
+            vec![(100_000, 0), (110_000, 20), (125_000, 30)],
+            index.entries
+        );
+
+        assert_eq!(Some(0), index.offset_for_time(at(50), 40));
+        assert_eq!(Some(0), index.offset_for_time(at(100), 40));
+        assert_eq!(Some(0), index.offset_for_time(at(105), 40));
+        assert_eq!(Some(20), index.offset_for_time(at(110), 40));
+        assert_eq!(Some(20), index.offset_for_time(at(124), 40));
+        assert_eq!(Some(30), index.offset_for_time(at(125), 40));
+        // after the last append
+        assert_eq!(Some(40), index.offset_for_time(at(126), 40));
+        // in the future
+        let future = SystemTime::now() + Duration::from_secs(3600);
+        assert_eq!(None, index.offset_for_time(future, 40));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recovers_against_log() {
+        let dir = test_dir("recover");
+        {
+            let mut index = TimeIndex::open(&dir, Duration::from_secs(10), 0, 0).unwrap();
+            for i in 0..5 {
+                index.append(at(100 + i * 10), i * 10).unwrap();
+            }
+        }
+        // a partial entry from an interrupted write
+        OpenOptions::new()
+            .append(true)
+            .open(dir.join(TIME_INDEX_FILE))
+            .unwrap()
+            .write_all(&[1, 2, 3])
+            .unwrap();
+
+        // segments before offset 15 deleted, entries from 35 truncated
+        let index = TimeIndex::open(&dir, Duration::from_secs(10), 15, 35).unwrap();
+        assert_eq!(
+            vec![(110_000, 15), (120_000, 20), (130_000, 30)],
+            index.entries
+        );
+        assert_eq!(Some(15), index.offset_for_time(at(100), 35));
+        assert_eq!(Some(35), index.offset_for_time(at(141), 35));
+
+        let index = TimeIndex::open(&dir, Duration::from_secs(10), 15, 35).unwrap();
+        assert_eq!(3, index.entries.len());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,180 @@
+use super::persist::write_atomically;
+use byteorder::{ByteOrder, LittleEndian};
+use commitlog::Offset;
+use std::fs::File;
+use std::io::{self, Read};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+pub const TOMBSTONE_FILE: &str = "tombstones";
+const TOMBSTONE_TMP_FILE: &str = "tombstones.tmp";
+const ENTRY_SIZE: usize = 16;
+
+/// Offset ranges that have been logically deleted from the log.
+///
+/// Tombstoned offsets are hidden from reads as soon as they are recorded.
+/// The bytes remain in the segment files until compaction rewrites the
+/// segments containing them. Tombstones are local to the node.
+#[derive(Clone, PartialEq)]
+pub struct Tombstones {
+    path: PathBuf,
+    /// Sorted, non-overlapping ranges (end exclusive).
+    ranges: Vec<Range<Offset>>,
+}
+
+impl Tombstones {
+    /// Loads the tombstones persisted in the log directory.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Tombstones> {
+        let path = dir.as_ref().join(TOMBSTONE_FILE);
+        let ranges = match File::open(&path) {
+            Ok(mut f) => {
+                let mut bytes = vec![];
+                f.read_to_end(&mut bytes)?;
+                decode(&bytes)?
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(Tombstones { path, ranges })
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Tests whether the offset has been tombstoned.
+    pub fn contains(&self, offset: Offset) -> bool {
+        match self.ranges.binary_search_by(|r| r.start.cmp(&offset)) {
+            Ok(_) => true,
+            Err(0) => false,
+            Err(i) => offset < self.ranges[i - 1].end,
+        }
+    }
+
+    /// Records the range as deleted, persisting the tombstones to disk.
+    pub fn insert(&mut self, range: Range<Offset>) -> io::Result<()> {
+        self.add(range);
+        self.persist()
+    }
+
+    fn add(&mut self, range: Range<Offset>) {
+        if range.start >= range.end {
+            return;
+        }
+
+        self.ranges.push(range);
+        self.ranges.sort_by_key(|r| r.start);
+
+        // coalesce overlapping and adjacent ranges
+        let mut merged: Vec<Range<Offset>> = Vec::with_capacity(self.ranges.len());
+        for r in self.ranges.drain(..) {
+            if let Some(last) = merged.last_mut() {
+                if r.start <= last.end {
+                    if r.end > last.end {
+                        last.end = r.end;
+                    }
+                    continue;
+                }
+            }
+            merged.push(r);
+        }
+        self.ranges = merged;
+    }
+
+    fn persist(&self) -> io::Result<()> {
+        let mut bytes = vec![0u8; self.ranges.len() * ENTRY_SIZE];
+        for (r, buf) in self.ranges.iter().zip(bytes.chunks_mut(ENTRY_SIZE)) {
+            LittleEndian::write_u64(&mut buf[0..8], r.start);
+            LittleEndian::write_u64(&mut buf[8..16], r.end);
+        }
+
+        write_atomically(&self.path, TOMBSTONE_TMP_FILE, &bytes)
+    }
+}
+
+fn decode(bytes: &[u8]) -> io::Result<Vec<Range<Offset>>> {
+    if bytes.len() % ENTRY_SIZE != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid tombstone file length",
+        ));
+    }
+
+    Ok(bytes
+        .chunks(ENTRY_SIZE)
+        .map(|buf| LittleEndian::read_u64(&buf[0..8])..LittleEndian::read_u64(&buf[8..16]))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asynclog::{Messages, MessagesMut};
+    use bytes::BytesMut;
+    use commitlog::message::{set_offsets, MessageSet};
+
+    fn empty() -> Tombstones {
+        Tombstones {
+            path: PathBuf::new(),
+            ranges: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn contains_range() {
+        let mut t = empty();
+        t.add(5..10);
+
+        assert!(!t.contains(4));
+        assert!(t.contains(5));
+        assert!(t.contains(9));
+        assert!(!t.contains(10));
+    }
+
+    #[test]
+    fn merges_overlapping_ranges() {
+        let mut t = empty();
+        t.add(20..30);
+        t.add(5..10);
+        t.add(8..12);
+        t.add(12..15);
+        t.add(7..7);
+
+        assert_eq!(vec![5..15, 20..30], t.ranges);
+    }
+
+    #[test]
+    fn decode_encoded() {
+        let mut bytes = vec![0u8; 32];
+        LittleEndian::write_u64(&mut bytes[0..8], 1);
+        LittleEndian::write_u64(&mut bytes[8..16], 3);
+        LittleEndian::write_u64(&mut bytes[16..24], 10);
+        LittleEndian::write_u64(&mut bytes[24..32], 11);
+        assert_eq!(vec![1..3, 10..11], decode(&bytes).unwrap());
+        assert!(decode(&bytes[0..10]).is_err());
+    }
+
+    #[test]
+    fn tombstoned_offsets_not_read() {
+        let mut buf = MessagesMut(BytesMut::with_capacity(1024));
+        for i in 0..6u64 {
+            buf.push(0, i, b"0123456789").unwrap();
+        }
+        set_offsets(&mut buf, 100);
+
+        let mut t = empty();
+        t.add(101..103);
+        t.add(105..106);
+
+        let msgs = Messages::copy_filtered(&buf, |off| !t.contains(off));
+        assert_eq!(3, msgs.len());
+        assert_eq!(
+            vec![100, 103, 104],
+            msgs.iter().map(|m| m.offset()).collect::<Vec<_>>()
+        );
+        assert!(msgs.iter().all(|m| m.payload() == b"0123456789"));
+        assert_eq!(Some(106), msgs.next_offset());
+    }
+}
@@ -0,0 +1,264 @@
+use super::trace::untraced;
+use super::AsyncLog;
+use config::LogConfig;
+use futures::future::{join_all, Future};
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Name of the topic served by the log in the log directory itself.
+pub const DEFAULT_TOPIC: &str = "default";
+
+/// Directory under the log directory holding a directory for each topic.
+const TOPICS_DIR: &str = "topics";
+
+const TOPIC_MAX_LEN: usize = 64;
+
+type OpenTopic = Fn(&str, &LogConfig) -> Result<AsyncLog, Error> + Send + Sync;
+
+/// Tests whether the topic is the default topic, served by the log of the
+/// log directory itself.
+#[inline]
+pub fn is_default(topic: &str) -> bool {
+    topic.is_empty() || topic == DEFAULT_TOPIC
+}
+
+/// Append to a topic paused for maintenance, rejected until the topic is
+/// resumed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicPaused {
+    pub topic: String,
+}
+
+impl fmt::Display for TopicPaused {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Topic {} is paused, appends are rejected", self.topic)
+    }
+}
+
+impl error::Error for TopicPaused {}
+
+impl TopicPaused {
+    /// Error of kind `ErrorKind::PermissionDenied` failing an append to the
+    /// paused topic.
+    pub fn error(topic: &str) -> Error {
+        let topic = topic.to_string();
+        Error::new(ErrorKind::PermissionDenied, TopicPaused { topic })
+    }
+
+    /// The paused topic, if the append failed as its topic is paused rather
+    /// than for any other reason.
+    pub fn from_error(e: &Error) -> Option<TopicPaused> {
+        untraced(e)
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<TopicPaused>())
+            .cloned()
+    }
+}
+
+/// Whether appends to the log of a topic are paused, shared by the handles
+/// to the log, which check it before sending an append.
+pub struct TopicPause {
+    topic: String,
+    paused: AtomicBool,
+}
+
+impl TopicPause {
+    pub fn new(topic: &str) -> TopicPause {
+        TopicPause {
+            topic: topic.to_string(),
+            paused: AtomicBool::new(false),
+        }
+    }
+
+    /// Pauses or resumes the appends to the topic.
+    pub fn set(&self, paused: bool) {
+        if self.paused.swap(paused, Ordering::AcqRel) != paused {
+            let action = if paused { "Pausing" } else { "Resuming" };
+            info!("{} appends to topic {}", action, self.topic);
+        }
+    }
+
+    #[inline]
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Acquire)
+    }
+
+    /// Fails with `TopicPaused` while the topic is paused.
+    #[inline]
+    pub fn check(&self) -> Result<(), Error> {
+        if rare!(self.is_paused()) {
+            Err(TopicPaused::error(&self.topic))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Named logs besides the default topic sharing the process, each in a
+/// directory of its own, opened on first use and created on the first
+/// append. The log of a topic is opened with the settings configured for
+/// the topic in `topics` of the log configuration, such as its flush mode.
+///
+/// The logs of topics are not replicated, so topics are rejected while the
+/// node is in a chain with other nodes.
+#[derive(Clone)]
+pub struct Topics {
+    cfg: LogConfig,
+    logs: Arc<Mutex<HashMap<String, AsyncLog>>>,
+    open: Arc<OpenTopic>,
+    chained: Arc<AtomicBool>,
+}
+
+impl Topics {
+    /// Opens the logs of topics with `open`, called with the name of the
+    /// topic and the configuration of the log directory.
+    pub fn new<F>(cfg: &LogConfig, open: F) -> Topics
+    where
+        F: Fn(&str, &LogConfig) -> Result<AsyncLog, Error> + Send + Sync + 'static,
+    {
+        Topics {
+            cfg: cfg.clone(),
+            logs: Arc::new(Mutex::new(HashMap::new())),
+            open: Arc::new(open),
+            chained: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Sets whether the node is in a chain with other nodes, rejecting
+    /// topics while it is.
+    pub fn set_chained(&self, chained: bool) {
+        if self.chained.swap(chained, Ordering::AcqRel) != chained && chained {
+            warn!("Node joined a chain, rejecting topics other than the default");
+        }
+    }
+
+    /// Log of an existing topic.
+    ///
+    /// Fails with `ErrorKind::NotFound` if nothing has been appended to the
+    /// topic, and with `ErrorKind::PermissionDenied` while the node is in a
+    /// chain with other nodes.
+    pub fn log(&self, topic: &str) -> Result<AsyncLog, Error> {
+        self.get(topic, false)
+    }
+
+    /// Log of the topic, created if missing.
+    pub fn log_or_create(&self, topic: &str) -> Result<AsyncLog, Error> {
+        self.get(topic, true)
+    }
+
+    /// Shuts down the log of every opened topic as `AsyncLog::shutdown`,
+    /// resolving once each is flushed. Fails with the first error, while the
+    /// other logs are still shut down.
+    pub fn shutdown_all(&self) -> impl Future<Item = (), Error = Error> {
+        let logs = self.logs.lock().unwrap();
+        let shutdowns = logs
+            .values()
+            .map(|log| log.clone().shutdown())
+            .collect::<Vec<_>>();
+        join_all(shutdowns).map(|_| ())
+    }
+
+    fn get(&self, topic: &str, create: bool) -> Result<AsyncLog, Error> {
+        if is_default(topic) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "The default topic is served by the log of the log directory",
+            ));
+        }
+        validate(topic)?;
+        if self.chained.load(Ordering::Acquire) {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!(
+                    "Topic {} is not replicated, so not served in a chain",
+                    topic
+                ),
+            ));
+        }
+
+        let mut logs = self.logs.lock().unwrap();
+        if let Some(log) = logs.get(topic) {
+            return Ok(log.clone());
+        }
+
+        let dir = PathBuf::from(&self.cfg.dir).join(TOPICS_DIR).join(topic);
+        if !create && !dir.is_dir() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("Topic {} does not exist", topic),
+            ));
+        }
+        if logs.len() >= self.cfg.max_topics {
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!("Topic limit of {} reached", self.cfg.max_topics),
+            ));
+        }
+
+        info!("Opening topic {} in {}", topic, dir.display());
+        let mut cfg = self.cfg.clone();
+        cfg.dir = dir.to_string_lossy().into_owned();
+        if let Some(overrides) = self.cfg.topics.get(topic) {
+            overrides.apply(&mut cfg);
+        }
+        let log = (self.open)(topic, &cfg)?;
+        logs.insert(topic.to_string(), log.clone());
+        Ok(log)
+    }
+}
+
+/// Validates a topic name, which names the directory of the topic. Names
+/// are ASCII letters, digits, `.`, `_` and `-`, and cannot start with `.`.
+fn validate(topic: &str) -> Result<(), Error> {
+    let valid = topic.len() <= TOPIC_MAX_LEN
+        && !topic.starts_with('.')
+        && topic
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'_' || b == b'-');
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid topic name {:?}", topic),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_topic_names() {
+        assert!(validate("orders").is_ok());
+        assert!(validate("orders.v2_eu-west").is_ok());
+        assert!(validate(&"a".repeat(TOPIC_MAX_LEN)).is_ok());
+
+        for name in &["..", ".hidden", "../etc", "a/b", "a\\b", "caf\u{e9}", "a b"] {
+            assert_eq!(ErrorKind::InvalidInput, validate(name).unwrap_err().kind());
+        }
+        assert!(validate(&"a".repeat(TOPIC_MAX_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn paused_errors_name_the_topic() {
+        let err = TopicPaused::error("orders");
+        assert_eq!(ErrorKind::PermissionDenied, err.kind());
+        let paused = TopicPaused {
+            topic: "orders".to_string(),
+        };
+        assert_eq!(Some(paused), TopicPaused::from_error(&err));
+
+        let err = Error::new(
+            ErrorKind::PermissionDenied,
+            "Topic orders is not replicated",
+        );
+        assert_eq!(None, TopicPaused::from_error(&err));
+    }
+}
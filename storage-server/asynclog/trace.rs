@@ -0,0 +1,71 @@
+use std::error;
+use std::fmt;
+use std::io::Error;
+
+/// Identifier of a request, assigned by the server frontend or supplied by
+/// the client, logged with the request on the frontend and the log thread.
+/// Zero for requests without one.
+pub type TraceId = u64;
+
+/// Error of a traced request, naming the trace of the request.
+#[derive(Debug)]
+pub struct TracedError {
+    pub trace_id: TraceId,
+    pub error: Error,
+}
+
+impl fmt::Display for TracedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (trace {})", self.error, self.trace_id)
+    }
+}
+
+impl error::Error for TracedError {
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl TracedError {
+    /// The trace of the request that failed with the error, if traced.
+    pub fn trace_id(e: &Error) -> Option<TraceId> {
+        e.get_ref()
+            .and_then(|e| e.downcast_ref::<TracedError>())
+            .map(|e| e.trace_id)
+    }
+}
+
+/// Names the trace in the error of a traced request, keeping the kind.
+pub fn traced(e: Error, trace_id: TraceId) -> Error {
+    if trace_id == 0 {
+        return e;
+    }
+    Error::new(e.kind(), TracedError { trace_id, error: e })
+}
+
+/// The error of a request without the trace, for inspecting the cause.
+pub fn untraced(e: &Error) -> &Error {
+    e.get_ref()
+        .and_then(|e| e.downcast_ref::<TracedError>())
+        .map(|e| &e.error)
+        .unwrap_or(e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+
+    #[test]
+    fn traced_errors_keep_the_cause() {
+        let e = traced(Error::new(ErrorKind::NotFound, "Offset deleted"), 42);
+        assert_eq!(ErrorKind::NotFound, e.kind());
+        assert_eq!(Some(42), TracedError::trace_id(&e));
+        assert_eq!("Offset deleted (trace 42)", e.to_string());
+        assert_eq!("Offset deleted", untraced(&e).to_string());
+
+        let e = traced(Error::new(ErrorKind::NotFound, "Offset deleted"), 0);
+        assert_eq!(None, TracedError::trace_id(&e));
+        assert_eq!("Offset deleted", untraced(&e).to_string());
+    }
+}
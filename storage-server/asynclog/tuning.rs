@@ -0,0 +1,157 @@
+use config::{FlushMode, RetentionConfig};
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+/// Changes to the flush and retention settings of a running log, taking
+/// effect at the next flush or retention check. Unset values are unchanged.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogTuning {
+    /// When appended entries are flushed. A log switched to interval
+    /// flushing takes the interval unset by the tuning from its
+    /// configuration.
+    pub flush_mode: Option<FlushMode>,
+
+    /// Time between flushes of appended entries. Only applies to interval
+    /// flushing, so is rejected on a log left flushing each append or never.
+    pub flush_interval: Option<Duration>,
+
+    /// Delete segments that have not been written for this many seconds.
+    pub retention_max_age_secs: Option<u64>,
+
+    /// Delete the oldest segments beyond this total size, in bytes.
+    pub retention_max_bytes: Option<u64>,
+
+    /// Seconds between retention checks.
+    pub retention_check_interval_secs: Option<u64>,
+}
+
+impl LogTuning {
+    /// Rejects a zero flush interval, as flushing each append is its own
+    /// mode, an interval with another flush mode, and zero retention values,
+    /// which would check retention continuously or delete every inactive
+    /// segment.
+    pub fn validate(&self) -> Result<(), Error> {
+        let invalid = |msg| Err(Error::new(ErrorKind::InvalidInput, msg));
+        if self.flush_interval == Some(Duration::from_millis(0)) {
+            return invalid("Flush interval must be positive");
+        }
+        match self.flush_mode {
+            Some(FlushMode::EveryAppend) | Some(FlushMode::Never)
+                if self.flush_interval.is_some() =>
+            {
+                return invalid("Flush interval only applies to interval flushing");
+            }
+            _ => {}
+        }
+        if self.retention_max_age_secs == Some(0) {
+            return invalid("Retention max age must be positive");
+        }
+        if self.retention_max_bytes == Some(0) {
+            return invalid("Retention max bytes must be positive");
+        }
+        if self.retention_check_interval_secs == Some(0) {
+            return invalid("Retention check interval must be positive");
+        }
+        Ok(())
+    }
+
+    /// Tests whether the tuning changes the retention settings.
+    pub fn changes_retention(&self) -> bool {
+        self.retention_max_age_secs.is_some()
+            || self.retention_max_bytes.is_some()
+            || self.retention_check_interval_secs.is_some()
+    }
+
+    /// Applies the retention changes to the configuration.
+    pub fn apply_retention(&self, cfg: &RetentionConfig) -> RetentionConfig {
+        let mut cfg = cfg.clone();
+        if let Some(secs) = self.retention_max_age_secs {
+            cfg.max_age_secs = Some(secs);
+        }
+        if let Some(bytes) = self.retention_max_bytes {
+            cfg.max_bytes = Some(bytes);
+        }
+        if let Some(secs) = self.retention_check_interval_secs {
+            cfg.check_interval_secs = secs;
+        }
+        cfg
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_zero_values() {
+        assert!(LogTuning::default().validate().is_ok());
+
+        let tuning = LogTuning {
+            flush_interval: Some(Duration::from_millis(0)),
+            ..LogTuning::default()
+        };
+        assert_eq!(
+            ErrorKind::InvalidInput,
+            tuning.validate().unwrap_err().kind()
+        );
+
+        let tuning = LogTuning {
+            flush_mode: Some(FlushMode::Never),
+            flush_interval: Some(Duration::from_millis(10)),
+            ..LogTuning::default()
+        };
+        assert!(tuning.validate().is_err());
+
+        let tuning = LogTuning {
+            flush_mode: Some(FlushMode::Interval),
+            flush_interval: Some(Duration::from_millis(10)),
+            ..LogTuning::default()
+        };
+        assert!(tuning.validate().is_ok());
+
+        let tuning = LogTuning {
+            retention_check_interval_secs: Some(0),
+            ..LogTuning::default()
+        };
+        assert_eq!(
+            ErrorKind::InvalidInput,
+            tuning.validate().unwrap_err().kind()
+        );
+
+        let tuning = LogTuning {
+            retention_max_bytes: Some(0),
+            ..LogTuning::default()
+        };
+        assert!(tuning.validate().is_err());
+
+        let tuning = LogTuning {
+            flush_interval: Some(Duration::from_millis(10)),
+            retention_max_age_secs: Some(60),
+            ..LogTuning::default()
+        };
+        assert!(tuning.validate().is_ok());
+    }
+
+    #[test]
+    fn applies_retention_changes() {
+        let cfg = RetentionConfig {
+            max_age_secs: None,
+            max_bytes: Some(1000),
+            compact: true,
+            check_interval_secs: 300,
+        };
+        let tuning = LogTuning {
+            retention_max_age_secs: Some(60),
+            ..LogTuning::default()
+        };
+        assert!(tuning.changes_retention());
+        assert_eq!(
+            RetentionConfig {
+                max_age_secs: Some(60),
+                ..cfg.clone()
+            },
+            tuning.apply_retention(&cfg)
+        );
+        assert!(!LogTuning::default().changes_retention());
+    }
+}
@@ -0,0 +1,153 @@
+use prometheus::Counter;
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    static ref LOG_STALLS: Counter = register_counter!(opts!(
+        "log_stalls",
+        "Number of flushes or appends exceeding the stall threshold.",
+        labels! {"mod" => "log",}
+    ))
+    .unwrap();
+}
+
+/// Error for requests while the log thread is stalled.
+pub fn stalled_error() -> Error {
+    Error::new(
+        ErrorKind::TimedOut,
+        "Log stalled, a flush or append is not making progress",
+    )
+}
+
+/// Progress of the flush or append running on the log thread, observed by
+/// the watchdog thread.
+pub struct Progress {
+    epoch: Instant,
+    // millis from the epoch to the start of the operation plus one, or zero
+    // when no operation is running
+    op_start: AtomicU64,
+    stalled: AtomicBool,
+    fail_fast: bool,
+}
+
+impl Progress {
+    pub fn new(fail_fast: bool) -> Progress {
+        Progress {
+            epoch: Instant::now(),
+            op_start: AtomicU64::new(0),
+            stalled: AtomicBool::new(false),
+            fail_fast,
+        }
+    }
+
+    /// Marks the start of an operation, which ends when the guard is dropped.
+    #[inline]
+    pub fn begin(&self) -> OpGuard {
+        self.op_start.store(self.millis() + 1, Ordering::Release);
+        OpGuard(self)
+    }
+
+    /// Tests whether new requests should fail, as the log thread is stalled.
+    #[inline]
+    pub fn is_stalled(&self) -> bool {
+        self.fail_fast && self.stalled.load(Ordering::Acquire)
+    }
+
+    /// Checks the running operation against the threshold, returning true
+    /// when the operation is first detected as stalled.
+    fn check(&self, threshold: Duration) -> bool {
+        let start = self.op_start.load(Ordering::Acquire);
+        if start == 0 {
+            return false;
+        }
+
+        let elapsed = self.millis().saturating_sub(start - 1);
+        elapsed > as_millis(threshold) && !self.stalled.swap(true, Ordering::AcqRel)
+    }
+
+    fn millis(&self) -> u64 {
+        as_millis(self.epoch.elapsed())
+    }
+}
+
+/// Ends an operation on drop, clearing a detected stall.
+pub struct OpGuard<'a>(&'a Progress);
+
+impl<'a> Drop for OpGuard<'a> {
+    fn drop(&mut self) {
+        self.0.op_start.store(0, Ordering::Release);
+        if rare!(self.0.stalled.swap(false, Ordering::AcqRel)) {
+            info!("Log thread recovered from stall");
+        }
+    }
+}
+
+fn as_millis(d: Duration) -> u64 {
+    d.as_secs() * 1_000 + u64::from(d.subsec_millis())
+}
+
+/// Spawns the watchdog thread, which reports operations on the log thread
+/// running longer than the threshold. The thread exits once the log is
+/// dropped.
+pub fn spawn(progress: &Arc<Progress>, threshold: Duration) {
+    let progress: Weak<Progress> = Arc::downgrade(progress);
+    let interval = (threshold / 4).max(Duration::from_millis(10));
+    thread::Builder::new()
+        .name("log-watchdog".to_string())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            let progress = match progress.upgrade() {
+                Some(progress) => progress,
+                None => return,
+            };
+
+            if progress.check(threshold) {
+                error!(
+                    "Log stalled, a flush or append has exceeded {}ms",
+                    as_millis(threshold)
+                );
+                LOG_STALLS.inc();
+            }
+        })
+        .expect("Unable to spawn the log watchdog thread");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_stalled_operation() {
+        let threshold = Duration::from_millis(10);
+        let progress = Progress::new(true);
+        assert!(!progress.check(threshold));
+
+        {
+            let _op = progress.begin();
+            assert!(!progress.check(threshold));
+            assert!(!progress.is_stalled());
+
+            thread::sleep(Duration::from_millis(30));
+            assert!(progress.check(threshold));
+            assert!(progress.is_stalled());
+
+            // reported once per stall
+            assert!(!progress.check(threshold));
+        }
+
+        assert!(!progress.is_stalled());
+        assert!(!progress.check(threshold));
+    }
+
+    #[test]
+    fn stall_only_fails_requests_with_fail_fast() {
+        let progress = Progress::new(false);
+        let _op = progress.begin();
+        thread::sleep(Duration::from_millis(30));
+        assert!(progress.check(Duration::from_millis(10)));
+        assert!(!progress.is_stalled());
+    }
+}
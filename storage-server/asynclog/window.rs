@@ -0,0 +1,78 @@
+/// Accounting of the bytes appended to the log that have not been flushed.
+///
+/// With a maximum set, an append that would push the window past the maximum
+/// requires a flush before the append proceeds. A single append larger than
+/// the window is allowed once the window is empty.
+pub struct UncommittedWindow {
+    bytes: usize,
+    max_bytes: Option<usize>,
+}
+
+impl UncommittedWindow {
+    pub fn new(max_bytes: Option<usize>) -> UncommittedWindow {
+        UncommittedWindow {
+            bytes: 0,
+            max_bytes,
+        }
+    }
+
+    /// Tests whether the log must be flushed before appending the bytes.
+    #[inline]
+    pub fn requires_flush(&self, bytes: usize) -> bool {
+        match self.max_bytes {
+            Some(max) => self.bytes > 0 && self.bytes + bytes > max,
+            None => false,
+        }
+    }
+
+    /// Bytes appended since the last flush.
+    #[inline]
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Adds appended bytes to the window.
+    #[inline]
+    pub fn append(&mut self, bytes: usize) {
+        self.bytes += bytes;
+    }
+
+    /// Resets the window after a flush.
+    #[inline]
+    pub fn flushed(&mut self) {
+        self.bytes = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn appends_stall_until_flush() {
+        let mut window = UncommittedWindow::new(Some(100));
+        assert!(!window.requires_flush(60));
+        window.append(60);
+
+        assert!(window.requires_flush(60));
+        assert!(!window.requires_flush(40));
+
+        window.flushed();
+        assert!(!window.requires_flush(60));
+    }
+
+    #[test]
+    fn allows_large_append_when_empty() {
+        let mut window = UncommittedWindow::new(Some(100));
+        assert!(!window.requires_flush(500));
+        window.append(500);
+        assert!(window.requires_flush(1));
+    }
+
+    #[test]
+    fn unbounded_never_flushes() {
+        let mut window = UncommittedWindow::new(None);
+        window.append(1_000_000);
+        assert!(!window.requires_flush(1_000_000));
+    }
+}
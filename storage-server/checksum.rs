@@ -0,0 +1,41 @@
+use crc32fast;
+use std::io::{Error, ErrorKind};
+
+/// CRC-32 checksum of the payload, as computed by the client.
+#[inline]
+pub fn crc32(payload: &[u8]) -> u32 {
+    crc32fast::hash(payload)
+}
+
+/// Verifies the payload against the CRC-32 checksum computed by the client,
+/// catching corruption between the client and the server.
+pub fn verify(payload: &[u8], expected: u32) -> Result<(), Error> {
+    let actual = crc32(payload);
+    if rare!(actual != expected) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Checksum mismatch, expected {:08x} but payload has {:08x}",
+                expected, actual
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_corrupted_payload() {
+        let mut payload = b"0123456789".to_vec();
+        let crc32 = crc32fast::hash(&payload);
+        assert!(verify(&payload, crc32).is_ok());
+
+        // a bit flipped in transit
+        payload[4] ^= 0x10;
+        let err = verify(&payload, crc32).unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+    }
+}
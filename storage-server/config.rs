@@ -1,6 +1,12 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
 use std::net::SocketAddr;
 use std::usize;
 
+/// Fewest entries of a segment index. Smaller indexes roll the segments
+/// every few entries.
+const LOG_MIN_INDEX_MAX_ITEMS: usize = 16;
+
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
 pub struct Config {
     #[serde(default)]
@@ -14,6 +20,9 @@ pub struct Config {
 
     #[serde(default)]
     pub admin: Option<AdminConfig>,
+
+    #[serde(default)]
+    pub socket: SocketConfig,
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
@@ -27,14 +36,160 @@ pub struct LogConfig {
     #[serde(default = "log_default_segment_max_bytes")]
     pub segment_max_bytes: usize,
 
+    /// Largest payload accepted by an append, in bytes. Larger payloads are
+    /// rejected before they are queued.
     #[serde(default = "log_default_message_max_bytes")]
     pub message_max_bytes: usize,
 
+    /// Accepts appends with an empty payload, such as for consumers treating
+    /// empty entries as tombstones. Empty appends fail by default.
+    #[serde(default)]
+    pub allow_empty_payloads: bool,
+
+    /// Capacity of the pooled buffers batching appends, raised to
+    /// `message_max_bytes` if smaller.
     #[serde(default = "log_default_message_buffer_bytes")]
     pub message_buffer_bytes: usize,
 
+    /// Maximum buffers held by the append buffer pool. Batches created while
+    /// the pooled buffers are in use count towards `msg_unpooled_buffer`.
+    /// Unbounded if not set.
+    #[serde(default)]
+    pub message_pool_buffers: Option<usize>,
+
+    /// Maximum entries in a batch of appends. Batches are otherwise bound by
+    /// `message_buffer_bytes` only, so a burst of small appends is written
+    /// in a few large batches.
+    #[serde(default)]
+    pub append_batch_max_entries: Option<usize>,
+
     #[serde(default = "log_default_replication_max_bytes")]
     pub replication_max_bytes: usize,
+
+    #[serde(default)]
+    pub retention: RetentionConfig,
+
+    /// Maximum number of appends queued for the log thread. Appends past
+    /// the limit are rejected. Unbounded if not set.
+    #[serde(default)]
+    pub append_queue_max: Option<usize>,
+
+    /// Maximum number of reads queued for the log thread. Reads past the
+    /// limit wait for a slot, up to `read_queue_timeout_ms`. Unbounded if
+    /// not set.
+    #[serde(default)]
+    pub read_queue_max: Option<usize>,
+
+    /// Milliseconds a read waits for a slot in a full read queue before
+    /// failing as the log is busy.
+    #[serde(default = "log_default_read_queue_timeout_ms")]
+    pub read_queue_timeout_ms: u64,
+
+    /// Number of times an append failing with a transient error is retried
+    /// before the append fails.
+    #[serde(default = "log_default_append_retries")]
+    pub append_retries: usize,
+
+    /// Delay between retries of a failed append, in milliseconds. The log
+    /// thread is blocked during the delay.
+    #[serde(default = "log_default_append_retry_delay_ms")]
+    pub append_retry_delay_ms: u64,
+
+    /// Number of recent reads cached for readers of the same offsets.
+    /// Zero disables the cache.
+    #[serde(default = "log_default_read_cache_entries")]
+    pub read_cache_entries: usize,
+
+    /// Capacity of the pooled buffers holding read results. Larger reads
+    /// allocate a buffer of their own.
+    #[serde(default = "log_default_read_buffer_bytes")]
+    pub read_buffer_bytes: usize,
+
+    /// Verifies the hash of each entry read from disk, failing reads of
+    /// corrupt entries. Disable to save the hashing cost on reads.
+    #[serde(default = "log_default_verify_reads")]
+    pub verify_reads: bool,
+
+    /// Truncates a torn write at the end of the newest segment when the log
+    /// is opened, such as an entry partially written by a killed process,
+    /// discarding the bytes after the last valid entry. Disable to fail
+    /// opening the log instead.
+    #[serde(default = "log_default_repair")]
+    pub repair: bool,
+
+    /// Maximum bytes appended to the log that have not been flushed to disk.
+    /// Appends past the limit wait for a flush. Unbounded if not set.
+    #[serde(default)]
+    pub max_uncommitted_bytes: Option<usize>,
+
+    /// Elevated scheduling for the log thread, to reduce append latency
+    /// jitter on dedicated log nodes. Best-effort; the thread runs at
+    /// normal priority if the OS denies the request.
+    #[serde(default)]
+    pub thread_priority: Option<ThreadPriority>,
+
+    /// Verifies that each append is assigned the offsets directly following
+    /// the end of the log, failing the append on a gap. For debugging offset
+    /// anomalies; requires an offset allocator that leaves no gaps, as an
+    /// append after a gap fails.
+    #[serde(default)]
+    pub strict_offsets: bool,
+
+    /// Time after which a flush or append on the log thread is reported as
+    /// stalled, such as on a hung disk. Not monitored if not set.
+    #[serde(default)]
+    pub stall_threshold_ms: Option<u64>,
+
+    /// Fails new requests while the log thread is stalled, rather than
+    /// queueing them behind the stalled operation.
+    #[serde(default)]
+    pub stall_fail_fast: bool,
+
+    /// Result of a read at an offset past the end of the log, for reads
+    /// without a maximum wait.
+    #[serde(default)]
+    pub read_beyond_end: BeyondEnd,
+
+    /// When appended entries are flushed to disk.
+    #[serde(default)]
+    pub flush_mode: FlushMode,
+
+    /// Milliseconds between flushes of appended entries to disk, for the
+    /// interval flush mode. Zero flushes each append before it is
+    /// acknowledged.
+    #[serde(default = "log_default_flush_interval_ms")]
+    pub flush_interval_ms: u64,
+
+    /// Bytes appended but not flushed after which the log is flushed without
+    /// waiting for the flush interval, for the interval flush mode. Unlike
+    /// `max_uncommitted_bytes`, appends are not blocked. Flushed only by the
+    /// interval if not set.
+    #[serde(default)]
+    pub flush_max_bytes: Option<usize>,
+
+    /// Assigns a sequence to each record appended with a key or headers,
+    /// stored in the record. Sequences increase with each record and are
+    /// never reused, giving records an identity that is kept when the log
+    /// is rewritten.
+    #[serde(default)]
+    pub record_sequence: bool,
+
+    /// Maximum number of topics besides the default topic. Each topic is a
+    /// log in a directory of its own under `topics` in the log directory,
+    /// created on the first append to the topic.
+    #[serde(default = "log_default_max_topics")]
+    pub max_topics: usize,
+
+    /// Settings of topics overriding those of the log, by topic name,
+    /// applied when the log of the topic is opened.
+    #[serde(default)]
+    pub topics: HashMap<String, TopicConfig>,
+
+    /// Threads serving reads of segments closed by a roll, so large reads do
+    /// not delay appends on the log thread. Zero serves every read on the
+    /// log thread.
+    #[serde(default)]
+    pub read_threads: usize,
 }
 
 fn log_default_dir() -> String {
@@ -61,6 +216,42 @@ fn log_default_replication_max_bytes() -> usize {
     2_097_152
 }
 
+fn log_default_append_retries() -> usize {
+    2
+}
+
+fn log_default_append_retry_delay_ms() -> u64 {
+    10
+}
+
+fn log_default_read_cache_entries() -> usize {
+    64
+}
+
+fn log_default_read_buffer_bytes() -> usize {
+    65_536
+}
+
+fn log_default_verify_reads() -> bool {
+    true
+}
+
+fn log_default_repair() -> bool {
+    true
+}
+
+fn log_default_read_queue_timeout_ms() -> u64 {
+    1000
+}
+
+fn log_default_flush_interval_ms() -> u64 {
+    1_000
+}
+
+fn log_default_max_topics() -> usize {
+    64
+}
+
 impl Default for LogConfig {
     fn default() -> LogConfig {
         LogConfig {
@@ -68,8 +259,172 @@ impl Default for LogConfig {
             index_max_items: log_default_index_max_items(),
             segment_max_bytes: log_default_segment_max_bytes(),
             message_max_bytes: log_default_message_max_bytes(),
+            allow_empty_payloads: false,
             message_buffer_bytes: log_default_message_buffer_bytes(),
+            message_pool_buffers: None,
+            append_batch_max_entries: None,
             replication_max_bytes: log_default_replication_max_bytes(),
+            retention: RetentionConfig::default(),
+            append_queue_max: None,
+            read_queue_max: None,
+            read_queue_timeout_ms: log_default_read_queue_timeout_ms(),
+            append_retries: log_default_append_retries(),
+            append_retry_delay_ms: log_default_append_retry_delay_ms(),
+            read_cache_entries: log_default_read_cache_entries(),
+            read_buffer_bytes: log_default_read_buffer_bytes(),
+            verify_reads: log_default_verify_reads(),
+            repair: log_default_repair(),
+            max_uncommitted_bytes: None,
+            thread_priority: None,
+            strict_offsets: false,
+            stall_threshold_ms: None,
+            stall_fail_fast: false,
+            read_beyond_end: BeyondEnd::Empty,
+            flush_mode: FlushMode::Interval,
+            flush_interval_ms: log_default_flush_interval_ms(),
+            flush_max_bytes: None,
+            record_sequence: false,
+            max_topics: log_default_max_topics(),
+            topics: HashMap::new(),
+            read_threads: 0,
+        }
+    }
+}
+
+impl LogConfig {
+    /// Rejects segment and index sizes the log cannot be opened with.
+    pub fn validate(&self) -> Result<(), Error> {
+        let invalid = |msg: String| Err(Error::new(ErrorKind::InvalidInput, msg));
+        if self.segment_max_bytes == 0 {
+            return invalid("Log segment_max_bytes must be positive".to_string());
+        }
+        if self.message_max_bytes == 0 {
+            return invalid("Log message_max_bytes must be positive".to_string());
+        }
+        if self.index_max_items < LOG_MIN_INDEX_MAX_ITEMS {
+            return invalid(format!(
+                "Log index_max_items of {} is below the minimum of {}",
+                self.index_max_items, LOG_MIN_INDEX_MAX_ITEMS
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Result of a read at an offset that has not yet been appended.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum BeyondEnd {
+    /// Returns no entries, for the reader to retry later.
+    Empty,
+
+    /// Fails the read as out of range.
+    Error,
+
+    /// Waits until entries are appended at the offset.
+    Wait,
+}
+
+impl Default for BeyondEnd {
+    fn default() -> BeyondEnd {
+        BeyondEnd::Empty
+    }
+}
+
+/// When the log thread flushes appended entries to disk.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum FlushMode {
+    /// Flushes each append before it is acknowledged, so acknowledged
+    /// entries survive a crash.
+    EveryAppend,
+
+    /// Flushes at the flush interval, or once the unflushed bytes reach
+    /// `flush_max_bytes`.
+    Interval,
+
+    /// Leaves flushing to the operating system. The log is only flushed on
+    /// request, on shutdown and when the uncommitted window is full.
+    Never,
+}
+
+impl Default for FlushMode {
+    fn default() -> FlushMode {
+        FlushMode::Interval
+    }
+}
+
+/// Settings of a topic overriding those of the log, such as lazy flushing
+/// of a topic tolerating the loss of recent entries.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, Default)]
+pub struct TopicConfig {
+    /// When appended entries of the topic are flushed to disk.
+    #[serde(default)]
+    pub flush_mode: Option<FlushMode>,
+
+    /// Milliseconds between flushes of the topic, for the interval flush
+    /// mode.
+    #[serde(default)]
+    pub flush_interval_ms: Option<u64>,
+}
+
+impl TopicConfig {
+    /// Applies the settings set for the topic to the configuration of its
+    /// log.
+    pub fn apply(&self, cfg: &mut LogConfig) {
+        if let Some(mode) = self.flush_mode {
+            cfg.flush_mode = mode;
+        }
+        if let Some(interval) = self.flush_interval_ms {
+            cfg.flush_interval_ms = interval;
+        }
+    }
+}
+
+/// Scheduling requested for the log thread (Linux only).
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ThreadPriority {
+    /// Nice value for the thread, lower values have higher priority.
+    /// Negative values typically require `CAP_SYS_NICE`.
+    Nice(i32),
+
+    /// Real-time `SCHED_FIFO` scheduling with the static priority (1-99).
+    /// Typically requires `CAP_SYS_NICE`.
+    Fifo(i32),
+}
+
+/// Retention policies for the log. Segments are deleted only when
+/// all of the configured policies allow it.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct RetentionConfig {
+    /// Delete segments that have not been written for this many seconds.
+    #[serde(default)]
+    pub max_age_secs: Option<u64>,
+
+    /// Delete the oldest segments while the log exceeds this many bytes.
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+
+    /// Compact inactive segments.
+    #[serde(default)]
+    pub compact: bool,
+
+    #[serde(default = "retention_default_check_interval_secs")]
+    pub check_interval_secs: u64,
+}
+
+fn retention_default_check_interval_secs() -> u64 {
+    60
+}
+
+impl Default for RetentionConfig {
+    fn default() -> RetentionConfig {
+        RetentionConfig {
+            max_age_secs: None,
+            max_bytes: None,
+            compact: false,
+            check_interval_secs: retention_default_check_interval_secs(),
         }
     }
 }
@@ -77,6 +432,16 @@ impl Default for LogConfig {
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
 pub struct FrontendConfig {
     pub server_addr: SocketAddr,
+
+    /// Addresses to listen on in addition to the server address, such as
+    /// for both IPv4 and IPv6. Connections to any address are equivalent.
+    #[serde(default)]
+    pub additional_addrs: Vec<SocketAddr>,
+
+    /// Path of a Unix domain socket to listen on, in addition to the TCP
+    /// address, for clients on the same host.
+    #[serde(default)]
+    pub uds_path: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
@@ -88,6 +453,53 @@ pub struct ReplicationConfig {
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
 pub struct AdminConfig {
     pub server_addr: SocketAddr,
+
+    /// Token required as a bearer token by every admin endpoint but the
+    /// metrics. The endpoints are not authenticated if not set.
+    #[serde(default)]
+    pub token: Option<String>,
+
+    /// Directory the snapshots requested through the admin server are
+    /// written under, each in a directory named by the request. Snapshots
+    /// are rejected if not set.
+    #[serde(default)]
+    pub snapshot_dir: Option<String>,
+}
+
+/// Options for the sockets of the server.
+///
+/// `TCP_NODELAY` is on by default, so small appends and acks are not delayed
+/// by Nagle's algorithm at the cost of more, smaller packets. It applies to
+/// the replication and admin sockets; gRPC always sets it on the frontend.
+/// The buffer sizes default to those of the OS (and of gRPC on the frontend).
+/// Larger buffers favor replication and read throughput at the cost of memory
+/// per connection.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct SocketConfig {
+    #[serde(default = "socket_default_nodelay")]
+    pub nodelay: bool,
+
+    /// Size of the socket send buffer, in bytes.
+    #[serde(default)]
+    pub send_buffer_bytes: Option<usize>,
+
+    /// Size of the socket receive buffer, in bytes.
+    #[serde(default)]
+    pub recv_buffer_bytes: Option<usize>,
+}
+
+fn socket_default_nodelay() -> bool {
+    true
+}
+
+impl Default for SocketConfig {
+    fn default() -> SocketConfig {
+        SocketConfig {
+            nodelay: socket_default_nodelay(),
+            send_buffer_bytes: None,
+            recv_buffer_bytes: None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
@@ -101,6 +513,26 @@ mod tests {
     use super::*;
     use toml;
 
+    #[test]
+    fn validates_segment_options() {
+        assert!(LogConfig::default().validate().is_ok());
+
+        let mut cfg = LogConfig::default();
+        cfg.segment_max_bytes = 0;
+        assert_eq!(ErrorKind::InvalidInput, cfg.validate().unwrap_err().kind());
+
+        let mut cfg = LogConfig::default();
+        cfg.index_max_items = 1;
+        assert!(cfg
+            .validate()
+            .unwrap_err()
+            .to_string()
+            .contains("index_max_items"));
+
+        cfg.index_max_items = LOG_MIN_INDEX_MAX_ITEMS;
+        assert!(cfg.validate().is_ok());
+    }
+
     #[test]
     fn full_config() {
         let decoded: Config = toml::from_str(
@@ -110,11 +542,50 @@ mod tests {
         index_max_items = 10
         segment_max_bytes = 1000
         message_max_bytes = 100
+        allow_empty_payloads = true
         message_buffer_bytes = 10000
+        message_pool_buffers = 64
+        append_batch_max_entries = 500
         replication_max_bytes = 200
+        append_queue_max = 5000
+        read_queue_max = 2000
+        read_queue_timeout_ms = 250
+        append_retries = 5
+        append_retry_delay_ms = 20
+        read_cache_entries = 16
+        read_buffer_bytes = 8192
+        verify_reads = false
+        repair = false
+        max_uncommitted_bytes = 4096
+        thread_priority = { nice = -5 }
+        strict_offsets = true
+        stall_threshold_ms = 5000
+        stall_fail_fast = true
+        read_beyond_end = "wait"
+        flush_mode = "never"
+        flush_interval_ms = 200
+        flush_max_bytes = 4194304
+        record_sequence = true
+        max_topics = 8
+        read_threads = 2
+
+        [log.retention]
+        max_age_secs = 3600
+        max_bytes = 5000
+        compact = true
+        check_interval_secs = 10
+
+        [log.topics.metrics]
+        flush_mode = "interval"
+        flush_interval_ms = 5000
+
+        [log.topics.orders]
+        flush_mode = "every_append"
 
         [frontend]
         server_addr = "0.0.0.0:8080"
+        additional_addrs = ["[::]:8080"]
+        uds_path = "/tmp/log.sock"
 
         [replication]
         server_addr = "0.0.0.0:8081"
@@ -122,6 +593,16 @@ mod tests {
 
         [management]
         management_server_addr = "mgmt:4000"
+
+        [admin]
+        server_addr = "127.0.0.1:8082"
+        token = "secret"
+        snapshot_dir = "/var/lib/log/snapshots"
+
+        [socket]
+        nodelay = false
+        send_buffer_bytes = 65536
+        recv_buffer_bytes = 131072
     "#,
         )
         .unwrap();
@@ -133,20 +614,79 @@ mod tests {
                     index_max_items: 10,
                     segment_max_bytes: 1_000,
                     message_max_bytes: 100,
+                    allow_empty_payloads: true,
                     message_buffer_bytes: 10_000,
+                    message_pool_buffers: Some(64),
+                    append_batch_max_entries: Some(500),
                     replication_max_bytes: 200,
+                    retention: RetentionConfig {
+                        max_age_secs: Some(3600),
+                        max_bytes: Some(5000),
+                        compact: true,
+                        check_interval_secs: 10,
+                    },
+                    append_queue_max: Some(5000),
+                    read_queue_max: Some(2000),
+                    read_queue_timeout_ms: 250,
+                    append_retries: 5,
+                    append_retry_delay_ms: 20,
+                    read_cache_entries: 16,
+                    read_buffer_bytes: 8192,
+                    verify_reads: false,
+                    repair: false,
+                    max_uncommitted_bytes: Some(4096),
+                    thread_priority: Some(ThreadPriority::Nice(-5)),
+                    strict_offsets: true,
+                    stall_threshold_ms: Some(5000),
+                    stall_fail_fast: true,
+                    read_beyond_end: BeyondEnd::Wait,
+                    flush_mode: FlushMode::Never,
+                    flush_interval_ms: 200,
+                    flush_max_bytes: Some(4_194_304),
+                    record_sequence: true,
+                    max_topics: 8,
+                    topics: vec![
+                        (
+                            "metrics".to_string(),
+                            TopicConfig {
+                                flush_mode: Some(FlushMode::Interval),
+                                flush_interval_ms: Some(5000),
+                            },
+                        ),
+                        (
+                            "orders".to_string(),
+                            TopicConfig {
+                                flush_mode: Some(FlushMode::EveryAppend),
+                                flush_interval_ms: None,
+                            },
+                        ),
+                    ]
+                    .into_iter()
+                    .collect(),
+                    read_threads: 2,
                 },
                 frontend: FrontendConfig {
                     server_addr: "0.0.0.0:8080".parse().unwrap(),
+                    additional_addrs: vec!["[::]:8080".parse().unwrap()],
+                    uds_path: Some("/tmp/log.sock".to_string()),
                 },
                 replication: ReplicationConfig {
                     server_addr: "0.0.0.0:8081".parse().unwrap(),
                     upstream_addr: Some("0.0.0.0:4000".parse().unwrap()),
                 },
-                admin: None,
+                admin: Some(AdminConfig {
+                    server_addr: "127.0.0.1:8082".parse().unwrap(),
+                    token: Some("secret".to_string()),
+                    snapshot_dir: Some("/var/lib/log/snapshots".to_string()),
+                }),
                 management: ManagementConfig {
                     management_server_addr: "mgmt:4000".to_string()
-                }
+                },
+                socket: SocketConfig {
+                    nodelay: false,
+                    send_buffer_bytes: Some(65536),
+                    recv_buffer_bytes: Some(131_072),
+                },
             },
             decoded
         )
@@ -176,11 +716,39 @@ mod tests {
                     index_max_items: 10_000_000,
                     segment_max_bytes: 1_073_741_824,
                     message_max_bytes: 1_048_576,
+                    allow_empty_payloads: false,
                     message_buffer_bytes: 1_048_576,
+                    message_pool_buffers: None,
+                    append_batch_max_entries: None,
                     replication_max_bytes: 2_097_152,
+                    retention: RetentionConfig::default(),
+                    append_queue_max: None,
+                    read_queue_max: None,
+                    read_queue_timeout_ms: 1000,
+                    append_retries: 2,
+                    append_retry_delay_ms: 10,
+                    read_cache_entries: 64,
+                    read_buffer_bytes: 65_536,
+                    verify_reads: true,
+                    repair: true,
+                    max_uncommitted_bytes: None,
+                    thread_priority: None,
+                    strict_offsets: false,
+                    stall_threshold_ms: None,
+                    stall_fail_fast: false,
+                    read_beyond_end: BeyondEnd::Empty,
+                    flush_mode: FlushMode::Interval,
+                    flush_interval_ms: 1_000,
+                    flush_max_bytes: None,
+                    record_sequence: false,
+                    max_topics: 64,
+                    topics: HashMap::new(),
+                    read_threads: 0,
                 },
                 frontend: FrontendConfig {
                     server_addr: "0.0.0.0:8080".parse().unwrap(),
+                    additional_addrs: vec![],
+                    uds_path: None,
                 },
                 replication: ReplicationConfig {
                     server_addr: "0.0.0.0:8081".parse().unwrap(),
@@ -189,7 +757,8 @@ mod tests {
                 admin: None,
                 management: ManagementConfig {
                     management_server_addr: "mgmt:4000".to_string()
-                }
+                },
+                socket: SocketConfig::default(),
             },
             decoded
         )
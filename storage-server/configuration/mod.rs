@@ -34,6 +34,11 @@ impl<'a> NodeConfiguration<'a> {
             && id == self.config.active_chain.iter().last().unwrap().id
     }
 
+    /// Tests whether the node is in a chain with other nodes.
+    pub fn is_chained(&self) -> bool {
+        self.config.active_chain.len() > 1
+    }
+
     pub fn upstream_addr(&self) -> Option<SocketAddr> {
         assert!(self.config.has_self_node());
         let id = self.config.get_self_node().id;
@@ -0,0 +1,87 @@
+use asynclog::{AsyncLog, ConsumerId};
+use commitlog::Offset;
+use futures::future::{join_all, Future};
+use std::collections::HashMap;
+use tail_reply::{Subscription, TailReplyRegistrar};
+
+/// Offsets to commit for the final positions of drained subscriptions.
+///
+/// Only subscribers that have committed an offset are consumers, and the
+/// committed offset of a consumer never moves backwards.
+fn drained_commits(
+    subs: &[Subscription],
+    committed: &[(ConsumerId, Offset)],
+) -> Vec<(ConsumerId, Offset)> {
+    let committed: HashMap<ConsumerId, Offset> = committed.iter().cloned().collect();
+    subs.iter()
+        .filter_map(|sub| {
+            let position = sub.position?;
+            match committed.get(&sub.client_id) {
+                Some(off) if *off < position => Some((sub.client_id, position)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Persists the final position of the subscriptions closed by
+/// `TailReplyRegistrar::begin_drain` in the committed offsets of the
+/// subscribed consumers, so consumers resume after the last delivered entry
+/// when reconnecting to another node.
+pub fn await_drained(
+    subs: Vec<Subscription>,
+    mut log: AsyncLog,
+) -> impl Future<Item = (), Error = ()> {
+    log.consumer_offsets()
+        .map_err(|e| error!("Unable to read consumer offsets: {}", e))
+        .and_then(move |committed| {
+            let commits: Vec<_> = drained_commits(&subs, &committed)
+                .into_iter()
+                .map(|(consumer, offset)| {
+                    info!(
+                        "Committing offset {} of drained consumer {}",
+                        offset, consumer
+                    );
+                    log.commit_offset(consumer, offset)
+                })
+                .collect();
+            join_all(commits).map_err(|e| error!("Unable to commit drained offsets: {}", e))
+        })
+        .map(|_| ())
+}
+
+/// Drains the subscriptions: subscribers are sent a draining goodbye and
+/// their final positions are persisted.
+pub fn drain(tail: &TailReplyRegistrar, log: AsyncLog) -> impl Future<Item = (), Error = ()> {
+    info!("Draining subscriptions");
+    tail.begin_drain()
+        .map_err(|_| error!("Unable to drain subscriptions"))
+        .and_then(move |subs| await_drained(subs, log))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sub(client_id: u64, position: Option<Offset>) -> Subscription {
+        Subscription {
+            client_id,
+            position,
+            lag: 0,
+        }
+    }
+
+    #[test]
+    fn commits_consumer_positions() {
+        let subs = vec![
+            sub(1, Some(20)),
+            sub(2, Some(5)),
+            sub(3, None),
+            sub(4, Some(30)),
+        ];
+        let committed = vec![(1, 10), (2, 8), (3, 2)];
+
+        // only consumers moving forward are committed
+        assert_eq!(vec![(1, 20)], drained_commits(&subs, &committed));
+    }
+}
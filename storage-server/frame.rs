@@ -0,0 +1,59 @@
+use byteorder::{ByteOrder, LittleEndian};
+use bytes::{BufMut, Bytes, BytesMut};
+use commitlog::message::MessageSet;
+
+/// Size of the frame header: offset (u64) followed by payload length (u32).
+pub const FRAME_HEADER_SIZE: usize = 12;
+
+/// Encodes the messages as a contiguous sequence of frames, as returned
+/// to clients for framed reads.
+///
+/// Each frame is laid out as:
+///
+/// ```text
+/// | offset (u64 LE) | payload length (u32 LE) | payload |
+/// ```
+///
+/// Frames are ordered by offset with no padding or trailer.
+pub fn encode<M: MessageSet>(msgs: &M) -> Bytes {
+    let size = msgs
+        .iter()
+        .map(|m| FRAME_HEADER_SIZE + m.payload().len())
+        .sum();
+    let mut buf = BytesMut::with_capacity(size);
+    for m in msgs.iter() {
+        let payload = m.payload();
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+        LittleEndian::write_u64(&mut header[0..8], m.offset());
+        LittleEndian::write_u32(&mut header[8..12], payload.len() as u32);
+        buf.put_slice(&header);
+        buf.put_slice(payload);
+    }
+    buf.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asynclog::MessagesMut;
+    use commitlog::message::set_offsets;
+
+    #[test]
+    fn encodes_frames() {
+        let mut msgs = MessagesMut(BytesMut::with_capacity(1024));
+        msgs.push(1, 1, b"abc").unwrap();
+        msgs.push(1, 2, b"de").unwrap();
+        set_offsets(&mut msgs, 10);
+
+        let bytes = encode(&msgs);
+        assert_eq!(2 * FRAME_HEADER_SIZE + 5, bytes.len());
+
+        assert_eq!(10, LittleEndian::read_u64(&bytes[0..8]));
+        assert_eq!(3, LittleEndian::read_u32(&bytes[8..12]));
+        assert_eq!(b"abc", &bytes[12..15]);
+
+        assert_eq!(11, LittleEndian::read_u64(&bytes[15..23]));
+        assert_eq!(2, LittleEndian::read_u32(&bytes[23..27]));
+        assert_eq!(b"de", &bytes[27..29]);
+    }
+}
@@ -1,6 +1,7 @@
 #![feature(core_intrinsics, test)]
 extern crate bytes;
 extern crate commitlog;
+extern crate crc32fast;
 extern crate test;
 #[macro_use]
 extern crate futures;
@@ -13,13 +14,17 @@ extern crate lazy_static;
 extern crate log;
 extern crate either;
 extern crate fnv;
+#[cfg(feature = "bench-append")]
+extern crate histogram;
 extern crate http;
 extern crate hyper;
 extern crate libc;
 extern crate nix;
+extern crate serde;
 extern crate tokio;
 extern crate tokio_codec;
 extern crate tokio_io;
+extern crate tokio_signal;
 extern crate tokio_sync;
 #[macro_use]
 extern crate serde_derive;
@@ -27,36 +32,103 @@ extern crate env_logger;
 extern crate grpcio;
 extern crate protobuf;
 extern crate rand;
+extern crate serde_json;
 extern crate toml;
+#[cfg(feature = "tracing")]
+extern crate tracing;
 
 mod admin_server;
 
 #[macro_use]
 mod macros;
 mod asynclog;
+mod checksum;
 mod config;
 mod configuration;
+mod drain;
+mod frame;
 mod protocol;
 mod replication;
 mod retry;
 mod server;
+mod socket;
+mod spans;
 mod tail_reply;
 
-use futures::{future::lazy, Future};
+use futures::{future::lazy, Future, Stream};
 use std::io::Read;
 use std::process::exit;
+use std::time::{Duration, Instant};
 use std::{env, fs, str};
 use tokio::executor::current_thread::spawn;
 use tokio::runtime::current_thread::Runtime;
+use tokio::timer::Delay;
 
-fn config() -> config::Config {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: {} [config_file]", args[0]);
+/// Time allowed for goodbye replies to be sent before exiting
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_millis(500);
+
+/// Command to compact the log offline, rather than start the server
+const COMPACT_COMMAND: &str = "compact";
+
+/// Flag overriding the log directory of the configuration, such as to run
+/// several instances from one configuration file
+const LOG_DIR_FLAG: &str = "--log-dir=";
+
+/// Flag overriding the maximum size of a log segment, in bytes
+const SEGMENT_MAX_BYTES_FLAG: &str = "--segment-max-bytes=";
+
+/// Flag overriding the maximum entries of a segment index
+const INDEX_MAX_ITEMS_FLAG: &str = "--index-max-items=";
+
+const FLAGS: [&str; 3] = [LOG_DIR_FLAG, SEGMENT_MAX_BYTES_FLAG, INDEX_MAX_ITEMS_FLAG];
+
+/// Environment variables overriding the segment options of the configuration.
+/// The flags take precedence.
+const SEGMENT_MAX_BYTES_ENV: &str = "LOG_SEGMENT_MAX_BYTES";
+const INDEX_MAX_ITEMS_ENV: &str = "LOG_INDEX_MAX_ITEMS";
+
+/// Value of the flag, if given.
+fn flag_value(flags: &[String], flag: &str) -> Option<String> {
+    flags
+        .iter()
+        .find(|f| f.starts_with(flag))
+        .map(|f| f[flag.len()..].to_string())
+}
+
+/// Size given by the flag or else the environment variable, exiting if it is
+/// not a number.
+fn size_override(flags: &[String], flag: &str, var: &str) -> Option<usize> {
+    let value = flag_value(flags, flag).or_else(|| env::var(var).ok())?;
+    match value.parse() {
+        Ok(size) => Some(size),
+        Err(_) => {
+            eprintln!("Invalid value {:?} for {} or {}", value, flag, var);
+            exit(1);
+        }
+    }
+}
+
+/// Parses the configuration, returning whether the compact command was given.
+fn config() -> (config::Config, bool) {
+    let (flags, args): (Vec<String>, Vec<String>) =
+        env::args().partition(|arg| arg.starts_with("--"));
+    let log_dir = flag_value(&flags, LOG_DIR_FLAG);
+    let valid_flags = flags
+        .iter()
+        .all(|flag| FLAGS.iter().any(|f| flag.starts_with(f)));
+    if !valid_flags
+        || args.len() < 2
+        || args.len() > 3
+        || (args.len() == 3 && args[2] != COMPACT_COMMAND)
+    {
+        println!(
+            "Usage: {} [{}<dir>] [{}<bytes>] [{}<items>] [config_file] [{}]",
+            args[0], LOG_DIR_FLAG, SEGMENT_MAX_BYTES_FLAG, INDEX_MAX_ITEMS_FLAG, COMPACT_COMMAND
+        );
         exit(1);
     }
 
-    let config: config::Config = {
+    let mut config: config::Config = {
         let mut f = fs::File::open(&args[1]).expect("Unable to open config file");
         let mut bytes = vec![];
         f.read_to_end(&mut bytes)
@@ -64,35 +136,124 @@ fn config() -> config::Config {
         let cfg = str::from_utf8(&bytes).expect("Invalid UTF-8");
         toml::from_str(cfg).expect("Unable to parse TOML")
     };
+    if let Some(dir) = log_dir {
+        config.log.dir = dir;
+    }
+    if let Some(bytes) = size_override(&flags, SEGMENT_MAX_BYTES_FLAG, SEGMENT_MAX_BYTES_ENV) {
+        config.log.segment_max_bytes = bytes;
+    }
+    if let Some(items) = size_override(&flags, INDEX_MAX_ITEMS_FLAG, INDEX_MAX_ITEMS_ENV) {
+        config.log.index_max_items = items;
+    }
+    if let Err(e) = config.log.validate() {
+        eprintln!("Invalid configuration: {}", e);
+        exit(1);
+    }
 
     info!("Starting with configuration {:?}", config);
-    config
+    (config, args.len() == 3)
+}
+
+/// Waits for ctrl-c, then drains the subscriptions and flushes the log and
+/// the logs of the topics before the server shuts down.
+fn shutdown(
+    register: tail_reply::TailReplyRegistrar,
+    log: asynclog::AsyncLog,
+    topics: asynclog::Topics,
+) -> impl Future<Item = (), Error = ()> {
+    tokio_signal::ctrl_c()
+        .flatten_stream()
+        .into_future()
+        .map_err(|_| error!("Unable to capture ctrl-c"))
+        .and_then(move |_| {
+            info!("Shutting down");
+            drain::drain(&register, log.clone()).and_then(move |_| {
+                let log = log
+                    .shutdown()
+                    .map_err(|e| error!("Log shutdown error: {}", e));
+                let topics = topics
+                    .shutdown_all()
+                    .map_err(|e| error!("Topic shutdown error: {}", e));
+                log.join(topics)
+            })
+        })
+        .and_then(|_| Delay::new(Instant::now() + SHUTDOWN_GRACE_PERIOD).map_err(|_| ()))
 }
 
 pub fn main() {
     env_logger::init();
 
-    let config = config();
+    let (config, compact) = config();
+    if compact {
+        match asynclog::compact_offline(&config.log) {
+            Ok(report) => println!(
+                "Compacted {} records ({} bytes) to {} records ({} bytes)",
+                report.records_before,
+                report.bytes_before,
+                report.records_after,
+                report.bytes_after
+            ),
+            Err(e) => {
+                eprintln!("Compaction failed: {}", e);
+                exit(1);
+            }
+        }
+        return;
+    }
+
     let mut rt = Runtime::new().unwrap();
     // TODO: remove unwrap here
     rt.block_on(lazy(move || {
         let (listener, register) = tail_reply::new();
         let lr = replication::log_reader::FileSliceMessageReader;
-        let (log, r_log) = asynclog::open(&config.log, listener, lr);
+        let (log, r_log) = match asynclog::open(&config.log, listener, lr) {
+            Ok(logs) => logs,
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        };
 
         spawn(replication::server(
             &config.replication.server_addr,
+            &config.socket,
             r_log.clone(),
         ));
 
+        // topics other than the default are not replicated, so their
+        // appends are replied to by this node
+        let topic_register = register.clone();
+        let topics = asynclog::Topics::new(&config.log, move |topic, cfg| {
+            let listener = topic_register.topic_listener();
+            let reader = replication::log_reader::FileSliceMessageReader;
+            asynclog::open_topic(topic, cfg, listener, reader).map(|(log, _)| log)
+        });
+        let log = log.with_topics(topics.clone());
+
         if let Some(ref admin) = config.admin {
-            spawn(admin_server::server(&admin.server_addr));
+            spawn(admin_server::server(
+                admin,
+                &config.socket,
+                log.clone(),
+                register.clone(),
+            ));
         }
 
-        spawn(server::server(&config.frontend, log, register));
+        let shutdown = shutdown(register.clone(), log.clone(), topics.clone());
+        spawn(server::server(
+            &config.frontend,
+            &config.socket,
+            log,
+            register,
+        ));
 
         configuration::ClusterJoin::new(&config)
-            .and_then(move |node_mgr| replication::ReplicationController::new(node_mgr, r_log))
+            .and_then(move |node_mgr| {
+                replication::ReplicationController::new(node_mgr, r_log, topics)
+            })
+            .select(shutdown)
+            .map(|_| ())
+            .map_err(|_| ())
     }))
     .unwrap();
 }
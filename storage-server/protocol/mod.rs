@@ -6,4 +6,6 @@ mod storage_grpc;
 pub use self::manage::*;
 pub use self::manage_grpc::ConfigurationClient;
 pub use self::storage::*;
+#[cfg(test)]
+pub use self::storage_grpc::LogStorageClient;
 pub use self::storage_grpc::{create_log_storage, LogStorage};
@@ -1,6 +1,6 @@
 use super::log_reader::FileSlice;
 use super::poll::UpstreamReplication;
-use asynclog::ReplicatorAsyncLog;
+use asynclog::{ReplicatorAsyncLog, Topics};
 use configuration::{NodeConfigFuture, NodeManager};
 use either::Either;
 use futures::future::Either as EitherFut;
@@ -22,13 +22,20 @@ enum ControllerState {
 pub struct ReplicationController {
     manager: NodeManager,
     state: ControllerState,
+    // topics are not replicated, so are rejected while the node is chained
+    topics: Topics,
 }
 
 impl ReplicationController {
-    pub fn new(manager: NodeManager, log: ReplicatorAsyncLog<FileSlice>) -> ReplicationController {
+    pub fn new(
+        manager: NodeManager,
+        log: ReplicatorAsyncLog<FileSlice>,
+        topics: Topics,
+    ) -> ReplicationController {
         let repoll = Repoll::new(&manager).into_future();
         let state = {
             let current_config = manager.current();
+            topics.set_chained(current_config.is_chained());
             match current_config.upstream_addr() {
                 Some(addr) => ControllerState::Replicating(
                     repoll.select2(UpstreamReplication::new(&addr, log)),
@@ -36,7 +43,11 @@ impl ReplicationController {
                 None => ControllerState::WaitingForAssignment(repoll, log),
             }
         };
-        ReplicationController { manager, state }
+        ReplicationController {
+            manager,
+            state,
+            topics,
+        }
     }
 }
 
@@ -51,6 +62,7 @@ impl Future for ReplicationController {
                 ControllerState::Empty => unreachable!("Reached Empty state"),
                 ControllerState::WaitingForAssignment(mut repoll, log) => match repoll.poll() {
                     Ok(Async::Ready((_, repoll_stream))) => {
+                        self.topics.set_chained(self.manager.current().is_chained());
                         match self.manager.current().upstream_addr() {
                             Some(addr) => {
                                 info!("Assigned upstream address={}", addr);
@@ -75,6 +87,7 @@ impl Future for ReplicationController {
                 ControllerState::Replicating(mut select_future) => match select_future.poll() {
                     Ok(Async::Ready(EitherFut::A(((_, repoll_stream), replication)))) => {
                         let repoll = repoll_stream.into_future();
+                        self.topics.set_chained(self.manager.current().is_chained());
 
                         // check if upstream needs to be changed
                         match self.manager.current().upstream_addr() {
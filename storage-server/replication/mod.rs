@@ -1,5 +1,7 @@
 use asynclog::ReplicatorAsyncLog;
+use config::SocketConfig;
 use futures::{Future, Stream};
+use socket;
 use std::net::SocketAddr;
 use tokio;
 use tokio::net::TcpListener;
@@ -18,8 +20,10 @@ pub use self::log_reader::{FileSlice, FileSliceMessageReader};
 
 pub fn server(
     addr: &SocketAddr,
+    socket_cfg: &SocketConfig,
     log: ReplicatorAsyncLog<FileSlice>,
 ) -> impl Future<Item = (), Error = ()> {
+    let socket_cfg = socket_cfg.clone();
     let listener =
         TcpListener::bind(addr).expect("unable to bind TCP listener for replication server");
     listener
@@ -28,9 +32,7 @@ pub fn server(
         .for_each(move |sock| {
             let mut log = log.clone();
 
-            if let Err(e) = sock.set_nodelay(true) {
-                warn!("Unable to set nodelay on socket: {}", e);
-            }
+            socket::apply(&sock, &socket_cfg);
 
             let (request_stream, write_buf) = self::io::replication_framed(sock);
 
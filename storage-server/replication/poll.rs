@@ -2,8 +2,8 @@ use super::client::{connect, ClientConnectFuture, ClientRequestFuture, Connectio
 use super::log_reader::FileSlice;
 use super::protocol::ReplicationResponse;
 use asynclog::Messages;
-use asynclog::{LogFuture, ReplicatorAsyncLog};
-use commitlog::{Offset, OffsetRange};
+use asynclog::{AppendRange, LogFuture, ReplicatorAsyncLog};
+use commitlog::Offset;
 use futures::future::{Join, Map};
 use futures::{Async, Future, Poll};
 use std::io;
@@ -50,8 +50,8 @@ enum ReplicationState {
     // * Appending current batch of messages to log
     RequestAndAppend(
         Map<
-            Join<LogFuture<OffsetRange>, ClientRequestFuture>,
-            fn((OffsetRange, ResponseConnectionPair)) -> ResponseConnectionPair,
+            Join<LogFuture<AppendRange>, ClientRequestFuture>,
+            fn((AppendRange, ResponseConnectionPair)) -> ResponseConnectionPair,
         >,
     ),
 }
@@ -86,7 +86,7 @@ fn next_batch(
     p: ResponseConnectionPair,
     log: &mut ReplicatorAsyncLog<FileSlice>,
 ) -> Result<ReplicationState, io::Error> {
-    fn map_second_elem(res: (OffsetRange, ResponseConnectionPair)) -> ResponseConnectionPair {
+    fn map_second_elem(res: (AppendRange, ResponseConnectionPair)) -> ResponseConnectionPair {
         res.1
     }
 
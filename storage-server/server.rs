@@ -1,25 +1,254 @@
-use asynclog::AsyncLog;
+use asynclog::{
+    self, stop_at_key, AsyncLog, Cursor, MessageTooLarge, Messages, Predicate, Priority,
+    RecordMeta, TopicPaused, TraceId,
+};
 use bytes::Bytes;
-use commitlog::{message::MessageSet, ReadLimit};
-use config::FrontendConfig;
+use checksum;
+use commitlog::message::MessageSet;
+use config::{FrontendConfig, SocketConfig};
+use frame;
 use futures::{Async, Future, Poll, Sink, Stream};
 use grpcio::{
-    self, Environment, RpcContext, Server as GrpcServer, ServerBuilder, ServerStreamingSink,
-    UnarySink, WriteFlags,
+    self, Environment, RpcContext, RpcStatus, RpcStatusCode, Server as GrpcServer, ServerBuilder,
+    ServerStreamingSink, UnarySink, WriteFlags,
 };
 use protocol::*;
+use socket;
+use spans;
 use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tail_reply::TailReplyRegistrar;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fs, io, iter};
+use tail_reply::{ClientReply, TailReplyRegistrar};
+
+/// Next trace assigned to a request without one from the client.
+static NEXT_TRACE_ID: AtomicU64 = AtomicU64::new(1);
 
 #[derive(Clone)]
 struct Service(AsyncLog, TailReplyRegistrar);
 
 impl LogStorage for Service {
     fn append(&mut self, ctx: RpcContext, req: AppendRequest, sink: UnarySink<AppendAck>) {
-        self.0
-            .append(req.client_id, req.client_request_id, req.payload);
-        ctx.spawn(LogErr(sink.success(AppendAck::new())));
+        let _span = spans::append(req.get_trace_id());
+        let trace_id = request_trace(req.request_trace);
+        trace!(
+            "[trace {}] Append {} from client {}",
+            trace_id,
+            req.client_request_id,
+            req.client_id
+        );
+        let crc32 = if req.has_crc32() {
+            Some(req.get_crc32())
+        } else {
+            None
+        };
+        if let Err(status) = verify_checksum(&req.payload, crc32) {
+            warn!(
+                "[trace {}] Rejecting append from client {}",
+                trace_id, req.client_id
+            );
+            ctx.spawn(LogErr(sink.fail(status)));
+            return;
+        }
+
+        let mut log = match self.0.topic_or_create(req.get_topic()) {
+            Ok(log) => log.with_trace(trace_id),
+            Err(e) => {
+                ctx.spawn(LogErr(sink.fail(topic_status(&e))));
+                return;
+            }
+        };
+        let priority = match req.priority {
+            AppendPriority::HIGH => Priority::High,
+            AppendPriority::BULK => Priority::Bulk,
+        };
+        let producer = if req.has_producer() {
+            Some(producer_sequence(req.get_producer()))
+        } else {
+            None
+        };
+        match log.append(
+            req.client_id,
+            req.client_request_id,
+            req.payload,
+            priority,
+            producer,
+        ) {
+            Ok(()) => ctx.spawn(LogErr(sink.success(AppendAck::new()))),
+            Err(e) => {
+                debug!("[trace {}] Append failed: {}", trace_id, e);
+                let code = if log.is_read_only() || e.kind() == io::ErrorKind::TimedOut {
+                    RpcStatusCode::Unavailable
+                } else {
+                    RpcStatusCode::ResourceExhausted
+                };
+                ctx.spawn(LogErr(sink.fail(append_status(&e, code))))
+            }
+        }
+    }
+
+    fn append_batch(
+        &mut self,
+        ctx: RpcContext,
+        mut req: AppendBatchRequest,
+        sink: ServerStreamingSink<AppendBatchAck>,
+    ) {
+        if let Err(status) = verify_checksums(req.get_payloads(), req.get_crc32s()) {
+            ctx.spawn(LogErr(sink.fail(status)));
+            return;
+        }
+        let mut log = match self.0.topic_or_create(req.get_topic()) {
+            Ok(log) => log,
+            Err(e) => {
+                ctx.spawn(LogErr(sink.fail(topic_status(&e))));
+                return;
+            }
+        };
+        let wf = WriteFlags::default().buffer_hint(false);
+        let stream = log
+            .append_stream(req.client_id, req.take_payloads().into_vec())
+            .map(move |(index, offset)| {
+                let mut ack = AppendBatchAck::new();
+                ack.set_index(index as u64);
+                ack.set_offset(offset);
+                (ack, wf)
+            })
+            .map_err(|e| grpcio::Error::RpcFailure(append_status(&e, RpcStatusCode::Internal)));
+
+        ctx.spawn(LogErr(sink.send_all(stream)));
+    }
+
+    fn append_and_fetch(
+        &mut self,
+        ctx: RpcContext,
+        mut req: AppendBatchRequest,
+        sink: UnarySink<QueryResult>,
+    ) {
+        if let Err(status) = verify_checksums(req.get_payloads(), req.get_crc32s()) {
+            ctx.spawn(LogErr(sink.fail(status)));
+            return;
+        }
+        let mut log = match self.0.topic_or_create(req.get_topic()) {
+            Ok(log) => log,
+            Err(e) => {
+                ctx.spawn(LogErr(sink.fail(topic_status(&e))));
+                return;
+            }
+        };
+        let f = log
+            .append_and_fetch(req.client_id, req.take_payloads().into_vec())
+            .then(move |res| match res {
+                Ok((_, msgs)) => {
+                    let mut res = QueryResult::new();
+                    for m in msgs.iter() {
+                        res.mut_entries()
+                            .push(log_entry(m.offset(), m.metadata(), m.payload()));
+                    }
+                    LogErr(sink.success(res))
+                }
+                Err(e) => LogErr(sink.fail(append_status(&e, RpcStatusCode::Internal))),
+            });
+        ctx.spawn(f);
+    }
+
+    fn append_now(
+        &mut self,
+        ctx: RpcContext,
+        req: AppendNowRequest,
+        sink: UnarySink<AppendNowResult>,
+    ) {
+        let crc32 = if req.has_crc32() {
+            Some(req.get_crc32())
+        } else {
+            None
+        };
+        if let Err(status) = verify_checksum(&req.payload, crc32) {
+            ctx.spawn(LogErr(sink.fail(status)));
+            return;
+        }
+        let mut log = match self.0.topic_or_create(req.get_topic()) {
+            Ok(log) => log,
+            Err(e) => {
+                ctx.spawn(LogErr(sink.fail(topic_status(&e))));
+                return;
+            }
+        };
+        let append = if req.has_producer() {
+            let seq = producer_sequence(req.get_producer());
+            log.append_sequenced(req.client_id, seq, req.payload, req.flush)
+        } else {
+            log.append_now(req.client_id, req.payload, req.flush)
+        };
+        let f = append.then(move |res| match res {
+            Ok(offset) => {
+                let mut res = AppendNowResult::new();
+                res.set_offset(offset);
+                LogErr(sink.success(res))
+            }
+            Err(e) => {
+                let code = match e.kind() {
+                    io::ErrorKind::TimedOut => RpcStatusCode::Unavailable,
+                    io::ErrorKind::AlreadyExists => RpcStatusCode::AlreadyExists,
+                    _ => RpcStatusCode::Internal,
+                };
+                let status = append_status(&e, code);
+                LogErr(sink.fail(status))
+            }
+        });
+        ctx.spawn(f);
+    }
+
+    fn append_record(
+        &mut self,
+        ctx: RpcContext,
+        mut req: AppendRecordRequest,
+        sink: UnarySink<AppendNowResult>,
+    ) {
+        let crc32 = if req.has_crc32() {
+            Some(req.get_crc32())
+        } else {
+            None
+        };
+        if let Err(status) = verify_checksum(&req.payload, crc32) {
+            ctx.spawn(LogErr(sink.fail(status)));
+            return;
+        }
+        let mut log = match self.0.topic_or_create(req.get_topic()) {
+            Ok(log) => log,
+            Err(e) => {
+                ctx.spawn(LogErr(sink.fail(topic_status(&e))));
+                return;
+            }
+        };
+        let key = if req.has_key() {
+            Some(req.take_key())
+        } else {
+            None
+        };
+        let headers = req
+            .get_headers()
+            .iter()
+            .map(|h| (h.get_name().to_string(), h.get_value().to_string()))
+            .collect();
+        let f = log
+            .append_record(req.client_id, key, headers, req.payload)
+            .then(move |res| match res {
+                Ok(offset) => {
+                    let mut res = AppendNowResult::new();
+                    res.set_offset(offset);
+                    LogErr(sink.success(res))
+                }
+                Err(e) => {
+                    let code = match e.kind() {
+                        io::ErrorKind::InvalidInput => RpcStatusCode::InvalidArgument,
+                        io::ErrorKind::TimedOut => RpcStatusCode::Unavailable,
+                        _ => RpcStatusCode::Internal,
+                    };
+                    LogErr(sink.fail(append_status(&e, code)))
+                }
+            });
+        ctx.spawn(f);
     }
 
     fn replies(&mut self, ctx: RpcContext, req: ReplyRequest, sink: ServerStreamingSink<Reply>) {
@@ -27,12 +256,18 @@ impl LogStorage for Service {
             .force_no_compress(true)
             .buffer_hint(false);
 
-        let stream = self
-            .1
-            .listen(req.client_id)
+        let replies = if req.initial_credit > 0 {
+            self.1.listen_with_credit(req.client_id, req.initial_credit)
+        } else {
+            self.1.listen(req.client_id)
+        };
+        let stream = replies
             .map(move |m| {
                 let mut reply = Reply::new();
-                reply.set_client_request_ids(m);
+                match m {
+                    ClientReply::Appended(ids) => reply.set_client_request_ids(ids),
+                    ClientReply::Goodbye(reason) => reply.set_goodbye(reason),
+                }
                 (reply, wf)
             })
             .map_err(|_| grpcio::Error::RemoteStopped);
@@ -40,40 +275,316 @@ impl LogStorage for Service {
         ctx.spawn(LogErr(sink.send_all(stream)));
     }
 
+    fn grant_reply_credit(
+        &mut self,
+        ctx: RpcContext,
+        req: ReplyCredit,
+        sink: UnarySink<ReplyCreditAck>,
+    ) {
+        self.1.grant(req.client_id, req.credit);
+        ctx.spawn(LogErr(sink.success(ReplyCreditAck::new())));
+    }
+
     fn latest_offset(
         &mut self,
         ctx: RpcContext,
-        _req: LatestOffsetQuery,
+        req: LatestOffsetQuery,
         sink: UnarySink<LatestOffsetResult>,
     ) {
-        let f = self.0.last_offset().map_err(|_| ()).and_then(move |off| {
-            let mut res = LatestOffsetResult::new();
-            if let Some(off) = off {
-                res.set_offset(off);
+        let mut log = match self.0.topic(req.get_topic()) {
+            Ok(log) => log,
+            Err(e) => {
+                ctx.spawn(LogErr(sink.fail(topic_status(&e))));
+                return;
+            }
+        };
+        // a failed log thread still serves the appended offset
+        let durable = log
+            .flushed_offset()
+            .then(|res| Ok::<_, io::Error>(res.unwrap_or(None)));
+        let low_watermark = log
+            .low_watermark()
+            .then(|res| Ok::<_, io::Error>(res.unwrap_or(0)));
+        let f = log
+            .last_offset()
+            .join3(durable, low_watermark)
+            .map_err(|_| ())
+            .and_then(move |(off, durable, low_watermark)| {
+                let mut res = LatestOffsetResult::new();
+                if let Some(off) = off {
+                    res.set_offset(off);
+                }
+                if let Some(durable) = durable {
+                    res.set_durable_offset(durable);
+                }
+                res.set_low_watermark(low_watermark);
+                LogErr(sink.success(res))
+            });
+        ctx.spawn(f);
+    }
+
+    fn summary(&mut self, ctx: RpcContext, req: SummaryQuery, sink: UnarySink<SummaryResult>) {
+        let mut log = match self.0.topic(req.get_topic()) {
+            Ok(log) => log,
+            Err(e) => {
+                ctx.spawn(LogErr(sink.fail(topic_status(&e))));
+                return;
+            }
+        };
+        let f = log.summary().then(move |res| match res {
+            Ok(summary) => {
+                let mut res = SummaryResult::new();
+                if let Some(off) = summary.first_offset {
+                    res.set_first_offset(off);
+                }
+                if let Some(off) = summary.last_offset {
+                    res.set_last_offset(off);
+                }
+                res.set_bytes(summary.bytes);
+                res.set_segments(summary.segments as u64);
+                res.set_last_flush_ms(unix_millis(summary.last_flush));
+                LogErr(sink.success(res))
+            }
+            Err(e) => {
+                let code = match e.kind() {
+                    io::ErrorKind::BrokenPipe => RpcStatusCode::Unavailable,
+                    _ => RpcStatusCode::Internal,
+                };
+                LogErr(sink.fail(RpcStatus::new(code, Some(e.to_string()))))
+            }
+        });
+        ctx.spawn(f);
+    }
+
+    fn segments(&mut self, ctx: RpcContext, req: SegmentsQuery, sink: UnarySink<SegmentsResult>) {
+        let mut log = match self.0.topic(req.get_topic()) {
+            Ok(log) => log,
+            Err(e) => {
+                ctx.spawn(LogErr(sink.fail(topic_status(&e))));
+                return;
+            }
+        };
+        let f = log.segments().then(move |res| match res {
+            Ok(segments) => {
+                let mut res = SegmentsResult::new();
+                for s in segments {
+                    let mut segment = Segment::new();
+                    segment.set_base_offset(s.base_offset);
+                    if let Some(off) = s.last_offset {
+                        segment.set_last_offset(off);
+                    }
+                    segment.set_bytes(s.bytes);
+                    if let Some(created) = s.created {
+                        segment.set_created_ms(unix_millis(created));
+                    }
+                    res.mut_segments().push(segment);
+                }
+                LogErr(sink.success(res))
+            }
+            Err(e) => {
+                let code = match e.kind() {
+                    io::ErrorKind::BrokenPipe => RpcStatusCode::Unavailable,
+                    _ => RpcStatusCode::Internal,
+                };
+                LogErr(sink.fail(RpcStatus::new(code, Some(e.to_string()))))
+            }
+        });
+        ctx.spawn(f);
+    }
+
+    fn flush(&mut self, ctx: RpcContext, req: FlushRequest, sink: UnarySink<FlushResult>) {
+        let mut log = match self.0.topic(req.get_topic()) {
+            Ok(log) => log,
+            Err(e) => {
+                ctx.spawn(LogErr(sink.fail(topic_status(&e))));
+                return;
+            }
+        };
+        let f = log.flush().then(move |res| match res {
+            Ok(durable) => {
+                let mut res = FlushResult::new();
+                if let Some(off) = durable {
+                    res.set_durable_offset(off);
+                }
+                LogErr(sink.success(res))
+            }
+            Err(e) => {
+                let code = match e.kind() {
+                    io::ErrorKind::BrokenPipe => RpcStatusCode::Unavailable,
+                    _ => RpcStatusCode::Internal,
+                };
+                LogErr(sink.fail(RpcStatus::new(code, Some(e.to_string()))))
             }
-            LogErr(sink.success(res))
         });
         ctx.spawn(f);
     }
 
     fn query_log(&mut self, ctx: RpcContext, req: QueryRequest, sink: UnarySink<QueryResult>) {
-        trace!("Query log: {:?}", req);
-        let read_limit = ReadLimit::max_bytes(req.max_bytes as usize);
-        let f = self
-            .0
-            .read(req.start_offset, read_limit)
-            .map_err(|_| ())
-            .and_then(move |b| {
-                let mut res = QueryResult::new();
+        let trace_id = request_trace(req.request_trace);
+        trace!("[trace {}] Query log: {:?}", trace_id, req);
+        let mut log = match self.0.topic(req.get_topic()) {
+            Ok(log) => log.with_trace(trace_id),
+            Err(e) => {
+                ctx.spawn(LogErr(sink.fail(topic_status(&e))));
+                return;
+            }
+        };
+        let span = spans::read(req.get_trace_id());
+        let max_wait = Duration::from_millis(u64::from(req.max_wait_ms));
+        let framed = req.framed;
+        let max_messages = match req.max_messages {
+            0 => None,
+            n => Some(n as usize),
+        };
+        if req.metadata_only {
+            if framed || req.has_filter() || req.has_stop_at_key() {
+                let status = RpcStatus::new(
+                    RpcStatusCode::InvalidArgument,
+                    Some("Metadata-only reads cannot be framed, filtered or stopped".to_string()),
+                );
+                ctx.spawn(LogErr(sink.fail(status)));
+                return;
+            }
+
+            let end = if req.has_end_offset() {
+                Some(req.get_end_offset())
+            } else {
+                None
+            };
+            let f = log
+                .read_metadata(req.start_offset, end, req.max_bytes as usize)
+                .then(move |res| {
+                    let read = match res {
+                        Ok(read) => read,
+                        Err(e) => return LogErr(sink.fail(read_status(&e))),
+                    };
+                    let read = match max_messages {
+                        Some(n) => read.take(n),
+                        None => read,
+                    };
+                    let mut res = QueryResult::new();
+                    if let Some(next) = read.next_offset {
+                        res.set_next_offset(next);
+                    }
+                    for e in &read.entries {
+                        let mut entry = log_entry(e.offset, &e.metadata, &[]);
+                        entry.clear_crc32();
+                        entry.set_payload_size(e.payload_len as u32);
+                        res.mut_entries().push(entry);
+                    }
+
+                    trace!("Query log metadata done");
+                    let _span = span;
+                    LogErr(sink.success(res))
+                });
+            ctx.spawn(f);
+            return;
+        }
+        let filter = if req.has_filter() {
+            match read_filter(req.get_filter()) {
+                Ok(pred) => Some(pred),
+                Err(e) => {
+                    let status =
+                        RpcStatus::new(RpcStatusCode::InvalidArgument, Some(e.to_string()));
+                    ctx.spawn(LogErr(sink.fail(status)));
+                    return;
+                }
+            }
+        } else {
+            None
+        };
+        let stop = if req.has_stop_at_key() {
+            let stop = req.get_stop_at_key();
+            Some((Bytes::from(stop.get_key()), stop.inclusive))
+        } else {
+            None
+        };
+        let read: Box<Future<Item = Messages, Error = io::Error> + Send> = if req.has_end_offset() {
+            Box::new(log.read_range(
+                req.start_offset,
+                Some(req.get_end_offset()),
+                req.max_bytes as usize,
+            ))
+        } else {
+            Box::new(log.read_wait(req.start_offset, req.max_bytes as usize, max_wait))
+        };
+        let f = read.then(move |res| {
+            let b = match res {
+                Ok(b) => b,
+                Err(e) => return LogErr(sink.fail(read_status(&e))),
+            };
+            let (b, stopped) = match stop {
+                Some((ref key, inclusive)) => stop_at_key(b, key, inclusive),
+                None => (b, false),
+            };
+            let b = match filter {
+                Some(ref pred) => pred.apply(&b),
+                None => b,
+            };
+            // entries removed by the count end the read before the key
+            let (b, stopped) = match max_messages {
+                Some(n) if b.len() > n => (b.take(n), false),
+                _ => (b, stopped),
+            };
+
+            let mut res = QueryResult::new();
+            if let Some(next) = b.next_offset() {
+                res.set_next_offset(next);
+            }
+            res.set_stopped(stopped);
+            if framed {
+                res.set_framed_entries(frame::encode(&b));
+            } else {
                 for m in b.iter() {
-                    let mut entry = LogEntry::new();
-                    entry.set_offset(m.offset());
-                    entry.set_payload(Bytes::from(m.payload()));
-                    res.mut_entries().push(entry);
+                    res.mut_entries()
+                        .push(log_entry(m.offset(), m.metadata(), m.payload()));
                 }
+            }
 
-                trace!("Query log done");
-                LogErr(sink.success(res))
+            trace!("Query log done");
+            let _span = span;
+            LogErr(sink.success(res))
+        });
+        ctx.spawn(f);
+    }
+
+    fn read_page(&mut self, ctx: RpcContext, req: PageRequest, sink: UnarySink<PageResult>) {
+        let cursor = if req.has_cursor() {
+            match Cursor::parse(req.get_cursor()) {
+                Ok(cursor) => cursor,
+                Err(e) => {
+                    let status =
+                        RpcStatus::new(RpcStatusCode::InvalidArgument, Some(e.to_string()));
+                    ctx.spawn(LogErr(sink.fail(status)));
+                    return;
+                }
+            }
+        } else {
+            Cursor::start(req.get_start_offset())
+        };
+
+        let mut log = match self.0.topic(req.get_topic()) {
+            Ok(log) => log,
+            Err(e) => {
+                ctx.spawn(LogErr(sink.fail(topic_status(&e))));
+                return;
+            }
+        };
+        let f = log
+            .read_page(cursor, req.max_bytes as usize)
+            .then(move |res| match res {
+                Ok((msgs, next)) => {
+                    let mut res = PageResult::new();
+                    for m in msgs.iter() {
+                        res.mut_entries()
+                            .push(log_entry(m.offset(), m.metadata(), m.payload()));
+                    }
+                    if let Some(next) = next {
+                        res.set_next_cursor(next.token().into());
+                    }
+                    LogErr(sink.success(res))
+                }
+                Err(e) => LogErr(sink.fail(read_status(&e))),
             });
         ctx.spawn(f);
     }
@@ -81,6 +592,7 @@ impl LogStorage for Service {
 
 pub fn server(
     cfg: &FrontendConfig,
+    socket_cfg: &SocketConfig,
     log: AsyncLog,
     tail: TailReplyRegistrar,
 ) -> impl Future<Item = (), Error = ()> {
@@ -89,16 +601,25 @@ pub fn server(
     let service = create_log_storage(Service(log, tail));
     let env = Arc::new(Environment::new(1));
 
-    let host = cfg.server_addr.ip().to_string();
-    let port = cfg.server_addr.port();
-
-    info!("STARTING GRPC SERVER: {}:{}", host, port);
+    if let Some(ref path) = cfg.uds_path {
+        // remove the socket left behind by a previous run
+        if let Err(e) = fs::remove_file(path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("Unable to remove existing socket {}: {}", path, e);
+            }
+        }
+    }
 
-    let mut server = ServerBuilder::new(env)
-        .register_service(service)
-        .bind(host, port)
-        .build()
-        .unwrap();
+    // a single server accepts connections from every address, all served
+    // by the same service
+    let mut builder = ServerBuilder::new(env.clone())
+        .channel_args(socket::grpc_channel_args(env, socket_cfg))
+        .register_service(service);
+    for (host, port) in bind_addrs(cfg) {
+        info!("STARTING GRPC SERVER: {}:{}", host, port);
+        builder = builder.bind(host, port);
+    }
+    let mut server = builder.build().unwrap();
     server.start();
 
     for &(ref host, port) in server.bind_addrs() {
@@ -108,6 +629,173 @@ pub fn server(
     WaitFuture(server)
 }
 
+/// Status of a failed append, with the code unless the payload is larger
+/// than the maximum message size, reported as `OutOfRange` on every append,
+/// or otherwise invalid, as an empty payload, reported as `InvalidArgument`
+/// so that clients do not retry it. Appends to a paused topic are reported
+/// as `FailedPrecondition`.
+fn append_status(e: &io::Error, code: RpcStatusCode) -> RpcStatus {
+    let code = match MessageTooLarge::from_error(e) {
+        Some(_) => RpcStatusCode::OutOfRange,
+        None if TopicPaused::from_error(e).is_some() => RpcStatusCode::FailedPrecondition,
+        None if e.kind() == io::ErrorKind::InvalidInput => RpcStatusCode::InvalidArgument,
+        None => code,
+    };
+    RpcStatus::new(code, Some(e.to_string()))
+}
+
+/// Trace of a request, the trace supplied by the client or else the next
+/// assigned by the frontend.
+fn request_trace(supplied: TraceId) -> TraceId {
+    match supplied {
+        0 => NEXT_TRACE_ID.fetch_add(1, Ordering::Relaxed),
+        trace_id => trace_id,
+    }
+}
+
+/// Status of a failed read. Reads of offsets deleted by a trim or retention
+/// fail with `OutOfRange`, the details naming the low watermark, and reads
+/// of a busy log with `ResourceExhausted`.
+fn read_status(e: &io::Error) -> RpcStatus {
+    let code = match e.kind() {
+        io::ErrorKind::InvalidInput => RpcStatusCode::InvalidArgument,
+        io::ErrorKind::NotFound => RpcStatusCode::OutOfRange,
+        // the read queue stayed full for the read timeout
+        io::ErrorKind::WouldBlock => RpcStatusCode::ResourceExhausted,
+        _ => RpcStatusCode::Internal,
+    };
+    RpcStatus::new(code, Some(e.to_string()))
+}
+
+/// Milliseconds since the Unix epoch, zero for earlier times.
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1000 + u64::from(d.subsec_millis()))
+        .unwrap_or(0)
+}
+
+/// Status of a request for a topic that cannot be served. Topics are not
+/// replicated, so fail with `FailedPrecondition` while the node is chained.
+fn topic_status(e: &io::Error) -> RpcStatus {
+    let code = match e.kind() {
+        io::ErrorKind::InvalidInput => RpcStatusCode::InvalidArgument,
+        io::ErrorKind::NotFound => RpcStatusCode::NotFound,
+        io::ErrorKind::PermissionDenied => RpcStatusCode::FailedPrecondition,
+        io::ErrorKind::Other => RpcStatusCode::ResourceExhausted,
+        _ => RpcStatusCode::Internal,
+    };
+    RpcStatus::new(code, Some(e.to_string()))
+}
+
+/// Converts the filter of a query to a predicate.
+fn read_filter(filter: &ReadFilter) -> Result<Predicate, io::Error> {
+    let pred = if filter.has_key_equals() {
+        Predicate::KeyEquals(Bytes::from(filter.get_key_equals()))
+    } else if filter.has_key_in() {
+        let keys = filter.get_key_in().get_keys();
+        Predicate::KeyIn(keys.iter().map(|k| Bytes::from(&k[..])).collect())
+    } else if filter.has_header_equals() {
+        let header = filter.get_header_equals();
+        Predicate::HeaderEquals(
+            header.get_name().to_string(),
+            header.get_value().to_string(),
+        )
+    } else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Read filter has no predicate",
+        ));
+    };
+    pred.validate()?;
+    Ok(pred)
+}
+
+/// Sequence of an append from an idempotent producer.
+fn producer_sequence(seq: &ProducerSequence) -> asynclog::ProducerSequence {
+    asynclog::ProducerSequence {
+        producer_id: seq.producer_id,
+        sequence: seq.sequence,
+    }
+}
+
+/// Verifies a payload against the checksum sent by the client, if any. A
+/// mismatch is reported as `DataLoss`, for the client to tell the corrupted
+/// payload apart from an invalid request.
+fn verify_checksum(payload: &[u8], crc32: Option<u32>) -> Result<(), RpcStatus> {
+    match crc32 {
+        Some(crc32) => checksum::verify(payload, crc32)
+            .map_err(|e| RpcStatus::new(RpcStatusCode::DataLoss, Some(e.to_string()))),
+        None => Ok(()),
+    }
+}
+
+/// Verifies the payloads of a batch against the checksums sent by the
+/// client, either none or one for each payload. A mismatch is reported as
+/// `DataLoss` with the index of the entry.
+fn verify_checksums(payloads: &[Bytes], crc32s: &[u32]) -> Result<(), RpcStatus> {
+    if crc32s.is_empty() {
+        return Ok(());
+    }
+    if crc32s.len() != payloads.len() {
+        let details = format!(
+            "Batch of {} payloads has {} checksums",
+            payloads.len(),
+            crc32s.len()
+        );
+        return Err(RpcStatus::new(
+            RpcStatusCode::InvalidArgument,
+            Some(details),
+        ));
+    }
+    for (i, (payload, &crc32)) in payloads.iter().zip(crc32s).enumerate() {
+        checksum::verify(payload, crc32).map_err(|e| {
+            let details = format!("Entry {}: {}", i, e);
+            RpcStatus::new(RpcStatusCode::DataLoss, Some(details))
+        })?;
+    }
+    Ok(())
+}
+
+/// Converts a log entry for a response, with the key and headers if the entry
+/// is a record, and the checksum of the payload as stored.
+fn log_entry(offset: u64, metadata: &[u8], payload: &[u8]) -> LogEntry {
+    let mut entry = LogEntry::new();
+    entry.set_offset(offset);
+    entry.set_payload(Bytes::from(payload));
+    entry.set_crc32(checksum::crc32(payload));
+    match RecordMeta::parse(metadata) {
+        Ok(meta) => {
+            if let Some(seq) = meta.sequence {
+                entry.set_sequence(seq);
+            }
+            if let Some(key) = meta.key {
+                entry.set_key(Bytes::from(key));
+            }
+            for (name, value) in meta.headers {
+                let mut header = Header::new();
+                header.set_name(name.into());
+                header.set_value(value.into());
+                entry.mut_headers().push(header);
+            }
+        }
+        Err(e) => warn!("Invalid key or headers at offset {}: {:?}", offset, e),
+    }
+    entry
+}
+
+/// Hosts and ports the server binds to. Unix domain sockets are bound with
+/// a `unix:` host and no port.
+fn bind_addrs(cfg: &FrontendConfig) -> Vec<(String, u16)> {
+    let mut addrs: Vec<(String, u16)> = iter::once(&cfg.server_addr)
+        .chain(cfg.additional_addrs.iter())
+        .map(|addr| (addr.ip().to_string(), addr.port()))
+        .collect();
+    if let Some(ref path) = cfg.uds_path {
+        addrs.push((format!("unix:{}", path), 0));
+    }
+    addrs
+}
+
 struct WaitFuture(GrpcServer);
 
 impl Future for WaitFuture {
@@ -142,3 +830,163 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asynclog::OffsetTrimmed;
+    use config::LogConfig;
+    use futures::future::lazy;
+    use grpcio::ChannelBuilder;
+    use replication::FileSliceMessageReader;
+    use std::{env, process};
+    use tail_reply;
+    use tokio::runtime::Runtime;
+
+    fn failed_code<T: Debug>(res: grpcio::Result<T>) -> RpcStatusCode {
+        match res {
+            Err(grpcio::Error::RpcFailure(status)) => status.status,
+            res => panic!("Expected a failed request, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn binds_all_addresses() {
+        let cfg = FrontendConfig {
+            server_addr: "0.0.0.0:8080".parse().unwrap(),
+            additional_addrs: vec!["[::1]:8081".parse().unwrap()],
+            uds_path: Some("/tmp/log.sock".to_string()),
+        };
+        assert_eq!(
+            vec![
+                ("0.0.0.0".to_string(), 8080),
+                ("::1".to_string(), 8081),
+                ("unix:/tmp/log.sock".to_string(), 0),
+            ],
+            bind_addrs(&cfg)
+        );
+    }
+
+    #[test]
+    fn too_large_appends_fail_out_of_range() {
+        let too_large = MessageTooLarge {
+            bytes: 2048,
+            max_bytes: 1024,
+        };
+        let e = io::Error::new(io::ErrorKind::InvalidInput, too_large);
+        let status = append_status(&e, RpcStatusCode::InvalidArgument);
+        assert_eq!(RpcStatusCode::OutOfRange, status.status);
+
+        let e = io::Error::new(io::ErrorKind::InvalidInput, "Key too large");
+        let status = append_status(&e, RpcStatusCode::InvalidArgument);
+        assert_eq!(RpcStatusCode::InvalidArgument, status.status);
+    }
+
+    #[test]
+    fn invalid_appends_are_not_retryable() {
+        let e = io::Error::new(io::ErrorKind::InvalidInput, "Entry 0 has an empty payload");
+        let status = append_status(&e, RpcStatusCode::ResourceExhausted);
+        assert_eq!(RpcStatusCode::InvalidArgument, status.status);
+        let status = append_status(&e, RpcStatusCode::Internal);
+        assert_eq!(RpcStatusCode::InvalidArgument, status.status);
+
+        let e = io::Error::new(io::ErrorKind::WouldBlock, "Append queue is full");
+        let status = append_status(&e, RpcStatusCode::ResourceExhausted);
+        assert_eq!(RpcStatusCode::ResourceExhausted, status.status);
+    }
+
+    #[test]
+    fn paused_topic_appends_fail_precondition() {
+        let e = TopicPaused::error("orders");
+        let status = append_status(&e, RpcStatusCode::ResourceExhausted);
+        assert_eq!(RpcStatusCode::FailedPrecondition, status.status);
+        assert_eq!(Some(e.to_string()), status.details);
+    }
+
+    #[test]
+    fn trimmed_reads_fail_out_of_range() {
+        let trimmed = OffsetTrimmed {
+            offset: 5,
+            low_watermark: 20,
+        };
+        let status = read_status(&io::Error::new(io::ErrorKind::NotFound, trimmed));
+        assert_eq!(RpcStatusCode::OutOfRange, status.status);
+        assert!(status.details.unwrap().contains("low watermark is 20"));
+
+        let e = io::Error::new(io::ErrorKind::InvalidInput, "Offset out of range");
+        assert_eq!(RpcStatusCode::InvalidArgument, read_status(&e).status);
+    }
+
+    #[test]
+    fn mismatched_checksums_fail_data_loss() {
+        let dir = env::temp_dir().join(format!("log-server-checksum-test-{}", process::id()));
+        let mut cfg = LogConfig::default();
+        cfg.dir = dir.to_string_lossy().into_owned();
+        let mut rt = Runtime::new().unwrap();
+        let (listener, registrar) = rt
+            .block_on(lazy(|| Ok::<_, ()>(tail_reply::new())))
+            .unwrap();
+        let (log, _) = asynclog::open(&cfg, listener, FileSliceMessageReader).unwrap();
+
+        let env = Arc::new(Environment::new(1));
+        let mut server = ServerBuilder::new(env.clone())
+            .register_service(create_log_storage(Service(log, registrar)))
+            .bind("127.0.0.1", 0)
+            .build()
+            .unwrap();
+        server.start();
+        let port = server.bind_addrs()[0].1;
+        let client =
+            LogStorageClient::new(ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port)));
+
+        // a payload corrupted after the client computed the checksum
+        let mut append = AppendRequest::new();
+        append.set_client_id(1);
+        append.set_client_request_id(1);
+        append.set_payload(Bytes::from("foo"));
+        append.set_crc32(checksum::crc32(b"fo0"));
+        assert_eq!(RpcStatusCode::DataLoss, failed_code(client.append(&append)));
+
+        let mut now = AppendNowRequest::new();
+        now.set_payload(Bytes::from("foo"));
+        now.set_crc32(checksum::crc32(b"fo0"));
+        assert_eq!(
+            RpcStatusCode::DataLoss,
+            failed_code(client.append_now(&now))
+        );
+
+        let mut record = AppendRecordRequest::new();
+        record.set_key(Bytes::from("key"));
+        record.set_payload(Bytes::from("foo"));
+        record.set_crc32(checksum::crc32(b"fo0"));
+        assert_eq!(
+            RpcStatusCode::DataLoss,
+            failed_code(client.append_record(&record))
+        );
+
+        let mut batch = AppendBatchRequest::new();
+        batch.set_payloads(vec![Bytes::from("foo"), Bytes::from("bar")].into());
+        batch.set_crc32s(vec![checksum::crc32(b"foo"), checksum::crc32(b"baz")]);
+        let res = client.append_and_fetch(&batch);
+        assert_eq!(RpcStatusCode::DataLoss, failed_code(res));
+        // a checksum missing for an entry
+        batch.set_crc32s(vec![checksum::crc32(b"foo")]);
+        let res = client.append_and_fetch(&batch);
+        assert_eq!(RpcStatusCode::InvalidArgument, failed_code(res));
+
+        // nothing was appended, and matching payloads are
+        now.set_crc32(checksum::crc32(b"foo"));
+        assert_eq!(0, client.append_now(&now).unwrap().offset);
+
+        // reads return the checksum of the stored payload
+        let mut query = QueryRequest::new();
+        query.set_max_bytes(4096);
+        let res = client.query_log(&query).unwrap();
+        assert_eq!(1, res.get_entries().len());
+        assert_eq!(checksum::crc32(b"foo"), res.get_entries()[0].get_crc32());
+
+        drop(client);
+        drop(server);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,39 @@
+use config::SocketConfig;
+use grpcio::{ChannelArgs, ChannelBuilder, Environment};
+use std::ffi::CString;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+
+// gRPC core channel arguments for the transport buffers
+const READ_CHUNK_SIZE_ARG: &str = "grpc.experimental.tcp_read_chunk_size";
+const WRITE_BUFFER_SIZE_ARG: &str = "grpc.http2.write_buffer_size";
+
+/// Applies the socket options to an accepted or connected socket.
+pub fn apply(sock: &TcpStream, cfg: &SocketConfig) {
+    if let Err(e) = sock.set_nodelay(cfg.nodelay) {
+        warn!("Unable to set nodelay on socket: {}", e);
+    }
+    if let Some(bytes) = cfg.send_buffer_bytes {
+        if let Err(e) = sock.set_send_buffer_size(bytes) {
+            warn!("Unable to set send buffer size on socket: {}", e);
+        }
+    }
+    if let Some(bytes) = cfg.recv_buffer_bytes {
+        if let Err(e) = sock.set_recv_buffer_size(bytes) {
+            warn!("Unable to set receive buffer size on socket: {}", e);
+        }
+    }
+}
+
+/// Channel arguments applying the buffer sizes to the gRPC server. gRPC
+/// always sets `TCP_NODELAY` on its sockets.
+pub fn grpc_channel_args(env: Arc<Environment>, cfg: &SocketConfig) -> ChannelArgs {
+    let mut cb = ChannelBuilder::new(env);
+    if let Some(bytes) = cfg.send_buffer_bytes {
+        cb = cb.raw_cfg_int(CString::new(WRITE_BUFFER_SIZE_ARG).unwrap(), bytes as i32);
+    }
+    if let Some(bytes) = cfg.recv_buffer_bytes {
+        cb = cb.raw_cfg_int(CString::new(READ_CHUNK_SIZE_ARG).unwrap(), bytes as i32);
+    }
+    cb.build_args()
+}
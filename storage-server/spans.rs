@@ -0,0 +1,76 @@
+//! Spans around log operations for distributed tracing, enabled with the
+//! `tracing` feature. Without the feature, spans are empty and free.
+//!
+//! A span is open from creation until it is dropped. Request spans carry the
+//! trace id sent by the client, so that a request can be followed from the
+//! producer to the log write. Appends are batched on the log thread, so the
+//! log spans are not attributed to individual requests.
+
+#[cfg(feature = "tracing")]
+pub use self::enabled::*;
+
+#[cfg(not(feature = "tracing"))]
+pub use self::disabled::*;
+
+#[cfg(feature = "tracing")]
+mod enabled {
+    use tracing::{self, Level};
+
+    pub type Span = tracing::Span;
+
+    /// Span for an append request.
+    pub fn append(trace_id: &str) -> Span {
+        tracing::span!(Level::INFO, "append", trace_id = trace_id)
+    }
+
+    /// Span for a query request.
+    pub fn read(trace_id: &str) -> Span {
+        tracing::span!(Level::INFO, "read", trace_id = trace_id)
+    }
+
+    /// Span for a batch of entries appended on the log thread.
+    pub fn log_append(entries: usize) -> Span {
+        tracing::span!(Level::DEBUG, "log_append", entries = entries as u64)
+    }
+
+    /// Span for a flush on the log thread.
+    pub fn log_flush() -> Span {
+        tracing::span!(Level::DEBUG, "log_flush")
+    }
+
+    /// Span for a read on the log thread.
+    pub fn log_read(offset: u64) -> Span {
+        tracing::span!(Level::DEBUG, "log_read", offset = offset)
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+mod disabled {
+    #[derive(Clone)]
+    pub struct Span;
+
+    #[inline]
+    pub fn append(_trace_id: &str) -> Span {
+        Span
+    }
+
+    #[inline]
+    pub fn read(_trace_id: &str) -> Span {
+        Span
+    }
+
+    #[inline]
+    pub fn log_append(_entries: usize) -> Span {
+        Span
+    }
+
+    #[inline]
+    pub fn log_flush() -> Span {
+        Span
+    }
+
+    #[inline]
+    pub fn log_read(_offset: u64) -> Span {
+        Span
+    }
+}
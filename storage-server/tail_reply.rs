@@ -2,36 +2,84 @@ use asynclog::AppendListener;
 use asynclog::Messages;
 use byteorder::{ByteOrder, LittleEndian};
 use commitlog::message::MessageSet;
+use commitlog::Offset;
 use fnv::FnvHashMap;
-use futures::sync::mpsc;
+use futures::sync::{mpsc, oneshot};
 use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
+use prometheus::GaugeVec;
+use protocol::GoodbyeReason;
+use std::cmp::min;
 use std::collections::hash_map;
 use tokio::spawn;
 
+lazy_static! {
+    static ref SUBSCRIPTION_LAG: GaugeVec = register_gauge_vec!(
+        "log_subscription_lag",
+        "Number of entries appended after the last entry delivered to the subscription.",
+        &["client"]
+    )
+    .unwrap();
+}
+
 // TODO: bound sending
-type ReplySender = mpsc::UnboundedSender<Vec<u64>>;
+type ReplySender = mpsc::UnboundedSender<ClientReply>;
+
+/// Reply sent to a single client
+#[derive(Debug, PartialEq, Eq)]
+pub enum ClientReply {
+    /// Client request IDs that have been appended
+    Appended(Vec<u64>),
+
+    /// The server is closing the stream. This is the last reply.
+    Goodbye(GoodbyeReason),
+}
 
 /// Stream of replies for a single client
-pub struct ReplyStream(mpsc::UnboundedReceiver<Vec<u64>>);
+pub struct ReplyStream(mpsc::UnboundedReceiver<ClientReply>);
 
 impl Stream for ReplyStream {
-    type Item = Vec<u64>;
+    type Item = ClientReply;
     type Error = ();
 
-    fn poll(&mut self) -> Poll<Option<Vec<u64>>, ()> {
+    fn poll(&mut self) -> Poll<Option<ClientReply>, ()> {
         self.0.poll()
     }
 }
 
+/// Active subscription to the replies of a client.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Subscription {
+    /// Identifier of the subscribed client
+    pub client_id: u64,
+
+    /// Offset of the last entry delivered to the subscription, if any
+    pub position: Option<Offset>,
+
+    /// Number of entries appended after the last delivered entry
+    pub lag: u64,
+}
+
 /// Listener for `AsyncLog` appends
 pub struct TailReplyListener {
     sender: mpsc::UnboundedSender<TailReplyMsg>,
+    // appends to a topic other than the default reply to clients, but have
+    // offsets of their own log
+    topic: bool,
 }
 
 impl AppendListener for TailReplyListener {
     fn notify_append(&mut self, append: Messages) {
+        let msg = if self.topic {
+            TailReplyMsg::NotifyTopic(append)
+        } else {
+            TailReplyMsg::Notify(append)
+        };
+        self.sender.unbounded_send(msg).unwrap();
+    }
+
+    fn notify_duplicate(&mut self, client_id: u64, client_req_id: u64) {
         self.sender
-            .unbounded_send(TailReplyMsg::Notify(append))
+            .unbounded_send(TailReplyMsg::Duplicate(client_id, client_req_id))
             .unwrap();
     }
 }
@@ -45,17 +93,140 @@ pub struct TailReplyRegistrar {
 impl TailReplyRegistrar {
     /// Listens for for client changes
     pub fn listen(&self, client_id: u64) -> ReplyStream {
+        self.register(client_id, None)
+    }
+
+    /// Listens for client changes with flow control. At most `credit` client
+    /// request IDs are sent until the client grants more credit with
+    /// `grant`. Replies beyond the credit are held back, in order.
+    pub fn listen_with_credit(&self, client_id: u64, credit: u64) -> ReplyStream {
+        self.register(client_id, Some(credit))
+    }
+
+    fn register(&self, client_id: u64, credit: Option<u64>) -> ReplyStream {
         let (snd, recv) = mpsc::unbounded();
         self.sender
-            .unbounded_send(TailReplyMsg::Register(client_id, snd))
+            .unbounded_send(TailReplyMsg::Register(client_id, snd, credit))
             .unwrap();
         ReplyStream(recv)
     }
+
+    /// Grants credit for more client request IDs to a client listening with
+    /// flow control, sending the replies held back.
+    pub fn grant(&self, client_id: u64, credit: u64) {
+        self.sender
+            .unbounded_send(TailReplyMsg::Grant(client_id, credit))
+            .unwrap_or_default();
+    }
+
+    /// Sends a goodbye to all the listening clients and closes their streams,
+    /// resolving with the final position of each subscription.
+    pub fn goodbye(
+        &self,
+        reason: GoodbyeReason,
+    ) -> impl Future<Item = Vec<Subscription>, Error = ()> {
+        let (snd, recv) = oneshot::channel();
+        self.sender
+            .unbounded_send(TailReplyMsg::Goodbye(reason, snd))
+            .unwrap_or_default();
+        recv.map_err(|_| ())
+    }
+
+    /// Sends a draining goodbye to all the listening clients, telling them to
+    /// reconnect to another node.
+    #[inline]
+    pub fn begin_drain(&self) -> impl Future<Item = Vec<Subscription>, Error = ()> {
+        self.goodbye(GoodbyeReason::DRAINING)
+    }
+
+    /// Active subscriptions, ordered by client.
+    pub fn subscriptions(&self) -> impl Future<Item = Vec<Subscription>, Error = ()> {
+        let (snd, recv) = oneshot::channel();
+        self.sender
+            .unbounded_send(TailReplyMsg::Subscriptions(snd))
+            .unwrap_or_default();
+        recv.map_err(|_| ())
+    }
+
+    /// Listener for the appends to a topic log. Clients are sent replies for
+    /// the appends, while subscription positions and lag follow the offsets
+    /// of the default topic only.
+    pub fn topic_listener(&self) -> TailReplyListener {
+        TailReplyListener {
+            sender: self.sender.clone(),
+            topic: true,
+        }
+    }
 }
 
 enum TailReplyMsg {
-    Register(u64, ReplySender),
+    Register(u64, ReplySender, Option<u64>),
+    Grant(u64, u64),
     Notify(Messages),
+    NotifyTopic(Messages),
+    Duplicate(u64, u64),
+    Goodbye(GoodbyeReason, oneshot::Sender<Vec<Subscription>>),
+    Subscriptions(oneshot::Sender<Vec<Subscription>>),
+}
+
+struct Registration {
+    sender: ReplySender,
+    position: Option<Offset>,
+    // remaining client request IDs the client accepts, if flow controlled
+    credit: Option<u64>,
+    // client request IDs held back for credit, with the offset of each in
+    // the default topic
+    pending: Vec<(u64, Option<Offset>)>,
+}
+
+impl Registration {
+    fn new(sender: ReplySender, credit: Option<u64>) -> Registration {
+        Registration {
+            sender,
+            position: None,
+            credit,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Sends the pending client request IDs within the credit. Fails if the
+    /// client has dropped the stream.
+    fn send_pending(&mut self, client_id: u64) -> Result<(), ()> {
+        let n = match self.credit {
+            Some(credit) => min(credit, self.pending.len() as u64) as usize,
+            None => self.pending.len(),
+        };
+        if n == 0 {
+            if !self.pending.is_empty() {
+                trace!("Client {} out of credit, holding back replies", client_id);
+            }
+            return Ok(());
+        }
+
+        let offset = self.pending[..n].iter().filter_map(|&(_, off)| off).last();
+        let ids = self.pending.drain(..n).map(|(id, _)| id).collect();
+        match self.sender.start_send(ClientReply::Appended(ids)) {
+            Ok(AsyncSink::Ready) => {
+                trace!("Tail reply sent to client {}", client_id);
+                if offset.is_some() {
+                    self.position = offset;
+                }
+                if let Some(ref mut credit) = self.credit {
+                    *credit -= n as u64;
+                }
+                Ok(())
+            }
+            Ok(AsyncSink::NotReady(_)) => {
+                // TODO: what should we do here...?
+                warn!("Client not ready, dropping notification");
+                Ok(())
+            }
+            Err(_) => {
+                trace!("Tail dropped");
+                Err(())
+            }
+        }
+    }
 }
 
 /// Opens a listener and tail reply pair
@@ -65,10 +236,12 @@ pub fn new() -> (TailReplyListener, TailReplyRegistrar) {
     spawn(TailReplySender {
         receiver,
         registered: FnvHashMap::default(),
+        latest: None,
     });
 
     let listener = TailReplyListener {
         sender: sender.clone(),
+        topic: false,
     };
     let registrar = TailReplyRegistrar { sender };
     (listener, registrar)
@@ -76,17 +249,19 @@ pub fn new() -> (TailReplyListener, TailReplyRegistrar) {
 
 struct TailReplySender {
     receiver: mpsc::UnboundedReceiver<TailReplyMsg>,
-    registered: FnvHashMap<u64, ReplySender>,
+    registered: FnvHashMap<u64, Registration>,
+    latest: Option<Offset>,
 }
 
 impl TailReplySender {
-    fn notify_clients(&mut self, append_set: Messages) {
-        let mut req_batches: FnvHashMap<u64, Vec<u64>> = FnvHashMap::default();
+    fn notify_clients(&mut self, append_set: Messages, topic: bool) {
+        let mut req_batches: FnvHashMap<u64, Vec<(u64, Option<Offset>)>> = FnvHashMap::default();
 
         // batch by client_id
         for msg in append_set.iter() {
+            // records carry the key and headers after the client metadata
             let bytes = msg.metadata();
-            if bytes.len() != 16 {
+            if bytes.len() < 16 {
                 warn!("Invalid log entry appended");
                 continue;
             }
@@ -94,33 +269,110 @@ impl TailReplySender {
             let client_id = LittleEndian::read_u64(&bytes[0..8]);
             if self.registered.contains_key(&client_id) {
                 let client_req_id = LittleEndian::read_u64(&bytes[8..16]);
-                let reqs = req_batches.entry(client_id).or_insert_with(Vec::new);
-                reqs.push(client_req_id);
+                let offset = if topic { None } else { Some(msg.offset()) };
+                req_batches
+                    .entry(client_id)
+                    .or_insert_with(Vec::new)
+                    .push((client_req_id, offset));
+            }
+        }
+
+        if !topic {
+            if let Some(msg) = append_set.iter().last() {
+                self.latest = Some(msg.offset());
             }
         }
 
         // notify the clients
         for (client_id, client_req_ids) in req_batches {
             if let hash_map::Entry::Occupied(mut entry) = self.registered.entry(client_id) {
-                let send_res = entry.get_mut().start_send(client_req_ids);
-                match send_res {
-                    Ok(AsyncSink::Ready) => {
-                        trace!("Tail reply sent to client {}", client_id);
-                    }
-                    Ok(AsyncSink::NotReady(_)) => {
-                        // TODO: what should we do here...?
-                        warn!("Client not ready, dropping notification");
-                    }
-                    Err(_) => {
-                        trace!("Tail dropped");
-                        entry.remove();
-                    }
+                entry.get_mut().pending.extend(client_req_ids);
+                if entry.get_mut().send_pending(client_id).is_err() {
+                    entry.remove();
+                    remove_lag_metric(client_id);
                 }
             }
         }
+
+        self.update_metrics();
+    }
+
+    /// Replies to the client for a retried append, acknowledged without
+    /// appending an entry.
+    fn acknowledge(&mut self, client_id: u64, client_req_id: u64) {
+        if let hash_map::Entry::Occupied(mut entry) = self.registered.entry(client_id) {
+            entry.get_mut().pending.push((client_req_id, None));
+            if entry.get_mut().send_pending(client_id).is_err() {
+                entry.remove();
+                remove_lag_metric(client_id);
+            }
+        }
+    }
+
+    fn grant_credit(&mut self, client_id: u64, credit: u64) {
+        if let hash_map::Entry::Occupied(mut entry) = self.registered.entry(client_id) {
+            let reg = entry.get_mut();
+            match reg.credit {
+                Some(ref mut c) => *c = c.saturating_add(credit),
+                None => return,
+            }
+            if reg.send_pending(client_id).is_err() {
+                entry.remove();
+                remove_lag_metric(client_id);
+                return;
+            }
+        }
+        self.update_metrics();
+    }
+
+    fn goodbye_clients(&mut self, reason: GoodbyeReason) {
+        info!("Sending goodbye to {} clients", self.registered.len());
+        for (client_id, mut reg) in self.registered.drain() {
+            remove_lag_metric(client_id);
+            if reg.sender.start_send(ClientReply::Goodbye(reason)).is_err() {
+                trace!("Client {} already dropped", client_id);
+            }
+        }
+    }
+
+    fn subscriptions(&self) -> Vec<Subscription> {
+        let mut subs: Vec<Subscription> = self
+            .registered
+            .iter()
+            .map(|(client_id, reg)| Subscription {
+                client_id: *client_id,
+                position: reg.position,
+                lag: lag(reg.position, self.latest),
+            })
+            .collect();
+        subs.sort_by_key(|s| s.client_id);
+        subs
+    }
+
+    fn update_metrics(&self) {
+        for (client_id, reg) in &self.registered {
+            SUBSCRIPTION_LAG
+                .with_label_values(&[&client_id.to_string()])
+                .set(lag(reg.position, self.latest) as f64);
+        }
     }
 }
 
+#[inline]
+fn lag(position: Option<Offset>, latest: Option<Offset>) -> u64 {
+    match (position, latest) {
+        (Some(pos), Some(latest)) => latest.saturating_sub(pos),
+        _ => 0,
+    }
+}
+
+#[inline]
+fn remove_lag_metric(client_id: u64) {
+    SUBSCRIPTION_LAG
+        .remove_label_values(&[&client_id.to_string()])
+        .unwrap_or_default();
+}
+
 impl Future for TailReplySender {
     type Item = ();
     type Error = ();
@@ -128,12 +380,30 @@ impl Future for TailReplySender {
     fn poll(&mut self) -> Poll<(), ()> {
         loop {
             match try_ready!(self.receiver.poll()) {
-                Some(TailReplyMsg::Register(client_id, listener)) => {
+                Some(TailReplyMsg::Register(client_id, sender, credit)) => {
                     trace!("Registered client {}", client_id);
-                    self.registered.insert(client_id, listener);
+                    self.registered
+                        .insert(client_id, Registration::new(sender, credit));
+                }
+                Some(TailReplyMsg::Grant(client_id, credit)) => {
+                    self.grant_credit(client_id, credit);
                 }
                 Some(TailReplyMsg::Notify(append_set)) => {
-                    self.notify_clients(append_set);
+                    self.notify_clients(append_set, false);
+                }
+                Some(TailReplyMsg::NotifyTopic(append_set)) => {
+                    self.notify_clients(append_set, true);
+                }
+                Some(TailReplyMsg::Duplicate(client_id, client_req_id)) => {
+                    self.acknowledge(client_id, client_req_id);
+                }
+                Some(TailReplyMsg::Goodbye(reason, res)) => {
+                    let subs = self.subscriptions();
+                    self.goodbye_clients(reason);
+                    res.send(subs).unwrap_or_default();
+                }
+                Some(TailReplyMsg::Subscriptions(res)) => {
+                    res.send(self.subscriptions()).unwrap_or_default();
                 }
                 None => {
                     warn!("Tail reply stream completed");
@@ -149,6 +419,7 @@ mod tests {
     use super::*;
     use asynclog::{Messages, MessagesMut};
     use bytes::BytesMut;
+    use commitlog::message::set_offsets;
     use futures::executor::{spawn, Notify, NotifyHandle, Spawn};
     use test::Bencher;
 
@@ -194,6 +465,62 @@ mod tests {
         assert_eq!(vec![vec![100, 200]], poll_client_ids(&mut client_2));
     }
 
+    #[test]
+    fn notify_duplicates() {
+        let handle = notify_noop();
+
+        let (reg, mut listener, sender) = fake_registrar();
+        let mut stream = spawn(sender);
+        let mut client = spawn(reg.listen(0));
+        assert!(!stream.poll_future_notify(&handle, 120).unwrap().is_ready());
+
+        listener.notify_append(msgs(vec![(0, 10)]));
+        listener.notify_duplicate(0, 11);
+        listener.notify_duplicate(1, 100);
+        assert!(!stream.poll_future_notify(&handle, 120).unwrap().is_ready());
+
+        assert_eq!(vec![vec![10], vec![11]], poll_client_ids(&mut client));
+    }
+
+    #[test]
+    fn flow_controlled_client() {
+        let handle = notify_noop();
+
+        let (reg, mut listener, sender) = fake_registrar();
+        let mut stream = spawn(sender);
+
+        let mut slow = spawn(reg.listen_with_credit(0, 2));
+        let mut fast = spawn(reg.listen(1));
+        assert!(!stream.poll_future_notify(&handle, 120).unwrap().is_ready());
+
+        let m = msgs(vec![(0, 10), (1, 100), (0, 20), (0, 30), (0, 40), (0, 50)]);
+        listener.notify_append(m);
+        assert!(!stream.poll_future_notify(&handle, 120).unwrap().is_ready());
+
+        // the server stops sending once the credit is exhausted
+        assert_eq!(vec![vec![10, 20]], poll_client_ids(&mut slow));
+        assert_eq!(vec![vec![100]], poll_client_ids(&mut fast));
+        listener.notify_append(msgs(vec![(0, 60)]));
+        assert!(!stream.poll_future_notify(&handle, 120).unwrap().is_ready());
+        assert!(poll_client_ids(&mut slow).is_empty());
+        assert_eq!(4, stream.get_ref().registered[&0].pending.len());
+
+        // and resumes, in order, when credit is granted
+        reg.grant(0, 3);
+        assert!(!stream.poll_future_notify(&handle, 120).unwrap().is_ready());
+        assert_eq!(vec![vec![30, 40, 50]], poll_client_ids(&mut slow));
+
+        reg.grant(0, 10);
+        assert!(!stream.poll_future_notify(&handle, 120).unwrap().is_ready());
+        assert_eq!(vec![vec![60]], poll_client_ids(&mut slow));
+        assert!(stream.get_ref().registered[&0].pending.is_empty());
+
+        // the remaining credit applies to later appends
+        listener.notify_append(msgs(vec![(0, 70)]));
+        assert!(!stream.poll_future_notify(&handle, 120).unwrap().is_ready());
+        assert_eq!(vec![vec![70]], poll_client_ids(&mut slow));
+    }
+
     #[test]
     fn notify_unknown_client() {
         let handle = notify_noop();
@@ -218,6 +545,161 @@ mod tests {
         assert!(poll_client_ids(&mut client_2).is_empty());
     }
 
+    #[test]
+    fn goodbye_clients() {
+        let handle = notify_noop();
+
+        let (reg, _, sender) = fake_registrar();
+        let mut stream = spawn(sender);
+
+        let mut client_1 = spawn(reg.listen(0));
+
+        // pool the stream to register
+        assert!(!stream.poll_future_notify(&handle, 120).unwrap().is_ready());
+        assert_eq!(1, stream.get_ref().registered.len());
+
+        let _ = reg.goodbye(GoodbyeReason::SHUTDOWN);
+        assert!(!stream.poll_future_notify(&handle, 120).unwrap().is_ready());
+        assert_eq!(0, stream.get_ref().registered.len());
+
+        assert_eq!(
+            Async::Ready(Some(ClientReply::Goodbye(GoodbyeReason::SHUTDOWN))),
+            client_1.poll_stream_notify(&handle, 0).unwrap()
+        );
+        assert_eq!(
+            Async::Ready(None),
+            client_1.poll_stream_notify(&handle, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn list_subscriptions() {
+        let handle = notify_noop();
+
+        let (reg, mut listener, sender) = fake_registrar();
+        let mut stream = spawn(sender);
+
+        let mut client_1 = spawn(reg.listen(0));
+        let mut client_2 = spawn(reg.listen(1));
+
+        // pool the stream to register
+        assert!(!stream.poll_future_notify(&handle, 120).unwrap().is_ready());
+        assert_eq!(
+            vec![
+                Subscription {
+                    client_id: 0,
+                    position: None,
+                    lag: 0,
+                },
+                Subscription {
+                    client_id: 1,
+                    position: None,
+                    lag: 0,
+                },
+            ],
+            stream.get_ref().subscriptions()
+        );
+
+        let mut m = MessagesMut(BytesMut::with_capacity(1024));
+        for (client_id, req_id) in &[(0, 10), (1, 100), (0, 20), (2, 1000)] {
+            m.push(*client_id, *req_id, b"123").unwrap();
+        }
+        set_offsets(&mut m, 5);
+        listener.notify_append(m.freeze());
+
+        // pool the stream to notify
+        assert!(!stream.poll_future_notify(&handle, 120).unwrap().is_ready());
+        assert_eq!(vec![vec![10, 20]], poll_client_ids(&mut client_1));
+        assert_eq!(vec![vec![100]], poll_client_ids(&mut client_2));
+
+        assert_eq!(
+            vec![
+                Subscription {
+                    client_id: 0,
+                    position: Some(7),
+                    lag: 1,
+                },
+                Subscription {
+                    client_id: 1,
+                    position: Some(6),
+                    lag: 2,
+                },
+            ],
+            stream.get_ref().subscriptions()
+        );
+    }
+
+    #[test]
+    fn topic_replies_keep_positions() {
+        let handle = notify_noop();
+
+        let (reg, mut listener, sender) = fake_registrar();
+        let mut topic_listener = reg.topic_listener();
+        let mut stream = spawn(sender);
+
+        let mut client = spawn(reg.listen(0));
+        assert!(!stream.poll_future_notify(&handle, 120).unwrap().is_ready());
+
+        let mut m = MessagesMut(BytesMut::with_capacity(1024));
+        m.push(0, 10, b"123").unwrap();
+        set_offsets(&mut m, 5);
+        listener.notify_append(m.freeze());
+
+        // the topic log has offsets of its own
+        let mut m = MessagesMut(BytesMut::with_capacity(1024));
+        m.push(0, 11, b"123").unwrap();
+        m.push(1, 100, b"123").unwrap();
+        set_offsets(&mut m, 100);
+        topic_listener.notify_append(m.freeze());
+
+        assert!(!stream.poll_future_notify(&handle, 120).unwrap().is_ready());
+        assert_eq!(vec![vec![10], vec![11]], poll_client_ids(&mut client));
+        assert_eq!(
+            vec![Subscription {
+                client_id: 0,
+                position: Some(5),
+                lag: 0,
+            }],
+            stream.get_ref().subscriptions()
+        );
+    }
+
+    #[test]
+    fn drain_clients() {
+        let handle = notify_noop();
+
+        let (reg, mut listener, sender) = fake_registrar();
+        let mut stream = spawn(sender);
+
+        let mut client_1 = spawn(reg.listen(0));
+
+        // pool the stream to register
+        assert!(!stream.poll_future_notify(&handle, 120).unwrap().is_ready());
+
+        let mut m = MessagesMut(BytesMut::with_capacity(1024));
+        m.push(0, 10, b"123").unwrap();
+        set_offsets(&mut m, 3);
+        listener.notify_append(m.freeze());
+
+        let mut drained = spawn(reg.begin_drain());
+        assert!(!stream.poll_future_notify(&handle, 120).unwrap().is_ready());
+        assert_eq!(0, stream.get_ref().registered.len());
+
+        assert_eq!(vec![vec![10]], poll_client_ids(&mut client_1));
+        assert_eq!(
+            Async::Ready(None),
+            client_1.poll_stream_notify(&handle, 0).unwrap()
+        );
+        assert_eq!(
+            Async::Ready(vec![Subscription {
+                client_id: 0,
+                position: Some(3),
+                lag: 0,
+            }]),
+            drained.poll_future_notify(&handle, 0).unwrap()
+        );
+    }
+
     #[bench]
     fn bench_notify_clients(b: &mut Bencher) {
         let mbuf = msgs(vec![(0, 10), (1, 100), (1, 200), (0, 20), (0, 30)]);
@@ -245,7 +727,9 @@ mod tests {
     fn poll_client_ids(s: &mut Spawn<ReplyStream>) -> Vec<Vec<u64>> {
         let mut req_id_batches = Vec::new();
         let handle = notify_noop();
-        while let Async::Ready(Some(v)) = s.poll_stream_notify(&handle, 0).unwrap() {
+        while let Async::Ready(Some(ClientReply::Appended(v))) =
+            s.poll_stream_notify(&handle, 0).unwrap()
+        {
             req_id_batches.push(v);
         }
         req_id_batches
@@ -257,10 +741,12 @@ mod tests {
         let reply_sndr = TailReplySender {
             receiver,
             registered: FnvHashMap::default(),
+            latest: None,
         };
 
         let listener = TailReplyListener {
             sender: sender.clone(),
+            topic: false,
         };
 
         let registrar = TailReplyRegistrar { sender };